@@ -0,0 +1,253 @@
+use std::path::{Component, Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tokio::time::Duration;
+use crate::broker::broker::Broker;
+use crate::consumer::shutdown::Shutdown;
+use crate::queue::lazy::LazyQueueConfig;
+use crate::storage::backend::StorageConfig;
+use crate::storage::storage::{ReencryptionReport, SnapshotManifest, Storage};
+use log::{debug, info};
+
+/// Address the admin API listens on unless configured otherwise.
+pub const DEFAULT_ADMIN_ADDRESS: &str = "127.0.0.1:9101";
+
+/// Longest request line the admin API reads, in bytes; longer requests are skipped unread.
+pub const MAX_REQUEST_BYTES: usize = 64 * 1024;
+
+/// A request to the admin API, sent as one JSON object per line,
+/// e.g. `{"command":"snapshot","dir":"2024-06-01"}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminRequest {
+    /// Lists the declared queues.
+    ListQueues,
+    /// Takes a snapshot of every queue into `dir`, a relative path under the broker's
+    /// snapshot directory (see `AdminApi::with_snapshot_dir`).
+    Snapshot { dir: String },
+    /// Re-encrypts every stored message that is not under the active key; see `Storage::reencrypt`.
+    Reencrypt,
+}
+
+/// The admin API's answer to a request, sent back as one JSON object per line.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AdminResponse {
+    /// The declared queues, sorted by name.
+    Queues { queues: Vec<String> },
+    /// The snapshot was taken.
+    Snapshot { manifest: SnapshotManifest },
+    /// The re-encryption pass finished.
+    Reencrypted { report: ReencryptionReport },
+    /// The request failed.
+    Error { message: String },
+}
+
+/// Administrative operations on a broker and its storage.
+#[derive(Debug, Clone)]
+pub struct AdminApi {
+    broker: Broker,                 // The broker being administered
+    storage: Storage,               // Storage holding the broker's queues
+    snapshot_dir: Option<PathBuf>,  // Directory snapshots are written under, if they are allowed
+    shutdown: Shutdown,             // Signal that stops serving
+}
+
+impl AdminApi {
+    /// Creates an admin API for a broker whose queues are kept in `storage`.
+    ///
+    /// Snapshot requests are refused until a snapshot directory is set with `with_snapshot_dir`.
+    pub fn new(broker: Broker, storage: Storage) -> AdminApi {
+        AdminApi { broker, storage, snapshot_dir: None, shutdown: Shutdown::new() }
+    }
+
+    /// Stops serving when the given signal is triggered.
+    ///
+    /// # Arguments
+    ///
+    /// * `shutdown` - The signal to observe.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> AdminApi {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Allows snapshots, writing them under `dir`.
+    ///
+    /// Snapshot requests name a relative path under this directory; absolute paths and paths
+    /// with `..` are refused, so clients cannot write anywhere else on the broker's host.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory snapshots are written under.
+    pub fn with_snapshot_dir(mut self, dir: impl Into<PathBuf>) -> AdminApi {
+        self.snapshot_dir = Some(dir.into());
+        self
+    }
+
+    /// Boots a broker from storage; see `Broker::recover`.
+    ///
+    /// # Arguments
+    ///
+    /// * `storage` - The storage holding the queues.
+    /// * `config` - How much of each queue may be held in memory.
+    pub async fn boot(storage: Storage, config: LazyQueueConfig) -> Result<AdminApi, String> {
+        let broker = Broker::recover(&storage, config).await.map_err(|e| format!("{:?}", e))?;
+        Ok(AdminApi::new(broker, storage))
+    }
+
+    /// Boots a broker from a snapshot, restoring it into an empty storage engine first.
+    ///
+    /// # Arguments
+    ///
+    /// * `snapshot_dir` - The snapshot directory, as written by a `Snapshot` request.
+    /// * `target` - The engine the broker will use; it must not hold any data.
+    /// * `config` - How much of each queue may be held in memory.
+    pub async fn restore(snapshot_dir: &str, target: &StorageConfig, config: LazyQueueConfig) -> Result<AdminApi, String> {
+        let (snapshot_dir, target) = (snapshot_dir.to_string(), target.clone());
+        let storage = tokio::task::spawn_blocking(move || Storage::restore(&snapshot_dir, &target))
+            .await
+            .map_err(|e| e.to_string())??;
+        AdminApi::boot(storage, config).await
+    }
+
+    /// Returns the administered broker.
+    pub fn broker(&self) -> &Broker {
+        &self.broker
+    }
+
+    /// Returns the storage holding the broker's queues.
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    /// Executes a request.
+    pub async fn handle(&self, request: AdminRequest) -> AdminResponse {
+        match request {
+            AdminRequest::ListQueues => {
+                let mut queues = self.broker.queue_names().await;
+                queues.sort();
+                AdminResponse::Queues { queues }
+            }
+            AdminRequest::Snapshot { dir } => {
+                let dir = match self.resolve_snapshot_dir(&dir) {
+                    Ok(dir) => dir,
+                    Err(message) => return AdminResponse::Error { message },
+                };
+                let storage = self.storage.clone();
+                match tokio::task::spawn_blocking(move || storage.snapshot(&dir.to_string_lossy())).await {
+                    Ok(Ok(manifest)) => AdminResponse::Snapshot { manifest },
+                    Ok(Err(message)) => AdminResponse::Error { message },
+                    Err(e) => AdminResponse::Error { message: e.to_string() },
+                }
+            }
+            AdminRequest::Reencrypt => match self.storage.reencrypt(Duration::ZERO).await {
+                Ok(report) => AdminResponse::Reencrypted { report },
+                Err(message) => AdminResponse::Error { message },
+            },
+        }
+    }
+
+    /// Returns where a snapshot requested into `dir` is written, or why it is refused.
+    fn resolve_snapshot_dir(&self, dir: &str) -> Result<PathBuf, String> {
+        let root = self.snapshot_dir.as_ref().ok_or("snapshots are disabled: no snapshot directory is configured")?;
+        let path = Path::new(dir);
+        let relative = path.components().next().is_some() && path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if !relative {
+            return Err(format!("snapshot directory {:?} must be a relative path without '..'", dir));
+        }
+        Ok(root.join(path))
+    }
+
+    /// Serves requests on a TCP address until the listener fails or the shutdown signal is triggered.
+    ///
+    /// Each connection may send any number of requests, one JSON object per line, and gets
+    /// one response line per request. A line longer than `MAX_REQUEST_BYTES` is skipped without
+    /// being buffered and answered with an error. On shutdown no more connections are accepted,
+    /// and this returns once every open connection has answered the request it was handling.
+    pub async fn serve(&self, address: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind(address).await?;
+        info!("Admin API listening on {}", address);
+        let mut connections = JoinSet::new();
+
+        loop {
+            let (socket, peer) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = self.shutdown.triggered() => break,
+            };
+            let api = self.clone();
+            // Reap finished connections so the set does not grow with every client
+            while connections.try_join_next().is_some() {}
+            connections.spawn(async move {
+                let (reader, mut writer) = socket.into_split();
+                let mut reader = BufReader::new(reader);
+                let mut line = Vec::new();
+                loop {
+                    // Reads one byte past the limit to tell a line at the limit from a longer one
+                    line.clear();
+                    let mut limited = (&mut reader).take(MAX_REQUEST_BYTES as u64 + 1);
+                    let read = tokio::select! {
+                        read = limited.read_until(b'\n', &mut line) => read,
+                        _ = api.shutdown.triggered() => break,
+                    };
+                    match read {
+                        Ok(0) | Err(_) => break, // The client closed the connection
+                        Ok(_) => {}
+                    }
+                    let too_long = line.len() > MAX_REQUEST_BYTES && line.last() != Some(&b'\n');
+                    if too_long {
+                        // Skip the rest of the line a chunk at a time
+                        while line.last() != Some(&b'\n') {
+                            line.clear();
+                            if !matches!((&mut reader).take(MAX_REQUEST_BYTES as u64).read_until(b'\n', &mut line).await, Ok(1..)) {
+                                break;
+                            }
+                        }
+                    }
+
+                    let response = if too_long {
+                        AdminResponse::Error { message: format!("request longer than {} bytes", MAX_REQUEST_BYTES) }
+                    } else {
+                        match serde_json::from_slice::<AdminRequest>(&line) {
+                            Ok(request) => {
+                                debug!("Admin request from {}: {:?}", peer, request);
+                                api.handle(request).await
+                            }
+                            Err(e) => AdminResponse::Error { message: format!("invalid request: {}", e) },
+                        }
+                    };
+
+                    let mut json = serde_json::to_vec(&response).unwrap_or_default();
+                    json.push(b'\n');
+                    if writer.write_all(&json).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        info!("Admin API stopped accepting connections, waiting for {} open ones", connections.len());
+        while connections.join_next().await.is_some() {}
+        Ok(())
+    }
+}
+
+/// Sends one request to the admin API at `address` and waits for its response.
+///
+/// # Arguments
+///
+/// * `address` - The address the admin API listens on.
+/// * `request` - The request to send.
+pub async fn send_request(address: &str, request: &AdminRequest) -> Result<AdminResponse, String> {
+    let socket = TcpStream::connect(address).await.map_err(|e| format!("cannot reach the admin API at {}: {}", address, e))?;
+    let (reader, mut writer) = socket.into_split();
+
+    let mut json = serde_json::to_vec(request).map_err(|e| e.to_string())?;
+    json.push(b'\n');
+    writer.write_all(&json).await.map_err(|e| e.to_string())?;
+
+    match BufReader::new(reader).lines().next_line().await.map_err(|e| e.to_string())? {
+        Some(line) => serde_json::from_str(&line).map_err(|e| e.to_string()),
+        None => Err("the admin API closed the connection without answering".to_string()),
+    }
+}
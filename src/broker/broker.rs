@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{Mutex, RwLock};
+use uuid::Uuid;
+use tokio::time::Duration;
+use crate::codec::codec::Codec;
+use crate::broker::consumer_group::{ConsumerGroup, GroupMembership, GroupStats};
+use crate::queue::lazy::LazyQueueConfig;
+use crate::queue::stream::Stream;
+use crate::queue::{Message, Queue, QueueError};
+use crate::storage::storage::Storage;
+use log::warn;
+
+/// Registry of named queues shared by the producers and consumers of a node.
+#[derive(Debug, Clone)]
+pub struct Broker {
+    queues: Arc<RwLock<HashMap<String, Queue>>>,          // Queues indexed by name
+    streams: Arc<RwLock<HashMap<String, Stream>>>,        // Streams indexed by name
+    groups: Arc<RwLock<HashMap<String, SharedGroup>>>,    // Consumer groups indexed by name
+}
+
+/// A consumer group, locked on its own so operations on different groups do not wait for each other.
+type SharedGroup = Arc<Mutex<ConsumerGroup>>;
+
+impl Default for Broker {
+    fn default() -> Self {
+        Broker::new()
+    }
+}
+
+impl Broker {
+    /// Creates a new broker with no declared queues.
+    pub fn new() -> Broker {
+        Broker {
+            queues: Arc::new(RwLock::new(HashMap::new())),
+            streams: Arc::new(RwLock::new(HashMap::new())),
+            groups: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Declares a queue, creating it if it does not exist yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the queue.
+    ///
+    /// Returns a handle to the (possibly pre-existing) queue.
+    pub async fn declare_queue(&self, name: &str) -> Queue {
+        let mut queues = self.queues.write().await;
+        queues.entry(name.to_string()).or_insert_with(Queue::new).clone()
+    }
+
+    /// Declares a queue whose typed messages are encoded with `codec`, creating it if it does
+    /// not exist yet; see `Queue::set_codec`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the queue.
+    /// * `codec` - The codec typed producers and consumers use for the queue's messages.
+    ///
+    /// Returns a handle to the (possibly pre-existing) queue, now using `codec`.
+    pub async fn declare_queue_with_codec<C: Codec>(&self, name: &str, codec: &C) -> Queue {
+        let queue = self.declare_queue(name).await;
+        queue.set_codec(codec).await;
+        queue
+    }
+
+    /// Boots a broker from storage, declaring a lazy queue for every queue in its catalog and a
+    /// stream for every stream.
+    ///
+    /// Each queue pages its own backlog in as it is consumed; nothing is loaded up front.
+    ///
+    /// # Arguments
+    ///
+    /// * `storage` - The storage to recover the queues from.
+    /// * `config` - How much of each queue may be held in memory.
+    pub async fn recover(storage: &Storage, config: LazyQueueConfig) -> Result<Broker, QueueError> {
+        let broker = Broker::new();
+        for queue in storage.queues().map_err(QueueError::Storage)? {
+            if storage.queue(&queue.name).and_then(|scoped| scoped.is_stream()).map_err(QueueError::Storage)? {
+                broker.declare_stream(&queue.name, storage).await?;
+            } else {
+                broker.declare_lazy_queue(&queue.name, storage, config).await?;
+            }
+        }
+        Ok(broker)
+    }
+
+    /// Declares a stream kept in storage, creating it if it does not exist yet.
+    ///
+    /// If a stream with this name was already declared, it is returned unchanged. Fails with
+    /// `QueueError::NameInUse` if the name belongs to a queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the stream.
+    /// * `storage` - The storage to keep the stream in.
+    pub async fn declare_stream(&self, name: &str, storage: &Storage) -> Result<Stream, QueueError> {
+        let mut streams = self.streams.write().await;
+        if let Some(stream) = streams.get(name) {
+            return Ok(stream.clone());
+        }
+        if self.queues.read().await.contains_key(name) {
+            return Err(QueueError::NameInUse(name.to_string()));
+        }
+        let stream = Stream::open(storage, name)?;
+        streams.insert(name.to_string(), stream.clone());
+        Ok(stream)
+    }
+
+    /// Returns the stream with the given name, if it has been declared.
+    pub async fn get_stream(&self, name: &str) -> Option<Stream> {
+        self.streams.read().await.get(name).cloned()
+    }
+
+    /// Returns the names of the declared queues, in no particular order.
+    pub async fn queue_names(&self) -> Vec<String> {
+        self.queues.read().await.keys().cloned().collect()
+    }
+
+    /// Declares a lazy queue backed by storage, creating it if it does not exist yet.
+    ///
+    /// The queue keeps its messages in its own keyspace of `storage` and only a bounded window
+    /// of them in memory; see `Queue::lazy`. Messages the keyspace already holds, e.g. from
+    /// before a restart, are delivered once they are due. If a queue with this name was already
+    /// declared, it is returned unchanged. Fails with `QueueError::NameInUse` if the name belongs
+    /// to a stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the queue.
+    /// * `storage` - The storage to keep the queue's messages in.
+    /// * `config` - How much of the queue may be held in memory.
+    pub async fn declare_lazy_queue(&self, name: &str, storage: &Storage, config: LazyQueueConfig) -> Result<Queue, QueueError> {
+        let mut queues = self.queues.write().await;
+        if let Some(queue) = queues.get(name) {
+            return Ok(queue.clone());
+        }
+
+        let scoped = storage.queue(name).map_err(QueueError::Storage)?;
+        if self.streams.read().await.contains_key(name) || scoped.is_stream().map_err(QueueError::Storage)? {
+            return Err(QueueError::NameInUse(name.to_string()));
+        }
+        let queue = Queue::lazy(scoped, config);
+        queues.insert(name.to_string(), queue.clone());
+        Ok(queue)
+    }
+
+    /// Declares a temporary queue with a unique, broker-generated name.
+    ///
+    /// Temporary queues are meant to be short-lived (e.g. reply queues) and
+    /// should be removed with `delete_queue` once they are no longer needed.
+    ///
+    /// Returns the generated name together with a handle to the queue.
+    pub async fn declare_temporary_queue(&self) -> (String, Queue) {
+        let name = format!("tmp.{}", Uuid::new_v4());
+        let queue = self.declare_queue(&name).await;
+        (name, queue)
+    }
+
+    /// Returns the queue with the given name, if it has been declared.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the queue.
+    pub async fn get_queue(&self, name: &str) -> Option<Queue> {
+        let queues = self.queues.read().await;
+        queues.get(name).cloned()
+    }
+
+    /// Removes a queue from the broker.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the queue to remove.
+    ///
+    /// Returns the removed queue, or `None` if no queue had that name.
+    pub async fn delete_queue(&self, name: &str) -> Option<Queue> {
+        let mut queues = self.queues.write().await;
+        queues.remove(name)
+    }
+
+    /// Joins a consumer group, registering the group first if it does not exist yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `group` - The name of the consumer group.
+    /// * `queue` - The name of the queue the group consumes from; it is declared if needed.
+    /// * `session_timeout` - How long members may go without a heartbeat before they are considered dead.
+    ///
+    /// The queue and session timeout of an existing group are kept as they are.
+    /// Returns the membership of the new member.
+    pub async fn join_group(&self, group: &str, queue: &str, session_timeout: Duration) -> GroupMembership {
+        let queue = self.declare_queue(queue).await;
+        let consumer_group = self
+            .groups
+            .write()
+            .await
+            .entry(group.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(ConsumerGroup::new(group, queue, session_timeout))))
+            .clone();
+
+        let mut consumer_group = consumer_group.lock().await;
+        let member_id = consumer_group.join();
+        GroupMembership::new(self.clone(), group, member_id, consumer_group.session_timeout())
+    }
+
+    /// Applies an operation to a member's group after reclaiming the leases of the group's
+    /// dead members.
+    ///
+    /// Only that group is locked while the operation runs. Returns `None` if no group with
+    /// the given name exists.
+    pub(crate) async fn with_group<R>(&self, group: &str, operation: impl FnOnce(&mut ConsumerGroup) -> R) -> Option<R> {
+        let consumer_group = self.groups.read().await.get(group).cloned()?;
+        let (result, queue, reclaimed) = {
+            let mut consumer_group = consumer_group.lock().await;
+            let reclaimed = consumer_group.expire_members();
+            (operation(&mut consumer_group), consumer_group.queue().clone(), reclaimed)
+        };
+        reclaim(&queue, reclaimed).await;
+        Some(result)
+    }
+
+    /// Removes the members that missed their heartbeats from every group and returns
+    /// the messages they held to their queues.
+    ///
+    /// Group operations only do this for their own group, so this should run periodically
+    /// (see `Scheduler::start_group_reaper`) to detect dead members of idle groups.
+    ///
+    /// Returns the number of messages reclaimed.
+    pub async fn reap_dead_members(&self) -> usize {
+        let groups: Vec<SharedGroup> = self.groups.read().await.values().cloned().collect();
+        let mut count = 0;
+        for consumer_group in groups {
+            let (queue, reclaimed) = {
+                let mut consumer_group = consumer_group.lock().await;
+                (consumer_group.queue().clone(), consumer_group.expire_members())
+            };
+            count += reclaim(&queue, reclaimed).await;
+        }
+        count
+    }
+
+    /// Returns the member list, lag and per-member throughput of a consumer group.
+    ///
+    /// # Arguments
+    ///
+    /// * `group` - The name of the consumer group.
+    ///
+    /// Returns `Ok(None)` if no group with the given name exists.
+    pub async fn group_stats(&self, group: &str) -> Result<Option<GroupStats>, QueueError> {
+        let Some(consumer_group) = self.groups.read().await.get(group).cloned() else { return Ok(None) };
+        let (queue, reclaimed) = {
+            let mut consumer_group = consumer_group.lock().await;
+            (consumer_group.queue().clone(), consumer_group.expire_members())
+        };
+        reclaim(&queue, reclaimed).await;
+        let stats = consumer_group.lock().await.stats().await?;
+        Ok(Some(stats))
+    }
+}
+
+/// Returns the messages held by dead group members to their queue, and returns how many were
+/// returned.
+async fn reclaim(queue: &Queue, messages: Vec<Message>) -> usize {
+    let mut count = 0;
+    for message in messages {
+        match queue.requeue(message).await {
+            Ok(()) => count += 1,
+            Err(e) => warn!("Failed to reclaim message of a dead group member: {:?}", e),
+        }
+    }
+    count
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+use uuid::Uuid;
+use crate::broker::broker::Broker;
+use crate::queue::{Message, Queue, QueueError};
+use log::{info, warn};
+
+/// How long a member may go without a heartbeat before it is considered dead.
+pub const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A member of a consumer group, with the messages it currently holds.
+#[derive(Debug, Clone)]
+struct GroupMember {
+    joined_at: Instant,
+    last_heartbeat: Instant,
+    leases: HashMap<u64, Message>,   // Messages fetched by this member and not yet settled
+    processed: u64,                  // Messages settled by this member since it joined
+}
+
+/// A named set of consumers sharing the messages of one queue.
+///
+/// Every message a member fetches is leased to it until settled. When a member leaves
+/// or misses heartbeats for longer than the session timeout, its leases are returned
+/// to the queue right away so other members can pick them up.
+#[derive(Debug, Clone)]
+pub struct ConsumerGroup {
+    name: String,
+    queue: Queue,
+    session_timeout: Duration,
+    members: HashMap<Uuid, GroupMember>,
+}
+
+/// Operational statistics of a consumer group.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupStats {
+    /// The name of the group.
+    pub name: String,
+    /// Messages still in the group's queue (ready or delayed).
+    pub lag: usize,
+    /// Statistics of every live member.
+    pub members: Vec<MemberStats>,
+}
+
+/// Operational statistics of a consumer group member.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemberStats {
+    /// The member id assigned when the member joined.
+    pub member_id: Uuid,
+    /// Messages currently leased to the member.
+    pub in_flight: usize,
+    /// Messages settled by the member since it joined.
+    pub processed: u64,
+    /// Average messages settled per second since the member joined.
+    pub throughput: f64,
+    /// Time since the member's last heartbeat.
+    pub since_heartbeat: Duration,
+}
+
+impl ConsumerGroup {
+    /// Creates a new group with no members.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the group.
+    /// * `queue` - The queue the group's members consume from.
+    /// * `session_timeout` - How long a member may go without a heartbeat before it is considered dead.
+    pub fn new(name: &str, queue: Queue, session_timeout: Duration) -> ConsumerGroup {
+        ConsumerGroup {
+            name: name.to_string(),
+            queue,
+            session_timeout,
+            members: HashMap::new(),
+        }
+    }
+
+    /// Returns the queue the group's members consume from.
+    pub fn queue(&self) -> &Queue {
+        &self.queue
+    }
+
+    /// Returns how long a member may go without a heartbeat before it is considered dead.
+    pub fn session_timeout(&self) -> Duration {
+        self.session_timeout
+    }
+
+    /// Adds a new member and returns its member id.
+    pub fn join(&mut self) -> Uuid {
+        let member_id = Uuid::new_v4();
+        let now = Instant::now();
+        self.members.insert(member_id, GroupMember {
+            joined_at: now,
+            last_heartbeat: now,
+            leases: HashMap::new(),
+            processed: 0,
+        });
+        info!("Member {:?} joined consumer group {}", member_id, self.name);
+        member_id
+    }
+
+    /// Records a heartbeat from a member.
+    ///
+    /// Returns `false` if the member is unknown (e.g. it was already declared dead).
+    pub fn heartbeat(&mut self, member_id: Uuid) -> bool {
+        match self.members.get_mut(&member_id) {
+            Some(member) => {
+                member.last_heartbeat = Instant::now();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Leases a fetched message to a member.
+    ///
+    /// Returns `false` if the member is unknown, in which case the caller should return the message.
+    pub fn lease(&mut self, member_id: Uuid, message: &Message) -> bool {
+        match self.members.get_mut(&member_id) {
+            Some(member) => {
+                member.leases.insert(message.id, message.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Releases a lease after the member settled the message (ack, retry or dead-letter).
+    pub fn complete(&mut self, member_id: Uuid, message_id: u64) {
+        if let Some(member) = self.members.get_mut(&member_id) {
+            if member.leases.remove(&message_id).is_some() {
+                member.processed += 1;
+            }
+        }
+    }
+
+    /// Releases a lease after the member returned the message to the queue itself.
+    pub fn release(&mut self, member_id: Uuid, message_id: u64) {
+        if let Some(member) = self.members.get_mut(&member_id) {
+            member.leases.remove(&message_id);
+        }
+    }
+
+    /// Removes a member and returns the messages it still held.
+    pub fn leave(&mut self, member_id: Uuid) -> Vec<Message> {
+        match self.members.remove(&member_id) {
+            Some(member) => {
+                info!("Member {:?} left consumer group {} holding {} leases", member_id, self.name, member.leases.len());
+                member.leases.into_values().collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Removes members that missed their heartbeats and returns the messages they held.
+    pub fn expire_members(&mut self) -> Vec<Message> {
+        let now = Instant::now();
+        let dead: Vec<Uuid> = self
+            .members
+            .iter()
+            .filter(|(_, member)| now.duration_since(member.last_heartbeat) > self.session_timeout)
+            .map(|(member_id, _)| *member_id)
+            .collect();
+
+        let mut reclaimed = Vec::new();
+        for member_id in dead {
+            warn!("Member {:?} of consumer group {} missed its heartbeats", member_id, self.name);
+            reclaimed.extend(self.leave(member_id));
+        }
+        reclaimed
+    }
+
+    /// Returns the group's statistics.
+    pub async fn stats(&self) -> Result<GroupStats, QueueError> {
+        let now = Instant::now();
+        let mut members: Vec<MemberStats> = self
+            .members
+            .iter()
+            .map(|(member_id, member)| {
+                let alive_for = now.duration_since(member.joined_at).as_secs_f64();
+                MemberStats {
+                    member_id: *member_id,
+                    in_flight: member.leases.len(),
+                    processed: member.processed,
+                    throughput: if alive_for > 0.0 { member.processed as f64 / alive_for } else { 0.0 },
+                    since_heartbeat: now.duration_since(member.last_heartbeat),
+                }
+            })
+            .collect();
+        members.sort_by_key(|member| member.member_id);
+
+        Ok(GroupStats {
+            name: self.name.clone(),
+            lag: self.queue.pending_count().await?,
+            members,
+        })
+    }
+}
+
+/// A consumer's membership in a consumer group, as returned by `Broker::join_group`.
+///
+/// Clones refer to the same member, and follow it when it rejoins.
+#[derive(Debug, Clone)]
+pub struct GroupMembership {
+    broker: Broker,              // Broker the group is registered with
+    group: String,               // Name of the group
+    member_id: Arc<Mutex<Uuid>>, // Id assigned to this member when it last joined
+    session_timeout: Duration,   // Heartbeat deadline of the group
+}
+
+impl GroupMembership {
+    pub(crate) fn new(broker: Broker, group: &str, member_id: Uuid, session_timeout: Duration) -> GroupMembership {
+        GroupMembership {
+            broker,
+            group: group.to_string(),
+            member_id: Arc::new(Mutex::new(member_id)),
+            session_timeout,
+        }
+    }
+
+    /// Returns the id assigned to this member when it last joined.
+    pub fn member_id(&self) -> Uuid {
+        *self.member_id.lock().unwrap()
+    }
+
+    /// Returns the name of the group.
+    pub fn group(&self) -> &str {
+        &self.group
+    }
+
+    /// Returns how often the member should heartbeat to stay comfortably within the session timeout.
+    pub fn heartbeat_interval(&self) -> Duration {
+        self.session_timeout / 3
+    }
+
+    /// Tells the group this member is still alive.
+    ///
+    /// Returns `false` if the member is no longer part of the group, e.g. because it
+    /// missed its heartbeats and its leases were reclaimed.
+    pub async fn heartbeat(&self) -> bool {
+        let member_id = self.member_id();
+        self.broker
+            .with_group(&self.group, |group| group.heartbeat(member_id))
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Joins the group again under a new member id, after the group dropped this member
+    /// (e.g. because it missed its heartbeats).
+    ///
+    /// Returns `false` if the group no longer exists.
+    pub async fn rejoin(&self) -> bool {
+        match self.broker.with_group(&self.group, |group| group.join()).await {
+            Some(member_id) => {
+                *self.member_id.lock().unwrap() = member_id;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records that this member fetched a message and holds it until it is settled.
+    ///
+    /// Returns `false` if the member is no longer part of the group.
+    pub async fn lease(&self, message: &Message) -> bool {
+        let member_id = self.member_id();
+        self.broker
+            .with_group(&self.group, |group| group.lease(member_id, message))
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Records that this member settled a leased message (ack, retry or dead-letter).
+    pub async fn complete(&self, message_id: u64) {
+        let member_id = self.member_id();
+        self.broker.with_group(&self.group, |group| group.complete(member_id, message_id)).await;
+    }
+
+    /// Records that this member returned a leased message to the queue unprocessed.
+    pub async fn release(&self, message_id: u64) {
+        let member_id = self.member_id();
+        self.broker.with_group(&self.group, |group| group.release(member_id, message_id)).await;
+    }
+
+    /// Leaves the group, returning any messages still leased to this member to the queue.
+    ///
+    /// Returns the number of messages returned.
+    pub async fn leave(&self) -> Result<usize, QueueError> {
+        let member_id = self.member_id();
+        let left = self
+            .broker
+            .with_group(&self.group, |group| (group.queue().clone(), group.leave(member_id)))
+            .await;
+
+        match left {
+            Some((queue, leases)) => {
+                let count = leases.len();
+                for message in leases {
+                    queue.requeue(message).await?;
+                }
+                Ok(count)
+            }
+            None => Ok(0),
+        }
+    }
+}
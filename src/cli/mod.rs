@@ -0,0 +1,148 @@
+use crate::api::admin::{send_request, AdminRequest, AdminResponse, DEFAULT_ADMIN_ADDRESS};
+use crate::storage::backend::StorageConfig;
+use crate::storage::encryption::Keyring;
+use crate::storage::storage::Storage;
+
+/// Usage shown when the command line cannot be parsed.
+pub const USAGE: &str = "\
+usage:
+  hexbolt [serve] [--data-dir DIR] [--restore-from SNAPSHOT_DIR] [--keyfile FILE] [--snapshot-dir DIR] [--admin ADDRESS]
+  hexbolt snapshot NAME [--admin ADDRESS]
+  hexbolt reencrypt [--admin ADDRESS]
+  hexbolt restore SNAPSHOT_DIR DATA_DIR
+  hexbolt verify DATA_DIR [--keyfile FILE] [--repair]";
+
+/// A command given on the `hexbolt` command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Runs the broker; with a data directory, its queues are kept in RocksDB there and the
+    /// admin API is served. A snapshot to restore into the (empty) data directory first, a
+    /// keyfile to encrypt stored messages with, and a directory to allow snapshots under may
+    /// be given.
+    Serve { data_dir: Option<String>, restore_from: Option<String>, keyfile: Option<String>, snapshot_dir: Option<String>, admin_address: String },
+    /// Asks a running broker to take a snapshot into a directory under its snapshot directory.
+    Snapshot { dir: String, admin_address: String },
+    /// Asks a running broker to re-encrypt its stored messages with its active key.
+    Reencrypt { admin_address: String },
+    /// Restores a snapshot into an empty RocksDB data directory without starting the broker.
+    Restore { snapshot_dir: String, data_dir: String },
+    /// Checks that every stored message in a RocksDB or file log data directory no broker is
+    /// using can be read, decrypting with a keyfile if one is given, and migrates the data and
+    /// quarantines the corrupted messages if asked to.
+    Verify { data_dir: String, keyfile: Option<String>, repair: bool },
+}
+
+/// Parses the command-line arguments, without the program name.
+///
+/// # Arguments
+///
+/// * `args` - The arguments, e.g. `["snapshot", "today"]`.
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut positional = Vec::new();
+    let mut data_dir = None;
+    let mut restore_from = None;
+    let mut keyfile = None;
+    let mut snapshot_dir = None;
+    let mut repair = false;
+    let mut admin_address = DEFAULT_ADMIN_ADDRESS.to_string();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| args.next().ok_or_else(|| format!("{} needs a value", flag));
+        match arg.as_str() {
+            "--data-dir" => data_dir = Some(value("--data-dir")?),
+            "--restore-from" => restore_from = Some(value("--restore-from")?),
+            "--keyfile" => keyfile = Some(value("--keyfile")?),
+            "--snapshot-dir" => snapshot_dir = Some(value("--snapshot-dir")?),
+            "--admin" => admin_address = value("--admin")?,
+            "--repair" => repair = true,
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            _ => positional.push(arg),
+        }
+    }
+
+    let positional: Vec<&str> = positional.iter().map(String::as_str).collect();
+    match positional.as_slice() {
+        [] | ["serve"] => {
+            if restore_from.is_some() && data_dir.is_none() {
+                return Err("--restore-from needs --data-dir".to_string());
+            }
+            if keyfile.is_some() && data_dir.is_none() {
+                return Err("--keyfile needs --data-dir".to_string());
+            }
+            if snapshot_dir.is_some() && data_dir.is_none() {
+                return Err("--snapshot-dir needs --data-dir".to_string());
+            }
+            Ok(Command::Serve { data_dir, restore_from, keyfile, snapshot_dir, admin_address })
+        }
+        ["snapshot", dir] => Ok(Command::Snapshot { dir: dir.to_string(), admin_address }),
+        ["reencrypt"] => Ok(Command::Reencrypt { admin_address }),
+        ["restore", snapshot_dir, data_dir] => Ok(Command::Restore { snapshot_dir: snapshot_dir.to_string(), data_dir: data_dir.to_string() }),
+        ["verify", data_dir] => Ok(Command::Verify { data_dir: data_dir.to_string(), keyfile, repair }),
+        _ => Err(format!("unexpected arguments: {}", positional.join(" "))),
+    }
+}
+
+/// Runs the `snapshot` command against a running broker.
+pub async fn snapshot(dir: &str, admin_address: &str) -> Result<(), String> {
+    match send_request(admin_address, &AdminRequest::Snapshot { dir: dir.to_string() }).await? {
+        AdminResponse::Snapshot { manifest } => {
+            println!("Snapshot {} written with {} queues", dir, manifest.queues.len());
+            for queue in manifest.queues {
+                println!("  {}", queue.name);
+            }
+            Ok(())
+        }
+        AdminResponse::Error { message } => Err(message),
+        other => Err(format!("unexpected response: {:?}", other)),
+    }
+}
+
+/// Runs the `reencrypt` command against a running broker.
+pub async fn reencrypt(admin_address: &str) -> Result<(), String> {
+    match send_request(admin_address, &AdminRequest::Reencrypt).await? {
+        AdminResponse::Reencrypted { report } => {
+            println!("Re-encrypted {} of {} stored messages", report.rewritten, report.scanned);
+            Ok(())
+        }
+        AdminResponse::Error { message } => Err(message),
+        other => Err(format!("unexpected response: {:?}", other)),
+    }
+}
+
+/// Runs the `restore` command.
+pub fn restore(snapshot_dir: &str, data_dir: &str) -> Result<(), String> {
+    let manifest = Storage::read_snapshot_manifest(snapshot_dir)?;
+    Storage::restore(snapshot_dir, &StorageConfig::RocksDb { path: data_dir.to_string() })?;
+    println!("Restored {} queues from {} into {}", manifest.queues.len(), snapshot_dir, data_dir);
+    Ok(())
+}
+
+/// Runs the `verify` command; fails if corrupted records were found and left in place.
+///
+/// The data directory is only written to with `repair`: data in an older layout is then
+/// migrated first, and is refused otherwise.
+pub fn verify(data_dir: &str, keyfile: Option<&str>, repair: bool) -> Result<(), String> {
+    let storage = Storage::with_backend(StorageConfig::detect(data_dir)?.open()?);
+    let storage = match keyfile {
+        Some(keyfile) => storage.with_encryption(Keyring::load(keyfile)?),
+        None => storage,
+    };
+    if repair {
+        storage.migrate()?;
+    } else if storage.needs_migration()? {
+        return Err(format!("{} holds data in an older layout; run with --repair to migrate it before verifying", data_dir));
+    }
+
+    let report = storage.verify(repair)?;
+    for record in &report.corrupted {
+        println!("  corrupted {:?} record of message {} in queue {}", record.kind, record.message_id, record.queue);
+    }
+    if report.unverified > 0 {
+        println!("  {} encrypted records were only checked against their checksums; pass --keyfile to decrypt them", report.unverified);
+    }
+    if report.corrupted.len() > report.quarantined {
+        return Err(format!("{} corrupted records found; run with --repair to quarantine them", report.corrupted.len() - report.quarantined));
+    }
+    Ok(())
+}
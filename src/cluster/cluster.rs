@@ -2,13 +2,46 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::{RwLock, Mutex};
 use uuid::Uuid;
-use tokio::time::{Duration, Instant};
-use tokio::net::TcpListener;
+use tokio::time::{timeout, Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use crate::storage::backend::{BatchOp, StorageBackend};
+use crate::utils::id_generator;
+use log::{debug, info, warn};
+
+/// How long to wait for a peer to acknowledge a replicated record.
+const REPLICATION_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Byte sent back by a node once it has persisted a replicated record.
+const REPLICATION_ACK: u8 = 1;
+
+/// Largest record that can be replicated; a peer announcing a longer frame is disconnected
+/// before anything is allocated for it.
+pub const MAX_RECORD_LEN: u32 = 16 * 1024 * 1024;
+
+/// Key prefix of the records replicated to this node: `r/ | sequence number (u64)`.
+const REPLICA_PREFIX: &[u8] = b"r/";
+
+/// Key of the sequence number of the next replicated record.
+const REPLICA_NEXT_KEY: &[u8] = b"c/next_replica_seq";
+
+/// Errors that can occur when managing the cluster.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClusterError {
+    /// The message id node id does not fit in a message id; see `id_generator::MAX_NODE_ID`.
+    InvalidNodeId(u16),
+    /// Another node already generates message ids with this node id.
+    DuplicateNodeId { node_id: u16, existing: Uuid },
+    /// The process-wide message id generator already uses another node id, set by an earlier
+    /// `Cluster::new` or by ids generated before the node joined the cluster.
+    NodeIdMismatch { configured: u16, existing: u16 },
+}
 
 /// Represents a node in the cluster, which can either be a leader or a follower.
 #[derive(Debug, Clone)]
 pub struct Node {
     pub id: Uuid,             // Unique ID for the node
+    pub id_generator_node_id: u16, // Configured node id embedded in the message ids the node generates; unique in the cluster
     pub is_leader: bool,      // Indicates whether the node is the leader
     pub address: String,      // The address (host:port) of the node
     pub last_heartbeat: Instant, // Last heartbeat received from the node (for health checks)
@@ -20,6 +53,67 @@ pub struct Cluster {
     nodes: Arc<RwLock<HashMap<Uuid, Node>>>,  // All nodes in the cluster
     self_node: Node,                          // The node running this instance
     leader: Arc<Mutex<Option<Uuid>>>,         // Current leader's ID (if elected)
+    replica_log: Option<Arc<ReplicaLog>>,     // Records replicated to this node by its peers
+    connections: Arc<Mutex<HashMap<String, PeerConnection>>>, // Connection to each peer, by address
+}
+
+/// The connection records are replicated to a peer over, opened on first use; its lock keeps
+/// one record in flight per peer.
+type PeerConnection = Arc<Mutex<Option<TcpStream>>>;
+
+/// Records replicated to this node, persisted to a storage backend before they are acknowledged.
+#[derive(Debug)]
+struct ReplicaLog {
+    backend: Arc<dyn StorageBackend>,   // Engine the records are stored in
+    next: Mutex<u64>,                   // Sequence number of the next record; serializes appends
+}
+
+impl ReplicaLog {
+    /// Opens the replica log kept in a backend, continuing its sequence.
+    fn open(backend: Arc<dyn StorageBackend>) -> Result<ReplicaLog, String> {
+        let next = match backend.load(REPLICA_NEXT_KEY)? {
+            Some(value) => u64::from_be_bytes(value.as_slice().try_into().map_err(|_| "corrupt replica sequence number".to_string())?),
+            None => 0,
+        };
+        Ok(ReplicaLog { backend, next: Mutex::new(next) })
+    }
+
+    /// Appends a record and waits until it is durable on disk.
+    async fn append(&self, record: Vec<u8>) -> Result<(), String> {
+        let mut next = self.next.lock().await;
+        let seq = *next;
+        let ops = vec![
+            BatchOp::Put(replica_key(seq), record),
+            BatchOp::Put(REPLICA_NEXT_KEY.to_vec(), (seq + 1).to_be_bytes().to_vec()),
+        ];
+        let backend = self.backend.clone();
+        tokio::task::spawn_blocking(move || backend.write_batch(ops, true))
+            .await
+            .map_err(|e| e.to_string())??;
+        *next = seq + 1;
+        Ok(())
+    }
+
+    /// Removes and returns every record, oldest first.
+    async fn take(&self) -> Result<Vec<Vec<u8>>, String> {
+        let _next = self.next.lock().await;
+        let backend = self.backend.clone();
+        tokio::task::spawn_blocking(move || {
+            let records = backend.scan(REPLICA_PREFIX)?;
+            if let Some((last, _)) = records.last() {
+                let mut end = last.clone();
+                end.push(0);
+                backend.write_batch(vec![BatchOp::DeleteRange(REPLICA_PREFIX.to_vec(), end)], true)?;
+            }
+            Ok(records.into_iter().map(|(_, record)| record).collect())
+        })
+        .await
+        .map_err(|e| e.to_string())?
+    }
+}
+
+fn replica_key(seq: u64) -> Vec<u8> {
+    [REPLICA_PREFIX, &seq.to_be_bytes()].concat()
 }
 
 impl Cluster {
@@ -28,11 +122,27 @@ impl Cluster {
     /// # Arguments
     ///
     /// * `address` - The address of the current node (host:port).
+    /// * `id_generator_node_id` - The node id embedded in the message ids this node generates;
+    ///   every node of the cluster must be configured with a different one.
+    ///
+    /// The process-wide message id generator is initialized with this node's id.
     ///
-    pub async fn new(address: String) -> Cluster {
+    /// # Errors
+    ///
+    /// Returns `ClusterError::InvalidNodeId` if the node id does not fit in a message id, and
+    /// `ClusterError::NodeIdMismatch` if the message id generator already uses a different
+    /// node id, since this node's message ids would then not be unique in the cluster.
+    pub async fn new(address: String, id_generator_node_id: u16) -> Result<Cluster, ClusterError> {
+        if id_generator_node_id > id_generator::MAX_NODE_ID {
+            return Err(ClusterError::InvalidNodeId(id_generator_node_id));
+        }
+        id_generator::init(id_generator_node_id)
+            .map_err(|existing| ClusterError::NodeIdMismatch { configured: id_generator_node_id, existing })?;
+
         let node_id = Uuid::new_v4();
         let self_node = Node {
             id: node_id,
+            id_generator_node_id,
             is_leader: false,
             address,
             last_heartbeat: Instant::now(),
@@ -41,26 +151,54 @@ impl Cluster {
         let mut nodes = HashMap::new();
         nodes.insert(node_id, self_node.clone());
 
-        Cluster {
+        Ok(Cluster {
             nodes: Arc::new(RwLock::new(nodes)),
             self_node,
             leader: Arc::new(Mutex::new(None)), // No leader initially
-        }
+            replica_log: None,
+            connections: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Persists the records peers replicate to this node in a storage backend, which
+    /// `start_listener` requires.
+    ///
+    /// The records are kept under their own key prefix, so the backend can be shared with
+    /// `Storage`.
+    ///
+    /// # Arguments
+    ///
+    /// * `backend` - The engine to store replicated records in.
+    pub fn with_replica_storage(mut self, backend: Arc<dyn StorageBackend>) -> Result<Self, String> {
+        self.replica_log = Some(Arc::new(ReplicaLog::open(backend)?));
+        Ok(self)
     }
 
     /// Adds a new node to the cluster.
     ///
     /// # Arguments
     ///
-    /// * `node` - The node to add to the cluster.
+    /// * `node` - The node to add to the cluster; adding a node again updates it.
+    ///
+    /// # Errors
     ///
-    pub async fn add_node(&self, node: Node) {
+    /// Returns `ClusterError::DuplicateNodeId` if another node already uses the node's message
+    /// id node id, since their message ids could collide, and `ClusterError::InvalidNodeId` if
+    /// the node id does not fit in a message id.
+    pub async fn add_node(&self, node: Node) -> Result<(), ClusterError> {
+        if node.id_generator_node_id > id_generator::MAX_NODE_ID {
+            return Err(ClusterError::InvalidNodeId(node.id_generator_node_id));
+        }
         let mut nodes = self.nodes.write().await;
-        
+        if let Some(other) = nodes.values().find(|n| n.id != node.id && n.id_generator_node_id == node.id_generator_node_id) {
+            return Err(ClusterError::DuplicateNodeId { node_id: node.id_generator_node_id, existing: other.id });
+        }
+
         // Insert the cloned node into the map
         nodes.insert(node.id, node.clone());
-        
-        println!("Node added to the cluster: {:?}", node);
+
+        info!("Node added to the cluster: {:?}", node);
+        Ok(())
     }
 
     /// Removes a node from the cluster by its ID.
@@ -70,8 +208,10 @@ impl Cluster {
     /// * `node_id` - The ID of the node to remove.
     pub async fn remove_node(&self, node_id: Uuid) {
         let mut nodes = self.nodes.write().await;
-        nodes.remove(&node_id);
-        println!("Node removed from the cluster: {:?}", node_id);
+        if let Some(node) = nodes.remove(&node_id) {
+            self.connections.lock().await.remove(&node.address);
+        }
+        info!("Node removed from the cluster: {:?}", node_id);
     }
 
     /// Elects a leader from the nodes in the cluster.
@@ -82,33 +222,135 @@ impl Cluster {
         // Choose the first node as the leader for simplicity
         if let Some((leader_id, _)) = nodes.iter().next() {
             *leader = Some(*leader_id);
-            println!("Leader elected: {:?}", leader_id);
+            info!("Leader elected: {:?}", leader_id);
         }
     }
 
     /// Starts listening for other nodes joining the cluster and heartbeat messages.
+    ///
+    /// Incoming connections carry length-prefixed records replicated by peers. Each record
+    /// is persisted to this node's replica log and only then acknowledged with a single byte.
+    /// A peer that announces a record longer than `MAX_RECORD_LEN` is disconnected.
+    ///
+    /// Fails if no replica storage was set with `with_replica_storage`.
     pub async fn start_listener(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let replica_log = self
+            .replica_log
+            .clone()
+            .ok_or("replicated records must be persisted before they are acknowledged; set a replica storage")?;
         let listener = TcpListener::bind(&self.self_node.address).await?;
-        println!("Listening on {}", self.self_node.address);
+        info!("Listening on {}", self.self_node.address);
     
         loop {
-            let (_socket, _) = listener.accept().await?;
-            println!("Connection established with another node");
+            let (mut socket, _) = listener.accept().await?;
+            debug!("Connection established with another node");
     
             // Handle incoming requests from other nodes (e.g., heartbeats, join requests)
+            let replica_log = replica_log.clone();
             tokio::spawn(async move {
-                // Handle the socket (e.g., read messages, send responses)
+                // Stops once the peer closes the connection
+                while let Ok(length) = socket.read_u32().await {
+                    if length > MAX_RECORD_LEN {
+                        warn!("Peer sent a record of {} bytes, more than the limit of {}; disconnecting", length, MAX_RECORD_LEN);
+                        break;
+                    }
+                    let mut record = vec![0u8; length as usize];
+                    if socket.read_exact(&mut record).await.is_err() {
+                        break;
+                    }
+
+                    // Without an ack the peer does not count this node as a replica
+                    if let Err(e) = replica_log.append(record).await {
+                        warn!("Failed to persist replicated record: {}", e);
+                        break;
+                    }
+                    if socket.write_u8(REPLICATION_ACK).await.is_err() {
+                        break;
+                    }
+                }
             });
         }
     }
 
+    /// Replicates a record to every other node in the cluster.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The serialized record to replicate.
+    ///
+    /// Returns the number of peers that acknowledged the record within `REPLICATION_TIMEOUT`.
+    /// Each peer is sent records over a single connection, opened on first use and again after
+    /// a failure; a record longer than `MAX_RECORD_LEN` is not sent at all.
+    pub async fn replicate(&self, record: &[u8]) -> usize {
+        if record.len() > MAX_RECORD_LEN as usize {
+            warn!("Record of {} bytes is too large to replicate", record.len());
+            return 0;
+        }
+        let peers: Vec<String> = {
+            let nodes = self.nodes.read().await;
+            nodes
+                .values()
+                .filter(|node| node.id != self.self_node.id)
+                .map(|node| node.address.clone())
+                .collect()
+        };
+
+        let mut acknowledged = 0;
+        for address in peers {
+            let connection = self.connections.lock().await.entry(address.clone()).or_default().clone();
+            let mut connection = connection.lock().await;
+            match timeout(REPLICATION_TIMEOUT, Self::send_record(&mut connection, &address, record)).await {
+                Ok(Ok(())) => acknowledged += 1,
+                Ok(Err(e)) => {
+                    warn!("Replication to node at {} failed: {}", address, e);
+                    *connection = None;
+                }
+                Err(_) => {
+                    warn!("Replication to node at {} timed out", address);
+                    *connection = None;
+                }
+            }
+        }
+        acknowledged
+    }
+
+    /// Sends a single length-prefixed record to a peer over its connection, opening it if
+    /// needed, and waits for its acknowledgment.
+    ///
+    /// The caller closes the connection on any failure, since a partially sent record or an
+    /// unread ack would corrupt the next exchange.
+    async fn send_record(connection: &mut Option<TcpStream>, address: &str, record: &[u8]) -> std::io::Result<()> {
+        let stream = match connection {
+            Some(stream) => stream,
+            None => connection.insert(TcpStream::connect(address).await?),
+        };
+        stream.write_u32(record.len() as u32).await?;
+        stream.write_all(record).await?;
+
+        if stream.read_u8().await? == REPLICATION_ACK {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "unexpected replication ack"))
+        }
+    }
+
+    /// Removes and returns the records replicated to this node so far, oldest first.
+    ///
+    /// Returns an empty list if no replica storage was set.
+    pub async fn take_replicated(&self) -> Result<Vec<Vec<u8>>, String> {
+        match &self.replica_log {
+            Some(replica_log) => replica_log.take().await,
+            None => Ok(Vec::new()),
+        }
+    }
+
     /// Sends a heartbeat message to all other nodes in the cluster to indicate this node is still alive.
     pub async fn send_heartbeat(&self) {
         let nodes = self.nodes.read().await;
         for (_, node) in nodes.iter() {
             if node.id != self.self_node.id {
                 // Send heartbeat to each node (for simplicity, we just print here)
-                println!("Sending heartbeat to node at {}", node.address);
+                debug!("Sending heartbeat to node at {}", node.address);
             }
         }
     }
@@ -123,7 +365,7 @@ impl Cluster {
         nodes.retain(|_, node| {
             let is_alive = current_time.duration_since(node.last_heartbeat) < Duration::from_secs(30);
             if !is_alive {
-                println!("Node at {} failed health check and was removed", node.address);
+                warn!("Node at {} failed health check and was removed", node.address);
             }
             is_alive
         });
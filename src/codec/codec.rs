@@ -0,0 +1,111 @@
+use std::fmt::Debug;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Errors that can occur when encoding or decoding message payloads.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+    /// The value could not be serialized.
+    Encode(String),
+    /// The message content could not be deserialized.
+    Decode(String),
+    /// The message was encoded with a different codec than the one decoding it.
+    ContentTypeMismatch { expected: String, actual: String },
+}
+
+/// Converts typed values to and from the bytes of a message payload.
+///
+/// Typed producers and consumers are generic over their codec, so values are serialized
+/// with the codec's own format directly. A queue records the content type of the codec its
+/// messages are encoded with (see `Queue::set_codec`). Implement this trait to plug a custom
+/// serialization format into a queue.
+pub trait Codec: Debug + Send + Sync + 'static {
+    /// The content type written to the `content-type` header of encoded messages.
+    fn content_type(&self) -> &str;
+
+    /// Encodes a value into payload bytes.
+    fn encode_bytes<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, CodecError>;
+
+    /// Decodes payload bytes into a value.
+    fn decode_bytes<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError>;
+
+    /// Returns `true` if payloads are UTF-8 text that can be used as message content as is;
+    /// binary payloads are stored in the content as base64.
+    fn is_text(&self) -> bool {
+        false
+    }
+}
+
+/// Encodes a typed value into message content with a codec.
+///
+/// # Arguments
+///
+/// * `codec` - The codec of the queue the message is sent to.
+/// * `value` - The value to encode.
+pub fn encode<C: Codec, T: Serialize + ?Sized>(codec: &C, value: &T) -> Result<String, CodecError> {
+    let bytes = codec.encode_bytes(value)?;
+    if codec.is_text() {
+        String::from_utf8(bytes).map_err(|e| CodecError::Encode(e.to_string()))
+    } else {
+        Ok(BASE64.encode(bytes))
+    }
+}
+
+/// Decodes message content into a typed value with a codec.
+///
+/// # Arguments
+///
+/// * `codec` - The codec of the queue the message was received from.
+/// * `content` - The message content.
+pub fn decode<C: Codec, T: DeserializeOwned>(codec: &C, content: &str) -> Result<T, CodecError> {
+    if codec.is_text() {
+        codec.decode_bytes(content.as_bytes())
+    } else {
+        let bytes = BASE64.decode(content).map_err(|e| CodecError::Decode(e.to_string()))?;
+        codec.decode_bytes(&bytes)
+    }
+}
+
+/// Encodes values as JSON using serde_json.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn content_type(&self) -> &str {
+        "application/json"
+    }
+
+    fn encode_bytes<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(value).map_err(|e| CodecError::Encode(e.to_string()))
+    }
+
+    fn decode_bytes<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(bytes).map_err(|e| CodecError::Decode(e.to_string()))
+    }
+
+    fn is_text(&self) -> bool {
+        true
+    }
+}
+
+/// Encodes values with bincode, so the payload is what `bincode::serialize` makes of the value.
+///
+/// Message content is text, so the binary encoding is stored as base64.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn content_type(&self) -> &str {
+        "application/x-bincode"
+    }
+
+    fn encode_bytes<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        bincode::serialize(value).map_err(|e| CodecError::Encode(e.to_string()))
+    }
+
+    fn decode_bytes<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        bincode::deserialize(bytes).map_err(|e| CodecError::Decode(e.to_string()))
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use metrics::{gauge, increment_counter};
+use tokio::time::{Duration, Instant};
+use crate::consumer::delivery::HandlerError;
+use log::warn;
+
+/// When a circuit breaker trips and how long it stays open.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CircuitBreakerConfig {
+    /// Number of consecutive failures that opens the circuit; `0` disables this trigger.
+    pub consecutive_failures: u32,
+    /// Share of failures among the last `window` outcomes that opens the circuit, if any.
+    pub failure_rate: Option<f64>,
+    /// Number of recent outcomes `failure_rate` is computed over; the rate is only checked once the window is full.
+    pub window: usize,
+    /// How long the circuit stays open before a probe message is let through.
+    pub cool_down: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            consecutive_failures: 5,
+            failure_rate: None,
+            window: 20,
+            cool_down: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The state of a circuit breaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Messages are delivered normally.
+    Closed,
+    /// Delivery is paused until the cool-down has passed.
+    Open,
+    /// A single probe message is delivered; its outcome closes or re-opens the circuit.
+    HalfOpen,
+}
+
+/// Permission to make one delivery, handed out by `CircuitBreaker::try_acquire`.
+///
+/// The delivery's outcome is recorded with its permit, so outcomes of deliveries let
+/// through before the circuit last changed state are ignored. Dropping the half-open probe's
+/// permit without recording an outcome, e.g. because its handler was aborted, lets another
+/// probe through.
+#[derive(Debug)]
+pub struct CircuitPermit {
+    generation: u64,                              // Number of state changes the breaker had gone through when the permit was issued
+    probe: Option<Arc<Mutex<BreakerState>>>,     // The breaker's state, if this is the half-open probe's permit
+}
+
+impl Drop for CircuitPermit {
+    fn drop(&mut self) {
+        if let Some(inner) = self.probe.take() {
+            let mut inner = inner.lock().unwrap();
+            if inner.generation == self.generation {
+                inner.probe_in_flight = false;
+            }
+        }
+    }
+}
+
+/// Pauses a consumer's deliveries while its handler keeps failing.
+///
+/// Only `HandlerError::Retryable` outcomes (including panics) count as failures, since they
+/// usually mean a dependency is unavailable. Permanent failures are about the message itself
+/// and are ignored, except that they close a half-open circuit like a success. While half-open,
+/// only the probe's outcome is taken into account.
+///
+/// Every state change is logged and recorded in the `consumer_circuit_transitions_total`
+/// counter and the `consumer_circuit_state` gauge (0 = closed, 1 = open, 2 = half-open).
+/// Clones share the same state.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    name: String,                      // Name used in logs and metric labels
+    config: CircuitBreakerConfig,      // Trip and cool-down settings
+    inner: Arc<Mutex<BreakerState>>,   // Shared mutable state
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    state: CircuitState,
+    opened_at: Option<Instant>,   // When the circuit last opened
+    probe_in_flight: bool,        // Whether the half-open probe has been handed out
+    generation: u64,              // Number of state changes so far
+    consecutive_failures: u32,
+    recent: VecDeque<bool>,       // Last outcomes, `true` for failures
+}
+
+impl CircuitBreaker {
+    /// Creates a closed circuit breaker.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Identifies the breaker in logs and metrics (e.g. the queue or consumer name).
+    /// * `config` - When to trip and how long to stay open.
+    pub fn new(name: &str, config: CircuitBreakerConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            name: name.to_string(),
+            config,
+            inner: Arc::new(Mutex::new(BreakerState {
+                state: CircuitState::Closed,
+                opened_at: None,
+                probe_in_flight: false,
+                generation: 0,
+                consecutive_failures: 0,
+                recent: VecDeque::with_capacity(config.window),
+            })),
+        }
+    }
+
+    /// Returns the current state, moving from open to half-open once the cool-down has passed.
+    pub fn state(&self) -> CircuitState {
+        let mut inner = self.inner.lock().unwrap();
+        self.refresh(&mut inner);
+        inner.state
+    }
+
+    /// Asks whether a message may be delivered now.
+    ///
+    /// Always grants a permit while closed. While half-open it grants one exactly once, for the
+    /// probe. Returns `None` if the delivery has to wait.
+    pub fn try_acquire(&self) -> Option<CircuitPermit> {
+        let mut inner = self.inner.lock().unwrap();
+        self.refresh(&mut inner);
+        let probe = match inner.state {
+            CircuitState::Closed => None,
+            CircuitState::Open => return None,
+            CircuitState::HalfOpen if inner.probe_in_flight => return None,
+            CircuitState::HalfOpen => {
+                inner.probe_in_flight = true;
+                Some(self.inner.clone())
+            }
+        };
+        Some(CircuitPermit { generation: inner.generation, probe })
+    }
+
+    /// Returns how long until the circuit half-opens, or `None` if it is not open.
+    pub fn remaining_cool_down(&self) -> Option<Duration> {
+        let mut inner = self.inner.lock().unwrap();
+        self.refresh(&mut inner);
+        match (inner.state, inner.opened_at) {
+            (CircuitState::Open, Some(opened_at)) => Some(self.config.cool_down.saturating_sub(opened_at.elapsed())),
+            _ => None,
+        }
+    }
+
+    /// Records the outcome of a delivered message.
+    ///
+    /// # Arguments
+    ///
+    /// * `permit` - The permit the message was delivered under.
+    /// * `outcome` - What the handler returned for the message.
+    pub fn record(&self, mut permit: CircuitPermit, outcome: &Result<(), HandlerError>) {
+        let failed = matches!(outcome, Err(HandlerError::Retryable(_)));
+        // The probe's outcome decides the circuit below, so dropping the permit must not free the probe
+        permit.probe = None;
+
+        let mut inner = self.inner.lock().unwrap();
+        // Outcomes of messages delivered before the last state change do not matter any more
+        if permit.generation != inner.generation {
+            return;
+        }
+        match inner.state {
+            CircuitState::HalfOpen if failed => self.transition(&mut inner, CircuitState::Open),
+            // A permanent failure still shows the dependency answered the probe
+            CircuitState::HalfOpen => self.transition(&mut inner, CircuitState::Closed),
+            // No permits are handed out while open
+            CircuitState::Open => {}
+            CircuitState::Closed if matches!(outcome, Err(HandlerError::Permanent(_))) => {}
+            CircuitState::Closed => {
+                inner.consecutive_failures = if failed { inner.consecutive_failures + 1 } else { 0 };
+                if self.config.window > 0 {
+                    if inner.recent.len() == self.config.window {
+                        inner.recent.pop_front();
+                    }
+                    inner.recent.push_back(failed);
+                }
+
+                if self.should_trip(&inner) {
+                    self.transition(&mut inner, CircuitState::Open);
+                }
+            }
+        }
+    }
+
+    fn should_trip(&self, inner: &BreakerState) -> bool {
+        let consecutive = self.config.consecutive_failures > 0 && inner.consecutive_failures >= self.config.consecutive_failures;
+        let rate = match self.config.failure_rate {
+            Some(rate) if self.config.window > 0 && inner.recent.len() == self.config.window => {
+                let failures = inner.recent.iter().filter(|failed| **failed).count();
+                failures as f64 / self.config.window as f64 >= rate
+            }
+            _ => false,
+        };
+        consecutive || rate
+    }
+
+    /// Half-opens the circuit once its cool-down has passed.
+    fn refresh(&self, inner: &mut BreakerState) {
+        if inner.state == CircuitState::Open && inner.opened_at.is_some_and(|opened_at| opened_at.elapsed() >= self.config.cool_down) {
+            self.transition(inner, CircuitState::HalfOpen);
+        }
+    }
+
+    fn transition(&self, inner: &mut BreakerState, state: CircuitState) {
+        let (label, value) = match state {
+            CircuitState::Closed => ("closed", 0.0),
+            CircuitState::Open => ("open", 1.0),
+            CircuitState::HalfOpen => ("half-open", 2.0),
+        };
+        warn!("Circuit breaker {} is now {}", self.name, label);
+        let name = self.name.clone();
+        increment_counter!("consumer_circuit_transitions_total", "breaker" => name.clone(), "state" => label);
+        gauge!("consumer_circuit_state", value, "breaker" => name);
+
+        inner.state = state;
+        inner.generation += 1;
+        inner.probe_in_flight = false;
+        match state {
+            CircuitState::Open => inner.opened_at = Some(Instant::now()),
+            CircuitState::Closed => {
+                inner.opened_at = None;
+                inner.consecutive_failures = 0;
+                inner.recent.clear();
+            }
+            CircuitState::HalfOpen => {}
+        }
+    }
+}
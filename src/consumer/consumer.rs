@@ -1,15 +1,119 @@
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Semaphore};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
 use std::sync::Arc;
-use crate::queue::Queue;
+use crate::broker::broker::Broker;
+use crate::broker::consumer_group::GroupMembership;
+use crate::consumer::circuit_breaker::CircuitBreaker;
+use crate::consumer::delivery::{self, BatchOutcome, Delivery, HandlerError};
+use crate::consumer::middleware::MiddlewareStack;
+use crate::consumer::shutdown::Shutdown;
+use crate::producer::message_builder::MessageBuilder;
+use crate::utils::rate_limiter::{RateLimit, RateLimitStats, RateLimiter};
+use crate::queue::{Message, Queue, QueueError, CORRELATION_ID_HEADER, REPLY_TO_HEADER};
+use crate::queue::selector::Selector;
 use uuid::Uuid;
 use std::time::Duration;
-use tokio::time::sleep;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Instant};
+use log::{debug, info, warn};
+
+/// Errors that can occur when consuming messages.
+#[derive(Debug)]
+pub enum ConsumerError {
+    /// The underlying queue operation failed.
+    Queue(QueueError),
+    /// The consumer was created without a broker, so named queues cannot be resolved.
+    NoBroker,
+    /// The message being replied to has no `reply-to` header.
+    MissingReplyTo,
+    /// No queue with the given name has been declared on the broker.
+    QueueNotFound(String),
+}
+
+impl From<QueueError> for ConsumerError {
+    fn from(error: QueueError) -> Self {
+        ConsumerError::Queue(error)
+    }
+}
+
+/// How long consumers wait before polling the queue again when it has no available messages, by default.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long in-flight handlers may keep running after shutdown, by default.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Settings for consuming messages with several concurrent handlers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorkerPoolConfig {
+    /// Maximum number of handlers running at the same time.
+    pub concurrency: usize,
+    /// Maximum number of messages fetched ahead and held locally while waiting for a free handler.
+    pub prefetch: usize,
+    /// How long to wait before polling the queue again when it has no available messages.
+    pub poll_interval: Duration,
+    /// How long in-flight handlers may keep running after shutdown before their messages are returned to the queue.
+    pub grace_period: Duration,
+}
+
+impl Default for WorkerPoolConfig {
+    fn default() -> Self {
+        WorkerPoolConfig {
+            concurrency: 4,
+            prefetch: 4,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            grace_period: DEFAULT_GRACE_PERIOD,
+        }
+    }
+}
+
+/// Settings for consuming messages in batches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchConsumerConfig {
+    /// Maximum number of messages handed to the handler at once.
+    pub max_size: usize,
+    /// How long to wait for a batch to fill up after its first message arrived.
+    pub max_wait: Duration,
+    /// Same as `WorkerPoolConfig::poll_interval`.
+    pub poll_interval: Duration,
+    /// How long a running batch handler may keep running after shutdown before its messages are returned to the queue.
+    pub grace_period: Duration,
+}
+
+impl Default for BatchConsumerConfig {
+    fn default() -> Self {
+        BatchConsumerConfig {
+            max_size: 100,
+            max_wait: Duration::from_secs(1),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            grace_period: DEFAULT_GRACE_PERIOD,
+        }
+    }
+}
+
+/// What happened to the messages a consumer still held when it shut down.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DrainReport {
+    /// Prefetched messages that were returned to the queue without being handled.
+    pub returned_prefetched: usize,
+    /// In-flight messages whose handlers finished within the grace period.
+    pub completed_in_flight: usize,
+    /// In-flight messages returned to the queue because their handlers outlived the grace period.
+    pub returned_in_flight: usize,
+}
 
 /// Represents a consumer responsible for retrieving messages from the queue and processing them.
 #[derive(Debug, Clone)]
 pub struct Consumer {
     id: Uuid,                    // Unique ID for the consumer
     queue: Arc<Mutex<Queue>>,     // Reference to the queue
+    broker: Option<Broker>,       // Broker used to resolve reply queues
+    shutdown: Shutdown,           // Signal that stops the consume loops
+    membership: Option<GroupMembership>, // Consumer group this consumer is a member of
+    selector: Option<Selector>,   // Filter restricting which messages this consumer receives
+    middleware: MiddlewareStack,  // Middleware wrapped around per-message handlers
+    circuit_breaker: Option<CircuitBreaker>, // Pauses deliveries while the handler keeps failing
+    rate_limiter: Option<RateLimiter>, // Limits this consumer's delivery rate
 }
 
 impl Consumer {
@@ -23,11 +127,133 @@ impl Consumer {
         Consumer {
             id: Uuid::new_v4(),
             queue,
+            broker: None,
+            shutdown: Shutdown::new(),
+            membership: None,
+            selector: None,
+            middleware: MiddlewareStack::new(),
+            circuit_breaker: None,
+            rate_limiter: None,
         }
     }
 
+    /// Creates a new consumer that can reply to requests through a broker.
+    ///
+    /// # Arguments
+    ///
+    /// * `queue` - The reference to the queue the consumer will pull messages from.
+    /// * `broker` - The broker used to resolve the reply queues named in requests.
+    ///
+    pub fn with_broker(queue: Arc<Mutex<Queue>>, broker: Broker) -> Consumer {
+        Consumer {
+            id: Uuid::new_v4(),
+            queue,
+            broker: Some(broker),
+            shutdown: Shutdown::new(),
+            membership: None,
+            selector: None,
+            middleware: MiddlewareStack::new(),
+            circuit_breaker: None,
+            rate_limiter: None,
+        }
+    }
+
+    /// Creates a new consumer as a member of a consumer group.
+    ///
+    /// # Arguments
+    ///
+    /// * `broker` - The broker the group is registered with.
+    /// * `group` - The name of the consumer group; it is registered if it does not exist yet.
+    /// * `queue` - The name of the queue the group consumes from.
+    /// * `session_timeout` - How long the consumer may go without a heartbeat before the group considers it dead.
+    ///
+    /// While `consume_with` or `consume_concurrently` runs, the consumer heartbeats and leases
+    /// every message it fetches; it leaves the group once it has drained.
+    pub async fn join_group(broker: Broker, group: &str, queue: &str, session_timeout: Duration) -> Consumer {
+        let membership = broker.join_group(group, queue, session_timeout).await;
+        let queue = broker.declare_queue(queue).await;
+        let mut consumer = Consumer::with_broker(Arc::new(Mutex::new(queue)), broker);
+        consumer.membership = Some(membership);
+        consumer
+    }
+
+    /// Only receives messages that match the given selector.
+    ///
+    /// Messages that do not match stay in the queue for other consumers.
+    ///
+    /// # Arguments
+    ///
+    /// * `selector` - The filter messages must satisfy, e.g. `Selector::parse("region = 'eu' AND priority > 5")`.
+    pub fn with_selector(mut self, selector: Selector) -> Consumer {
+        self.selector = Some(selector);
+        self
+    }
+
+    /// Wraps the handlers passed to `consume_with`, `spawn`, `consume_concurrently` and
+    /// `consume_batches` with middleware; see `MiddlewareStack::run_batch` for batches.
+    ///
+    /// # Arguments
+    ///
+    /// * `middleware` - The middleware to apply, outermost first.
+    pub fn with_middleware(mut self, middleware: MiddlewareStack) -> Consumer {
+        self.middleware = middleware;
+        self
+    }
+
+    /// Pauses deliveries from `consume_with`, `spawn` and `consume_concurrently` while the handler keeps failing.
+    ///
+    /// While the circuit is open the consumer returns its prefetched messages to the queue
+    /// and stops fetching; once the cool-down has passed it delivers a single probe message
+    /// and resumes if the probe does not fail.
+    ///
+    /// # Arguments
+    ///
+    /// * `circuit_breaker` - The breaker to consult and feed with handler outcomes.
+    pub fn with_circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Consumer {
+        self.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    /// Limits how fast `consume_with`, `spawn`, `consume_concurrently` and `consume_batches`
+    /// dispatch messages to the handler.
+    ///
+    /// The limit applies to this consumer (and its clones); a queue-wide limit set with
+    /// `Queue::set_rate_limit` is honoured as well. Messages wait in the prefetch buffer
+    /// until both limits allow them through; batches take a token per message and are only
+    /// filled with as many messages as there are tokens.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The delivery rate and burst size.
+    pub fn with_rate_limit(mut self, limit: RateLimit) -> Consumer {
+        self.rate_limiter = Some(RateLimiter::new(limit));
+        self
+    }
+
+    /// Returns the state of the consumer's own rate limit, if one is set.
+    pub fn rate_limit_stats(&self) -> Option<RateLimitStats> {
+        self.rate_limiter.as_ref().map(|rate_limiter| rate_limiter.stats())
+    }
+
+    /// Returns the consumer's group membership, if it joined a group.
+    pub fn membership(&self) -> Option<&GroupMembership> {
+        self.membership.as_ref()
+    }
+
+    /// Stops the consume loops when the given signal is triggered.
+    ///
+    /// # Arguments
+    ///
+    /// * `shutdown` - The signal to observe; clones of it can be shared by many consumers.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Consumer {
+        self.shutdown = shutdown;
+        self
+    }
+
     /// Consumes a message from the queue and processes it.
     ///
+    /// Runs until the consumer's shutdown signal is triggered.
+    ///
     /// # Arguments
     ///
     /// * `process_message` - A closure that processes the message.
@@ -38,24 +264,556 @@ impl Consumer {
     where
         F: Fn(&str) + Send + 'static,
     {
-        loop {
-            let queue = self.queue.clone();
-            let mut locked_queue = queue.lock().await;
+        while !self.shutdown.is_triggered() {
+            // Work on a handle to the queue, so the closure does not run with the queue locked
+            let queue = self.queue.lock().await.clone();
 
             // Attempt to retrieve a message from the queue
-            if let Some(message) = locked_queue.pop().await.unwrap() {
-                println!("Consumer {:?} processing message: {:?}", self.id, message);
-                
+            if let Some(message) = self.fetch(&queue, 1).await.pop() {
+                debug!("Consumer {:?} processing message: {:?}", self.id, message);
+
                 // Process the message using the provided closure
                 process_message(&message.content);
 
                 // Acknowledge the message (if the queue supports acknowledgment)
-                locked_queue.acknowledge(message.id).await.unwrap();
+                if let Err(e) = queue.acknowledge(message.id).await {
+                    warn!("Consumer {:?} failed to acknowledge message {}: {:?}", self.id, message.id, e);
+                }
             } else {
                 // If no message is available, wait before retrying
-                println!("No messages available, retrying...");
-                sleep(Duration::from_secs(5)).await;
+                debug!("No messages available, retrying...");
+                tokio::select! {
+                    _ = sleep(Duration::from_secs(5)) => {}
+                    _ = self.shutdown.triggered() => {}
+                }
             }
         }
     }
+
+    /// Consumes messages one at a time with an async, fallible handler.
+    ///
+    /// See `consume_concurrently` for how handler outcomes are settled and how shutdown drains.
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - An async closure that processes a delivery.
+    pub async fn consume_with<F, Fut>(&self, handler: F) -> DrainReport
+    where
+        F: Fn(Delivery) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), HandlerError>> + Send + 'static,
+    {
+        let config = WorkerPoolConfig {
+            concurrency: 1,
+            prefetch: 1,
+            ..WorkerPoolConfig::default()
+        };
+        self.consume_concurrently(config, handler).await
+    }
+
+    /// Starts `consume_concurrently` in a background task.
+    ///
+    /// Returns a handle that resolves with the `DrainReport` once the consumer has
+    /// shut down and drained.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The concurrency, prefetch and grace period settings.
+    /// * `handler` - An async closure that processes a delivery.
+    pub fn spawn<F, Fut>(&self, config: WorkerPoolConfig, handler: F) -> JoinHandle<DrainReport>
+    where
+        F: Fn(Delivery) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), HandlerError>> + Send + 'static,
+    {
+        let consumer = self.clone();
+        tokio::spawn(async move { consumer.consume_concurrently(config, handler).await })
+    }
+
+    /// Consumes messages with up to `config.concurrency` handlers running concurrently.
+    ///
+    /// Each message is handled in its own Tokio task and settled as soon as its handler
+    /// completes, so acknowledgments may happen out of order: `Ok` acknowledges the message,
+    /// `HandlerError::Retryable` routes it through `Queue::retry`, `HandlerError::Permanent`
+    /// moves it to the dead-letter queue, and a panicking handler counts as a retryable failure.
+    /// Up to `config.prefetch` messages are fetched ahead of time. The queue lock is only held
+    /// while fetching and settling, never while a handler runs.
+    ///
+    /// When the consumer's shutdown signal is triggered, it stops fetching and returns its
+    /// prefetched messages to the queue. In-flight handlers get `config.grace_period` to
+    /// finish; the messages of any still running after that are returned to the queue and
+    /// their outcomes are ignored.
+    ///
+    /// A consumer that joined a group heartbeats while it runs and leases every fetched
+    /// message to its member until the message is settled or handed back; it leaves the
+    /// group after draining.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The concurrency, prefetch and grace period settings.
+    /// * `handler` - An async closure that processes a delivery.
+    ///
+    /// Returns a `DrainReport` describing how the remaining messages were handled.
+    pub async fn consume_concurrently<F, Fut>(&self, config: WorkerPoolConfig, handler: F) -> DrainReport
+    where
+        F: Fn(Delivery) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), HandlerError>> + Send + 'static,
+    {
+        let handler = self.middleware.apply(handler);
+        let concurrency = config.concurrency.max(1);
+        let workers = Arc::new(Semaphore::new(concurrency));
+        let prefetch = config.prefetch.max(1);
+        let mut buffer = VecDeque::with_capacity(prefetch);
+        let in_flight: Arc<Mutex<HashMap<u64, Message>>> = Arc::new(Mutex::new(HashMap::new()));
+        let mut tasks = Vec::new();
+        let queue = self.queue.lock().await.clone();
+        let heartbeats = self.spawn_heartbeats();
+
+        while !self.shutdown.is_triggered() {
+            // Top up the local buffer without holding the lock while handlers run
+            if buffer.len() < prefetch {
+                let fetched = self.fetch(&queue, prefetch - buffer.len()).await;
+                match self.lease(&queue, fetched).await {
+                    Some(fetched) => buffer.extend(fetched),
+                    // The group already returned the buffered messages to the queue
+                    None => buffer.clear(),
+                }
+            }
+
+            if buffer.is_empty() {
+                tokio::select! {
+                    _ = sleep(config.poll_interval) => {}
+                    _ = self.shutdown.triggered() => {}
+                }
+                continue;
+            }
+
+            // Wait for a free worker before dispatching
+            let permit = tokio::select! {
+                permit = workers.clone().acquire_owned() => permit.unwrap(),
+                _ = self.shutdown.triggered() => break,
+            };
+
+            if let Err(wait) = self.acquire_rate_limits(&queue).await {
+                drop(permit);
+                tokio::select! {
+                    _ = sleep(wait) => {}
+                    _ = self.shutdown.triggered() => {}
+                }
+                continue;
+            }
+
+            let circuit_permit = match &self.circuit_breaker {
+                Some(circuit_breaker) => match circuit_breaker.try_acquire() {
+                    Some(circuit_permit) => Some((circuit_breaker.clone(), circuit_permit)),
+                    None => {
+                        // Let other consumers have the prefetched messages while the circuit is open
+                        for message in buffer.drain(..) {
+                            self.hand_back(&queue, message).await;
+                        }
+                        self.refund_rate_limits(&queue, 1).await;
+                        drop(permit);
+                        let wait = circuit_breaker.remaining_cool_down().unwrap_or(config.poll_interval);
+                        tokio::select! {
+                            _ = sleep(wait) => {}
+                            _ = self.shutdown.triggered() => {}
+                        }
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let message = buffer.pop_front().unwrap();
+            let handler = handler.clone();
+            let in_flight = in_flight.clone();
+            let queue = queue.clone();
+            let membership = self.membership.clone();
+            let consumer_id = self.id;
+
+            in_flight.lock().await.insert(message.id, message.clone());
+            tasks.retain(|task: &JoinHandle<()>| !task.is_finished());
+            tasks.push(tokio::spawn(async move {
+                debug!("Consumer {:?} processing message: {}", consumer_id, message.id);
+                let outcome = delivery::run_handler(handler.as_ref(), message.clone()).await;
+                if let Some((circuit_breaker, circuit_permit)) = circuit_permit {
+                    circuit_breaker.record(circuit_permit, &outcome);
+                }
+
+                // Only settle if shutdown has not already handed the message back
+                if in_flight.lock().await.remove(&message.id).is_some() {
+                    let message_id = message.id;
+                    if let Err(e) = delivery::settle(&queue, message, outcome).await {
+                        warn!("Consumer {:?} failed to settle message: {:?}", consumer_id, e);
+                    }
+                    if let Some(membership) = membership {
+                        membership.complete(message_id).await;
+                    }
+                }
+                drop(permit);
+            }));
+        }
+
+        // Hand prefetched messages back untouched
+        let mut report = DrainReport {
+            returned_prefetched: buffer.len(),
+            ..DrainReport::default()
+        };
+        for message in buffer {
+            self.hand_back(&queue, message).await;
+        }
+
+        let pending = in_flight.lock().await.len();
+        info!("Consumer {:?} draining {} in-flight messages", self.id, pending);
+
+        // Every worker permit is back once all in-flight handlers have finished
+        let finished = timeout(config.grace_period, workers.acquire_many(concurrency as u32)).await.is_ok();
+        if !finished {
+            let abandoned: Vec<Message> = in_flight.lock().await.drain().map(|(_, message)| message).collect();
+            report.returned_in_flight = abandoned.len();
+            // Stop the handlers before their messages can be delivered again
+            for task in &tasks {
+                task.abort();
+            }
+            for task in tasks {
+                let _ = task.await;
+            }
+            for message in abandoned {
+                self.hand_back(&queue, message).await;
+            }
+        }
+        report.completed_in_flight = pending - report.returned_in_flight;
+
+        self.leave_group(heartbeats).await;
+        info!("Consumer {:?} shut down: {:?}", self.id, report);
+        report
+    }
+
+    /// Consumes messages in batches with an async, fallible batch handler.
+    ///
+    /// A batch is handed to the handler once it holds `config.max_size` messages or
+    /// `config.max_wait` has passed since its first message arrived, whichever comes first.
+    /// Each message is then settled individually: the handler records failures in the
+    /// returned `BatchOutcome`, and messages without one are acknowledged. A handler error
+    /// or panic applies to the whole batch. One batch is handled at a time. Rate limits are
+    /// honoured per message, so a batch may be handed over smaller than `config.max_size`.
+    ///
+    /// When the consumer's shutdown signal is triggered, the batch being collected is
+    /// returned to the queue. A running handler gets `config.grace_period` to finish; if it
+    /// does not, its messages are returned to the queue and its outcome is ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The batch size, wait and grace period settings.
+    /// * `handler` - An async closure that processes a batch of deliveries.
+    ///
+    /// Returns a `DrainReport` describing how the remaining messages were handled.
+    pub async fn consume_batches<F, Fut>(&self, config: BatchConsumerConfig, handler: F) -> DrainReport
+    where
+        F: Fn(Vec<Delivery>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<BatchOutcome, HandlerError>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let max_size = config.max_size.max(1);
+        let mut batch: Vec<Message> = Vec::with_capacity(max_size);
+        let mut deadline: Option<Instant> = None;
+        let mut report = DrainReport::default();
+        let queue = self.queue.lock().await.clone();
+        let heartbeats = self.spawn_heartbeats();
+
+        while !self.shutdown.is_triggered() {
+            if batch.len() < max_size {
+                match self.acquire_rate_limits_up_to(&queue, max_size - batch.len()).await {
+                    Ok(permits) => {
+                        let fetched = self.fetch(&queue, permits).await;
+                        self.refund_rate_limits(&queue, permits - fetched.len()).await;
+                        match self.lease(&queue, fetched).await {
+                            Some(fetched) => {
+                                if deadline.is_none() && !fetched.is_empty() {
+                                    deadline = Some(Instant::now() + config.max_wait);
+                                }
+                                batch.extend(fetched);
+                            }
+                            // The group already returned the batched messages to the queue
+                            None => {
+                                batch.clear();
+                                deadline = None;
+                            }
+                        }
+                    }
+                    Err(wait) if batch.is_empty() => {
+                        tokio::select! {
+                            _ = sleep(wait) => {}
+                            _ = self.shutdown.triggered() => {}
+                        }
+                        continue;
+                    }
+                    // The partial batch is handed over once its wait is over
+                    Err(_) => {}
+                }
+            }
+
+            let full = batch.len() >= max_size;
+            let waited = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+            if !full && !waited {
+                let wait = match deadline {
+                    Some(deadline) => config.poll_interval.min(deadline.saturating_duration_since(Instant::now())),
+                    None => config.poll_interval,
+                };
+                tokio::select! {
+                    _ = sleep(wait) => {}
+                    _ = self.shutdown.triggered() => {}
+                }
+                continue;
+            }
+
+            let circuit_permit = match &self.circuit_breaker {
+                Some(circuit_breaker) => match circuit_breaker.try_acquire() {
+                    Some(circuit_permit) => Some((circuit_breaker, circuit_permit)),
+                    None => {
+                        // Let other consumers have the batch while the circuit is open
+                        for message in batch.drain(..) {
+                            self.hand_back(&queue, message).await;
+                        }
+                        deadline = None;
+                        let wait = circuit_breaker.remaining_cool_down().unwrap_or(config.poll_interval);
+                        tokio::select! {
+                            _ = sleep(wait) => {}
+                            _ = self.shutdown.triggered() => {}
+                        }
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            let messages = std::mem::take(&mut batch);
+            deadline = None;
+            debug!("Consumer {:?} processing batch of {} messages", self.id, messages.len());
+
+            // Run the handler in its own task so a panic surfaces as a `JoinError` instead of unwinding
+            let deliveries = messages.iter().cloned().map(Delivery::new).collect();
+            let (middleware, handler) = (self.middleware.clone(), handler.clone());
+            let mut task = tokio::spawn(async move { middleware.run_batch(handler.as_ref(), deliveries).await });
+            let joined = tokio::select! {
+                joined = &mut task => Some(joined),
+                _ = self.shutdown.triggered() => timeout(config.grace_period, &mut task).await.ok(),
+            };
+
+            let Some(joined) = joined else {
+                task.abort();
+                let _ = task.await;
+                report.returned_in_flight += messages.len();
+                for message in messages {
+                    self.hand_back(&queue, message).await;
+                }
+                break;
+            };
+            if self.shutdown.is_triggered() {
+                report.completed_in_flight += messages.len();
+            }
+
+            let outcome = joined.unwrap_or_else(|e| Err(HandlerError::Retryable(format!("handler panicked: {}", e))));
+            if let Some((circuit_breaker, circuit_permit)) = circuit_permit {
+                circuit_breaker.record(circuit_permit, &delivery::batch_health(&messages, &outcome));
+            }
+            let message_ids: Vec<u64> = messages.iter().map(|message| message.id).collect();
+            if let Err(e) = delivery::settle_batch(&queue, messages, outcome).await {
+                warn!("Consumer {:?} failed to settle batch: {:?}", self.id, e);
+            }
+            for message_id in message_ids {
+                self.complete(message_id).await;
+            }
+        }
+
+        // Hand the batch being collected back untouched
+        report.returned_prefetched = batch.len();
+        for message in batch {
+            self.hand_back(&queue, message).await;
+        }
+
+        self.leave_group(heartbeats).await;
+        info!("Consumer {:?} shut down: {:?}", self.id, report);
+        report
+    }
+
+    /// Takes a delivery token from the consumer's and the queue's rate limiters.
+    ///
+    /// Returns how long to wait before trying again if either limiter is empty; in that case
+    /// no token is kept from either.
+    async fn acquire_rate_limits(&self, queue: &Queue) -> Result<(), Duration> {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.try_acquire()?;
+        }
+        if let Some(queue_limiter) = queue.rate_limiter().await {
+            if let Err(wait) = queue_limiter.try_acquire() {
+                if let Some(rate_limiter) = &self.rate_limiter {
+                    rate_limiter.refund();
+                }
+                return Err(wait);
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes a delivery token from the consumer's and the queue's rate limiters for each of up
+    /// to `count` messages.
+    ///
+    /// Returns how many tokens were taken, or how long to wait before trying again if not even
+    /// one was available.
+    async fn acquire_rate_limits_up_to(&self, queue: &Queue, count: usize) -> Result<usize, Duration> {
+        let mut acquired = 0;
+        while acquired < count {
+            match self.acquire_rate_limits(queue).await {
+                Ok(()) => acquired += 1,
+                Err(wait) if acquired == 0 => return Err(wait),
+                Err(_) => break,
+            }
+        }
+        Ok(acquired)
+    }
+
+    /// Puts back delivery tokens taken by `acquire_rate_limits_up_to` that ended up unused.
+    async fn refund_rate_limits(&self, queue: &Queue, count: usize) {
+        if count == 0 {
+            return;
+        }
+        let queue_limiter = queue.rate_limiter().await;
+        for _ in 0..count {
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter.refund();
+            }
+            if let Some(queue_limiter) = &queue_limiter {
+                queue_limiter.refund();
+            }
+        }
+    }
+
+    /// Pops up to `count` available messages, honouring the consumer's selector.
+    ///
+    /// A failed pop is logged and yields no messages, so callers back off as for an empty queue.
+    async fn fetch(&self, queue: &Queue, count: usize) -> Vec<Message> {
+        let popped = match &self.selector {
+            Some(selector) => queue.pop_matching(selector, count).await,
+            None => queue.pop_batch(count).await,
+        };
+        popped.unwrap_or_else(|e| {
+            warn!("Consumer {:?} failed to fetch messages: {:?}", self.id, e);
+            Vec::new()
+        })
+    }
+
+    /// Starts heartbeating for the consumer's group membership until shutdown.
+    fn spawn_heartbeats(&self) -> Option<JoinHandle<()>> {
+        self.membership.clone().map(|membership| {
+            let shutdown = self.shutdown.clone();
+            tokio::spawn(async move {
+                while !shutdown.is_triggered() {
+                    tokio::select! {
+                        _ = sleep(membership.heartbeat_interval()) => {}
+                        _ = shutdown.triggered() => break,
+                    }
+                    if !membership.heartbeat().await {
+                        warn!("Member {:?} is no longer part of group {}", membership.member_id(), membership.group());
+                    }
+                }
+            })
+        })
+    }
+
+    /// Stops heartbeating and leaves the consumer's group, if it joined one.
+    async fn leave_group(&self, heartbeats: Option<JoinHandle<()>>) {
+        if let Some(heartbeats) = heartbeats {
+            heartbeats.abort();
+        }
+        if let Some(membership) = &self.membership {
+            if let Err(e) = membership.leave().await {
+                warn!("Member {:?} failed to leave group {}: {:?}", membership.member_id(), membership.group(), e);
+            }
+        }
+    }
+
+    /// Leases fetched messages to the consumer's group member and returns them.
+    ///
+    /// If the group dropped the member because it missed its heartbeats, the group has already
+    /// returned the member's leases to the queue. The messages that could not be leased are
+    /// handed back too, the consumer rejoins as a new member, and `None` is returned so the
+    /// caller drops the messages it still holds under the old membership.
+    async fn lease(&self, queue: &Queue, messages: Vec<Message>) -> Option<Vec<Message>> {
+        let Some(membership) = &self.membership else { return Some(messages) };
+        let mut leased = Vec::with_capacity(messages.len());
+        let mut messages = messages.into_iter();
+        while let Some(message) = messages.next() {
+            if membership.lease(&message).await {
+                leased.push(message);
+                continue;
+            }
+            warn!("Member {:?} is no longer part of group {}, rejoining", membership.member_id(), membership.group());
+            for message in std::iter::once(message).chain(messages) {
+                self.hand_back(queue, message).await;
+            }
+            if !membership.rejoin().await {
+                warn!("Consumer group {} no longer exists", membership.group());
+            }
+            return None;
+        }
+        Some(leased)
+    }
+
+    /// Releases a group lease on a message the consumer settled.
+    async fn complete(&self, message_id: u64) {
+        if let Some(membership) = &self.membership {
+            membership.complete(message_id).await;
+        }
+    }
+
+    /// Releases a group lease on a message the consumer is handing back to the queue.
+    async fn release(&self, message_id: u64) {
+        if let Some(membership) = &self.membership {
+            membership.release(message_id).await;
+        }
+    }
+
+    /// Returns a message the consumer will not handle to the queue, dead-lettering it if it
+    /// cannot be requeued so that it is not lost.
+    async fn hand_back(&self, queue: &Queue, message: Message) {
+        self.release(message.id).await;
+        if let Err(e) = queue.requeue(message.clone()).await {
+            warn!("Consumer {:?} failed to requeue message {}: {:?}", self.id, message.id, e);
+            let message_id = message.id;
+            if let Err(e) = queue.dead_letter(message, &format!("requeue failed: {:?}", e)).await {
+                warn!("Consumer {:?} failed to dead-letter message {}: {:?}", self.id, message_id, e);
+            }
+        }
+    }
+
+    /// Sends a reply to a request message.
+    ///
+    /// The reply is pushed to the queue named by the request's `reply-to` header and
+    /// carries the request's `correlation-id`, so the requesting producer can match it.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The request being answered.
+    /// * `payload` - The content of the reply.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ConsumerError::MissingReplyTo` if the request has no `reply-to` header and
+    /// `ConsumerError::QueueNotFound` if the reply queue no longer exists (e.g. the requester timed out).
+    pub async fn reply(&self, message: &Message, payload: String) -> Result<(), ConsumerError> {
+        let broker = self.broker.as_ref().ok_or(ConsumerError::NoBroker)?;
+        let reply_to = message.headers.get(REPLY_TO_HEADER).ok_or(ConsumerError::MissingReplyTo)?;
+        let reply_queue = broker
+            .get_queue(reply_to)
+            .await
+            .ok_or_else(|| ConsumerError::QueueNotFound(reply_to.clone()))?;
+
+        let mut reply = MessageBuilder::new(payload).priority(message.priority).max_retries(0);
+        if let Some(correlation_id) = message.headers.get(CORRELATION_ID_HEADER) {
+            reply = reply.header(CORRELATION_ID_HEADER, correlation_id.clone());
+        }
+        let reply = reply.build().message;
+
+        debug!("Consumer {:?} replying to {}: {:?}", self.id, reply_to, reply);
+        reply_queue.push(reply, Duration::from_secs(0)).await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::future::Future;
+use tokio::task::AbortHandle;
+use crate::queue::{Message, Queue, QueueError};
+use log::warn;
+
+/// Errors a message handler can report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandlerError {
+    /// A transient failure; the message is retried with backoff until it runs out of retries.
+    Retryable(String),
+    /// A failure that retrying cannot fix; the message is moved to the dead-letter queue.
+    Permanent(String),
+}
+
+/// Per-message outcomes reported by a batch handler.
+///
+/// Messages without a recorded failure are acknowledged.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchOutcome {
+    failures: HashMap<u64, HandlerError>,   // Failed messages indexed by id
+}
+
+impl BatchOutcome {
+    /// Creates an outcome that acknowledges every message of the batch.
+    pub fn new() -> BatchOutcome {
+        BatchOutcome::default()
+    }
+
+    /// Records that a message of the batch failed.
+    ///
+    /// # Arguments
+    ///
+    /// * `message_id` - The id of the failed message.
+    /// * `error` - Whether the message should be retried or dead-lettered.
+    pub fn fail(&mut self, message_id: u64, error: HandlerError) {
+        self.failures.insert(message_id, error);
+    }
+
+    /// Returns the outcome recorded for a message.
+    pub fn outcome_for(&self, message_id: u64) -> Result<(), HandlerError> {
+        match self.failures.get(&message_id) {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A message handed to a handler, with its metadata.
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    message: Message,
+}
+
+impl Delivery {
+    /// Wraps a message popped from the queue.
+    pub fn new(message: Message) -> Delivery {
+        Delivery { message }
+    }
+
+    /// Returns the id of the message.
+    pub fn id(&self) -> u64 {
+        self.message.id
+    }
+
+    /// Returns the content of the message.
+    pub fn content(&self) -> &str {
+        &self.message.content
+    }
+
+    /// Returns the priority of the message.
+    pub fn priority(&self) -> u8 {
+        self.message.priority
+    }
+
+    /// Returns how many times the message has already been retried.
+    pub fn retry_count(&self) -> u8 {
+        self.message.retry_count
+    }
+
+    /// Returns the headers of the message.
+    pub fn headers(&self) -> &HashMap<String, String> {
+        &self.message.headers
+    }
+
+    /// Returns the underlying message.
+    pub fn message(&self) -> &Message {
+        &self.message
+    }
+
+    /// Consumes the delivery, returning the underlying message.
+    pub fn into_message(self) -> Message {
+        self.message
+    }
+}
+
+/// Runs a handler on a message, catching panics, and settles the message according to the outcome.
+///
+/// `Ok` acknowledges the message, `HandlerError::Retryable` (and a panic) routes it
+/// through `Queue::retry`, and `HandlerError::Permanent` moves it to the dead-letter
+/// queue with the error as the reason.
+pub async fn handle<F, Fut>(queue: &Queue, message: Message, handler: &F) -> Result<(), QueueError>
+where
+    F: Fn(Delivery) -> Fut + ?Sized,
+    Fut: Future<Output = Result<(), HandlerError>> + Send + 'static,
+{
+    let outcome = run_handler(handler, message.clone()).await;
+    settle(queue, message, outcome).await
+}
+
+/// Runs a handler on a message, reporting a panic as a retryable failure.
+pub async fn run_handler<F, Fut>(handler: &F, message: Message) -> Result<(), HandlerError>
+where
+    F: Fn(Delivery) -> Fut + ?Sized,
+    Fut: Future<Output = Result<(), HandlerError>> + Send + 'static,
+{
+    // Run the handler in its own task so a panic surfaces as a `JoinError` instead of unwinding
+    let mut task = tokio::spawn(handler(Delivery::new(message)));
+    // If this future is dropped, e.g. when a consumer gives up on it after the grace period,
+    // the handler must not keep processing a message that was handed back to the queue
+    let _abort = AbortOnDrop(task.abort_handle());
+    match (&mut task).await {
+        Ok(outcome) => outcome,
+        Err(e) => Err(HandlerError::Retryable(format!("handler panicked: {}", e))),
+    }
+}
+
+/// Aborts a task when dropped; aborting a finished task does nothing.
+pub(crate) struct AbortOnDrop(pub(crate) AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Acknowledges, retries or dead-letters a message according to its handler's outcome.
+pub async fn settle(queue: &Queue, message: Message, outcome: Result<(), HandlerError>) -> Result<(), QueueError> {
+    match outcome {
+        Ok(()) => queue.acknowledge(message.id).await,
+        Err(HandlerError::Retryable(reason)) => {
+            warn!("Message {} failed, retrying: {}", message.id, reason);
+            queue.retry(message).await
+        }
+        Err(HandlerError::Permanent(reason)) => {
+            warn!("Message {} failed permanently: {}", message.id, reason);
+            queue.dead_letter(message, &reason).await
+        }
+    }
+}
+
+/// Settles every message of a batch according to its batch handler's outcome.
+///
+/// A handler error (or a panic) applies to every message of the batch; otherwise each
+/// message is settled with its own outcome from the `BatchOutcome`.
+pub async fn settle_batch(queue: &Queue, messages: Vec<Message>, outcome: Result<BatchOutcome, HandlerError>) -> Result<(), QueueError> {
+    for message in messages {
+        let message_outcome = match &outcome {
+            Ok(batch_outcome) => batch_outcome.outcome_for(message.id),
+            Err(error) => Err(error.clone()),
+        };
+        settle(queue, message, message_outcome).await?;
+    }
+    Ok(())
+}
+
+/// Sums up a batch's outcome as a single delivery outcome for a circuit breaker.
+///
+/// The batch counts as a retryable failure if the handler failed as a whole with a retryable
+/// error, or failed every message of the batch with one; otherwise it counts as a success.
+pub fn batch_health(messages: &[Message], outcome: &Result<BatchOutcome, HandlerError>) -> Result<(), HandlerError> {
+    match outcome {
+        Err(error) => Err(error.clone()),
+        Ok(batch_outcome) => {
+            let retryable = |message: &Message| matches!(batch_outcome.outcome_for(message.id), Err(HandlerError::Retryable(_)));
+            if !messages.is_empty() && messages.iter().all(retryable) {
+                Err(HandlerError::Retryable("every message of the batch failed".to_string()))
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
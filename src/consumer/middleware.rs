@@ -0,0 +1,381 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use log::{info, warn};
+use metrics::{histogram, increment_counter};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration, Instant};
+use crate::consumer::delivery::{AbortOnDrop, BatchOutcome, Delivery, HandlerError};
+
+/// The future returned by a type-erased handler.
+pub type HandlerFuture = Pin<Box<dyn Future<Output = Result<(), HandlerError>> + Send>>;
+
+/// A type-erased message handler, as seen by middleware.
+pub type BoxHandler = Arc<dyn Fn(Delivery) -> HandlerFuture + Send + Sync>;
+
+/// Wraps a handler with cross-cutting behaviour, similar to a tower `Layer`.
+///
+/// `wrap` receives the rest of the chain (`next`) and returns a handler that runs its own
+/// logic around calls to `next`. It may also skip `next` entirely, e.g. to drop duplicates.
+pub trait Middleware: Send + Sync + 'static {
+    /// Returns a handler that wraps `next`.
+    fn wrap(&self, next: BoxHandler) -> BoxHandler;
+}
+
+/// An ordered set of middleware applied around a consumer's handler.
+///
+/// The first middleware added is the outermost one: it sees each delivery first and the
+/// handler's outcome last.
+///
+/// # Examples
+///
+///
+/// use hexboltmq::consumer::middleware::{CatchPanic, Deadline, Logging, MiddlewareStack, Timing};
+/// let stack = MiddlewareStack::new()
+///     .layer(Logging)
+///     .layer(Timing::new())
+///     .layer(CatchPanic)
+///     .layer(Deadline::new(Duration::from_secs(5)));
+///
+#[derive(Clone, Default)]
+pub struct MiddlewareStack {
+    layers: Vec<Arc<dyn Middleware>>,   // Middleware from outermost to innermost
+}
+
+impl MiddlewareStack {
+    /// Creates an empty stack that leaves handlers unchanged.
+    pub fn new() -> MiddlewareStack {
+        MiddlewareStack::default()
+    }
+
+    /// Adds a middleware inside the ones already in the stack.
+    ///
+    /// # Arguments
+    ///
+    /// * `middleware` - The middleware to add.
+    pub fn layer(mut self, middleware: impl Middleware) -> MiddlewareStack {
+        self.layers.push(Arc::new(middleware));
+        self
+    }
+
+    /// Returns `true` if the stack has no middleware.
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Wraps a handler with every middleware in the stack.
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - An async closure that processes a delivery.
+    pub fn apply<F, Fut>(&self, handler: F) -> BoxHandler
+    where
+        F: Fn(Delivery) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), HandlerError>> + Send + 'static,
+    {
+        let handler: BoxHandler = Arc::new(move |delivery| Box::pin(handler(delivery)) as HandlerFuture);
+        self.wrap(handler)
+    }
+
+    /// Runs a batch handler with every middleware in the stack around each of its messages.
+    ///
+    /// Each delivery goes through the middleware on its own. The deliveries that reach the end
+    /// of the chain are handed to `handler` together, and each chain then finishes with its
+    /// message's outcome from the batch. A delivery the middleware does not pass on is left out
+    /// of the batch; every message is settled with the outcome its chain returns.
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - An async closure that processes a batch of deliveries.
+    /// * `deliveries` - The deliveries of the batch.
+    pub async fn run_batch<F, Fut>(&self, handler: &F, deliveries: Vec<Delivery>) -> Result<BatchOutcome, HandlerError>
+    where
+        F: Fn(Vec<Delivery>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<BatchOutcome, HandlerError>> + Send + 'static,
+    {
+        if self.is_empty() {
+            return handler(deliveries).await;
+        }
+
+        // The end of each chain hands its delivery over and waits for the batch's outcome
+        let (arrivals, mut arrived) = mpsc::unbounded_channel::<(Delivery, oneshot::Sender<Result<(), HandlerError>>)>();
+        let chain = self.wrap(Arc::new(move |delivery| {
+            let arrivals = arrivals.clone();
+            Box::pin(async move {
+                let (sender, receiver) = oneshot::channel();
+                let _ = arrivals.send((delivery, sender));
+                receiver.await.unwrap_or_else(|_| Err(HandlerError::Retryable("batch handler did not run".to_string())))
+            }) as HandlerFuture
+        }));
+
+        let (finished, mut finished_ids) = mpsc::unbounded_channel();
+        let mut waiting: HashSet<u64> = deliveries.iter().map(Delivery::id).collect();
+        let chains: Vec<(u64, JoinHandle<Result<(), HandlerError>>)> = deliveries
+            .into_iter()
+            .map(|delivery| {
+                let (message_id, chain) = (delivery.id(), chain.clone());
+                let finished = ChainFinished(message_id, finished.clone());
+                (message_id, tokio::spawn(async move {
+                    let _finished = finished;
+                    chain(delivery).await
+                }))
+            })
+            .collect();
+        // If the batch is abandoned, e.g. after the grace period, its chains must stop with it
+        let _abort: Vec<AbortOnDrop> = chains.iter().map(|(_, chain)| AbortOnDrop(chain.abort_handle())).collect();
+
+        // Wait until every chain has either reached the batch handler or finished without it
+        let mut batch = Vec::new();
+        let mut senders = Vec::new();
+        while !waiting.is_empty() {
+            tokio::select! {
+                Some((delivery, sender)) = arrived.recv() => {
+                    if waiting.remove(&delivery.id()) {
+                        batch.push(delivery);
+                        senders.push(sender);
+                    }
+                }
+                Some(message_id) = finished_ids.recv() => {
+                    waiting.remove(&message_id);
+                }
+            }
+        }
+        // Chains that call the handler again from now on get an error instead of waiting forever
+        drop(arrived);
+
+        let message_ids: Vec<u64> = batch.iter().map(Delivery::id).collect();
+        let outcome = if batch.is_empty() { Ok(BatchOutcome::new()) } else { handler(batch).await };
+        for (message_id, sender) in message_ids.into_iter().zip(senders) {
+            let _ = sender.send(match &outcome {
+                Ok(batch_outcome) => batch_outcome.outcome_for(message_id),
+                Err(error) => Err(error.clone()),
+            });
+        }
+
+        let mut batch_outcome = BatchOutcome::new();
+        for (message_id, chain) in chains {
+            let outcome = chain.await.unwrap_or_else(|e| Err(HandlerError::Retryable(format!("handler panicked: {}", e))));
+            if let Err(error) = outcome {
+                batch_outcome.fail(message_id, error);
+            }
+        }
+        Ok(batch_outcome)
+    }
+
+    /// Wraps a type-erased handler with every middleware in the stack.
+    fn wrap(&self, handler: BoxHandler) -> BoxHandler {
+        self.layers.iter().rev().fold(handler, |next, middleware| middleware.wrap(next))
+    }
+}
+
+/// Reports that a message's chain finished when dropped, even if the chain panicked.
+struct ChainFinished(u64, mpsc::UnboundedSender<u64>);
+
+impl Drop for ChainFinished {
+    fn drop(&mut self) {
+        let _ = self.1.send(self.0);
+    }
+}
+
+impl fmt::Debug for MiddlewareStack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MiddlewareStack").field("layers", &self.layers.len()).finish()
+    }
+}
+
+/// Middleware built from an async closure; see `from_fn`.
+pub struct FnMiddleware<F> {
+    function: Arc<F>,
+}
+
+/// Creates a middleware from an async closure taking the delivery and the rest of the chain.
+///
+/// # Examples
+///
+///
+/// use hexboltmq::consumer::middleware::from_fn;
+/// let skip_tests = from_fn(|delivery, next| async move {
+///     if delivery.headers().contains_key("test") { Ok(()) } else { next(delivery).await }
+/// });
+///
+pub fn from_fn<F, Fut>(function: F) -> FnMiddleware<F>
+where
+    F: Fn(Delivery, BoxHandler) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), HandlerError>> + Send + 'static,
+{
+    FnMiddleware { function: Arc::new(function) }
+}
+
+impl<F, Fut> Middleware for FnMiddleware<F>
+where
+    F: Fn(Delivery, BoxHandler) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), HandlerError>> + Send + 'static,
+{
+    fn wrap(&self, next: BoxHandler) -> BoxHandler {
+        let function = self.function.clone();
+        Arc::new(move |delivery| Box::pin(function(delivery, next.clone())) as HandlerFuture)
+    }
+}
+
+/// Aggregate handler timings recorded by a `Timing` middleware.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimingStats {
+    /// Number of handler calls timed.
+    pub count: u64,
+    /// Total time spent in the handler.
+    pub total: Duration,
+    /// Longest single handler call.
+    pub max: Duration,
+}
+
+/// Measures how long the rest of the chain takes for each message.
+///
+/// Durations are recorded in the `consumer_handler_duration_seconds` histogram, labelled
+/// with the outcome, and aggregated in-process; clones share the same aggregates.
+#[derive(Debug, Clone, Default)]
+pub struct Timing {
+    count: Arc<AtomicU64>,         // Handler calls timed
+    total_micros: Arc<AtomicU64>,  // Sum of handler durations in microseconds
+    max_micros: Arc<AtomicU64>,    // Longest handler duration in microseconds
+}
+
+impl Timing {
+    /// Creates a timing middleware with empty aggregates.
+    pub fn new() -> Timing {
+        Timing::default()
+    }
+
+    /// Returns the timings recorded so far.
+    pub fn stats(&self) -> TimingStats {
+        TimingStats {
+            count: self.count.load(Ordering::Relaxed),
+            total: Duration::from_micros(self.total_micros.load(Ordering::Relaxed)),
+            max: Duration::from_micros(self.max_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl Middleware for Timing {
+    fn wrap(&self, next: BoxHandler) -> BoxHandler {
+        let timing = self.clone();
+        Arc::new(move |delivery| {
+            let (timing, next) = (timing.clone(), next.clone());
+            Box::pin(async move {
+                let started = Instant::now();
+                let outcome = next(delivery).await;
+                let elapsed = started.elapsed();
+
+                let label = outcome_label(&outcome);
+                histogram!("consumer_handler_duration_seconds", elapsed, "outcome" => label);
+                let micros = elapsed.as_micros() as u64;
+                timing.count.fetch_add(1, Ordering::Relaxed);
+                timing.total_micros.fetch_add(micros, Ordering::Relaxed);
+                timing.max_micros.fetch_max(micros, Ordering::Relaxed);
+                outcome
+            })
+        })
+    }
+}
+
+/// Logs one `key=value` line when a message starts and one when it finishes, through the `log`
+/// facade; failures are logged as warnings.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Logging;
+
+impl Middleware for Logging {
+    fn wrap(&self, next: BoxHandler) -> BoxHandler {
+        Arc::new(move |delivery| {
+            let next = next.clone();
+            Box::pin(async move {
+                let (message_id, retry_count) = (delivery.id(), delivery.retry_count());
+                info!("event=handler_started message_id={} priority={} retry_count={}", message_id, delivery.priority(), retry_count);
+
+                let started = Instant::now();
+                let outcome = next(delivery).await;
+                let elapsed_ms = started.elapsed().as_millis();
+                match &outcome {
+                    Ok(()) => info!("event=handler_finished message_id={} outcome=ok elapsed_ms={}", message_id, elapsed_ms),
+                    Err(HandlerError::Retryable(reason)) | Err(HandlerError::Permanent(reason)) => warn!(
+                        "event=handler_finished message_id={} outcome={} elapsed_ms={} error={:?}",
+                        message_id,
+                        outcome_label(&outcome),
+                        elapsed_ms,
+                        reason
+                    ),
+                }
+                outcome
+            })
+        })
+    }
+}
+
+/// Turns a panic in the rest of the chain into a retryable failure.
+///
+/// The consumer already does this for the whole chain; adding `CatchPanic` lets outer
+/// middleware (e.g. `Timing` or `Logging`) observe the failure instead of being unwound.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CatchPanic;
+
+impl Middleware for CatchPanic {
+    fn wrap(&self, next: BoxHandler) -> BoxHandler {
+        Arc::new(move |delivery| {
+            let next = next.clone();
+            Box::pin(async move {
+                let mut task = tokio::spawn(next(delivery));
+                // The rest of the chain must not outlive the handler if the consumer abandons it
+                let _abort = AbortOnDrop(task.abort_handle());
+                match (&mut task).await {
+                    Ok(outcome) => outcome,
+                    Err(e) => {
+                        increment_counter!("consumer_handler_panics_total");
+                        Err(HandlerError::Retryable(format!("handler panicked: {}", e)))
+                    }
+                }
+            })
+        })
+    }
+}
+
+/// Fails a message as retryable if the rest of the chain does not finish within a deadline.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    limit: Duration,   // How long each message may take
+}
+
+impl Deadline {
+    /// Creates a deadline middleware.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - How long the handler may take for each message.
+    pub fn new(limit: Duration) -> Deadline {
+        Deadline { limit }
+    }
+}
+
+impl Middleware for Deadline {
+    fn wrap(&self, next: BoxHandler) -> BoxHandler {
+        let limit = self.limit;
+        Arc::new(move |delivery| {
+            let next = next.clone();
+            Box::pin(async move {
+                match timeout(limit, next(delivery)).await {
+                    Ok(outcome) => outcome,
+                    Err(_) => Err(HandlerError::Retryable(format!("deadline of {:?} exceeded", limit))),
+                }
+            })
+        })
+    }
+}
+
+fn outcome_label(outcome: &Result<(), HandlerError>) -> &'static str {
+    match outcome {
+        Ok(()) => "ok",
+        Err(HandlerError::Retryable(_)) => "retryable",
+        Err(HandlerError::Permanent(_)) => "permanent",
+    }
+}
@@ -1 +1,6 @@
+pub mod circuit_breaker;
 pub mod consumer;
+pub mod delivery;
+pub mod middleware;
+pub mod shutdown;
+pub mod typed_consumer;
@@ -0,0 +1,44 @@
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// A cloneable cancellation signal shared by consumers and whoever stops them.
+///
+/// All clones observe the same signal: once `trigger` is called on any of them,
+/// every consumer holding a clone stops fetching and drains.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl Shutdown {
+    /// Creates a new, untriggered signal.
+    pub fn new() -> Shutdown {
+        let (sender, _) = watch::channel(false);
+        Shutdown {
+            sender: Arc::new(sender),
+        }
+    }
+
+    /// Triggers the signal. Triggering more than once has no further effect.
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    /// Returns `true` if the signal has been triggered.
+    pub fn is_triggered(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Waits until the signal is triggered. Returns immediately if it already was.
+    pub async fn triggered(&self) {
+        let mut receiver = self.sender.subscribe();
+        // The sender lives as long as `self`, so this can only return once the value is `true`
+        let _ = receiver.wait_for(|triggered| *triggered).await;
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
@@ -0,0 +1,139 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+use serde::de::DeserializeOwned;
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use uuid::Uuid;
+use crate::codec::codec::{self, Codec, CodecError, JsonCodec};
+use crate::consumer::consumer::{ConsumerError, DEFAULT_POLL_INTERVAL};
+use crate::consumer::shutdown::Shutdown;
+use crate::queue::{Message, Queue, CONTENT_TYPE_HEADER};
+use log::{debug, warn};
+
+/// Reason recorded on messages dead-lettered because they could not be decoded.
+pub const DECODE_ERROR_REASON: &str = "decode error";
+
+/// A consumer that receives typed values, decoding them with its codec.
+///
+/// Messages whose `content-type` header does not match the codec, or whose content
+/// fails to decode, are moved straight to the dead-letter queue with the reason
+/// "decode error" instead of being delivered or retried.
+#[derive(Debug, Clone)]
+pub struct TypedConsumer<T, C = JsonCodec> {
+    id: Uuid,                    // Unique ID for the consumer
+    queue: Arc<Mutex<Queue>>,     // Reference to the queue
+    codec: C,                     // Codec the values are decoded with
+    shutdown: Shutdown,           // Signal that stops the consume loop
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> TypedConsumer<T, JsonCodec> {
+    /// Creates a new typed consumer that decodes JSON values.
+    ///
+    /// # Arguments
+    ///
+    /// * `queue` - The reference to the queue the consumer will pull messages from.
+    pub fn new(queue: Arc<Mutex<Queue>>) -> TypedConsumer<T, JsonCodec> {
+        TypedConsumer::with_codec(queue, JsonCodec)
+    }
+}
+
+impl<T: DeserializeOwned, C: Codec> TypedConsumer<T, C> {
+    /// Creates a new typed consumer that decodes values with `codec`.
+    ///
+    /// # Arguments
+    ///
+    /// * `queue` - The reference to the queue the consumer will pull messages from.
+    /// * `codec` - The codec of the queue's messages.
+    pub fn with_codec(queue: Arc<Mutex<Queue>>, codec: C) -> TypedConsumer<T, C> {
+        TypedConsumer {
+            id: Uuid::new_v4(),
+            queue,
+            codec,
+            shutdown: Shutdown::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Stops the consume loop when the given signal is triggered.
+    ///
+    /// # Arguments
+    ///
+    /// * `shutdown` - The signal to observe.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> TypedConsumer<T, C> {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Receives and decodes the next available message.
+    ///
+    /// Messages that cannot be decoded are dead-lettered and skipped.
+    ///
+    /// Returns the decoded value together with the raw message, or `None` if no message is available.
+    pub async fn receive(&self) -> Result<Option<(T, Message)>, ConsumerError> {
+        let queue = self.queue.lock().await.clone();
+
+        while let Some(message) = queue.pop().await? {
+            match self.decode(&message) {
+                Ok(value) => return Ok(Some((value, message))),
+                Err(e) => {
+                    warn!("Consumer {:?} failed to decode message {}: {:?}", self.id, message.id, e);
+                    queue.dead_letter(message, DECODE_ERROR_REASON).await?;
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Consumes messages from the queue, decodes them and processes the values.
+    ///
+    /// Runs until the consumer's shutdown signal is triggered.
+    ///
+    /// # Arguments
+    ///
+    /// * `process_value` - A closure that processes each decoded value.
+    pub async fn consume<F>(&self, process_value: F)
+    where
+        F: Fn(T) + Send + 'static,
+    {
+        while !self.shutdown.is_triggered() {
+            let wait = match self.receive().await {
+                Ok(Some((value, message))) => {
+                    debug!("Consumer {:?} processing message: {}", self.id, message.id);
+                    process_value(value);
+
+                    let queue = self.queue.lock().await;
+                    if let Err(e) = queue.acknowledge(message.id).await {
+                        warn!("Consumer {:?} failed to acknowledge message {}: {:?}", self.id, message.id, e);
+                    }
+                    continue;
+                }
+                // If no message is available, wait before retrying
+                Ok(None) => Duration::from_secs(5),
+                Err(e) => {
+                    warn!("Consumer {:?} failed to receive a message: {:?}", self.id, e);
+                    DEFAULT_POLL_INTERVAL
+                }
+            };
+            tokio::select! {
+                _ = sleep(wait) => {}
+                _ = self.shutdown.triggered() => {}
+            }
+        }
+    }
+
+    /// Decodes a message with the consumer's codec, checking its content type against the codec first.
+    fn decode(&self, message: &Message) -> Result<T, CodecError> {
+        if let Some(content_type) = message.headers.get(CONTENT_TYPE_HEADER) {
+            if content_type != self.codec.content_type() {
+                return Err(CodecError::ContentTypeMismatch {
+                    expected: self.codec.content_type().to_string(),
+                    actual: content_type.clone(),
+                });
+            }
+        }
+        codec::decode(&self.codec, &message.content)
+    }
+}
@@ -1,14 +1,16 @@
 pub mod queue;
-mod producer;
-mod consumer;
+pub mod api;
+pub mod broker;
+pub mod producer;
+pub mod consumer;
+pub mod codec;
 mod network;
-mod storage;
+pub mod storage;
 mod config;
 mod auth;
-mod scheduler;
+pub mod scheduler;
 mod metrics;
-mod cli;
-mod utils;
-mod cluster;
+pub mod utils;
+pub mod cluster;
 mod logging;
 mod plugins;
\ No newline at end of file
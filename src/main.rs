@@ -6,13 +6,18 @@
 //! ## Modules
 //!
 //! - `queue`: Core queue functionality with priority handling.
+//! - `broker`: Registry of named queues shared by producers and consumers.
 //! - `producer`: Module for managing message producers.
 //! - `consumer`: Module for managing message consumers.
+//! - `codec`: Serialization codecs for typed producers and consumers.
 //!
 
 mod queue;
+mod api;
+mod broker;
 mod producer;
 mod consumer;
+mod codec;
 mod network;
 mod storage;
 mod config;
@@ -25,9 +30,131 @@ mod cluster;
 mod logging;
 mod plugins;
 
-use log::info;
+use api::admin::AdminApi;
+use cli::Command;
+use consumer::consumer::DEFAULT_GRACE_PERIOD;
+use consumer::shutdown::Shutdown;
+use log::{info, warn};
+use queue::lazy::LazyQueueConfig;
+use storage::backend::StorageConfig;
+use storage::encryption::Keyring;
+use storage::storage::Storage;
+use tokio::time::{timeout, Duration};
 
-fn main() {
+/// Pause between pages of the re-encryption pass run at startup, so it does not compete with traffic.
+const REENCRYPTION_PAUSE: Duration = Duration::from_millis(10);
+
+#[tokio::main]
+async fn main() {
     env_logger::init();
+
+    let command = match cli::parse(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    let outcome = match command {
+        Command::Serve { data_dir, restore_from, keyfile, snapshot_dir, admin_address } => serve(data_dir, restore_from, keyfile, snapshot_dir, &admin_address).await,
+        Command::Snapshot { dir, admin_address } => cli::snapshot(&dir, &admin_address).await,
+        Command::Reencrypt { admin_address } => cli::reencrypt(&admin_address).await,
+        Command::Restore { snapshot_dir, data_dir } => cli::restore(&snapshot_dir, &data_dir),
+        Command::Verify { data_dir, keyfile, repair } => cli::verify(&data_dir, keyfile.as_deref(), repair),
+    };
+    if let Err(e) = outcome {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Runs the broker until a termination signal is received.
+///
+/// With a data directory, queues are recovered from RocksDB there (after restoring a snapshot
+/// into it, if one is given) and the admin API is served on `admin_address`, taking snapshots
+/// under `snapshot_dir` if one is given. With a keyfile, stored messages are encrypted, and
+/// those not under its active key are re-encrypted in the background.
+///
+/// On termination the admin API stops accepting connections and the server's tasks are given
+/// up to `DEFAULT_GRACE_PERIOD` to finish the work in hand before the process exits.
+async fn serve(data_dir: Option<String>, restore_from: Option<String>, keyfile: Option<String>, snapshot_dir: Option<String>, admin_address: &str) -> Result<(), String> {
     info!("HexboltMQ is starting...");
+    let shutdown = Shutdown::new();
+    let mut tasks = Vec::new();
+
+    if let Some(data_dir) = data_dir {
+        let config = StorageConfig::RocksDb { path: data_dir };
+        let storage = match restore_from {
+            Some(snapshot_dir) => tokio::task::spawn_blocking(move || Storage::restore(&snapshot_dir, &config))
+                .await
+                .map_err(|e| e.to_string())??,
+            None => Storage::open(&config)?,
+        };
+        let storage = match keyfile {
+            Some(keyfile) => {
+                let storage = storage.with_encryption(Keyring::load(&keyfile)?);
+                // Encrypted records left by the migration on open can be upgraded now
+                storage.migrate()?;
+                let mut reencryption = storage.spawn_reencryption(REENCRYPTION_PAUSE);
+                let shutdown = shutdown.clone();
+                // The pass skips messages already under the active key, so the next start resumes it
+                tasks.push(tokio::spawn(async move {
+                    tokio::select! {
+                        _ = &mut reencryption => {}
+                        _ = shutdown.triggered() => {
+                            info!("Re-encryption interrupted by shutdown");
+                            reencryption.abort();
+                            let _ = reencryption.await;
+                        }
+                    }
+                }));
+                storage
+            }
+            None => storage,
+        };
+        let admin = AdminApi::boot(storage, LazyQueueConfig::default()).await?.with_shutdown(shutdown.clone());
+        let admin = match snapshot_dir {
+            Some(snapshot_dir) => admin.with_snapshot_dir(snapshot_dir),
+            None => admin,
+        };
+        let admin_address = admin_address.to_string();
+        tasks.push(tokio::spawn(async move {
+            if let Err(e) = admin.serve(&admin_address).await {
+                info!("Admin API stopped: {}", e);
+            }
+        }));
+    }
+
+    wait_for_termination().await;
+    info!("HexboltMQ is shutting down...");
+    shutdown.trigger();
+    let drained = timeout(DEFAULT_GRACE_PERIOD, async {
+        for task in tasks {
+            let _ = task.await;
+        }
+    })
+    .await;
+    if drained.is_err() {
+        warn!("Tasks still running after {:?}, stopping anyway", DEFAULT_GRACE_PERIOD);
+    }
+    info!("HexboltMQ stopped");
+    Ok(())
+}
+
+/// Waits for SIGTERM (sent by deploy tooling) or Ctrl-C.
+#[cfg(unix)]
+async fn wait_for_termination() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = sigterm.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+/// Waits for Ctrl-C.
+#[cfg(not(unix))]
+async fn wait_for_termination() {
+    tokio::signal::ctrl_c().await.expect("Failed to listen for Ctrl-C");
 }
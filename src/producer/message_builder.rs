@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+use crate::queue::Message;
+use crate::utils::id_generator;
+
+/// Maximum number of retries for messages that do not set their own.
+pub const DEFAULT_MAX_RETRIES: u8 = 5;
+
+/// A message ready to be published, together with its routing and scheduling.
+#[derive(Debug, Clone)]
+pub struct OutgoingMessage {
+    /// The message itself, with a freshly assigned id.
+    pub message: Message,
+    /// The delay after which the message becomes available for processing.
+    pub delay: Duration,
+    /// The name of the queue the message is sent to, or `None` for the producer's own queue.
+    pub queue: Option<String>,
+}
+
+/// When a message becomes available for processing.
+#[derive(Debug, Clone, Copy)]
+enum Delivery {
+    After(Duration),
+    At(Instant),
+}
+
+/// Builder for messages with per-message options.
+///
+/// Only the payload is required; every other option has a default:
+/// priority 0, immediate delivery, no TTL, `DEFAULT_MAX_RETRIES` retries,
+/// no headers, group key or dedup id, and the producer's own queue.
+///
+/// # Examples
+///
+///
+/// use hexboltmq::producer::message_builder::MessageBuilder;
+/// use tokio::time::Duration;
+/// let message = MessageBuilder::new("order created")
+///     .priority(5)
+///     .ttl(Duration::from_secs(60))
+///     .header("region", "eu")
+///     .group_key("order-42")
+///     .queue("orders");
+/// producer.send(message).await.unwrap();
+///
+#[derive(Debug, Clone)]
+pub struct MessageBuilder {
+    content: String,
+    priority: u8,
+    delivery: Delivery,
+    ttl: Option<Duration>,
+    max_retries: u8,
+    headers: HashMap<String, String>,
+    group_key: Option<String>,
+    dedup_id: Option<String>,
+    queue: Option<String>,
+}
+
+impl MessageBuilder {
+    /// Starts building a message with the given payload.
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - The content of the message.
+    pub fn new(content: impl Into<String>) -> MessageBuilder {
+        MessageBuilder {
+            content: content.into(),
+            priority: 0,
+            delivery: Delivery::After(Duration::from_secs(0)),
+            ttl: None,
+            max_retries: DEFAULT_MAX_RETRIES,
+            headers: HashMap::new(),
+            group_key: None,
+            dedup_id: None,
+            queue: None,
+        }
+    }
+
+    /// Sets the priority (higher priority messages will be processed first).
+    pub fn priority(mut self, priority: u8) -> MessageBuilder {
+        self.priority = priority;
+        self
+    }
+
+    /// Delays delivery by the given duration. Replaces any earlier `deliver_at`.
+    pub fn delay(mut self, delay: Duration) -> MessageBuilder {
+        self.delivery = Delivery::After(delay);
+        self
+    }
+
+    /// Delivers the message at the given time. Replaces any earlier `delay`.
+    pub fn deliver_at(mut self, deliver_at: Instant) -> MessageBuilder {
+        self.delivery = Delivery::At(deliver_at);
+        self
+    }
+
+    /// Moves the message to the dead-letter queue if it has not been delivered within `ttl` of being built.
+    pub fn ttl(mut self, ttl: Duration) -> MessageBuilder {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the maximum number of retries allowed.
+    pub fn max_retries(mut self, max_retries: u8) -> MessageBuilder {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Adds a header, replacing any previous value for the same key.
+    pub fn header(mut self, key: impl Into<String>, value: impl Into<String>) -> MessageBuilder {
+        self.headers.insert(key.into(), value.into());
+        self
+    }
+
+    /// Sets the key grouping this message with related ones; a group has at most one message in flight.
+    pub fn group_key(mut self, group_key: impl Into<String>) -> MessageBuilder {
+        self.group_key = Some(group_key.into());
+        self
+    }
+
+    /// Sets the deduplication id; a message repeating the id of one pushed to the same queue
+    /// within `DEDUP_WINDOW` is dropped and confirmed as `Durability::Duplicate`.
+    pub fn dedup_id(mut self, dedup_id: impl Into<String>) -> MessageBuilder {
+        self.dedup_id = Some(dedup_id.into());
+        self
+    }
+
+    /// Sends the message to the named queue instead of the producer's own queue.
+    pub fn queue(mut self, queue: impl Into<String>) -> MessageBuilder {
+        self.queue = Some(queue.into());
+        self
+    }
+
+    /// Replaces the payload, e.g. once it is encoded with the target queue's codec.
+    pub(crate) fn content(mut self, content: String) -> MessageBuilder {
+        self.content = content;
+        self
+    }
+
+    /// Returns the name of the queue the message is sent to, or `None` for the producer's own queue.
+    pub(crate) fn target_queue(&self) -> Option<&str> {
+        self.queue.as_deref()
+    }
+
+    /// Builds the message, assigning it a fresh id.
+    pub fn build(self) -> OutgoingMessage {
+        let now = Instant::now();
+        let delay = match self.delivery {
+            Delivery::After(delay) => delay,
+            Delivery::At(deliver_at) => deliver_at.saturating_duration_since(now),
+        };
+
+        let message = Message {
+            id: id_generator::next_id(),         // Cluster-unique, time-ordered id
+            content: self.content,
+            priority: self.priority,
+            available_at: now + delay,
+            retry_count: 0,
+            max_retries: self.max_retries,
+            headers: self.headers,
+            expires_at: self.ttl.map(|ttl| now + ttl),
+            group_key: self.group_key,
+            dedup_id: self.dedup_id,
+        };
+
+        OutgoingMessage {
+            message,
+            delay,
+            queue: self.queue,
+        }
+    }
+}
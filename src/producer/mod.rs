@@ -1 +1,3 @@
-pub mod producer;
\ No newline at end of file
+pub mod producer;
+pub mod message_builder;
+pub mod typed_producer;
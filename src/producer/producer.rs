@@ -1,14 +1,139 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use uuid::Uuid;
-use tokio::time::{Duration, Instant};
-use crate::queue::{Message, Queue};
-use tokio::sync::Mutex;
-use std::sync::Arc;
+use tokio::time::{timeout_at, Duration, Instant};
+use crate::broker::broker::Broker;
+use crate::cluster::cluster::Cluster;
+use crate::codec::codec::CodecError;
+use crate::storage::storage::Message as StoredMessage;
+use crate::producer::message_builder::{MessageBuilder, OutgoingMessage};
+use crate::queue::{Message, Queue, QueueError, CORRELATION_ID_HEADER, REPLY_TO_HEADER};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+/// Errors that can occur when producing messages.
+#[derive(Debug, Clone)]
+pub enum ProducerError {
+    /// The underlying queue operation failed.
+    Queue(QueueError),
+    /// The producer was created without a broker, so named queues cannot be resolved.
+    NoBroker,
+    /// No queue with the given name has been declared on the broker.
+    QueueNotFound(String),
+    /// No reply was received before the request timed out.
+    Timeout,
+    /// The message could not be encoded for replication.
+    Encoding(String),
+    /// Fewer peers than required acknowledged the replicated message.
+    InsufficientReplicas { required: usize, acknowledged: usize },
+    /// The producer's pipeline shut down before the message was confirmed.
+    Closed,
+    /// The value could not be encoded into message content.
+    Codec(CodecError),
+}
+
+impl From<QueueError> for ProducerError {
+    fn from(error: QueueError) -> Self {
+        ProducerError::Queue(error)
+    }
+}
+
+/// The durability level a published message reached before it was confirmed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// The message is only held in the memory of the local queue.
+    InMemory,
+    /// The message was written to its lazy queue's storage and fsynced.
+    Fsynced,
+    /// The message was acknowledged by this many peers (and fsynced locally as for `Fsynced`).
+    Replicated(usize),
+    /// The message carried the dedup id of a message pushed to its queue within `DEDUP_WINDOW`,
+    /// so it was dropped without being replicated or written; the confirmation carries the id
+    /// of that original message.
+    Duplicate,
+}
+
+/// A record the producer replicates to the other cluster nodes, serialized with bincode.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReplicatedRecord {
+    /// Messages being published.
+    Publish(Vec<StoredMessage>),
+    /// Ids of replicated messages that could not be published after all; replicas drop them.
+    Retract(Vec<u64>),
+}
+
+/// Confirmation that a message was published.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Confirmation {
+    /// The id assigned to the message.
+    pub id: u64,
+    /// The durability level reached by the message.
+    pub durability: Durability,
+}
+
+/// A confirmation that resolves once a pipelined message has been published.
+///
+/// Returned by `Producer::send_message_pipelined`; await it to get the `Confirmation`.
+#[derive(Debug)]
+pub struct PendingConfirmation {
+    receiver: oneshot::Receiver<Result<Confirmation, ProducerError>>,
+}
+
+impl Future for PendingConfirmation {
+    type Output = Result<Confirmation, ProducerError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.receiver)
+            .poll(cx)
+            .map(|result| result.unwrap_or(Err(ProducerError::Closed)))
+    }
+}
+
+/// Thresholds controlling how the producer groups outgoing messages into batches.
+///
+/// A batch is flushed as soon as it holds `max_messages` messages or `max_bytes` bytes
+/// of content, or once `linger` has passed since its first message was buffered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchConfig {
+    /// Maximum number of messages in a batch.
+    pub max_messages: usize,
+    /// Maximum total size of the message contents and headers in a batch, in bytes.
+    pub max_bytes: usize,
+    /// Maximum time a message waits for its batch to fill up.
+    pub linger: Duration,
+}
+
+impl Default for BatchConfig {
+    /// Flushes every message on its own, i.e. no batching.
+    fn default() -> Self {
+        BatchConfig {
+            max_messages: 1,
+            max_bytes: usize::MAX,
+            linger: Duration::from_secs(0),
+        }
+    }
+}
+
+/// A message waiting in the producer's pipeline, with the channel its confirmation is sent on.
+type PipelinedMessage = (OutgoingMessage, oneshot::Sender<Result<Confirmation, ProducerError>>);
+
+/// A target queue (by name, `None` for the producer's own queue) with the messages of a batch bound for it.
+type QueueBatch = (Option<String>, Queue, Vec<(Message, Duration)>);
 
 /// Represents a producer responsible for sending messages to the queue or cluster.
 #[derive(Debug, Clone)]
 pub struct Producer {
     id: Uuid,                    // Unique ID for the producer
     queue: Arc<Mutex<Queue>>,     // Reference to the queue
+    broker: Option<Broker>,       // Broker used to resolve named queues (request/reply)
+    fsync: bool,                  // Whether pushes to lazy queues are fsynced before being confirmed
+    replication: Option<(Cluster, usize)>, // Cluster to replicate to, with the minimum number of acks
+    batching: Option<BatchConfig>, // Thresholds for batching outgoing messages (if enabled)
+    pipeline: Arc<OnceLock<mpsc::UnboundedSender<PipelinedMessage>>>, // Feeds the pipelined publisher task
 }
 
 impl Producer {
@@ -22,32 +147,400 @@ impl Producer {
         Producer {
             id: Uuid::new_v4(),
             queue,
+            broker: None,
+            fsync: false,
+            replication: None,
+            batching: None,
+            pipeline: Arc::new(OnceLock::new()),
         }
     }
 
+    /// Creates a new producer that can also address named queues on a broker.
+    ///
+    /// # Arguments
+    ///
+    /// * `queue` - The reference to the queue the producer will push messages into.
+    /// * `broker` - The broker used to resolve named queues and declare reply queues.
+    ///
+    pub fn with_broker(queue: Arc<Mutex<Queue>>, broker: Broker) -> Producer {
+        Producer {
+            id: Uuid::new_v4(),
+            queue,
+            broker: Some(broker),
+            fsync: false,
+            replication: None,
+            batching: None,
+            pipeline: Arc::new(OnceLock::new()),
+        }
+    }
+
+    /// Waits for messages pushed to lazy queues to be fsynced to the queue's storage before
+    /// confirming them, whatever the storage's `SyncPolicy`.
+    ///
+    /// Their confirmations report at least `Durability::Fsynced`. Regular queues only hold
+    /// their messages in memory, so messages pushed to them are not persisted.
+    pub fn with_fsync(mut self) -> Producer {
+        self.fsync = true;
+        self
+    }
+
+    /// Replicates every published message to the other cluster nodes before confirming it.
+    ///
+    /// # Arguments
+    ///
+    /// * `cluster` - The cluster whose peers receive the messages.
+    /// * `min_replicas` - The number of peers that must acknowledge a message for it to be confirmed.
+    pub fn replicate_to(mut self, cluster: Cluster, min_replicas: usize) -> Producer {
+        self.replication = Some((cluster, min_replicas));
+        self
+    }
+
+    /// Buffers outgoing messages and publishes them in batches.
+    ///
+    /// Each batch is replicated as a single unit and pushed to each of its queues as one,
+    /// while every message still gets its own confirmation. With batching enabled, `send_message`
+    /// waits for the batch containing its message to be flushed.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The thresholds at which a batch is flushed.
+    pub fn with_batching(mut self, config: BatchConfig) -> Producer {
+        self.batching = Some(config);
+        self
+    }
+
     /// Sends a message to the queue.
     ///
+    /// Shorthand for `send` with a message built from just a payload, priority and delay.
+    ///
     /// # Arguments
     ///
     /// * `content` - The content of the message to be sent.
     /// * `priority` - The priority of the message (higher priority messages will be processed first).
     /// * `delay` - Optional delay for delayed message delivery.
-    pub async fn send_message(&self, content: String, priority: u8, delay: Duration) {
-        // Generate a message ID by converting Uuid to u64 (use part of Uuid or implement custom logic)
-        let message = Message {
-            id: Uuid::new_v4().as_u128() as u64, // Convert Uuid to u64
-            content,
-            priority,
-            available_at: Instant::now() + delay, // Calculate availability time based on the delay
-            retry_count: 0,    // Default retry count for this message
-            max_retries: 5,    // Max retries allowed for this message
+    pub async fn send_message(&self, content: String, priority: u8, delay: Duration) -> Result<Confirmation, ProducerError> {
+        self.send(MessageBuilder::new(content).priority(priority).delay(delay)).await
+    }
+
+    /// Sends a message built with a `MessageBuilder`.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message to send, with its per-message options.
+    ///
+    /// Returns a `Confirmation` with the assigned id and the durability level reached.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `ProducerError` if the target queue could not be resolved, or if the
+    /// message could not be replicated to enough peers or pushed (and persisted) to the queue.
+    pub async fn send(&self, message: MessageBuilder) -> Result<Confirmation, ProducerError> {
+        if self.batching.is_some() {
+            return self.send_pipelined(message).await;
+        }
+
+        let outgoing = message.build();
+        debug!("Producer {:?} sending message: {:?}", self.id, outgoing.message);
+        self.publish_batch(vec![outgoing]).await.remove(0)
+    }
+
+    /// Sends a message without waiting for it to be published.
+    ///
+    /// Shorthand for `send_pipelined` with a message built from just a payload, priority and delay.
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - The content of the message to be sent.
+    /// * `priority` - The priority of the message (higher priority messages will be processed first).
+    /// * `delay` - Optional delay for delayed message delivery.
+    pub fn send_message_pipelined(&self, content: String, priority: u8, delay: Duration) -> PendingConfirmation {
+        self.send_pipelined(MessageBuilder::new(content).priority(priority).delay(delay))
+    }
+
+    /// Sends a message built with a `MessageBuilder` without waiting for it to be published.
+    ///
+    /// Messages are published in order by a background task, so callers can keep many
+    /// messages in flight and await the returned confirmations later. If batching is
+    /// enabled, the task groups messages according to the producer's `BatchConfig`.
+    /// Must be called from within a Tokio runtime.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message to send, with its per-message options.
+    pub fn send_pipelined(&self, message: MessageBuilder) -> PendingConfirmation {
+        let (sender, receiver) = oneshot::channel();
+
+        // A closed pipeline drops the sender, which resolves the confirmation as `Closed`
+        let _ = self.pipeline().send((message.build(), sender));
+        PendingConfirmation { receiver }
+    }
+
+    /// Returns the sender feeding the pipelined publisher, starting the publisher on first use.
+    fn pipeline(&self) -> &mpsc::UnboundedSender<PipelinedMessage> {
+        self.pipeline.get_or_init(|| {
+            let (sender, receiver) = mpsc::unbounded_channel::<PipelinedMessage>();
+
+            // The publisher gets its own (empty) pipeline so it does not keep this one open
+            let publisher = Producer {
+                pipeline: Arc::new(OnceLock::new()),
+                ..self.clone()
+            };
+            tokio::spawn(publisher.run_pipeline(receiver));
+
+            sender
+        })
+    }
+
+    /// Collects pipelined messages into batches and publishes them until the pipeline is closed.
+    async fn run_pipeline(self, mut receiver: mpsc::UnboundedReceiver<PipelinedMessage>) {
+        let config = self.batching.unwrap_or_default();
+
+        while let Some(first) = receiver.recv().await {
+            let deadline = Instant::now() + config.linger;
+            let mut bytes = Self::message_bytes(&first.0.message);
+            let mut batch = vec![first];
+
+            // Keep buffering until a threshold is reached or the linger time expires
+            while batch.len() < config.max_messages && bytes < config.max_bytes {
+                match timeout_at(deadline, receiver.recv()).await {
+                    Ok(Some(next)) => {
+                        bytes += Self::message_bytes(&next.0.message);
+                        batch.push(next);
+                    }
+                    Ok(None) | Err(_) => break,
+                }
+            }
+
+            let (messages, confirms): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+
+            debug!("Producer {:?} flushing batch of {} messages ({} bytes)", self.id, messages.len(), bytes);
+            let results = self.publish_batch(messages).await;
+            for (confirm, result) in confirms.into_iter().zip(results) {
+                let _ = confirm.send(result);
+            }
+        }
+    }
+
+    /// Returns the number of bytes a message counts for towards `BatchConfig::max_bytes`.
+    fn message_bytes(message: &Message) -> usize {
+        message.content.len()
+            + message.headers.iter().map(|(key, value)| key.len() + value.len()).sum::<usize>()
+    }
+
+    /// Resolves the queue a message is sent to: a named queue on the broker, or the producer's own queue.
+    pub(crate) async fn target_queue(&self, name: Option<&str>) -> Result<Queue, ProducerError> {
+        match name {
+            Some(name) => {
+                let broker = self.broker.as_ref().ok_or(ProducerError::NoBroker)?;
+                broker
+                    .get_queue(name)
+                    .await
+                    .ok_or_else(|| ProducerError::QueueNotFound(name.to_string()))
+            }
+            None => Ok(self.queue.lock().await.clone()),
+        }
+    }
+
+    /// Replicates a batch of messages and pushes them to their queues.
+    ///
+    /// Returns one result per message, in order. Duplicates are dropped first and confirmed as
+    /// `Durability::Duplicate`. If a target queue cannot be resolved or the batch is not
+    /// replicated to enough peers, every message fails. Otherwise the messages bound for one
+    /// queue are pushed as a unit: if that push fails, they all fail and peers are asked to
+    /// drop their replicated copies, while the other queues' messages still succeed.
+    async fn publish_batch(&self, messages: Vec<OutgoingMessage>) -> Vec<Result<Confirmation, ProducerError>> {
+        let count = messages.len();
+        match self.try_publish_batch(messages).await {
+            Ok(results) => results,
+            Err(e) => vec![Err(e); count],
+        }
+    }
+
+    /// Does the work of `publish_batch`, failing the whole batch with the error it returns.
+    async fn try_publish_batch(&self, messages: Vec<OutgoingMessage>) -> Result<Vec<Result<Confirmation, ProducerError>>, ProducerError> {
+        // Resolve every target queue up front so a bad queue name fails the batch before anything is written
+        let mut targets: Vec<QueueBatch> = Vec::new();
+        let mut queue_of = Vec::with_capacity(messages.len());
+        for outgoing in &messages {
+            let index = match targets.iter().position(|(name, _, _)| *name == outgoing.queue) {
+                Some(index) => index,
+                None => {
+                    let queue = self.target_queue(outgoing.queue.as_deref()).await?;
+                    targets.push((outgoing.queue.clone(), queue, Vec::new()));
+                    targets.len() - 1
+                }
+            };
+            queue_of.push(index);
+        }
+
+        let ids: Vec<u64> = messages.iter().map(|outgoing| outgoing.message.id).collect();
+        for (outgoing, &index) in messages.into_iter().zip(&queue_of) {
+            targets[index].2.push((outgoing.message, outgoing.delay));
+        }
+
+        // Drop duplicates before anything is replicated or written, confirming them with the original's id
+        let mut duplicates = HashMap::new();
+        for (_, queue, batch) in &mut targets {
+            let originals = queue.claim_dedup_ids(batch.iter().map(|(message, _)| message)).await;
+            let mut originals = originals.into_iter();
+            batch.retain(|(message, _)| match originals.next().flatten() {
+                Some(original) => {
+                    debug!("Producer {:?} dropping message {} as a duplicate of message {}", self.id, message.id, original);
+                    duplicates.insert(message.id, original);
+                    false
+                }
+                None => true,
+            });
+        }
+
+        let mut replicas = None;
+        let accepted: Vec<&Message> = targets.iter().flat_map(|(_, _, batch)| batch.iter().map(|(message, _)| message)).collect();
+        if let Some((cluster, min_replicas)) = self.replication.as_ref().filter(|_| !accepted.is_empty()) {
+            match Self::replicate(cluster, *min_replicas, &accepted).await {
+                Ok(acknowledged) => replicas = Some(acknowledged),
+                Err(e) => {
+                    // Nothing was published, so the messages may be sent again
+                    for (_, queue, batch) in &targets {
+                        let messages: Vec<Message> = batch.iter().map(|(message, _)| message.clone()).collect();
+                        queue.release_dedup_ids(&messages).await;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        // Push the messages to their queues, one batch per queue
+        let mut outcomes = Vec::with_capacity(targets.len());
+        for (_, queue, batch) in targets {
+            if batch.is_empty() {
+                // Every message for this queue was a duplicate
+                outcomes.push(Ok(Durability::Duplicate));
+                continue;
+            }
+            let batch_ids: Vec<u64> = batch.iter().map(|(message, _)| message.id).collect();
+            outcomes.push(match queue.push_claimed(batch, self.fsync).await {
+                Ok(()) => Ok(match replicas {
+                    Some(acknowledged) => Durability::Replicated(acknowledged),
+                    None if self.fsync && queue.is_durable() => Durability::Fsynced,
+                    None => Durability::InMemory,
+                }),
+                Err(e) => {
+                    if let Some((cluster, _)) = self.replication.as_ref().filter(|_| replicas.is_some()) {
+                        Self::retract(cluster, batch_ids).await;
+                    }
+                    Err(ProducerError::from(e))
+                }
+            });
+        }
+
+        Ok(ids
+            .into_iter()
+            .zip(queue_of)
+            .map(|(id, index)| match duplicates.get(&id) {
+                Some(&original) => Ok(Confirmation { id: original, durability: Durability::Duplicate }),
+                None => outcomes[index].clone().map(|durability| Confirmation { id, durability }),
+            })
+            .collect())
+    }
+
+    /// Replicates messages to the cluster and returns how many peers acknowledged them.
+    ///
+    /// Fails if fewer than `min_replicas` peers did, after asking those that did to drop them.
+    async fn replicate(cluster: &Cluster, min_replicas: usize, messages: &[&Message]) -> Result<usize, ProducerError> {
+        let stored = messages.iter().map(|message| StoredMessage::from(*message)).collect();
+        let record = bincode::serialize(&ReplicatedRecord::Publish(stored))
+            .map_err(|e| ProducerError::Encoding(e.to_string()))?;
+        let acknowledged = cluster.replicate(&record).await;
+
+        if acknowledged < min_replicas {
+            // The peers that did take the batch must not keep messages that were never confirmed
+            if acknowledged > 0 {
+                Self::retract(cluster, messages.iter().map(|message| message.id).collect()).await;
+            }
+            return Err(ProducerError::InsufficientReplicas { required: min_replicas, acknowledged });
+        }
+        Ok(acknowledged)
+    }
+
+    /// Asks the peers to drop replicated messages that were not published after all.
+    ///
+    /// This is best effort: a peer that cannot be reached keeps its copies.
+    async fn retract(cluster: &Cluster, ids: Vec<u64>) {
+        let count = ids.len();
+        match bincode::serialize(&ReplicatedRecord::Retract(ids)) {
+            Ok(record) => {
+                let acknowledged = cluster.replicate(&record).await;
+                debug!("Retracted {} replicated messages on {} peers", count, acknowledged);
+            }
+            Err(e) => warn!("Failed to encode the retraction of {} replicated messages: {}", count, e),
+        }
+    }
+
+    /// Sends a request to a named queue and waits for the matching reply.
+    ///
+    /// A temporary reply queue is declared on the broker for the duration of the call.
+    /// The request carries its name in the `reply-to` header and a fresh `correlation-id`;
+    /// replies with any other correlation id are discarded.
+    ///
+    /// # Arguments
+    ///
+    /// * `queue` - The name of the queue the request is sent to.
+    /// * `payload` - The content of the request.
+    /// * `timeout` - How long to wait for the reply.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ProducerError::NoBroker` if the producer has no broker,
+    /// `ProducerError::QueueNotFound` if the target queue is not declared and
+    /// `ProducerError::Timeout` if no reply arrives in time.
+    pub async fn request(&self, queue: &str, payload: String, timeout: Duration) -> Result<Message, ProducerError> {
+        let broker = self.broker.as_ref().ok_or(ProducerError::NoBroker)?;
+        let target = broker
+            .get_queue(queue)
+            .await
+            .ok_or_else(|| ProducerError::QueueNotFound(queue.to_string()))?;
+
+        let (reply_queue_name, reply_queue) = broker.declare_temporary_queue().await;
+        let correlation_id = Uuid::new_v4().to_string();
+
+        let message = MessageBuilder::new(payload)
+            .header(REPLY_TO_HEADER, reply_queue_name.clone())
+            .header(CORRELATION_ID_HEADER, correlation_id.clone())
+            .build()
+            .message;
+
+        debug!("Producer {:?} sending request: {:?}", self.id, message);
+
+        let result = match target.push(message, Duration::from_secs(0)).await {
+            Ok(()) => Self::await_reply(&reply_queue, &correlation_id, timeout).await,
+            Err(e) => Err(ProducerError::from(e)),
         };
 
-        println!("Producer {:?} sending message: {:?}", self.id, message);
+        // The reply queue is only needed for this request
+        broker.delete_queue(&reply_queue_name).await;
+        result
+    }
+
+    /// Waits until a message with the given correlation id arrives on the reply queue or the timeout expires.
+    ///
+    /// The reply queue is checked again each time messages are pushed to it.
+    async fn await_reply(reply_queue: &Queue, correlation_id: &str, timeout: Duration) -> Result<Message, ProducerError> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            // Registered before the queue is checked, so a reply pushed in between still wakes us
+            let pushed = reply_queue.pushed();
+            tokio::pin!(pushed);
+            pushed.as_mut().enable();
 
-        // Push the message to the queue
-        let queue = self.queue.clone();
-        let mut locked_queue = queue.lock().await;
-        locked_queue.push(message, delay).await.unwrap();
+            while let Some(reply) = reply_queue.pop().await? {
+                if reply.headers.get(CORRELATION_ID_HEADER).map(String::as_str) == Some(correlation_id) {
+                    return Ok(reply);
+                }
+                warn!("Discarding reply with unexpected correlation id: {:?}", reply);
+            }
+
+            timeout_at(deadline, pushed).await.map_err(|_| ProducerError::Timeout)?;
+        }
     }
 }
@@ -0,0 +1,82 @@
+use std::marker::PhantomData;
+use serde::Serialize;
+use crate::codec::codec::{self, Codec, CodecError, JsonCodec};
+use crate::producer::message_builder::MessageBuilder;
+use crate::producer::producer::{Confirmation, Producer, ProducerError};
+use crate::queue::CONTENT_TYPE_HEADER;
+
+/// A producer that sends typed values, encoding them with its codec.
+///
+/// Every message carries the codec's content type in its `content-type` header,
+/// so a `TypedConsumer` can reject payloads it cannot decode. Values are only sent to queues
+/// whose codec (see `Queue::set_codec`) is the producer's.
+#[derive(Debug, Clone)]
+pub struct TypedProducer<T, C = JsonCodec> {
+    producer: Producer,        // Producer the encoded messages are sent through
+    codec: C,                  // Codec the values are encoded with
+    _marker: PhantomData<fn(&T)>,
+}
+
+impl<T: Serialize> TypedProducer<T, JsonCodec> {
+    /// Creates a new typed producer that encodes values as JSON.
+    ///
+    /// # Arguments
+    ///
+    /// * `producer` - The producer the encoded messages are sent through.
+    pub fn new(producer: Producer) -> TypedProducer<T, JsonCodec> {
+        TypedProducer::with_codec(producer, JsonCodec)
+    }
+}
+
+impl<T: Serialize, C: Codec> TypedProducer<T, C> {
+    /// Creates a new typed producer that encodes values with `codec`.
+    ///
+    /// # Arguments
+    ///
+    /// * `producer` - The producer the encoded messages are sent through.
+    /// * `codec` - The codec of the queues the values are sent to.
+    pub fn with_codec(producer: Producer, codec: C) -> TypedProducer<T, C> {
+        TypedProducer {
+            producer,
+            codec,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Encodes a value and sends it with default message options.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value to send.
+    pub async fn send(&self, value: &T) -> Result<Confirmation, ProducerError> {
+        self.send_with(value, |message| message).await
+    }
+
+    /// Encodes a value and sends it with per-message options.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value to send.
+    /// * `configure` - Sets options (priority, TTL, target queue...) on the message builder.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ProducerError::Codec` with `CodecError::ContentTypeMismatch` if the target
+    /// queue uses a different codec.
+    pub async fn send_with<F>(&self, value: &T, configure: F) -> Result<Confirmation, ProducerError>
+    where
+        F: FnOnce(MessageBuilder) -> MessageBuilder,
+    {
+        let message = configure(MessageBuilder::new(String::new()));
+        let expected = self.producer.target_queue(message.target_queue()).await?.content_type().await;
+        if expected != self.codec.content_type() {
+            return Err(ProducerError::Codec(CodecError::ContentTypeMismatch {
+                expected,
+                actual: self.codec.content_type().to_string(),
+            }));
+        }
+        let content = codec::encode(&self.codec, value).map_err(ProducerError::Codec)?;
+        let message = message.content(content).header(CONTENT_TYPE_HEADER, self.codec.content_type());
+        self.producer.send(message).await
+    }
+}
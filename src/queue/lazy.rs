@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+use crate::queue::{Message, QueueError, DEAD_LETTER_REASON_HEADER};
+use crate::storage::storage::{instant_of, system_time_of, DueMessages, Message as StoredMessage, Storage, StorageBatch};
+use log::debug;
+
+/// How often an idle lazy queue looks in storage for messages whose delay has passed.
+const PAGE_IN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Bounds how much of a lazy queue is kept in memory.
+///
+/// Both limits are enforced: the window never holds more than `max_messages` messages nor
+/// more than `max_bytes` of them, except that a single message larger than `max_bytes` is
+/// still paged in on its own so the queue cannot get stuck.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LazyQueueConfig {
+    /// Maximum number of ready messages held in memory.
+    pub max_messages: usize,
+    /// Maximum estimated size in bytes of the messages held in memory.
+    pub max_bytes: usize,
+}
+
+impl Default for LazyQueueConfig {
+    fn default() -> Self {
+        LazyQueueConfig { max_messages: 10_000, max_bytes: 64 * 1024 * 1024 }
+    }
+}
+
+/// A snapshot of a lazy queue's in-memory window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowStats {
+    /// Messages currently held in memory.
+    pub messages: usize,
+    /// Estimated size of those messages in bytes.
+    pub bytes: usize,
+    /// The configured limits.
+    pub config: LazyQueueConfig,
+}
+
+/// The storage side of a lazy queue: every message lives in `Storage`, and only a bounded
+/// window of ready messages is copied into the queue's heap.
+#[derive(Debug)]
+pub(crate) struct LazyStore {
+    storage: Storage,          // Keyspace of the queue
+    config: LazyQueueConfig,   // Window limits
+    window: Mutex<Window>,     // What is currently in memory
+}
+
+#[derive(Debug)]
+struct Window {
+    cursor: DueMessages,              // Reads due messages from storage
+    resident: HashMap<u64, usize>,    // Ids of the messages in memory, with their estimated size
+    bytes: usize,                     // Sum of the resident sizes
+    dirty: bool,                      // Messages were written to storage since the last page-in
+    caught_up: bool,                  // The last page-in read every message that was due
+    paged_in_at: Option<Instant>,     // When storage was last read
+    recovered: bool,                  // Messages left in flight by a previous run were put back
+}
+
+impl LazyStore {
+    pub(crate) fn new(storage: Storage, config: LazyQueueConfig) -> LazyStore {
+        let cursor = storage.due_messages();
+        LazyStore {
+            storage,
+            config,
+            window: Mutex::new(Window { cursor, resident: HashMap::new(), bytes: 0, dirty: true, caught_up: false, paged_in_at: None, recovered: false }),
+        }
+    }
+
+    /// Persists pushed messages, each with the time it becomes available.
+    ///
+    /// With `sync`, waits for the messages to be fsynced whatever the storage's `SyncPolicy`.
+    pub(crate) async fn push(&self, messages: &[Message], sync: bool) -> Result<(), QueueError> {
+        let mut batch = StorageBatch::new();
+        for message in messages {
+            batch = batch.push_at(&StoredMessage::from(message), system_time_of(message.available_at)).map_err(QueueError::Storage)?;
+        }
+        let committed = if sync { self.storage.commit_synced(batch).await } else { self.storage.commit(batch).await };
+        committed.map_err(QueueError::Storage)?;
+        self.window.lock().await.dirty = true;
+        Ok(())
+    }
+
+    /// Returns the due messages that fit in the window and are not in memory yet.
+    ///
+    /// Storage is only read once the window is at most half full. Once a page-in has caught up
+    /// with every due message, storage is read again only after messages were written or
+    /// `PAGE_IN_INTERVAL` has passed. The first page-in puts the messages a previous run left
+    /// in flight back in the queue, since nothing will acknowledge them any more.
+    pub(crate) async fn page_in(&self) -> Result<Vec<Message>, QueueError> {
+        let mut window = self.window.lock().await;
+        if !window.recovered {
+            self.storage.requeue_in_flight().await.map_err(QueueError::Storage)?;
+            window.recovered = true;
+        }
+        if window.resident.len() > self.config.max_messages / 2 {
+            return Ok(Vec::new());
+        }
+        let idle = window.caught_up && !window.dirty;
+        if idle && window.paged_in_at.is_some_and(|paged_in_at| paged_in_at.elapsed() < PAGE_IN_INTERVAL) {
+            return Ok(Vec::new());
+        }
+        window.dirty = false;
+        window.caught_up = true;
+        window.paged_in_at = Some(Instant::now());
+
+        // Resident messages are still pending in storage, so read past them from the start
+        let now = SystemTime::now();
+        let page_size = self.config.max_messages.max(1);
+        let mut paged_in = Vec::new();
+        window.cursor.rewind();
+        'pages: loop {
+            let page = window.cursor.next_batch(now, page_size).await.map_err(QueueError::Storage)?;
+            if page.is_empty() {
+                break;
+            }
+            for stored in page {
+                if window.resident.contains_key(&stored.id) {
+                    continue;
+                }
+                let message = Message::from_stored(stored, Instant::now());
+                let size = message.memory_size();
+                let full = window.resident.len() >= self.config.max_messages
+                    || (!window.resident.is_empty() && window.bytes + size > self.config.max_bytes);
+                if full {
+                    window.caught_up = false;
+                    break 'pages;
+                }
+                window.resident.insert(message.id, size);
+                window.bytes += size;
+                paged_in.push(message);
+            }
+        }
+
+        if !paged_in.is_empty() {
+            debug!("Paged {} messages of queue {} into memory", paged_in.len(), self.storage.queue_name());
+        }
+        Ok(paged_in)
+    }
+
+    /// Records that messages were handed to consumers: they leave the window and are kept in
+    /// storage as in flight until they are acknowledged.
+    pub(crate) async fn deliver(&self, messages: &[Message]) -> Result<(), QueueError> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let mut batch = StorageBatch::new();
+        for message in messages {
+            batch = batch.deliver(&StoredMessage::from(message)).map_err(QueueError::Storage)?;
+        }
+        self.storage.commit(batch).await.map_err(QueueError::Storage)?;
+        self.evict(messages.iter().map(|message| message.id)).await;
+        Ok(())
+    }
+
+    /// Forgets messages that left the window without being delivered, once storage no longer
+    /// has them as pending.
+    pub(crate) async fn evict(&self, ids: impl IntoIterator<Item = u64>) {
+        let mut window = self.window.lock().await;
+        for id in ids {
+            if let Some(size) = window.resident.remove(&id) {
+                window.bytes -= size;
+            }
+        }
+    }
+
+    /// Removes an acknowledged message from storage.
+    pub(crate) async fn acknowledge(&self, message_id: u64) -> Result<(), QueueError> {
+        self.storage.commit(StorageBatch::new().ack(message_id)).await.map_err(QueueError::Storage)
+    }
+
+    /// Moves a message to the queue's dead letters in storage.
+    pub(crate) async fn dead_letter(&self, message: &Message) -> Result<(), QueueError> {
+        let reason = message.headers.get(DEAD_LETTER_REASON_HEADER).cloned().unwrap_or_default();
+        let batch = StorageBatch::new().dead_letter(&StoredMessage::from(message), &reason).map_err(QueueError::Storage)?;
+        self.storage.commit(batch).await.map_err(QueueError::Storage)
+    }
+
+    /// Loads the queue's dead letters from storage.
+    pub(crate) async fn dead_letters(&self) -> Result<Vec<Message>, QueueError> {
+        let dead_letters = self.storage.load_dead_letters().await.map_err(QueueError::Storage)?;
+        Ok(dead_letters
+            .into_iter()
+            .map(|dead_letter| {
+                let mut message = Message::from_stored(dead_letter.message, Instant::now());
+                message.headers.insert(DEAD_LETTER_REASON_HEADER.to_string(), dead_letter.reason);
+                message
+            })
+            .collect())
+    }
+
+    /// Counts the messages waiting in storage, including those in the window.
+    pub(crate) async fn pending_count(&self) -> Result<usize, QueueError> {
+        self.storage.count_pending().await.map_err(QueueError::Storage)
+    }
+
+    pub(crate) async fn stats(&self) -> WindowStats {
+        let window = self.window.lock().await;
+        WindowStats { messages: window.resident.len(), bytes: window.bytes, config: self.config }
+    }
+}
+
+impl Message {
+    /// Rebuilds a queue message from its stored form.
+    pub(crate) fn from_stored(stored: StoredMessage, available_at: Instant) -> Message {
+        Message {
+            id: stored.id,
+            content: stored.content,
+            priority: stored.priority,
+            available_at,
+            retry_count: stored.retry_count,
+            max_retries: stored.max_retries,
+            headers: stored.headers,
+            expires_at: stored.expires_at.map(instant_of),
+            group_key: stored.group_key,
+            dedup_id: None,
+        }
+    }
+
+    /// Estimates how much memory the message takes, including its heap allocations.
+    pub(crate) fn memory_size(&self) -> usize {
+        let headers: usize = self.headers.iter().map(|(key, value)| key.len() + value.len()).sum();
+        std::mem::size_of::<Message>()
+            + self.content.len()
+            + headers
+            + self.group_key.as_ref().map_or(0, String::len)
+            + self.dedup_id.as_ref().map_or(0, String::len)
+    }
+}
@@ -1,8 +1,38 @@
+pub mod lazy;
+pub mod selector;
+pub mod stream;
+
 use std::cmp::Ordering;
-use std::collections::BinaryHeap;
-use tokio::sync::Mutex;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use tokio::sync::futures::Notified;
+use tokio::sync::{Mutex, Notify};
 use std::sync::Arc;
-use tokio::time::{sleep, Duration, Instant};
+use tokio::time::{Duration, Instant};
+use crate::codec::codec::{Codec, JsonCodec};
+use crate::queue::lazy::{LazyQueueConfig, LazyStore, WindowStats};
+use crate::queue::selector::Selector;
+use crate::storage::storage::Storage;
+use crate::utils::rate_limiter::{RateLimit, RateLimitStats, RateLimiter};
+use log::{debug, warn};
+
+/// Header naming the queue a reply to this message should be sent to.
+pub const REPLY_TO_HEADER: &str = "reply-to";
+
+/// Header used to match a reply with the request that caused it.
+pub const CORRELATION_ID_HEADER: &str = "correlation-id";
+
+/// Header describing how the message payload is encoded (e.g. `application/json`).
+pub const CONTENT_TYPE_HEADER: &str = "content-type";
+
+/// Header recording why a message was moved to the dead-letter queue.
+pub const DEAD_LETTER_REASON_HEADER: &str = "dead-letter-reason";
+
+/// How long a deduplication id is remembered after a message carrying it was pushed.
+pub const DEDUP_WINDOW: Duration = Duration::from_secs(600);
+
+/// Maximum number of available messages `pop_matching` sets aside in one call because they do
+/// not match, so a selector matching little of a deep queue does not hold its locks for long.
+pub const MAX_SELECTOR_SKIPS: usize = 1000;
 
 /// A message that can be added to the queue.
 ///
@@ -24,6 +54,23 @@ pub struct Message {
     pub retry_count: u8,
     /// Maximum number of retries allowed
     pub max_retries: u8,
+    /// Application-defined key/value metadata (e.g. reply-to, correlation-id).
+    pub headers: HashMap<String, String>,
+    /// The time after which the message is moved to the dead-letter queue instead of delivered, if any.
+    pub expires_at: Option<Instant>,
+    /// Key grouping related messages (e.g. all events of one order), if any. Messages of a
+    /// group are delivered one at a time: while one is in flight, the rest of its group waits
+    /// until it is acknowledged, retried, requeued or dead-lettered.
+    pub group_key: Option<String>,
+    /// Producer-assigned id used to drop duplicate pushes within `DEDUP_WINDOW`, if any.
+    pub dedup_id: Option<String>,
+}
+
+impl Message {
+    /// Returns `true` if the message has a TTL that has elapsed at `now`.
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 }
 
 // Implement ordering for the message to be used in a priority queue.
@@ -51,10 +98,15 @@ impl PartialEq for Message {
 }
 
 /// Custom errors that can occur when interacting with the queue.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum QueueError {
     /// Error occurring when a lock cannot be acquired.
     LockError,
+    /// A lazy queue could not read or write its storage.
+    Storage(String),
+    /// The name is already used by a stream when declaring a queue, or by a queue when
+    /// declaring a stream.
+    NameInUse(String),
 }
 
 /// A thread-safe priority queue for managing `Message` objects with support for delayed processing and batch operations.
@@ -64,6 +116,13 @@ pub enum QueueError {
 #[derive(Debug, Clone)]
 pub struct Queue {
     messages: Arc<Mutex<BinaryHeap<Message>>>,
+    dedup_ids: Arc<Mutex<DedupWindow>>,                 // Dedup ids pushed within `DEDUP_WINDOW`
+    groups: Arc<Mutex<InFlightGroups>>,                 // Group keys with a message in flight
+    dead_letters: Arc<Mutex<Vec<Message>>>,             // Messages that could not be delivered
+    rate_limiter: Arc<Mutex<Option<RateLimiter>>>,      // Delivery rate shared by all consumers, if limited
+    content_type: Arc<Mutex<String>>,                   // Content type of the codec typed values sent through the queue are encoded with
+    lazy: Option<Arc<LazyStore>>,                       // Storage holding the backlog of a lazy queue
+    pushed: Arc<Notify>,                                // Wakes waiters when messages are added
 }
 
 impl Queue {
@@ -78,12 +137,46 @@ impl Queue {
     pub fn new() -> Self {
         Queue {
             messages: Arc::new(Mutex::new(BinaryHeap::new())),
+            dedup_ids: Arc::new(Mutex::new(DedupWindow::default())),
+            groups: Arc::new(Mutex::new(InFlightGroups::default())),
+            dead_letters: Arc::new(Mutex::new(Vec::new())),
+            rate_limiter: Arc::new(Mutex::new(None)),
+            content_type: Arc::new(Mutex::new(JsonCodec.content_type().to_string())),
+            lazy: None,
+            pushed: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Creates a lazy queue, which keeps its messages in storage and only a bounded window of
+    /// ready messages in memory.
+    ///
+    /// Pushed, retried and requeued messages are written to storage, and more are paged into
+    /// memory as consumers drain the window. Delivered messages stay in storage as in flight
+    /// until they are acknowledged, and dead letters are kept in storage too. Messages already
+    /// in `storage` are delivered once they are due, so a lazy queue recovers its backlog on
+    /// restart without loading it; messages left in flight are put back in the queue when it is
+    /// first consumed from. Only one queue may be opened on a keyspace at a time.
+    ///
+    /// # Arguments
+    ///
+    /// * `storage` - The storage scoped to this queue (see `Storage::queue`).
+    /// * `config` - How many messages, and how many bytes of them, may be held in memory.
+    pub fn lazy(storage: Storage, config: LazyQueueConfig) -> Self {
+        Queue { lazy: Some(Arc::new(LazyStore::new(storage, config))), ..Queue::new() }
+    }
+
+    /// Returns the state of a lazy queue's in-memory window, or `None` for a regular queue.
+    pub async fn window_stats(&self) -> Option<WindowStats> {
+        match &self.lazy {
+            Some(lazy) => Some(lazy.stats().await),
+            None => None,
         }
     }
 
     /// Adds a message to the queue with an optional delay.
     ///
     /// Messages are stored based on their priority and availability time.
+    /// A message whose `dedup_id` was already pushed within `DEDUP_WINDOW` is dropped.
     ///
     /// # Arguments
     ///
@@ -103,23 +196,132 @@ impl Queue {
     /// queue.push(Message { id: 1, content: String::from("Hello"), priority: 5 }, Duration::from_secs(2)).await.unwrap();
     ///
     pub async fn push(&self, message: Message, delay: Duration) -> Result<(), QueueError> {
-        // Calculate the availability time based on the current time and delay
-        let available_at = Instant::now() + delay;
+        self.push_batch(vec![(message, delay)]).await
+    }
 
-        // Create a new message with the updated availability time
-        let delayed_message = Message { available_at, ..message };
+    /// Adds several messages to the queue while holding the lock only once.
+    ///
+    /// Duplicates are dropped as in `push`.
+    ///
+    /// # Arguments
+    ///
+    /// * `messages` - The messages to add, each with the delay after which it becomes available.
+    ///
+    /// # Errors
+    ///
+    /// Returns `QueueError::LockError` if the queue lock cannot be acquired.
+    pub async fn push_batch(&self, messages: Vec<(Message, Duration)>) -> Result<(), QueueError> {
+        let originals = self.claim_dedup_ids(messages.iter().map(|(message, _)| message)).await;
+        let accepted = messages
+            .into_iter()
+            .zip(originals)
+            .filter_map(|((message, delay), original)| match original {
+                Some(original) => {
+                    debug!("Message {} dropped as a duplicate of message {}", message.id, original);
+                    None
+                }
+                None => Some((message, delay)),
+            })
+            .collect();
+        self.push_claimed(accepted, false).await
+    }
+
+    /// Returns `true` for a lazy queue, which keeps its messages in storage rather than only in memory.
+    pub fn is_durable(&self) -> bool {
+        self.lazy.is_some()
+    }
+
+    /// Adds messages whose dedup ids were claimed with `claim_dedup_ids`, fsyncing a lazy
+    /// queue's write if `sync` is set.
+    ///
+    /// If the messages cannot be added, their dedup ids are released again.
+    pub(crate) async fn push_claimed(&self, messages: Vec<(Message, Duration)>, sync: bool) -> Result<(), QueueError> {
+        let now = Instant::now();
+        let accepted: Vec<Message> = messages
+            .into_iter()
+            .map(|(message, delay)| Message { available_at: now + delay, ..message })
+            .collect();
+
+        if let Some(lazy) = &self.lazy {
+            if let Err(e) = lazy.push(&accepted, sync).await {
+                self.release_dedup_ids(&accepted).await;
+                return Err(e);
+            }
+            self.pushed.notify_waiters();
+            return Ok(());
+        }
 
-        // Lock the queue and push the message
         let mut queue = self.messages.lock().await;
-        queue.push(delayed_message.clone());
-        println!("Message pushed: {:?}", delayed_message);
+        queue.extend(accepted);
+        debug!("Message batch pushed, queue size: {}", queue.len());
+        self.pushed.notify_waiters();
+
+        Ok(())
+    }
+
+    /// Records the dedup ids of messages about to be pushed as seen.
+    ///
+    /// Returns, for each message, the id of the message that already carried its dedup id
+    /// within `DEDUP_WINDOW`, or `None` if it is not a duplicate; only the dedup ids of the
+    /// latter are recorded. A message repeating the dedup id of an earlier one in the same
+    /// call is a duplicate too.
+    pub(crate) async fn claim_dedup_ids<'a>(&self, messages: impl IntoIterator<Item = &'a Message>) -> Vec<Option<u64>> {
+        let mut dedup_ids = self.dedup_ids.lock().await;
+        let now = Instant::now();
+        messages
+            .into_iter()
+            .map(|message| match &message.dedup_id {
+                Some(dedup_id) => dedup_ids.check(dedup_id, message.id, now),
+                None => None,
+            })
+            .collect()
+    }
+
+    /// Forgets the dedup ids claimed for messages that were not pushed after all, so they
+    /// can be sent again.
+    pub(crate) async fn release_dedup_ids(&self, messages: &[Message]) {
+        let mut dedup_ids = self.dedup_ids.lock().await;
+        for message in messages {
+            if let Some(dedup_id) = &message.dedup_id {
+                dedup_ids.forget(dedup_id, message.id);
+            }
+        }
+    }
 
+    /// Returns a future that resolves the next time messages are pushed to the queue.
+    ///
+    /// Only pushes made after the future is created, or after it is `enable`d, wake it, so
+    /// create it before checking the queue to not miss a message pushed in between.
+    pub fn pushed(&self) -> Notified<'_> {
+        self.pushed.notified()
+    }
+
+    /// Returns a previously popped message to the queue unchanged.
+    ///
+    /// Unlike `push`, the message keeps its availability time and retry count and is
+    /// not subject to deduplication. Used to hand back messages that were fetched but
+    /// not processed, e.g. when a consumer shuts down.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message to return to the queue.
+    pub async fn requeue(&self, message: Message) -> Result<(), QueueError> {
+        self.groups.lock().await.finish(message.id);
+        if let Some(lazy) = &self.lazy {
+            debug!("Message requeued: {}", message.id);
+            return lazy.push(&[message], false).await;
+        }
+
+        let mut queue = self.messages.lock().await;
+        debug!("Message requeued: {}", message.id);
+        queue.push(message);
         Ok(())
     }
 
     /// Removes and returns the highest priority message from the queue that is available for processing.
     ///
-    /// Messages that are not yet available due to a delay are not returned.
+    /// Messages that are not yet available due to a delay are not returned, and messages
+    /// whose TTL has elapsed are moved to the dead-letter queue instead.
     ///
     /// Returns `None` if the queue is empty or if no messages are currently available.
     ///
@@ -138,26 +340,47 @@ impl Queue {
     /// assert_eq!(msg.unwrap().priority, 5);
     ///
     pub async fn pop(&self) -> Result<Option<Message>, QueueError> {
-        let mut queue = self.messages.lock().await;
+        self.page_in().await?;
+        let mut expired = Vec::new();
+        let mut msg = None;
 
-        // Check if the top message is available for processing
-        if let Some(top_message) = queue.peek() {
-            if top_message.available_at <= Instant::now() {
-                let msg = queue.pop();
-                if let Some(ref m) = msg {
-                    println!("Message popped: {:?}", m);
+        {
+            let mut queue = self.messages.lock().await;
+            let mut groups = self.groups.lock().await;
+            let now = Instant::now();
+            let mut skipped = Vec::new();
+
+            // Check if the top message is available for processing
+            while let Some(top_message) = queue.peek() {
+                if top_message.available_at > now {
+                    break;
+                }
+                let m = queue.pop().unwrap();
+                if m.is_expired(now) {
+                    expired.push(m);
+                } else if groups.is_blocked(&m) {
+                    skipped.push(m);
+                } else {
+                    debug!("Message popped: {:?}", m);
+                    groups.start(&m);
+                    msg = Some(m);
+                    break;
                 }
-                return Ok(msg);
             }
+            queue.extend(skipped);
         }
 
+        self.leave_window(msg.as_slice()).await?;
+        self.dead_letter_expired(expired).await?;
+
         // Return None if no messages are available for processing
-        Ok(None)
+        Ok(msg)
     }
 
     /// Removes and returns up to `batch_size` highest priority messages from the queue that are available for processing.
     ///
-    /// Messages that are not yet available due to a delay are not returned.
+    /// Messages that are not yet available due to a delay are not returned, and messages
+    /// whose TTL has elapsed are moved to the dead-letter queue instead.
     ///
     /// Returns an empty vector if no messages are currently available.
     ///
@@ -181,20 +404,31 @@ impl Queue {
     /// assert_eq!(messages.len(), 2);
     ///
     pub async fn pop_batch(&self, batch_size: usize) -> Result<Vec<Message>, QueueError> {
+        self.page_in().await?;
         let mut queue = self.messages.lock().await;
+        let mut groups = self.groups.lock().await;
         let mut batch = Vec::new();
+        let mut expired = Vec::new();
+        let mut skipped = Vec::new();
     
         while batch.len() < batch_size {
             if let Some(top_message) = queue.peek() {
-                println!(
+                debug!(
                     "Checking top message: id={}, priority={}, available_at={:?}, now={:?}",
                     top_message.id, top_message.priority, top_message.available_at, Instant::now()
                 );
     
                 if top_message.available_at <= Instant::now() {
                     if let Some(msg) = queue.pop() {
-                        println!("Popped message: {:?}", msg);
-                        batch.push(msg);
+                        if msg.is_expired(Instant::now()) {
+                            expired.push(msg);
+                        } else if groups.is_blocked(&msg) {
+                            skipped.push(msg);
+                        } else {
+                            debug!("Popped message: {:?}", msg);
+                            groups.start(&msg);
+                            batch.push(msg);
+                        }
                     }
                 } else {
                     break;
@@ -204,14 +438,75 @@ impl Queue {
             }
         }
     
-        println!("Batch size after pop: {}", batch.len());
+        queue.extend(skipped);
+        drop(groups);
+        drop(queue);
+        self.leave_window(&batch).await?;
+        self.dead_letter_expired(expired).await?;
+
+        debug!("Batch size after pop: {}", batch.len());
         Ok(batch)
     }
     
-    
+
+    /// Removes and returns up to `batch_size` available messages that match a selector.
+    ///
+    /// Matching messages are returned in the same order as `pop_batch` would return them.
+    /// Available messages that do not match are skipped and stay in the queue for other
+    /// consumers; expired messages met along the way are moved to the dead-letter queue.
+    /// At most `MAX_SELECTOR_SKIPS` messages are skipped per call, so fewer matching messages
+    /// than are available may be returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `selector` - The filter messages must satisfy.
+    /// * `batch_size` - The maximum number of messages to retrieve.
+    ///
+    /// # Errors
+    ///
+    /// Returns `QueueError::LockError` if the queue lock cannot be acquired.
+    pub async fn pop_matching(&self, selector: &Selector, batch_size: usize) -> Result<Vec<Message>, QueueError> {
+        self.page_in().await?;
+        let mut batch = Vec::new();
+        let mut expired = Vec::new();
+
+        {
+            let mut queue = self.messages.lock().await;
+            let mut groups = self.groups.lock().await;
+            let now = Instant::now();
+            let mut skipped = Vec::new();
+
+            // Walk the available messages in delivery order, setting aside the ones that do not
+            // match or whose group already has a message in flight
+            while batch.len() < batch_size && skipped.len() < MAX_SELECTOR_SKIPS {
+                match queue.peek() {
+                    Some(top_message) if top_message.available_at <= now => {}
+                    _ => break,
+                }
+                let message = queue.pop().unwrap();
+                if message.is_expired(now) {
+                    expired.push(message);
+                } else if selector.matches(&message) && !groups.is_blocked(&message) {
+                    groups.start(&message);
+                    batch.push(message);
+                } else {
+                    skipped.push(message);
+                }
+            }
+            queue.extend(skipped);
+        }
+
+        self.leave_window(&batch).await?;
+        self.dead_letter_expired(expired).await?;
+        debug!("Popped {} messages matching '{}'", batch.len(), selector.source());
+        Ok(batch)
+    }
 
     /// Returns the current size of the queue.
     ///
+    /// For a lazy queue this is the number of messages in its in-memory window; the backlog
+    /// in storage is not counted, see `pending_count`.
+    ///
     /// # Errors
     ///
     /// Returns `QueueError::LockError` if the queue lock cannot be acquired.
@@ -230,6 +525,20 @@ impl Queue {
         Ok(queue.len())
     }
 
+    /// Returns the number of messages waiting to be delivered, ready or delayed.
+    ///
+    /// Unlike `size`, this counts a lazy queue's whole backlog in storage.
+    ///
+    /// # Errors
+    ///
+    /// Returns `QueueError::Storage` if a lazy queue cannot read its storage.
+    pub async fn pending_count(&self) -> Result<usize, QueueError> {
+        match &self.lazy {
+            Some(lazy) => lazy.pending_count().await,
+            None => self.size().await,
+        }
+    }
+
     /// Acknowledges a message, confirming its successful processing.
     ///
     /// # Arguments
@@ -243,7 +552,14 @@ impl Queue {
         let mut queue = self.messages.lock().await;
         // Remove the acknowledged message from the queue (if needed, or update status in persistence layer)
         queue.retain(|message| message.id != message_id);
-        println!("Message acknowledged: {}", message_id);
+        drop(queue);
+        self.groups.lock().await.finish(message_id);
+
+        if let Some(lazy) = &self.lazy {
+            lazy.acknowledge(message_id).await?;
+            lazy.evict([message_id]).await;
+        }
+        debug!("Message acknowledged: {}", message_id);
         Ok(())
     }
 
@@ -257,10 +573,11 @@ impl Queue {
     ///
     /// Returns `Ok(())` if the message is successfully re-queued, or a `QueueError` if not.
     pub async fn retry(&self, mut message: Message) -> Result<(), QueueError> {
+        self.groups.lock().await.finish(message.id);
         if message.retry_count >= message.max_retries {
             // Push to dead-letter queue or handle exceeded retries
-            println!("Message exceeded max retries, moving to dead-letter queue: {:?}", message);
-            self.push_to_dead_letter(message).await?;
+            warn!("Message exceeded max retries, moving to dead-letter queue: {:?}", message);
+            self.dead_letter(message, "max retries exceeded").await?;
             return Ok(());
         }
 
@@ -268,16 +585,18 @@ impl Queue {
         message.retry_count += 1;
         let backoff_delay = Duration::from_secs(2u64.pow(message.retry_count as u32));
 
-        // Wait for the backoff delay before retrying
-        sleep(backoff_delay).await;
-
-        // Re-enqueue the message with a new available time
+        // Re-enqueue the message right away; it only becomes available once the backoff has passed
         let new_available_at = Instant::now() + backoff_delay;
         let retry_message = Message { available_at: new_available_at, ..message };
 
+        if let Some(lazy) = &self.lazy {
+            debug!("Message retried: {:?}", retry_message);
+            return lazy.push(&[retry_message], false).await;
+        }
+
         let mut queue = self.messages.lock().await;
         queue.push(retry_message.clone());
-        println!("Message retried: {:?}", retry_message);
+        debug!("Message retried: {:?}", retry_message);
 
         Ok(())
     }
@@ -292,8 +611,177 @@ impl Queue {
     ///
     /// Returns `Ok(())` if the message is successfully moved, or a `QueueError` if not.
     pub async fn push_to_dead_letter(&self, message: Message) -> Result<(), QueueError> {
-        // Implement logic to push messages to a dead-letter queue
-        println!("Message pushed to dead-letter queue: {:?}", message);
+        self.groups.lock().await.finish(message.id);
+        if let Some(lazy) = &self.lazy {
+            debug!("Message pushed to dead-letter queue: {:?}", message);
+            return lazy.dead_letter(&message).await;
+        }
+
+        let mut dead_letters = self.dead_letters.lock().await;
+        debug!("Message pushed to dead-letter queue: {:?}", message);
+        dead_letters.push(message);
+        Ok(())
+    }
+
+    /// Moves a message to the dead-letter queue, recording why in its `dead-letter-reason` header.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message to move to the dead-letter queue.
+    /// * `reason` - Why the message could not be delivered (e.g. "decode error").
+    pub async fn dead_letter(&self, mut message: Message, reason: &str) -> Result<(), QueueError> {
+        message.headers.insert(DEAD_LETTER_REASON_HEADER.to_string(), reason.to_string());
+        self.push_to_dead_letter(message).await
+    }
+
+    /// Returns the messages currently in the dead-letter queue, oldest first.
+    pub async fn dead_letters(&self) -> Result<Vec<Message>, QueueError> {
+        if let Some(lazy) = &self.lazy {
+            return lazy.dead_letters().await;
+        }
+
+        let dead_letters = self.dead_letters.lock().await;
+        Ok(dead_letters.clone())
+    }
+
+    /// Limits how fast messages are delivered from this queue, across all of its consumers.
+    ///
+    /// Consumers throttle themselves at dispatch time; popping from the queue directly is not limited.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The delivery rate, or `None` to remove the limit.
+    pub async fn set_rate_limit(&self, limit: Option<RateLimit>) {
+        let mut rate_limiter = self.rate_limiter.lock().await;
+        *rate_limiter = limit.map(RateLimiter::new);
+    }
+
+    /// Returns the limiter consumers share for this queue's delivery rate, if one is set.
+    pub async fn rate_limiter(&self) -> Option<RateLimiter> {
+        self.rate_limiter.lock().await.clone()
+    }
+
+    /// Returns the state of the queue's delivery rate limit, if one is set.
+    pub async fn rate_limit_stats(&self) -> Option<RateLimitStats> {
+        self.rate_limiter().await.map(|rate_limiter| rate_limiter.stats())
+    }
+
+    /// Sets the codec typed producers must encode this queue's messages with, by recording its
+    /// content type.
+    ///
+    /// Queues use `JsonCodec` until another codec is set.
+    ///
+    /// # Arguments
+    ///
+    /// * `codec` - The codec of the queue's messages.
+    pub async fn set_codec<C: Codec>(&self, codec: &C) {
+        *self.content_type.lock().await = codec.content_type().to_string();
+    }
+
+    /// Returns the content type of the codec of the queue's messages.
+    pub async fn content_type(&self) -> String {
+        self.content_type.lock().await.clone()
+    }
+
+    /// Tops up a lazy queue's window with due messages from storage.
+    async fn page_in(&self) -> Result<(), QueueError> {
+        if let Some(lazy) = &self.lazy {
+            let paged_in = lazy.page_in().await?;
+            if !paged_in.is_empty() {
+                self.messages.lock().await.extend(paged_in);
+            }
+        }
         Ok(())
     }
+
+    /// Takes delivered messages out of a lazy queue's window, recording them as in flight in storage.
+    async fn leave_window(&self, delivered: &[Message]) -> Result<(), QueueError> {
+        if let Some(lazy) = &self.lazy {
+            lazy.deliver(delivered).await?;
+        }
+        Ok(())
+    }
+
+    /// Moves messages whose TTL elapsed before they could be delivered to the dead-letter queue.
+    async fn dead_letter_expired(&self, expired: Vec<Message>) -> Result<(), QueueError> {
+        for message in expired {
+            debug!("Message expired before delivery: {}", message.id);
+            let message_id = message.id;
+            self.dead_letter(message, "expired").await?;
+            // Only once it is no longer pending in storage, so it cannot be paged in again
+            if let Some(lazy) = &self.lazy {
+                lazy.evict([message_id]).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The dedup ids pushed within `DEDUP_WINDOW`, kept in the order they were seen so expired ones
+/// can be dropped from the front without scanning the rest.
+#[derive(Debug, Default)]
+struct DedupWindow {
+    seen: HashMap<String, u64>,                 // Dedup ids still within the window, with the id of the message that carried each
+    order: VecDeque<(Instant, String, u64)>,    // The same ids, oldest first, with when they were seen
+}
+
+impl DedupWindow {
+    /// Records `dedup_id` as seen at `now` on message `message_id`, unless it was already seen
+    /// within the window; returns the id of the message it was seen on then.
+    fn check(&mut self, dedup_id: &str, message_id: u64, now: Instant) -> Option<u64> {
+        while let Some((seen_at, _, _)) = self.order.front() {
+            if now.duration_since(*seen_at) < DEDUP_WINDOW {
+                break;
+            }
+            let (_, expired, expired_message_id) = self.order.pop_front().unwrap();
+            // The id may have been released and claimed again by a later message since
+            if self.seen.get(&expired) == Some(&expired_message_id) {
+                self.seen.remove(&expired);
+            }
+        }
+
+        if let Some(original) = self.seen.get(dedup_id) {
+            return Some(*original);
+        }
+        self.seen.insert(dedup_id.to_string(), message_id);
+        self.order.push_back((now, dedup_id.to_string(), message_id));
+        None
+    }
+
+    /// Forgets `dedup_id` if it was recorded for message `message_id`.
+    fn forget(&mut self, dedup_id: &str, message_id: u64) {
+        if self.seen.get(dedup_id) == Some(&message_id) {
+            self.seen.remove(dedup_id);
+        }
+    }
+}
+
+/// The groups that have a message in flight, so the rest of each group is held back until
+/// that message is settled.
+#[derive(Debug, Default)]
+struct InFlightGroups {
+    groups: HashSet<String>,            // Group keys with a message in flight
+    messages: HashMap<u64, String>,     // The group key of each in-flight grouped message
+}
+
+impl InFlightGroups {
+    /// Returns `true` if another message of `message`'s group is in flight.
+    fn is_blocked(&self, message: &Message) -> bool {
+        message.group_key.as_ref().is_some_and(|group_key| self.groups.contains(group_key))
+    }
+
+    /// Records a grouped message as in flight.
+    fn start(&mut self, message: &Message) {
+        if let Some(group_key) = &message.group_key {
+            self.groups.insert(group_key.clone());
+            self.messages.insert(message.id, group_key.clone());
+        }
+    }
+
+    /// Releases the group of a message that is no longer in flight.
+    fn finish(&mut self, message_id: u64) {
+        if let Some(group_key) = self.messages.remove(&message_id) {
+            self.groups.remove(&group_key);
+        }
+    }
 }
@@ -0,0 +1,428 @@
+use std::cmp::Ordering;
+use std::iter::Peekable;
+use std::str::CharIndices;
+use crate::queue::Message;
+
+/// Deepest nesting of `NOT` and parentheses a selector may use.
+pub const MAX_SELECTOR_DEPTH: usize = 64;
+
+/// Error returned when a selector expression cannot be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectorError {
+    /// Byte offset in the expression where the problem was found.
+    pub position: usize,
+    /// What was wrong at that position.
+    pub message: String,
+}
+
+/// A filter over message headers, priority and content, e.g. `region = 'eu' AND priority > 5`.
+///
+/// Supported syntax:
+///
+/// * Comparisons `field op literal` with `=`, `<>` (or `!=`), `<`, `<=`, `>` and `>=`.
+/// * `field LIKE 'pattern'`, where `%` matches any run of characters and `_` a single one.
+/// * `AND`, `OR`, `NOT` and parentheses; keywords are case-insensitive.
+///
+/// `priority`, `retry_count` and `content` refer to the message itself; any other field
+/// name is looked up in the headers. Literals are either single-quoted strings or numbers.
+/// Against a number, the field is compared numerically and does not match if it is not a
+/// number; against a string, it is compared as text. A comparison on a missing header never
+/// matches, so `NOT region = 'eu'` also selects messages without a `region` header.
+///
+/// # Examples
+///
+///
+/// use hexboltmq::queue::selector::Selector;
+/// let selector = Selector::parse("region = 'eu' AND priority > 5").unwrap();
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    source: String,          // The expression as written
+    expression: Expression,  // The parsed expression
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expression {
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Not(Box<Expression>),
+    Compare(Field, Operator, Literal),
+    Like(Field, String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Field {
+    Priority,
+    RetryCount,
+    Content,
+    Header(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Literal {
+    Text(String),
+    Number(f64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    Text(String),
+    Number(f64),
+    Operator(Operator),
+    And,
+    Or,
+    Not,
+    Like,
+    OpenParen,
+    CloseParen,
+}
+
+impl Selector {
+    /// Parses a selector expression.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The expression, e.g. `region = 'eu' AND priority > 5`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `SelectorError` pointing at the first token that does not fit the grammar,
+    /// or at the first `NOT` or `(` nested deeper than `MAX_SELECTOR_DEPTH`.
+    pub fn parse(source: &str) -> Result<Selector, SelectorError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, next: 0, end: source.len(), depth: 0 };
+        let expression = parser.parse_or()?;
+        if let Some((position, token)) = parser.tokens.get(parser.next) {
+            return Err(error(*position, format!("unexpected {:?}", token)));
+        }
+
+        Ok(Selector {
+            source: source.to_string(),
+            expression,
+        })
+    }
+
+    /// Returns the expression the selector was parsed from.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Returns `true` if the message satisfies the selector.
+    pub fn matches(&self, message: &Message) -> bool {
+        self.expression.evaluate(message)
+    }
+}
+
+impl Expression {
+    fn evaluate(&self, message: &Message) -> bool {
+        match self {
+            Expression::And(left, right) => left.evaluate(message) && right.evaluate(message),
+            Expression::Or(left, right) => left.evaluate(message) || right.evaluate(message),
+            Expression::Not(inner) => !inner.evaluate(message),
+            Expression::Compare(field, operator, literal) => {
+                let value = match field.value(message) {
+                    Some(value) => value,
+                    None => return false,
+                };
+                let ordering = match literal {
+                    Literal::Text(text) => Some(value.as_str().cmp(text.as_str())),
+                    Literal::Number(number) => value.trim().parse::<f64>().ok().and_then(|value| value.partial_cmp(number)),
+                };
+                ordering.is_some_and(|ordering| operator.accepts(ordering))
+            }
+            Expression::Like(field, pattern) => field
+                .value(message)
+                .is_some_and(|value| like(&value.chars().collect::<Vec<_>>(), &pattern.chars().collect::<Vec<_>>())),
+        }
+    }
+}
+
+impl Field {
+    fn from_name(name: &str) -> Field {
+        match name {
+            "priority" => Field::Priority,
+            "retry_count" => Field::RetryCount,
+            "content" => Field::Content,
+            header => Field::Header(header.to_string()),
+        }
+    }
+
+    fn value(&self, message: &Message) -> Option<String> {
+        match self {
+            Field::Priority => Some(message.priority.to_string()),
+            Field::RetryCount => Some(message.retry_count.to_string()),
+            Field::Content => Some(message.content.clone()),
+            Field::Header(name) => message.headers.get(name).cloned(),
+        }
+    }
+}
+
+impl Operator {
+    fn accepts(self, ordering: Ordering) -> bool {
+        match self {
+            Operator::Equal => ordering == Ordering::Equal,
+            Operator::NotEqual => ordering != Ordering::Equal,
+            Operator::Less => ordering == Ordering::Less,
+            Operator::LessOrEqual => ordering != Ordering::Greater,
+            Operator::Greater => ordering == Ordering::Greater,
+            Operator::GreaterOrEqual => ordering != Ordering::Less,
+        }
+    }
+}
+
+/// Matches a value against a LIKE pattern.
+///
+/// Only the most recent `%` is ever backtracked to, which keeps matching linear in the
+/// pattern for each position in the value however many `%` it contains.
+fn like(value: &[char], pattern: &[char]) -> bool {
+    let (mut v, mut p) = (0, 0);
+    // Pattern position after the last `%` seen, and the value position it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('%') => {
+                p += 1;
+                backtrack = Some((p, v));
+            }
+            Some('_') => {
+                p += 1;
+                v += 1;
+            }
+            Some(&c) if c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            // Let the last `%` swallow one more character and retry from there
+            _ => match backtrack {
+                Some((after_percent, tried_at)) => {
+                    p = after_percent;
+                    v = tried_at + 1;
+                    backtrack = Some((after_percent, v));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '%')
+}
+
+fn error(position: usize, message: String) -> SelectorError {
+    SelectorError { position, message }
+}
+
+/// Splits an expression into tokens, each with its byte offset.
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, SelectorError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+
+    while let Some(&(position, c)) = chars.peek() {
+        let token = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '(' => {
+                chars.next();
+                Token::OpenParen
+            }
+            ')' => {
+                chars.next();
+                Token::CloseParen
+            }
+            '=' => {
+                chars.next();
+                Token::Operator(Operator::Equal)
+            }
+            '!' => {
+                chars.next();
+                match chars.next() {
+                    Some((_, '=')) => Token::Operator(Operator::NotEqual),
+                    _ => return Err(error(position, "expected '=' after '!'".to_string())),
+                }
+            }
+            '<' | '>' => {
+                chars.next();
+                let followed_by = chars.peek().map(|&(_, next)| next);
+                let operator = match (c, followed_by) {
+                    ('<', Some('=')) => Operator::LessOrEqual,
+                    ('<', Some('>')) => Operator::NotEqual,
+                    ('>', Some('=')) => Operator::GreaterOrEqual,
+                    ('<', _) => Operator::Less,
+                    _ => Operator::Greater,
+                };
+                if matches!(operator, Operator::LessOrEqual | Operator::NotEqual | Operator::GreaterOrEqual) {
+                    chars.next();
+                }
+                Token::Operator(operator)
+            }
+            '\'' => {
+                chars.next();
+                Token::Text(read_text(&mut chars, position)?)
+            }
+            c if c.is_ascii_digit() || c == '-' || c == '.' => {
+                let number = read_while(&mut chars, |c| c.is_ascii_digit() || c == '-' || c == '.');
+                let number = number
+                    .parse()
+                    .map_err(|_| error(position, format!("invalid number '{}'", number)))?;
+                Token::Number(number)
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let word = read_while(&mut chars, |c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.');
+                match word.to_ascii_uppercase().as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    "LIKE" => Token::Like,
+                    _ => Token::Identifier(word),
+                }
+            }
+            c => return Err(error(position, format!("unexpected character '{}'", c))),
+        };
+        tokens.push((position, token));
+    }
+
+    Ok(tokens)
+}
+
+fn read_while(chars: &mut Peekable<CharIndices>, accept: impl Fn(char) -> bool) -> String {
+    let mut word = String::new();
+    while let Some(&(_, c)) = chars.peek() {
+        if !accept(c) {
+            break;
+        }
+        word.push(c);
+        chars.next();
+    }
+    word
+}
+
+/// Reads a quoted string after its opening quote; `''` stands for a literal quote.
+fn read_text(chars: &mut Peekable<CharIndices>, start: usize) -> Result<String, SelectorError> {
+    let mut text = String::new();
+    loop {
+        match chars.next() {
+            Some((_, '\'')) => {
+                if chars.peek().map(|&(_, c)| c) == Some('\'') {
+                    chars.next();
+                    text.push('\'');
+                } else {
+                    return Ok(text);
+                }
+            }
+            Some((_, c)) => text.push(c),
+            None => return Err(error(start, "unterminated string".to_string())),
+        }
+    }
+}
+
+/// Recursive descent parser; `NOT` binds tighter than `AND`, which binds tighter than `OR`.
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    end: usize,
+    depth: usize, // Current nesting of `NOT` and parentheses
+}
+
+impl Parser {
+    fn parse_or(&mut self) -> Result<Expression, SelectorError> {
+        let mut expression = self.parse_and()?;
+        while self.eat(&Token::Or) {
+            expression = Expression::Or(Box::new(expression), Box::new(self.parse_and()?));
+        }
+        Ok(expression)
+    }
+
+    fn parse_and(&mut self) -> Result<Expression, SelectorError> {
+        let mut expression = self.parse_not()?;
+        while self.eat(&Token::And) {
+            expression = Expression::And(Box::new(expression), Box::new(self.parse_not()?));
+        }
+        Ok(expression)
+    }
+
+    fn parse_not(&mut self) -> Result<Expression, SelectorError> {
+        if self.eat(&Token::Not) {
+            self.enter()?;
+            let expression = Expression::Not(Box::new(self.parse_not()?));
+            self.depth -= 1;
+            return Ok(expression);
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expression, SelectorError> {
+        let (position, token) = self.advance("a comparison")?;
+        match token {
+            Token::OpenParen => {
+                self.enter()?;
+                let expression = self.parse_or()?;
+                self.depth -= 1;
+                match self.advance("')'")? {
+                    (_, Token::CloseParen) => Ok(expression),
+                    (position, token) => Err(error(position, format!("expected ')', found {:?}", token))),
+                }
+            }
+            Token::Identifier(name) => {
+                let field = Field::from_name(&name);
+                match self.advance("an operator")? {
+                    (_, Token::Operator(operator)) => match self.advance("a literal")? {
+                        (_, Token::Text(text)) => Ok(Expression::Compare(field, operator, Literal::Text(text))),
+                        (_, Token::Number(number)) => Ok(Expression::Compare(field, operator, Literal::Number(number))),
+                        (position, token) => Err(error(position, format!("expected a literal, found {:?}", token))),
+                    },
+                    (_, Token::Like) => match self.advance("a pattern")? {
+                        (_, Token::Text(pattern)) => Ok(Expression::Like(field, pattern)),
+                        (position, token) => Err(error(position, format!("expected a quoted pattern, found {:?}", token))),
+                    },
+                    (position, token) => Err(error(position, format!("expected an operator, found {:?}", token))),
+                }
+            }
+            token => Err(error(position, format!("expected a comparison, found {:?}", token))),
+        }
+    }
+
+    /// Goes one level deeper into a `NOT` or parentheses, failing past `MAX_SELECTOR_DEPTH`.
+    fn enter(&mut self) -> Result<(), SelectorError> {
+        if self.depth == MAX_SELECTOR_DEPTH {
+            let position = self.tokens[self.next - 1].0;
+            return Err(error(position, format!("nested deeper than {} levels", MAX_SELECTOR_DEPTH)));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Consumes the next token if it equals `expected`.
+    fn eat(&mut self, expected: &Token) -> bool {
+        match self.tokens.get(self.next) {
+            Some((_, token)) if token == expected => {
+                self.next += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Consumes the next token, failing at the end of the expression.
+    fn advance(&mut self, expected: &str) -> Result<(usize, Token), SelectorError> {
+        match self.tokens.get(self.next) {
+            Some(token) => {
+                self.next += 1;
+                Ok(token.clone())
+            }
+            None => Err(error(self.end, format!("expected {}, found end of selector", expected))),
+        }
+    }
+}
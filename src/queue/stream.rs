@@ -0,0 +1,147 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+use crate::queue::{Message, QueueError};
+use crate::storage::storage::{Message as StoredMessage, Storage, StreamOffsets, StreamRecord};
+
+/// An append-only, persistent log of messages, consumed Kafka-style by offset.
+///
+/// Unlike a `Queue`, reading a stream does not remove anything: every consumer group reads
+/// the whole stream at its own pace and resumes from the offset it last committed. Entries
+/// are only removed by the retention policy of the stream (see `Storage::set_retention`).
+#[derive(Debug, Clone)]
+pub struct Stream {
+    storage: Storage,   // Keyspace of the stream
+}
+
+/// A message read from a stream, with its position.
+#[derive(Debug, Clone)]
+pub struct StreamEntry {
+    /// Position of the message in the stream.
+    pub offset: u64,
+    /// When the message was appended.
+    pub appended_at: SystemTime,
+    /// The appended message.
+    pub message: Message,
+}
+
+/// Reads a stream on behalf of a consumer group, from a position it can move freely.
+///
+/// The position only outlives the reader once it is committed with `commit`.
+#[derive(Debug, Clone)]
+pub struct StreamReader {
+    stream: Stream,     // The stream being read
+    group: String,      // Consumer group whose offset is committed
+    position: u64,      // Offset of the next entry to read
+}
+
+impl Stream {
+    /// Opens the named stream in storage, creating it if it does not exist yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `storage` - The storage to keep the stream in.
+    /// * `name` - The name of the stream.
+    pub fn open(storage: &Storage, name: &str) -> Result<Stream, QueueError> {
+        Ok(Stream { storage: storage.stream(name).map_err(QueueError::Storage)? })
+    }
+
+    /// Returns the name of the stream.
+    pub fn name(&self) -> &str {
+        self.storage.queue_name()
+    }
+
+    /// Returns the storage the stream is kept in, scoped to the stream.
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    /// Appends a message and returns its offset.
+    pub async fn append(&self, message: Message) -> Result<u64, QueueError> {
+        self.append_batch(vec![message]).await
+    }
+
+    /// Appends messages atomically, in order, and returns the offset of the first one.
+    pub async fn append_batch(&self, messages: Vec<Message>) -> Result<u64, QueueError> {
+        let stored: Vec<StoredMessage> = messages.iter().map(StoredMessage::from).collect();
+        self.storage.append(&stored).await.map_err(QueueError::Storage)
+    }
+
+    /// Reads up to `limit` entries starting at `offset`; see `Storage::read_stream`.
+    pub async fn read(&self, offset: u64, limit: usize) -> Result<Vec<StreamEntry>, QueueError> {
+        let records = self.storage.read_stream(offset, limit).await.map_err(QueueError::Storage)?;
+        Ok(records.into_iter().map(StreamEntry::from).collect())
+    }
+
+    /// Returns the range of offsets the stream holds.
+    pub fn offsets(&self) -> Result<StreamOffsets, QueueError> {
+        self.storage.stream_offsets().map_err(QueueError::Storage)
+    }
+
+    /// Returns the offset of the first entry appended at or after `time`.
+    pub async fn offset_at(&self, time: SystemTime) -> Result<u64, QueueError> {
+        self.storage.stream_offset_at(time).await.map_err(QueueError::Storage)
+    }
+
+    /// Creates a reader for a consumer group, positioned at the offset the group last
+    /// committed, or at the oldest entry if it never committed one.
+    ///
+    /// # Arguments
+    ///
+    /// * `group` - The name of the consumer group.
+    pub async fn reader(&self, group: &str) -> Result<StreamReader, QueueError> {
+        let position = match self.storage.committed_stream_offset(group).await.map_err(QueueError::Storage)? {
+            Some(offset) => offset,
+            None => self.offsets()?.earliest,
+        };
+        Ok(StreamReader { stream: self.clone(), group: group.to_string(), position })
+    }
+}
+
+impl StreamReader {
+    /// Returns the consumer group the reader commits offsets for.
+    pub fn group(&self) -> &str {
+        &self.group
+    }
+
+    /// Returns the offset of the next entry to read.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Reads up to `max` entries from the current position and moves past them.
+    ///
+    /// Returns an empty vector if nothing was appended since the last poll.
+    pub async fn poll(&mut self, max: usize) -> Result<Vec<StreamEntry>, QueueError> {
+        let entries = self.stream.read(self.position, max).await?;
+        if let Some(last) = entries.last() {
+            self.position = last.offset + 1;
+        }
+        Ok(entries)
+    }
+
+    /// Persists the current position as the group's committed offset.
+    pub async fn commit(&self) -> Result<(), QueueError> {
+        self.stream.storage.commit_stream_offset(&self.group, self.position).await.map_err(QueueError::Storage)
+    }
+
+    /// Moves to an offset, e.g. to replay entries already read.
+    pub fn seek(&mut self, offset: u64) {
+        self.position = offset;
+    }
+
+    /// Moves to the first entry appended at or after `time` and returns its offset.
+    pub async fn seek_to_time(&mut self, time: SystemTime) -> Result<u64, QueueError> {
+        self.position = self.stream.offset_at(time).await?;
+        Ok(self.position)
+    }
+}
+
+impl From<StreamRecord> for StreamEntry {
+    fn from(record: StreamRecord) -> Self {
+        StreamEntry {
+            offset: record.offset,
+            appended_at: UNIX_EPOCH + Duration::from_millis(record.appended_at),
+            message: Message::from_stored(record.message, Instant::now()),
+        }
+    }
+}
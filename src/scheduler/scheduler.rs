@@ -1,6 +1,10 @@
 use tokio::time::{sleep, Duration, Interval};
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use crate::broker::broker::Broker;
+use crate::storage::storage::Storage;
+use log::{info, warn};
 
 /// Represents a scheduler that can handle delayed and periodic tasks.
 pub struct Scheduler {
@@ -86,4 +90,73 @@ impl Scheduler {
             });
         }
     }
+
+    /// Start applying the retention policies of the queues in `storage` every `cleanup_interval`.
+    ///
+    /// Each pass runs on the blocking thread pool; a failed pass is logged and retried at the
+    /// next tick. Abort the returned handle to stop.
+    ///
+    /// # Arguments
+    ///
+    /// * `storage` - The storage whose queues' policies, set with `Storage::set_retention`, are applied.
+    pub fn start_retention(&self, storage: Storage) -> JoinHandle<()> {
+        let cleanup_interval = self.cleanup_interval;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(cleanup_interval);
+            loop {
+                interval.tick().await;
+                let storage = storage.clone();
+                match tokio::task::spawn_blocking(move || storage.apply_retention()).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => warn!("Applying retention policies failed: {}", e),
+                    Err(e) => warn!("Retention task failed: {}", e),
+                }
+            }
+        })
+    }
+
+    /// Start compacting the storage engine every `compaction_interval`, reclaiming the space
+    /// freed by acks and retention.
+    ///
+    /// Abort the returned handle to stop.
+    ///
+    /// # Arguments
+    ///
+    /// * `storage` - The storage to compact.
+    /// * `compaction_interval` - The time between compactions; the first one runs after it has passed.
+    pub fn start_compaction(&self, storage: Storage, compaction_interval: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                sleep(compaction_interval).await;
+                let storage = storage.clone();
+                match tokio::task::spawn_blocking(move || storage.compact()).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => warn!("Storage compaction failed: {}", e),
+                    Err(e) => warn!("Compaction task failed: {}", e),
+                }
+            }
+        })
+    }
+
+    /// Start reclaiming the leases of consumer group members that missed their heartbeats
+    /// every `reap_interval`, so dead members of idle groups are detected too.
+    ///
+    /// Abort the returned handle to stop.
+    ///
+    /// # Arguments
+    ///
+    /// * `broker` - The broker whose consumer groups are checked.
+    /// * `reap_interval` - The time between checks; well below the groups' session timeouts.
+    pub fn start_group_reaper(&self, broker: Broker, reap_interval: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(reap_interval);
+            loop {
+                interval.tick().await;
+                let reclaimed = broker.reap_dead_members().await;
+                if reclaimed > 0 {
+                    info!("Reclaimed {} messages from dead consumer group members", reclaimed);
+                }
+            }
+        })
+    }
 }
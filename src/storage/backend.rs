@@ -0,0 +1,148 @@
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use crate::storage::file_log_backend::{self, FileLogBackend, DEFAULT_SEGMENT_BYTES};
+use crate::storage::memory_backend::MemoryBackend;
+use crate::storage::rocksdb_backend::RocksDbBackend;
+
+/// A key and its value, as returned by `StorageBackend::scan`.
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+/// A single write in a batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    /// Stores a value under a key, replacing any previous value.
+    Put(Vec<u8>, Vec<u8>),
+    /// Removes a key if it exists.
+    Delete(Vec<u8>),
+    /// Removes every key in `[start, end)`, without reading them first.
+    DeleteRange(Vec<u8>, Vec<u8>),
+}
+
+/// An ordered key-value engine that `Storage` persists messages to.
+///
+/// Keys are compared bytewise, so `scan` returns entries in key order. Implementations must
+/// be safe to share between threads; errors are reported as strings like the rest of the
+/// storage layer.
+pub trait StorageBackend: Send + Sync + Debug {
+    /// Stores a value under a key, replacing any previous value.
+    fn save(&self, key: &[u8], value: &[u8]) -> Result<(), String>;
+
+    /// Returns the value stored under a key, if any.
+    fn load(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String>;
+
+    /// Removes a key; removing a missing key is not an error.
+    fn delete(&self, key: &[u8]) -> Result<(), String>;
+
+    /// Returns every entry whose key starts with `prefix`, in key order.
+    fn scan(&self, prefix: &[u8]) -> Result<Vec<Entry>, String>;
+
+    /// Returns up to `limit` entries whose keys are in `[start, end)`, in key order.
+    ///
+    /// Unlike `scan`, only the requested page is read, so large keyspaces can be walked lazily.
+    fn scan_range(&self, start: &[u8], end: &[u8], limit: usize) -> Result<Vec<Entry>, String>;
+
+    /// Applies several writes atomically: after a crash either all or none of them are visible.
+    ///
+    /// # Arguments
+    ///
+    /// * `ops` - The writes, applied in order.
+    /// * `sync` - Whether to wait until the writes are durable on disk before returning.
+    fn write_batch(&self, ops: Vec<BatchOp>, sync: bool) -> Result<(), String>;
+
+    /// Makes every write that has already returned durable on disk.
+    fn flush(&self) -> Result<(), String>;
+
+    /// Writes a consistent point-in-time copy of every entry to a new directory, while
+    /// writes keep being accepted.
+    ///
+    /// Returns the configuration that opens the copy, which may use a different engine than
+    /// this backend (e.g. an in-memory backend is copied to a file log).
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory to create the copy in; it must not exist yet.
+    fn checkpoint(&self, dir: &str) -> Result<StorageConfig, String>;
+
+    /// Reclaims the space still taken by overwritten and deleted entries.
+    ///
+    /// Reads and writes keep working during and after compaction; it may be slow, so it is
+    /// meant to be run in the background (see `Scheduler::start_compaction`).
+    fn compact(&self) -> Result<(), String>;
+}
+
+/// Selects and configures the engine behind `Storage`.
+///
+/// Deserializes from configuration such as `{ "engine": "file_log", "dir": "/var/lib/hexbolt" }`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "engine", rename_all = "snake_case")]
+pub enum StorageConfig {
+    /// RocksDB database at the given path.
+    RocksDb { path: String },
+    /// Non-persistent in-memory map, for tests.
+    InMemory,
+    /// Append-only segmented log files in the given directory.
+    FileLog {
+        dir: String,
+        #[serde(default = "default_segment_bytes")]
+        segment_bytes: u64,
+    },
+}
+
+fn default_segment_bytes() -> u64 {
+    DEFAULT_SEGMENT_BYTES
+}
+
+impl StorageConfig {
+    /// Opens the configured engine.
+    pub fn open(&self) -> Result<Arc<dyn StorageBackend>, String> {
+        match self {
+            StorageConfig::RocksDb { path } => Ok(Arc::new(RocksDbBackend::open(path)?)),
+            StorageConfig::InMemory => Ok(Arc::new(MemoryBackend::new())),
+            StorageConfig::FileLog { dir, segment_bytes } => Ok(Arc::new(FileLogBackend::open(dir, *segment_bytes)?)),
+        }
+    }
+
+    /// Returns the configuration of the engine whose data is in a directory, telling a file log
+    /// from a RocksDB database by their files.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The data directory.
+    pub fn detect(path: &str) -> Result<StorageConfig, String> {
+        let dir = Path::new(path);
+        if !dir.is_dir() {
+            return Err(format!("{} is not a data directory", path));
+        }
+        if dir.join("CURRENT").is_file() {
+            return Ok(StorageConfig::RocksDb { path: path.to_string() });
+        }
+        if !file_log_backend::list_segments(dir)?.is_empty() {
+            return Ok(StorageConfig::FileLog { dir: path.to_string(), segment_bytes: DEFAULT_SEGMENT_BYTES });
+        }
+        Err(format!("{} holds neither a RocksDB database nor a file log", path))
+    }
+
+    /// Returns the path the engine keeps its data at, if any.
+    pub fn location(&self) -> Option<&str> {
+        match self {
+            StorageConfig::RocksDb { path } => Some(path),
+            StorageConfig::InMemory => None,
+            StorageConfig::FileLog { dir, .. } => Some(dir),
+        }
+    }
+
+    /// Returns the same engine configuration with its data kept at another path.
+    ///
+    /// # Arguments
+    ///
+    /// * `location` - The new path; ignored by engines that keep no data on disk.
+    pub fn with_location(&self, location: &str) -> StorageConfig {
+        match self {
+            StorageConfig::RocksDb { .. } => StorageConfig::RocksDb { path: location.to_string() },
+            StorageConfig::InMemory => StorageConfig::InMemory,
+            StorageConfig::FileLog { segment_bytes, .. } => StorageConfig::FileLog { dir: location.to_string(), segment_bytes: *segment_bytes },
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use log::warn;
+
+/// Key size of ChaCha20-Poly1305 in bytes.
+pub const KEY_LEN: usize = 32;
+
+/// Nonce size of ChaCha20-Poly1305 in bytes.
+const NONCE_LEN: usize = 12;
+
+/// Size of the authentication tag appended to every ciphertext.
+const TAG_LEN: usize = 16;
+
+/// Leading byte of a stored value that is not encrypted.
+const PLAIN: u8 = 0;
+
+/// Leading byte of a stored value encrypted with ChaCha20-Poly1305.
+const SEALED: u8 = 1;
+
+/// Length of the header of an encrypted value: format byte, key id and nonce.
+const SEALED_HEADER_LEN: usize = 1 + 4 + NONCE_LEN;
+
+/// The keys message values are encrypted with, each identified by a numeric id.
+///
+/// New values are encrypted with the active key, the one with the highest id. Every encrypted
+/// value records the id of its key, so older keys stay usable for reading after a rotation
+/// until `Storage::reencrypt` has moved every value to the active key.
+#[derive(Clone)]
+pub struct Keyring {
+    keys: HashMap<u32, ChaCha20Poly1305>, // Ciphers of the keys, by id
+    active: u32,                          // Id of the key new values are encrypted with
+}
+
+impl Keyring {
+    /// Creates a keyring holding a single key.
+    ///
+    /// # Arguments
+    /// * `id` - The id stored with every value encrypted with the key.
+    /// * `key` - The 256-bit key.
+    pub fn new(id: u32, key: [u8; KEY_LEN]) -> Keyring {
+        Keyring { keys: HashMap::from([(id, ChaCha20Poly1305::new(&key.into()))]), active: id }
+    }
+
+    /// Adds a key, which becomes the active key if its id is the highest.
+    ///
+    /// # Arguments
+    /// * `id` - The id stored with every value encrypted with the key.
+    /// * `key` - The 256-bit key.
+    pub fn with_key(mut self, id: u32, key: [u8; KEY_LEN]) -> Self {
+        self.keys.insert(id, ChaCha20Poly1305::new(&key.into()));
+        self.active = self.active.max(id);
+        self
+    }
+
+    /// Reads a keyring from a local keyfile.
+    ///
+    /// Each line holds a key id and a base64-encoded 32-byte key separated by whitespace;
+    /// empty lines and lines starting with `#` are ignored. A key can be generated with
+    /// `head -c 32 /dev/urandom | base64`. To rotate, append a line with a higher id.
+    ///
+    /// # Arguments
+    /// * `path` - The keyfile; it should be readable by the broker's user only.
+    pub fn load(path: &str) -> Result<Keyring, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("cannot read keyfile {}: {}", path, e))?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if let Ok(metadata) = fs::metadata(path) {
+                if metadata.permissions().mode() & 0o077 != 0 {
+                    warn!("Keyfile {} is readable by other users", path);
+                }
+            }
+        }
+
+        Keyring::parse(&text).map_err(|e| format!("invalid keyfile {}: {}", path, e))
+    }
+
+    /// Parses the contents of a keyfile; see `load`.
+    pub fn parse(text: &str) -> Result<Keyring, String> {
+        let mut keyring: Option<Keyring> = None;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let (Some(id), Some(key), None) = (fields.next(), fields.next(), fields.next()) else {
+                return Err(format!("line {}: expected `<id> <base64 key>`", number + 1));
+            };
+            let id: u32 = id.parse().map_err(|_| format!("line {}: invalid key id {}", number + 1, id))?;
+            let key: [u8; KEY_LEN] = BASE64
+                .decode(key)
+                .map_err(|e| format!("line {}: invalid key: {}", number + 1, e))?
+                .try_into()
+                .map_err(|_| format!("line {}: key must be {} bytes", number + 1, KEY_LEN))?;
+            if keyring.as_ref().is_some_and(|keyring| keyring.keys.contains_key(&id)) {
+                return Err(format!("line {}: duplicate key id {}", number + 1, id));
+            }
+
+            keyring = Some(match keyring {
+                Some(keyring) => keyring.with_key(id, key),
+                None => Keyring::new(id, key),
+            });
+        }
+        keyring.ok_or_else(|| "no keys".to_string())
+    }
+
+    /// Returns the id of the key new values are encrypted with.
+    pub fn active_key_id(&self) -> u32 {
+        self.active
+    }
+
+    /// Returns whether the keyring holds the key with the given id.
+    pub fn has_key(&self, id: u32) -> bool {
+        self.keys.contains_key(&id)
+    }
+
+    /// Returns the ids of every key, in ascending order.
+    pub fn key_ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.keys.keys().copied().collect();
+        ids.sort();
+        ids
+    }
+
+    /// Encrypts a value with the active key, binding it to the key it is stored under.
+    ///
+    /// The nonce is random; both it and the key id are stored in the header of the value.
+    fn seal(&self, record_key: &[u8], value: &[u8]) -> Vec<u8> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.keys[&self.active]
+            .encrypt(&nonce, Payload { msg: value, aad: record_key })
+            .expect("ChaCha20-Poly1305 encryption cannot fail for in-memory values");
+
+        let mut sealed = Vec::with_capacity(SEALED_HEADER_LEN + ciphertext.len());
+        sealed.push(SEALED);
+        sealed.extend_from_slice(&self.active.to_be_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend(ciphertext);
+        sealed
+    }
+
+    /// Decrypts a value written by `seal`, given its full stored form.
+    fn open(&self, record_key: &[u8], sealed: &[u8]) -> Result<Vec<u8>, String> {
+        if sealed.len() < SEALED_HEADER_LEN + TAG_LEN {
+            return Err("encrypted value is truncated".to_string());
+        }
+        let id = u32::from_be_bytes(sealed[1..5].try_into().unwrap());
+        let cipher = self.keys.get(&id).ok_or_else(|| format!("value is encrypted with unknown key {}", id))?;
+        let nonce = Nonce::from_slice(&sealed[5..SEALED_HEADER_LEN]);
+        cipher
+            .decrypt(nonce, Payload { msg: &sealed[SEALED_HEADER_LEN..], aad: record_key })
+            .map_err(|_| format!("cannot decrypt value with key {}: authentication failed", id))
+    }
+}
+
+// Keys must never end up in logs
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyring").field("key_ids", &self.key_ids()).field("active", &self.active).finish()
+    }
+}
+
+/// Wraps a serialized value for storage: encrypted with the active key if a keyring is
+/// given, tagged as plain otherwise.
+///
+/// # Arguments
+/// * `keyring` - The keys to encrypt with, if encryption is enabled.
+/// * `record_key` - The key the value is stored under, authenticated with the value.
+/// * `value` - The serialized value.
+pub(crate) fn seal_value(keyring: Option<&Keyring>, record_key: &[u8], value: &[u8]) -> Vec<u8> {
+    match keyring {
+        Some(keyring) => keyring.seal(record_key, value),
+        None => [&[PLAIN], value].concat(),
+    }
+}
+
+/// Unwraps a value written by `seal_value`.
+///
+/// Plain values are read whether or not encryption is enabled, so it can be turned on for
+/// existing data.
+pub(crate) fn open_value(keyring: Option<&Keyring>, record_key: &[u8], stored: &[u8]) -> Result<Vec<u8>, String> {
+    match (stored.first(), keyring) {
+        (Some(&PLAIN), _) => Ok(stored[1..].to_vec()),
+        (Some(&SEALED), Some(keyring)) => keyring.open(record_key, stored),
+        (Some(&SEALED), None) => Err("value is encrypted but no keyfile is configured".to_string()),
+        (Some(format), _) => Err(format!("unknown value format {}", format)),
+        (None, _) => Err("empty value".to_string()),
+    }
+}
+
+/// Returns whether a stored value is framed like an encrypted value written by `seal_value`,
+/// without decrypting it.
+pub(crate) fn is_sealed(stored: &[u8]) -> bool {
+    stored.first() == Some(&SEALED) && stored.len() >= SEALED_HEADER_LEN + TAG_LEN
+}
+
+/// Returns the id of the key a stored value is encrypted with, or `None` if it is plain.
+pub(crate) fn key_id_of(stored: &[u8]) -> Option<u32> {
+    match stored.first() {
+        Some(&SEALED) if stored.len() >= 5 => Some(u32::from_be_bytes(stored[1..5].try_into().unwrap())),
+        _ => None,
+    }
+}
@@ -0,0 +1,468 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use crate::storage::backend::{BatchOp, Entry, StorageBackend, StorageConfig};
+use log::{debug, info, warn};
+
+/// Size after which the active segment is closed and a new one is started.
+pub const DEFAULT_SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+
+const OP_PUT: u8 = 1;
+const OP_DELETE: u8 = 2;
+const OP_DELETE_RANGE: u8 = 3;
+
+/// Number of live entries copied per frame during compaction.
+const COMPACTION_FRAME_ENTRIES: usize = 1000;
+
+/// The append handle of a log segment.
+///
+/// Implemented by `File`; other implementations wrap one, e.g. to inject write failures in tests.
+pub trait SegmentFile: Write + Send + fmt::Debug {
+    /// Flushes the data written so far to disk.
+    fn sync_data(&self) -> io::Result<()>;
+
+    /// Truncates the file to `len` bytes; later writes are appended from there.
+    fn set_len(&self, len: u64) -> io::Result<()>;
+}
+
+impl SegmentFile for File {
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+}
+
+/// Opens a segment file for appending, creating it if needed.
+pub type SegmentOpener = Arc<dyn Fn(&Path) -> io::Result<Box<dyn SegmentFile>> + Send + Sync>;
+
+/// Stores entries in append-only, segmented log files, for deployments that cannot link RocksDB.
+///
+/// Every write (a single save or delete, or a whole batch) is appended to the active segment
+/// as one length-prefixed frame:
+///
+/// `frame length (u32) | op count (u32) | ops...`, where each op is
+/// `kind (u8) | key length (u32) | key | value length (u32) | value` (no value for deletes;
+/// range deletes store the start of the range as the key and its end as the value).
+///
+/// A frame that was only partially written when the process died is discarded on the next
+/// open, which makes batches atomic. Keys and the location of their latest value are indexed
+/// in memory; values are read back from the segment files.
+#[derive(Debug)]
+pub struct FileLogBackend {
+    state: Mutex<LogState>,   // Active segment and index, guarded together
+    compacting: Mutex<()>,    // Held for the whole of a compaction, so only one runs at a time
+}
+
+struct LogState {
+    dir: PathBuf,                            // Directory holding the segment files
+    segment_bytes: u64,                      // Rollover threshold for the active segment
+    active_id: u64,                          // Id of the segment being appended to
+    active: Box<dyn SegmentFile>,            // Append handle of the active segment
+    active_len: u64,                         // Length of the complete frames in the active segment
+    index: BTreeMap<Vec<u8>, ValueLocation>, // Latest value of every live key
+    open_segment: SegmentOpener,             // Opens the append handle of a new segment
+    poisoned: bool,                          // A failed write could not be undone, so the log no longer matches the index
+}
+
+impl fmt::Debug for LogState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogState")
+            .field("dir", &self.dir)
+            .field("active_id", &self.active_id)
+            .field("active_len", &self.active_len)
+            .field("keys", &self.index.len())
+            .field("poisoned", &self.poisoned)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Where a value is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ValueLocation {
+    segment: u64,
+    offset: u64,
+    len: u32,
+}
+
+impl FileLogBackend {
+    /// Opens the log in `dir`, creating the directory if needed and replaying existing segments.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory holding the segment files.
+    /// * `segment_bytes` - Size after which a new segment is started.
+    pub fn open(dir: &str, segment_bytes: u64) -> Result<FileLogBackend, String> {
+        FileLogBackend::open_with(dir, segment_bytes, Arc::new(open_segment_file))
+    }
+
+    /// Opens the log in `dir` like `open`, appending to segments through handles opened with
+    /// `open_segment`.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory holding the segment files.
+    /// * `segment_bytes` - Size after which a new segment is started.
+    /// * `open_segment` - Opens a segment file for appending, creating it if needed.
+    pub fn open_with(dir: &str, segment_bytes: u64, open_segment: SegmentOpener) -> Result<FileLogBackend, String> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+
+        let mut index = BTreeMap::new();
+        let segments = list_segments(&dir)?;
+        for segment in &segments {
+            replay_segment(&dir, *segment, &mut index)?;
+        }
+
+        let active_id = segments.last().copied().unwrap_or(1);
+        let active = open_segment(&segment_path(&dir, active_id)).map_err(|e| e.to_string())?;
+        let active_len = fs::metadata(segment_path(&dir, active_id)).map_err(|e| e.to_string())?.len();
+
+        info!("Opened file log at {:?} with {} segments and {} keys", dir, segments.len().max(1), index.len());
+        Ok(FileLogBackend {
+            state: Mutex::new(LogState {
+                dir,
+                segment_bytes: segment_bytes.max(1),
+                active_id,
+                active,
+                active_len,
+                index,
+                open_segment,
+                poisoned: false,
+            }),
+            compacting: Mutex::new(()),
+        })
+    }
+}
+
+impl StorageBackend for FileLogBackend {
+    fn save(&self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        self.write_batch(vec![BatchOp::Put(key.to_vec(), value.to_vec())], false)
+    }
+
+    fn load(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let state = self.state.lock().unwrap();
+        match state.index.get(key) {
+            Some(location) => read_value(&state.dir, *location).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), String> {
+        self.write_batch(vec![BatchOp::Delete(key.to_vec())], false)
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Result<Vec<Entry>, String> {
+        let state = self.state.lock().unwrap();
+        state
+            .index
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, location)| Ok((key.clone(), read_value(&state.dir, *location)?)))
+            .collect()
+    }
+
+    fn scan_range(&self, start: &[u8], end: &[u8], limit: usize) -> Result<Vec<Entry>, String> {
+        let state = self.state.lock().unwrap();
+        state
+            .index
+            .range(start.to_vec()..)
+            .take_while(|(key, _)| key.as_slice() < end)
+            .take(limit)
+            .map(|(key, location)| Ok((key.clone(), read_value(&state.dir, *location)?)))
+            .collect()
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>, sync: bool) -> Result<(), String> {
+        self.state.lock().unwrap().append(ops, sync)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        let state = self.state.lock().unwrap();
+        state.active.sync_data().map_err(|e| e.to_string())
+    }
+
+    fn checkpoint(&self, dir: &str) -> Result<StorageConfig, String> {
+        let target = PathBuf::from(dir);
+        if target.exists() {
+            return Err(format!("checkpoint directory {} already exists", dir));
+        }
+
+        // Writes wait while the segments are copied, so the copy is consistent
+        let state = self.state.lock().unwrap();
+        fs::create_dir_all(&target).map_err(|e| e.to_string())?;
+        for segment in list_segments(&state.dir)? {
+            let (source, copy) = (segment_path(&state.dir, segment), segment_path(&target, segment));
+            if segment == state.active_id {
+                // Only the frames written so far; anything past them is an incomplete write
+                let mut data = fs::read(&source).map_err(|e| e.to_string())?;
+                data.truncate(state.active_len as usize);
+                fs::write(&copy, data).map_err(|e| e.to_string())?;
+            } else {
+                fs::copy(&source, &copy).map_err(|e| e.to_string())?;
+            }
+            File::open(&copy).and_then(|file| file.sync_all()).map_err(|e| e.to_string())?;
+        }
+        File::open(&target).and_then(|dir| dir.sync_all()).map_err(|e| e.to_string())?;
+        Ok(StorageConfig::FileLog { dir: dir.to_string(), segment_bytes: state.segment_bytes })
+    }
+
+    /// Copies the live entries to new segments and deletes the old ones, if at least half of
+    /// the log is taken by overwritten or deleted entries.
+    ///
+    /// The lock is only held while one frame of entries is copied, so reads and writes carry
+    /// on in between. Entries written in the meantime already go to the new segments.
+    fn compact(&self) -> Result<(), String> {
+        let _compacting = self.compacting.lock().unwrap();
+        let (dir, old_segments, entries, total, live) = {
+            let mut state = self.state.lock().unwrap();
+            let old_segments = list_segments(&state.dir)?;
+            let mut total = 0;
+            for segment in &old_segments {
+                total += fs::metadata(segment_path(&state.dir, *segment)).map_err(|e| e.to_string())?.len();
+            }
+            let live: u64 = state.index.iter().map(|(key, location)| key.len() as u64 + location.len as u64).sum();
+            if total == 0 || live * 2 > total {
+                return Ok(());
+            }
+
+            state.roll_segment()?;
+            let entries: Vec<(Vec<u8>, ValueLocation)> = state.index.iter().map(|(key, location)| (key.clone(), *location)).collect();
+            (state.dir.clone(), old_segments, entries, total, live)
+        };
+
+        for chunk in entries.chunks(COMPACTION_FRAME_ENTRIES) {
+            let mut state = self.state.lock().unwrap();
+            let ops = chunk
+                .iter()
+                // Entries overwritten or deleted since the copy started are not copied back
+                .filter(|(key, location)| state.index.get(key) == Some(location))
+                .map(|(key, location)| Ok(BatchOp::Put(key.clone(), read_value(&dir, *location)?)))
+                .collect::<Result<Vec<_>, String>>()?;
+            if !ops.is_empty() {
+                state.append(ops, false)?;
+            }
+        }
+
+        // No entry points into the old segments any more
+        let state = self.state.lock().unwrap();
+        state.active.sync_data().map_err(|e| e.to_string())?;
+        // Oldest first: a crash part way leaves a suffix of the old log, which replays correctly
+        for segment in &old_segments {
+            fs::remove_file(segment_path(&dir, *segment)).map_err(|e| e.to_string())?;
+        }
+        info!("Compacted file log from {} to about {} bytes, removing {} segments", total, live, old_segments.len());
+        Ok(())
+    }
+}
+
+impl LogState {
+    /// Appends ops to the active segment as one frame, then indexes them.
+    ///
+    /// If the frame cannot be written or synced, whatever part of it reached the file is cut
+    /// off again, so the next frame starts where the index expects it. If even that fails, the
+    /// log refuses further writes until it is reopened, which discards the partial frame.
+    fn append(&mut self, ops: Vec<BatchOp>, sync: bool) -> Result<(), String> {
+        if self.poisoned {
+            return Err("file log refuses writes after a failed write could not be undone; reopen it".to_string());
+        }
+        if self.active_len >= self.segment_bytes {
+            self.roll_segment()?;
+        }
+
+        let (frame, value_offsets) = encode_frame(&ops);
+        let frame_offset = self.active_len;
+        let written = self.active.write_all(&frame).and_then(|()| if sync { self.active.sync_data() } else { Ok(()) });
+        if let Err(e) = written {
+            if let Err(truncate_error) = self.active.set_len(frame_offset) {
+                warn!("Could not undo a failed write to log segment {}: {}", self.active_id, truncate_error);
+                self.poisoned = true;
+            }
+            return Err(e.to_string());
+        }
+        self.active_len += frame.len() as u64;
+
+        // Only index the writes once the whole frame is in the file
+        let segment = self.active_id;
+        for (op, value_offset) in ops.into_iter().zip(value_offsets) {
+            match op {
+                BatchOp::Put(key, value) => {
+                    let location = ValueLocation { segment, offset: frame_offset + value_offset, len: value.len() as u32 };
+                    self.index.insert(key, location);
+                }
+                BatchOp::Delete(key) => {
+                    self.index.remove(&key);
+                }
+                BatchOp::DeleteRange(start, end) => remove_range(&mut self.index, &start, &end),
+            }
+        }
+        Ok(())
+    }
+
+    /// Closes the active segment and starts a new one.
+    fn roll_segment(&mut self) -> Result<(), String> {
+        self.active.sync_data().map_err(|e| e.to_string())?;
+        self.active = (self.open_segment)(&segment_path(&self.dir, self.active_id + 1)).map_err(|e| e.to_string())?;
+        self.active_id += 1;
+        self.active_len = 0;
+        debug!("Started log segment {}", self.active_id);
+        Ok(())
+    }
+}
+
+/// Opens a segment file for appending; the default `SegmentOpener`.
+fn open_segment_file(path: &Path) -> io::Result<Box<dyn SegmentFile>> {
+    Ok(Box::new(OpenOptions::new().create(true).append(true).open(path)?))
+}
+
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("{:020}.log", segment))
+}
+
+/// Returns the ids of the segment files in `dir`, oldest first.
+pub(crate) fn list_segments(dir: &Path) -> Result<Vec<u64>, String> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|e| e.to_string())? {
+        let name = entry.map_err(|e| e.to_string())?.file_name();
+        let name = name.to_string_lossy();
+        if let Some(id) = name.strip_suffix(".log").and_then(|id| id.parse::<u64>().ok()) {
+            segments.push(id);
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// Encodes ops as one frame; also returns, for each op, the offset of its value within the frame.
+fn encode_frame(ops: &[BatchOp]) -> (Vec<u8>, Vec<u64>) {
+    let mut body = Vec::new();
+    let mut value_offsets = Vec::with_capacity(ops.len());
+    body.extend_from_slice(&(ops.len() as u32).to_be_bytes());
+
+    for op in ops {
+        let (kind, key, value) = match op {
+            BatchOp::Put(key, value) => (OP_PUT, key, Some(value)),
+            BatchOp::Delete(key) => (OP_DELETE, key, None),
+            BatchOp::DeleteRange(start, end) => (OP_DELETE_RANGE, start, Some(end)),
+        };
+        body.push(kind);
+        body.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        body.extend_from_slice(key);
+        match value {
+            Some(value) => {
+                body.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                // The frame starts with its 4-byte length
+                value_offsets.push(4 + body.len() as u64);
+                body.extend_from_slice(value);
+            }
+            None => value_offsets.push(0),
+        }
+    }
+
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    (frame, value_offsets)
+}
+
+/// Applies every complete frame of a segment to the index, truncating a torn frame at its end.
+fn replay_segment(dir: &Path, segment: u64, index: &mut BTreeMap<Vec<u8>, ValueLocation>) -> Result<(), String> {
+    let path = segment_path(dir, segment);
+    let data = fs::read(&path).map_err(|e| e.to_string())?;
+
+    let mut offset = 0usize;
+    while let Some(body_len) = read_u32(&data, offset) {
+        let body_start = offset + 4;
+        let body_end = body_start + body_len as usize;
+        if body_end > data.len() {
+            break;
+        }
+        let body = &data[body_start..body_end];
+        if apply_frame(body, segment, body_start as u64, index).is_none() {
+            return Err(format!("corrupt frame at offset {} of {:?}", offset, path));
+        }
+        offset = body_end;
+    }
+
+    if offset < data.len() {
+        warn!("Discarding {} bytes of an incomplete write at the end of {:?}", data.len() - offset, path);
+        let file = OpenOptions::new().write(true).open(&path).map_err(|e| e.to_string())?;
+        file.set_len(offset as u64).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Applies the ops of one frame body; returns `None` if the body is malformed.
+fn apply_frame(body: &[u8], segment: u64, body_offset: u64, index: &mut BTreeMap<Vec<u8>, ValueLocation>) -> Option<()> {
+    let count = read_u32(body, 0)?;
+    let mut position = 4usize;
+    let mut updates = Vec::with_capacity(count as usize);
+
+    for _ in 0..count {
+        let kind = *body.get(position)?;
+        let key_len = read_u32(body, position + 1)? as usize;
+        let key = body.get(position + 5..position + 5 + key_len)?.to_vec();
+        position += 5 + key_len;
+
+        match kind {
+            OP_PUT => {
+                let value_len = read_u32(body, position)?;
+                let value_start = position + 4;
+                body.get(value_start..value_start + value_len as usize)?;
+                updates.push(IndexUpdate::Put(key, ValueLocation { segment, offset: body_offset + value_start as u64, len: value_len }));
+                position = value_start + value_len as usize;
+            }
+            OP_DELETE => updates.push(IndexUpdate::Delete(key)),
+            OP_DELETE_RANGE => {
+                let end_len = read_u32(body, position)? as usize;
+                let end = body.get(position + 4..position + 4 + end_len)?.to_vec();
+                updates.push(IndexUpdate::DeleteRange(key, end));
+                position += 4 + end_len;
+            }
+            _ => return None,
+        }
+    }
+
+    for update in updates {
+        match update {
+            IndexUpdate::Put(key, location) => {
+                index.insert(key, location);
+            }
+            IndexUpdate::Delete(key) => {
+                index.remove(&key);
+            }
+            IndexUpdate::DeleteRange(start, end) => remove_range(index, &start, &end),
+        }
+    }
+    Some(())
+}
+
+/// A decoded op, applied to the index once its whole frame has been validated.
+enum IndexUpdate {
+    Put(Vec<u8>, ValueLocation),
+    Delete(Vec<u8>),
+    DeleteRange(Vec<u8>, Vec<u8>),
+}
+
+fn remove_range(index: &mut BTreeMap<Vec<u8>, ValueLocation>, start: &[u8], end: &[u8]) {
+    let mut tail = index.split_off(start);
+    index.append(&mut tail.split_off(end));
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_value(dir: &Path, location: ValueLocation) -> Result<Vec<u8>, String> {
+    let mut file = File::open(segment_path(dir, location.segment)).map_err(|e| e.to_string())?;
+    file.seek(SeekFrom::Start(location.offset)).map_err(|e| e.to_string())?;
+    let mut value = vec![0u8; location.len as usize];
+    file.read_exact(&mut value).map_err(|e| e.to_string())?;
+    Ok(value)
+}
@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use crate::storage::backend::{BatchOp, Entry, StorageBackend, StorageConfig};
+use crate::storage::file_log_backend::{FileLogBackend, DEFAULT_SEGMENT_BYTES};
+
+/// Keeps entries in an in-memory ordered map; nothing survives the process.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    entries: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,   // Entries in key order
+}
+
+impl MemoryBackend {
+    /// Creates an empty in-memory backend.
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn save(&self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        self.entries.lock().unwrap().insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn load(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.entries.lock().unwrap().get(key).cloned())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), String> {
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Result<Vec<Entry>, String> {
+        let entries = self.entries.lock().unwrap();
+        Ok(entries
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn scan_range(&self, start: &[u8], end: &[u8], limit: usize) -> Result<Vec<Entry>, String> {
+        let entries = self.entries.lock().unwrap();
+        Ok(entries
+            .range(start.to_vec()..)
+            .take_while(|(key, _)| key.as_slice() < end)
+            .take(limit)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>, _sync: bool) -> Result<(), String> {
+        let mut entries = self.entries.lock().unwrap();
+        for op in ops {
+            match op {
+                BatchOp::Put(key, value) => {
+                    entries.insert(key, value);
+                }
+                BatchOp::Delete(key) => {
+                    entries.remove(&key);
+                }
+                BatchOp::DeleteRange(start, end) => {
+                    let mut tail = entries.split_off(&start);
+                    entries.append(&mut tail.split_off(&end));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn checkpoint(&self, dir: &str) -> Result<StorageConfig, String> {
+        if std::path::Path::new(dir).exists() {
+            return Err(format!("checkpoint directory {} already exists", dir));
+        }
+        let ops = self.entries.lock().unwrap().iter().map(|(key, value)| BatchOp::Put(key.clone(), value.clone())).collect();
+        FileLogBackend::open(dir, DEFAULT_SEGMENT_BYTES)?.write_batch(ops, true)?;
+        Ok(StorageConfig::FileLog { dir: dir.to_string(), segment_bytes: DEFAULT_SEGMENT_BYTES })
+    }
+
+    fn compact(&self) -> Result<(), String> {
+        // Deleted entries are freed right away
+        Ok(())
+    }
+}
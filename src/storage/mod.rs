@@ -1 +1,6 @@
 pub mod storage;
+pub mod backend;
+pub mod memory_backend;
+pub mod rocksdb_backend;
+pub mod file_log_backend;
+pub mod encryption;
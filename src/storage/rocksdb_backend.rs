@@ -0,0 +1,91 @@
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::{Direction, IteratorMode, Options, WriteBatch, WriteOptions, DB};
+use crate::storage::backend::{BatchOp, Entry, StorageBackend, StorageConfig};
+
+/// Stores entries in a RocksDB database.
+#[derive(Debug)]
+pub struct RocksDbBackend {
+    db: DB,   // RocksDB handles are safe to share between threads
+}
+
+impl RocksDbBackend {
+    /// Opens (or creates) the RocksDB database at the given path.
+    pub fn open(path: &str) -> Result<RocksDbBackend, String> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+
+        let db = DB::open(&options, path).map_err(|e| e.to_string())?;
+        Ok(RocksDbBackend { db })
+    }
+}
+
+impl StorageBackend for RocksDbBackend {
+    fn save(&self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        self.db.put(key, value).map_err(|e| e.to_string())
+    }
+
+    fn load(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.db.get(key).map_err(|e| e.to_string())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), String> {
+        self.db.delete(key).map_err(|e| e.to_string())
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Result<Vec<Entry>, String> {
+        let mut entries = Vec::new();
+        for item in self.db.iterator(IteratorMode::From(prefix, Direction::Forward)) {
+            let (key, value) = item.map_err(|e| e.to_string())?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            entries.push((key.to_vec(), value.to_vec()));
+        }
+        Ok(entries)
+    }
+
+    fn scan_range(&self, start: &[u8], end: &[u8], limit: usize) -> Result<Vec<Entry>, String> {
+        let mut entries = Vec::new();
+        for item in self.db.iterator(IteratorMode::From(start, Direction::Forward)) {
+            let (key, value) = item.map_err(|e| e.to_string())?;
+            if entries.len() == limit || &*key >= end {
+                break;
+            }
+            entries.push((key.to_vec(), value.to_vec()));
+        }
+        Ok(entries)
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>, sync: bool) -> Result<(), String> {
+        let mut batch = WriteBatch::default();
+        for op in ops {
+            match op {
+                BatchOp::Put(key, value) => batch.put(key, value),
+                BatchOp::Delete(key) => batch.delete(key),
+                // A range tombstone: constant cost however many keys it covers
+                BatchOp::DeleteRange(start, end) => batch.delete_range(start, end),
+            }
+        }
+
+        let mut write_options = WriteOptions::default();
+        write_options.set_sync(sync);
+        self.db.write_opt(batch, &write_options).map_err(|e| e.to_string())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.db.flush_wal(true).map_err(|e| e.to_string())
+    }
+
+    fn checkpoint(&self, dir: &str) -> Result<StorageConfig, String> {
+        // Hard-links the immutable SST files, so this is cheap even for large databases
+        let checkpoint = Checkpoint::new(&self.db).map_err(|e| e.to_string())?;
+        checkpoint.create_checkpoint(dir).map_err(|e| e.to_string())?;
+        Ok(StorageConfig::RocksDb { path: dir.to_string() })
+    }
+
+    fn compact(&self) -> Result<(), String> {
+        // Rewrites every SST file, dropping tombstones and the values they shadow
+        self.db.compact_range::<&[u8], &[u8]>(None, None);
+        Ok(())
+    }
+}
@@ -1,35 +1,1664 @@
-use rocksdb::{DB, Options, IteratorMode};
 use serde::{Serialize, Deserialize};
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use bincode;
+use std::collections::HashMap;
+use std::fs;
 use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock, RwLock, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+use bincode::{self, Options};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{sleep, Duration, Instant};
+use crate::storage::backend::{BatchOp, StorageBackend, StorageConfig};
+use crate::storage::encryption::{self, Keyring};
+use crate::storage::rocksdb_backend::RocksDbBackend;
+use log::{debug, info, warn};
 
-/// Represents a storage system backed by RocksDB for persisting messages.
+/// Name of the queue a `Storage` that was not scoped with `Storage::queue` writes to.
+pub const DEFAULT_QUEUE: &str = "default";
+
+/// Key prefix of the queue keyspaces: `q/ | queue id (u32) | kind | message id (u64)`.
+const QUEUE_PREFIX: &[u8] = b"q/";
+
+/// Key prefix of the queue catalog, which maps queue names to their `QueueInfo`.
+const CATALOG_PREFIX: &[u8] = b"n/";
+
+/// Key of the counter queue ids are allocated from.
+const NEXT_QUEUE_ID_KEY: &[u8] = b"c/next_queue_id";
+
+/// Key of the version of the layout the stored records are in.
+const FORMAT_VERSION_KEY: &[u8] = b"c/format_version";
+
+/// Version of the layout this build writes; `Storage::migrate` upgrades older data to it.
+///
+/// Version 2 added the dead-letter time index.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Key prefix of the retention policies: `p/ | queue id (u32)`.
+const RETENTION_PREFIX: &[u8] = b"p/";
+
+/// File describing a snapshot, next to the snapshot's data.
+pub const SNAPSHOT_MANIFEST: &str = "manifest.json";
+
+/// Directory of a snapshot holding the copied entries.
+const SNAPSHOT_DATA: &str = "data";
+
+/// Number of entries copied per write batch when restoring a snapshot.
+const RESTORE_PAGE_SIZE: usize = 1000;
+
+/// Number of entries re-encrypted at a time; writers wait for each page.
+const REENCRYPT_PAGE_SIZE: usize = 100;
+
+/// Number of entries upgraded per write batch by `migrate`.
+const MIGRATE_PAGE_SIZE: usize = 1000;
+
+/// Number of entries checked at a time by `verify`.
+const VERIFY_PAGE_SIZE: usize = 1000;
+
+/// Number of entries read at a time when applying retention policies.
+const RETENTION_PAGE_SIZE: usize = 1000;
+
+/// Number of pending messages read at a time by `count_pending`.
+const COUNT_PAGE_SIZE: usize = 1000;
+
+/// Length of the CRC-32C appended to every stored message value.
+const CHECKSUM_LEN: usize = 4;
+
+// Kinds of record within a queue keyspace
+const PENDING: u8 = b'm';
+const IN_FLIGHT: u8 = b'f';
+const DEAD_LETTER: u8 = b'd';
+const DUE_INDEX: u8 = b'i';
+const DEAD_LETTER_INDEX: u8 = b'e';
+const QUARANTINE: u8 = b'x';
+const ARCHIVE: u8 = b'a';
+const STREAM: u8 = b's';
+const STREAM_TIME_INDEX: u8 = b't';
+const STREAM_NEXT: u8 = b'n';
+const STREAM_COMMITTED: u8 = b'o';
+
+/// When writes are fsynced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Never fsync explicitly; the OS (or engine) decides when data reaches disk.
+    Never,
+    /// Fsync outstanding writes in the background at a fixed interval.
+    Interval(Duration),
+    /// Fsync every commit before it returns; concurrent commits share one fsync.
+    EveryWrite,
+}
+
+/// A write waiting for the group-commit task, with the channel its result is sent on.
+type CommitRequest = (Vec<BatchOp>, oneshot::Sender<Result<(), String>>);
+
+/// Represents a storage system for persisting messages, on top of a pluggable `StorageBackend`.
+///
+/// Every queue has its own keyspace holding its pending, in-flight and dead-lettered messages,
+/// so queues can share one backend, be recovered one at a time and be dropped without a scan.
+/// A `Storage` reads and writes the keyspace of one queue (`DEFAULT_QUEUE` unless scoped with
+/// `queue`); clones and scoped copies share the backend and the group-commit task.
 #[derive(Debug, Clone)]
 pub struct Storage {
-    db: Arc<Mutex<DB>>,   // Thread-safe access to RocksDB
+    backend: Arc<dyn StorageBackend>,   // Engine the messages are stored in
+    sync_policy: SyncPolicy,            // When commits are fsynced
+    committer: Arc<OnceLock<mpsc::UnboundedSender<CommitRequest>>>, // Feeds the group-commit task
+    flusher: Arc<OnceLock<()>>,         // Set once the interval flush task is running
+    catalog_lock: Arc<Mutex<()>>,       // Serializes queue creation and drops
+    append_lock: Arc<tokio::sync::Mutex<()>>, // Serializes stream appends, which allocate offsets
+    keyring: Option<Arc<Keyring>>,      // Keys message values are encrypted with, if enabled
+    retention: Arc<OnceLock<Mutex<RetentionPolicies>>>, // Read from storage on first use
+    rewrite_lock: Arc<RwLock<()>>,      // Held exclusively while re-encryption rewrites a page; only taken on blocking threads
+    queue: QueueInfo,                   // The queue whose keyspace is used
 }
 
-/// Represents a message that will be stored in the queue and the storage system.
+/// A queue's entry in the storage catalog.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueInfo {
+    pub id: u32,        // Identifies the queue's keyspace; never reused
+    pub name: String,   // Name of the queue
+}
+
+/// Describes a snapshot taken with `Storage::snapshot`; stored as `manifest.json`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    pub created_at: u64,            // When the snapshot was taken, in milliseconds since the Unix epoch
+    pub storage: StorageConfig,     // Opens the snapshot's data; the location is relative to the snapshot
+    pub queues: Vec<QueueInfo>,     // Queues in the catalog when the snapshot was taken
+}
+
+/// What a `Storage::reencrypt` pass did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReencryptionReport {
+    pub scanned: usize,     // Message values read
+    pub rewritten: usize,   // Values that were plain or under an older key, now under the active key
+}
+
+/// Which state a stored message record was in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecordKind {
+    Pending,
+    InFlight,
+    DeadLetter,
+    Archived,
+    Streamed,
+}
+
+/// How long a queue keeps messages it is done with; applied by `Storage::apply_retention`.
+///
+/// By default acked messages are deleted right away and dead letters are kept forever.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    pub keep_acked: Option<Duration>,           // How long acked messages are archived for replay and auditing
+    pub archive_max_bytes: Option<u64>,         // Size the archive is trimmed to, oldest first
+    pub dead_letter_max_age: Option<Duration>,  // How long dead letters are kept
+    pub dead_letter_max_bytes: Option<u64>,     // Size the dead letters are trimmed to, oldest first
+    pub stream_max_age: Option<Duration>,       // How long stream entries are kept
+    pub stream_max_bytes: Option<u64>,          // Size a stream is trimmed to, oldest entries first
+}
+
+/// The retention policy of every queue that has one, by queue id.
+type RetentionPolicies = HashMap<u32, (QueueInfo, RetentionPolicy)>;
+
+/// An acked message kept in the archive of its queue.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedMessage {
+    pub acked_at: u64,      // When the message was acked, in milliseconds since the Unix epoch
+    pub message: Message,   // The message as it was delivered
+}
+
+/// What a `Storage::apply_retention` pass removed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionReport {
+    pub archived_removed: usize,      // Archived messages past their retention
+    pub dead_letters_removed: usize,  // Dead letters past their retention
+    pub stream_entries_removed: usize, // Stream entries past their retention
+}
+
+/// A message appended to a stream, at its offset.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamRecord {
+    pub offset: u64,        // Position in the stream; offsets start at 0 and are never reused
+    pub appended_at: u64,   // When it was appended, in milliseconds since the Unix epoch; never decreases
+    pub message: Message,   // The appended message
+}
+
+/// The range of offsets a stream currently holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamOffsets {
+    pub earliest: u64,   // Offset of the oldest entry not removed by retention; `next` if there is none
+    pub next: u64,       // Offset the next appended message will get
+}
+
+/// A message record whose checksum did not match, moved aside so the rest of its queue stays
+/// readable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuarantinedRecord {
+    pub kind: RecordKind,   // What the record was before it was quarantined
+    pub message_id: u64,    // Id of the message it held
+    pub value: Vec<u8>,     // The stored bytes, as found
+}
+
+/// A corrupted message record found by `Storage::verify`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CorruptRecord {
+    pub queue: String,      // Name of the queue holding the record
+    pub kind: RecordKind,   // What the record is
+    pub message_id: u64,    // Id of the message it holds
+}
+
+/// What a `Storage::verify` pass found.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerifyReport {
+    pub scanned: usize,                 // Message records checked
+    pub corrupted: Vec<CorruptRecord>,  // Records that failed their checksum or could not be decrypted or decoded
+    pub quarantined: usize,             // Corrupted records moved to their queue's quarantine
+    pub unverified: usize,              // Records encrypted with a key that is not configured, checked against their checksum only
+}
+
+/// A set of message writes committed atomically with `Storage::commit`.
+///
+/// The writes apply to the queue of the `Storage` the batch is committed to.
+#[derive(Debug, Clone, Default)]
+pub struct StorageBatch {
+    writes: Vec<BatchWrite>,   // Writes in the order they were added
+}
+
+/// A write in a `StorageBatch`, with the message already serialized.
+#[derive(Debug, Clone)]
+enum BatchWrite {
+    Push(DueKey, Vec<u8>),
+    Deliver(u64, Vec<u8>),
+    Ack(u64),
+    DeadLetter(u64, u64, Vec<u8>),
+    Archive(u64, u64, Vec<u8>),
+    Unindex(DueKey),
+}
+
+/// A message that was moved to the dead-letter queue, with the reason why.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub message: Message,           // The dead-lettered message
+    pub reason: String,             // Why it could not be delivered
+    pub dead_lettered_at: u64,      // When it was dead-lettered, in milliseconds since the Unix epoch
+}
+
+/// How a pending message is stored: the message plus when it becomes available.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PendingRecord {
+    available_at: u64,   // Milliseconds since the Unix epoch
+    message: Message,
+}
+
+/// Layout of a message before headers, group keys and expiry times were added.
+#[derive(Debug, Deserialize)]
+struct MessageV0 {
+    id: u64,
+    content: String,
+    priority: u8,
+    retry_count: u8,
+    max_retries: u8,
+}
+
+/// Layout of a pending record before headers, group keys and expiry times were added.
+#[derive(Debug, Deserialize)]
+struct PendingRecordV0 {
+    available_at: u64,
+    message: MessageV0,
+}
+
+/// Layout of a dead letter before headers, group keys and expiry times were added.
+#[derive(Debug, Deserialize)]
+struct DeadLetterV0 {
+    message: MessageV0,
+    reason: String,
+}
+
+/// Layout of a dead letter before the time it was dead-lettered was recorded.
+#[derive(Debug, Deserialize)]
+struct DeadLetterV1 {
+    message: Message,
+    reason: String,
+}
+
+impl From<MessageV0> for Message {
+    fn from(message: MessageV0) -> Self {
+        Message {
+            id: message.id,
+            content: message.content,
+            priority: message.priority,
+            retry_count: message.retry_count,
+            max_retries: message.max_retries,
+            ..Default::default()
+        }
+    }
+}
+
+/// Position of a pending message in the due index: `(available_at, priority, id)`.
+///
+/// Index keys sort by availability time, then by descending priority, then by id, which is
+/// the order the queue delivers messages in. An entry is deleted in the same batch that moves
+/// its message out of pending; readers still check entries against the pending record and
+/// prune any stale ones, e.g. left by a corrupted record that was quarantined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DueKey {
+    available_at: u64,
+    priority: u8,
+    id: u64,
+}
+
+impl DueKey {
+    fn encode(&self, queue_id: u32) -> Vec<u8> {
+        let mut key = kind_prefix(queue_id, DUE_INDEX);
+        key.extend_from_slice(&self.available_at.to_be_bytes());
+        key.push(u8::MAX - self.priority);
+        key.extend_from_slice(&self.id.to_be_bytes());
+        key
+    }
+
+    fn decode(key: &[u8]) -> Option<DueKey> {
+        // Skip `q/ | queue id | kind`
+        let key = key.get(QUEUE_PREFIX.len() + 5..)?;
+        if key.len() != 17 {
+            return None;
+        }
+        Some(DueKey {
+            available_at: u64::from_be_bytes(key[..8].try_into().unwrap()),
+            priority: u8::MAX - key[8],
+            id: u64::from_be_bytes(key[9..].try_into().unwrap()),
+        })
+    }
+}
+
+/// Converts a wall-clock time to milliseconds since the Unix epoch, clamping times before it.
+pub(crate) fn epoch_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_millis() as u64).unwrap_or(0)
+}
+
+/// Converts a monotonic time to wall-clock time, so it can outlive the process.
+pub(crate) fn system_time_of(instant: Instant) -> SystemTime {
+    let (now, system_now) = (Instant::now(), SystemTime::now());
+    if instant >= now {
+        system_now + (instant - now)
+    } else {
+        system_now.checked_sub(now - instant).unwrap_or(UNIX_EPOCH)
+    }
+}
+
+/// Converts milliseconds since the Unix epoch back to a monotonic time.
+pub(crate) fn instant_of(epoch_millis: u64) -> Instant {
+    let time = UNIX_EPOCH + Duration::from_millis(epoch_millis);
+    let now = Instant::now();
+    match time.duration_since(SystemTime::now()) {
+        Ok(ahead) => now + ahead,
+        Err(behind) => now.checked_sub(behind.duration()).unwrap_or(now),
+    }
+}
+
+/// Streams the due messages of a queue in delivery order, a page at a time.
+///
+/// Created by `Storage::due_messages`. The cursor only moves forward: a message pushed with an
+/// availability time before the cursor's position is not returned until `rewind` is called.
+#[derive(Debug, Clone)]
+pub struct DueMessages {
+    storage: Storage,     // The queue being read
+    position: Vec<u8>,    // First index key not yet read
+}
+
+impl DueMessages {
+    /// Returns up to `limit` messages that are due at `now` and were not returned before.
+    ///
+    /// An empty result means no more messages are due yet.
+    ///
+    /// # Arguments
+    /// * `now` - The time messages must be available by.
+    /// * `limit` - The maximum number of messages to return.
+    pub async fn next_batch(&mut self, now: SystemTime, limit: usize) -> Result<Vec<Message>, String> {
+        let queue_id = self.storage.queue.id;
+        let end = DueKey { available_at: epoch_millis(now).saturating_add(1), priority: u8::MAX, id: 0 }.encode(queue_id);
+        let backend = &self.storage.backend;
+        let mut messages = Vec::new();
+        let mut stale = Vec::new();
+
+        while messages.len() < limit {
+            let page = backend.scan_range(&self.position, &end, limit - messages.len())?;
+            let exhausted = page.len() < limit - messages.len();
+            for (key, _) in page {
+                self.position = key.clone();
+                self.position.push(0);
+
+                let due_key = DueKey::decode(&key).ok_or_else(|| format!("corrupt due index key {:?}", key))?;
+                match self.storage.load_pending(due_key.id).await? {
+                    Some(record) if record.available_at == due_key.available_at && record.message.priority == due_key.priority => {
+                        messages.push(record.message);
+                    }
+                    // Acked, delivered, dead-lettered or pushed again since the entry was written
+                    _ => stale.push(BatchOp::Delete(key)),
+                }
+            }
+            if exhausted {
+                break;
+            }
+        }
+
+        if !stale.is_empty() {
+            backend.write_batch(stale, false)?;
+        }
+        Ok(messages)
+    }
+
+    /// Moves the cursor back to the start of the index.
+    pub fn rewind(&mut self) {
+        self.position = kind_prefix(self.storage.queue.id, DUE_INDEX);
+    }
+}
+
+/// Represents a message that will be stored in the queue and the storage system.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Message {
     pub id: u64,            // Unique identifier of the message
     pub content: String,    // Content of the message
     pub priority: u8,       // Message priority
     pub retry_count: u8,    // Retry count
     pub max_retries: u8,    // Max retries allowed
+    pub headers: HashMap<String, String>,   // Application-defined metadata
+    pub group_key: Option<String>,          // Key grouping related messages, if any
+    pub expires_at: Option<u64>,            // When the message expires, in milliseconds since the Unix epoch
+}
+
+impl StorageBatch {
+    /// Creates an empty batch.
+    pub fn new() -> StorageBatch {
+        StorageBatch::default()
+    }
+
+    /// Persists a newly pushed message that is available right away, or puts a delivered
+    /// message back in the queue.
+    pub fn push(self, message: &Message) -> Result<StorageBatch, String> {
+        self.push_at(message, SystemTime::now())
+    }
+
+    /// Persists a message that becomes available for delivery at the given time.
+    ///
+    /// # Arguments
+    /// * `message` - The message to persist.
+    /// * `available_at` - When the message may be delivered, e.g. after a delay or retry backoff.
+    pub fn push_at(mut self, message: &Message, available_at: SystemTime) -> Result<StorageBatch, String> {
+        let record = PendingRecord { available_at: epoch_millis(available_at), message: message.clone() };
+        let value = bincode::serialize(&record).map_err(|e| e.to_string())?;
+        let due_key = DueKey { available_at: record.available_at, priority: message.priority, id: message.id };
+        self.writes.push(BatchWrite::Push(due_key, value));
+        Ok(self)
+    }
+
+    /// Marks a message as handed to a consumer and awaiting its acknowledgement.
+    pub fn deliver(mut self, message: &Message) -> Result<StorageBatch, String> {
+        let value = bincode::serialize(message).map_err(|e| e.to_string())?;
+        self.writes.push(BatchWrite::Deliver(message.id, value));
+        Ok(self)
+    }
+
+    /// Removes an acknowledged message.
+    pub fn ack(mut self, message_id: u64) -> StorageBatch {
+        self.writes.push(BatchWrite::Ack(message_id));
+        self
+    }
+
+    /// Moves a message to the dead-letter queue.
+    pub fn dead_letter(mut self, message: &Message, reason: &str) -> Result<StorageBatch, String> {
+        let dead_letter = DeadLetter { message: message.clone(), reason: reason.to_string(), dead_lettered_at: epoch_millis(SystemTime::now()) };
+        let value = bincode::serialize(&dead_letter).map_err(|e| e.to_string())?;
+        self.writes.push(BatchWrite::DeadLetter(message.id, dead_letter.dead_lettered_at, value));
+        Ok(self)
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    /// Returns `true` if the batch holds no writes.
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Translates the writes into backend ops on the keyspace of the given queue.
+    ///
+    /// A message lives under exactly one kind at a time, so each write also removes the
+    /// message from the kinds it may be moving out of, along with the due index entry of a
+    /// message pushed earlier in the batch. Message values are sealed with `keyring` if one
+    /// is given, and checksummed.
+    fn into_ops(self, queue_id: u32, keyring: Option<&Keyring>) -> Vec<BatchOp> {
+        let key = |kind, message_id| queue_key(queue_id, kind, message_id);
+        let put = |key: Vec<u8>, value: Vec<u8>| {
+            let value = encode_value(keyring, &key, &value);
+            BatchOp::Put(key, value)
+        };
+        let mut pushed: HashMap<u64, DueKey> = HashMap::new();
+        let unindex = |ops: &mut Vec<BatchOp>, pushed: &mut HashMap<u64, DueKey>, id: u64| {
+            if let Some(due_key) = pushed.remove(&id) {
+                ops.push(BatchOp::Delete(due_key.encode(queue_id)));
+            }
+        };
+        let mut ops = Vec::with_capacity(self.writes.len() * 3);
+        for write in self.writes {
+            match write {
+                BatchWrite::Push(due_key, value) => {
+                    unindex(&mut ops, &mut pushed, due_key.id);
+                    ops.push(BatchOp::Delete(key(IN_FLIGHT, due_key.id)));
+                    ops.push(put(key(PENDING, due_key.id), value));
+                    ops.push(BatchOp::Put(due_key.encode(queue_id), Vec::new()));
+                    pushed.insert(due_key.id, due_key);
+                }
+                BatchWrite::Deliver(id, value) => {
+                    unindex(&mut ops, &mut pushed, id);
+                    ops.push(BatchOp::Delete(key(PENDING, id)));
+                    ops.push(put(key(IN_FLIGHT, id), value));
+                }
+                BatchWrite::Ack(id) => {
+                    unindex(&mut ops, &mut pushed, id);
+                    ops.push(BatchOp::Delete(key(PENDING, id)));
+                    ops.push(BatchOp::Delete(key(IN_FLIGHT, id)));
+                }
+                BatchWrite::DeadLetter(id, dead_lettered_at, value) => {
+                    unindex(&mut ops, &mut pushed, id);
+                    ops.push(BatchOp::Delete(key(PENDING, id)));
+                    ops.push(BatchOp::Delete(key(IN_FLIGHT, id)));
+                    ops.push(put(key(DEAD_LETTER, id), value));
+                    ops.push(BatchOp::Put(dead_letter_index_key(queue_id, dead_lettered_at, id), Vec::new()));
+                }
+                BatchWrite::Archive(acked_at, id, value) => ops.push(put(archive_key(queue_id, acked_at, id), value)),
+                BatchWrite::Unindex(due_key) => ops.push(BatchOp::Delete(due_key.encode(queue_id))),
+            }
+        }
+        ops
+    }
+}
+
+fn keyspace_prefix(queue_id: u32) -> Vec<u8> {
+    [QUEUE_PREFIX, &queue_id.to_be_bytes()].concat()
+}
+
+fn kind_prefix(queue_id: u32, kind: u8) -> Vec<u8> {
+    let mut prefix = keyspace_prefix(queue_id);
+    prefix.push(kind);
+    prefix
+}
+
+// Ids are time-ordered, so big-endian keys iterate in enqueue order
+fn queue_key(queue_id: u32, kind: u8, message_id: u64) -> Vec<u8> {
+    let mut key = kind_prefix(queue_id, kind);
+    key.extend_from_slice(&message_id.to_be_bytes());
+    key
+}
+
+// Archived messages sort by when they were acked, so old ones can be trimmed from the front
+fn archive_key(queue_id: u32, acked_at: u64, message_id: u64) -> Vec<u8> {
+    let mut key = kind_prefix(queue_id, ARCHIVE);
+    key.extend_from_slice(&acked_at.to_be_bytes());
+    key.extend_from_slice(&message_id.to_be_bytes());
+    key
+}
+
+// Dead letters are indexed by when they were dead-lettered, so old ones can be trimmed without
+// reading every one
+fn dead_letter_index_key(queue_id: u32, dead_lettered_at: u64, message_id: u64) -> Vec<u8> {
+    let mut key = kind_prefix(queue_id, DEAD_LETTER_INDEX);
+    key.extend_from_slice(&dead_lettered_at.to_be_bytes());
+    key.extend_from_slice(&message_id.to_be_bytes());
+    key
+}
+
+fn retention_key(queue_id: u32) -> Vec<u8> {
+    [RETENTION_PREFIX, &queue_id.to_be_bytes()].concat()
+}
+
+fn stream_time_key(queue_id: u32, appended_at: u64, offset: u64) -> Vec<u8> {
+    let mut key = kind_prefix(queue_id, STREAM_TIME_INDEX);
+    key.extend_from_slice(&appended_at.to_be_bytes());
+    key.extend_from_slice(&offset.to_be_bytes());
+    key
+}
+
+fn catalog_key(name: &str) -> Vec<u8> {
+    [CATALOG_PREFIX, name.as_bytes()].concat()
+}
+
+/// Splits the key of a pending, in-flight, dead-lettered or archived message into its queue
+/// id, kind and message id; returns `None` for any other key.
+fn parse_message_key(key: &[u8]) -> Option<(u32, RecordKind, u64)> {
+    let key = key.strip_prefix(QUEUE_PREFIX)?;
+    let kind = match (key.get(4)?, key.len()) {
+        (&PENDING, 13) => RecordKind::Pending,
+        (&IN_FLIGHT, 13) => RecordKind::InFlight,
+        (&DEAD_LETTER, 13) => RecordKind::DeadLetter,
+        (&ARCHIVE, 21) => RecordKind::Archived,
+        (&STREAM, 13) => RecordKind::Streamed,
+        _ => return None,
+    };
+    let message_id = u64::from_be_bytes(key[key.len() - 8..].try_into().unwrap());
+    Some((u32::from_be_bytes(key[..4].try_into().unwrap()), kind, message_id))
+}
+
+/// Returns where a corrupted message record is moved to: `q/ | queue id | x | kind | message id`.
+fn quarantine_key(key: &[u8]) -> Vec<u8> {
+    let split = QUEUE_PREFIX.len() + 4;
+    [&key[..split], &[QUARANTINE], &key[split..]].concat()
+}
+
+/// Returns the first key after every queue keyspace.
+fn queue_keys_end() -> Vec<u8> {
+    let mut end = QUEUE_PREFIX.to_vec();
+    *end.last_mut().unwrap() += 1;
+    end
+}
+
+/// Frames a serialized message for storage: sealed if a keyring is given, then followed by
+/// its CRC-32C so corruption is detected when it is read back.
+fn encode_value(keyring: Option<&Keyring>, key: &[u8], value: &[u8]) -> Vec<u8> {
+    append_checksum(encryption::seal_value(keyring, key, value))
+}
+
+fn append_checksum(mut stored: Vec<u8>) -> Vec<u8> {
+    let checksum = crc32c::crc32c(&stored);
+    stored.extend_from_slice(&checksum.to_be_bytes());
+    stored
+}
+
+/// Returns a stored message value without its checksum, or `None` if the checksum does not match.
+fn verify_checksum(stored: &[u8]) -> Option<&[u8]> {
+    let (value, checksum) = stored.split_at(stored.len().checked_sub(CHECKSUM_LEN)?);
+    (crc32c::crc32c(value).to_be_bytes() == checksum).then_some(value)
+}
+
+/// How `Storage::migrate` handles a message record.
+enum Upgrade {
+    Current(Option<IndexEntry>),            // Already in the current layout; its index entry may still be missing
+    Rewrite(Vec<u8>, Option<IndexEntry>),   // Serialized in the current layout, with its index entry
+    Checksum,                               // Encrypted before checksums were added; only needs one
+    NeedsKey,                               // An encrypted dead letter that cannot be read without its key
+    Unreadable,                             // In no known layout
+}
+
+/// An entry `Storage::migrate` writes to the index of the kind of a record.
+enum IndexEntry {
+    Due(DueKey),
+    DeadLettered(u64, u64),   // When the message was dead-lettered, and its id
+}
+
+impl IndexEntry {
+    fn encode(&self, queue_id: u32) -> Vec<u8> {
+        match self {
+            IndexEntry::Due(due_key) => due_key.encode(queue_id),
+            IndexEntry::DeadLettered(dead_lettered_at, id) => dead_letter_index_key(queue_id, *dead_lettered_at, *id),
+        }
+    }
+}
+
+/// Deserializes a value only if it is exactly one `T`, so layouts that differ by trailing
+/// fields are told apart.
+fn decode_exact<T: serde::de::DeserializeOwned>(value: &[u8]) -> Option<T> {
+    bincode::DefaultOptions::new().with_fixint_encoding().reject_trailing_bytes().deserialize(value).ok()
+}
+
+/// Decodes a serialized record of a kind in its current or any earlier layout, and returns
+/// the id of its message, the record serialized in the current layout and its index entry.
+///
+/// Pending messages from before availability times were stored become available at `now`,
+/// and dead letters from before the dead-lettering time was stored count as dead-lettered
+/// at `now`.
+fn upgrade_value(kind: RecordKind, value: &[u8], now: u64) -> Option<(u64, Vec<u8>, Option<IndexEntry>)> {
+    match kind {
+        RecordKind::Pending => {
+            let record = decode_exact::<PendingRecord>(value)
+                .or_else(|| decode_exact::<PendingRecordV0>(value).map(|record| PendingRecord { available_at: record.available_at, message: record.message.into() }))
+                .or_else(|| decode_exact::<MessageV0>(value).map(|message| PendingRecord { available_at: now, message: message.into() }))?;
+            let due_key = DueKey { available_at: record.available_at, priority: record.message.priority, id: record.message.id };
+            Some((record.message.id, bincode::serialize(&record).ok()?, Some(IndexEntry::Due(due_key))))
+        }
+        RecordKind::InFlight => {
+            let message = decode_exact::<Message>(value).or_else(|| decode_exact::<MessageV0>(value).map(Message::from))?;
+            Some((message.id, bincode::serialize(&message).ok()?, None))
+        }
+        RecordKind::DeadLetter => {
+            let dead_letter = decode_exact::<DeadLetter>(value)
+                .or_else(|| decode_exact::<DeadLetterV1>(value).map(|old| DeadLetter { message: old.message, reason: old.reason, dead_lettered_at: now }))
+                .or_else(|| decode_exact::<DeadLetterV0>(value).map(|old| DeadLetter { message: old.message.into(), reason: old.reason, dead_lettered_at: now }))?;
+            let entry = IndexEntry::DeadLettered(dead_letter.dead_lettered_at, dead_letter.message.id);
+            Some((dead_letter.message.id, bincode::serialize(&dead_letter).ok()?, Some(entry)))
+        }
+        // Archived and streamed records have only ever been written in the current layout
+        RecordKind::Archived | RecordKind::Streamed => None,
+    }
+}
+
+/// Works out how to bring a stored message record to the current layout.
+///
+/// Before checksums, values were stored framed by `seal_value` only, and before that as the
+/// bare serialized record. Plain values of any age are decoded. Of the encrypted ones, only
+/// dead letters have changed layout since encryption was added, so only they are decrypted,
+/// with `keyring`.
+fn upgrade_record(keyring: Option<&Keyring>, kind: RecordKind, key: &[u8], message_id: u64, stored: &[u8], now: u64) -> Upgrade {
+    let upgrade = |value: &[u8]| upgrade_value(kind, value, now).filter(|(id, _, _)| *id == message_id);
+    let dead_letter = matches!(kind, RecordKind::DeadLetter);
+
+    if let Some(framed) = verify_checksum(stored) {
+        if encryption::is_sealed(framed) && !dead_letter {
+            return Upgrade::Current(None);
+        }
+        return match encryption::open_value(keyring, key, framed) {
+            Ok(value) => match upgrade(&value) {
+                Some((_, upgraded, entry)) if upgraded == value => Upgrade::Current(entry),
+                Some((_, upgraded, entry)) => Upgrade::Rewrite(upgraded, entry),
+                None => Upgrade::Unreadable,
+            },
+            Err(_) if encryption::is_sealed(framed) => Upgrade::NeedsKey,
+            Err(_) => Upgrade::Unreadable,
+        };
+    }
+
+    let framed = encryption::open_value(None, key, stored).ok();
+    if let Some((_, upgraded, entry)) = framed.as_deref().and_then(upgrade).or_else(|| upgrade(stored)) {
+        return Upgrade::Rewrite(upgraded, entry);
+    }
+    if !encryption::is_sealed(stored) {
+        return Upgrade::Unreadable;
+    }
+    if !dead_letter {
+        return Upgrade::Checksum;
+    }
+    match encryption::open_value(keyring, key, stored).ok().and_then(|value| upgrade(&value)) {
+        Some((_, upgraded, entry)) => Upgrade::Rewrite(upgraded, entry),
+        None => Upgrade::NeedsKey,
+    }
+}
+
+/// Recognizes a message stored before queues had keyspaces: a pending message under its bare
+/// id or under `m/`, or a dead letter under `d/`.
+///
+/// Returns the kind of record, the message id, and the record in the current layout with
+/// its index entry.
+fn upgrade_unscoped_record(key: &[u8], stored: &[u8], now: u64) -> Option<(u8, u64, Vec<u8>, Option<IndexEntry>)> {
+    let (kind, id) = match (key.len(), key.get(..2)) {
+        (8, _) => (PENDING, key),
+        (10, Some(b"m/")) => (PENDING, &key[2..]),
+        (10, Some(b"d/")) => (DEAD_LETTER, &key[2..]),
+        _ => return None,
+    };
+    let message_id = u64::from_be_bytes(id.try_into().unwrap());
+    let (id, value, entry) = match kind {
+        PENDING => upgrade_value(RecordKind::Pending, stored, now)?,
+        _ => upgrade_value(RecordKind::DeadLetter, stored, now)?,
+    };
+    (id == message_id).then_some((kind, message_id, value, entry))
+}
+
+impl From<&crate::queue::Message> for Message {
+    fn from(message: &crate::queue::Message) -> Self {
+        Message {
+            id: message.id,
+            content: message.content.clone(),
+            priority: message.priority,
+            retry_count: message.retry_count,
+            max_retries: message.max_retries,
+            headers: message.headers.clone(),
+            group_key: message.group_key.clone(),
+            expires_at: message.expires_at.map(|expires_at| epoch_millis(system_time_of(expires_at))),
+        }
+    }
 }
 
 impl Storage {
     /// Initializes the RocksDB storage engine at the specified path.
     pub fn new(db_path: &str) -> Self {
-        let mut options = Options::default();
-        options.create_if_missing(true);
+        let backend = RocksDbBackend::open(db_path).expect("Failed to open RocksDB");
+        let storage = Storage::with_backend(Arc::new(backend));
+        storage.migrate().expect("Failed to migrate storage");
+        storage
+    }
 
-        let db = DB::open(&options, db_path).expect("Failed to open RocksDB");
+    /// Opens the storage engine selected by a configuration.
+    ///
+    /// # Arguments
+    /// * `config` - Which engine to use and where it keeps its data.
+    pub fn open(config: &StorageConfig) -> Result<Self, String> {
+        let storage = Storage::with_backend(config.open()?);
+        storage.migrate()?;
+        Ok(storage)
+    }
+
+    /// Stores messages in an already opened backend.
+    ///
+    /// Commits are not fsynced unless a different `SyncPolicy` is chosen with `with_sync_policy`.
+    /// The backend is used as it is; call `migrate` if it may hold data written by an older
+    /// version.
+    ///
+    /// # Arguments
+    /// * `backend` - The engine to store messages in.
+    pub fn with_backend(backend: Arc<dyn StorageBackend>) -> Self {
         Storage {
-            db: Arc::new(Mutex::new(db)),
+            backend,
+            sync_policy: SyncPolicy::Never,
+            committer: Arc::new(OnceLock::new()),
+            flusher: Arc::new(OnceLock::new()),
+            catalog_lock: Arc::new(Mutex::new(())),
+            append_lock: Arc::new(tokio::sync::Mutex::new(())),
+            keyring: None,
+            retention: Arc::new(OnceLock::new()),
+            rewrite_lock: Arc::new(RwLock::new(())),
+            // Id 0 is never allocated, so the default queue needs no catalog entry
+            queue: QueueInfo { id: 0, name: DEFAULT_QUEUE.to_string() },
+        }
+    }
+
+    /// Returns a copy of this storage that reads and writes the keyspace of the named queue,
+    /// registering the queue in the catalog if it is new.
+    ///
+    /// # Arguments
+    /// * `name` - The name of the queue.
+    pub fn queue(&self, name: &str) -> Result<Storage, String> {
+        let mut scoped = self.clone();
+        scoped.queue = self.queue_info(name)?;
+        Ok(scoped)
+    }
+
+    /// Returns the queue this storage reads and writes.
+    pub fn queue_name(&self) -> &str {
+        &self.queue.name
+    }
+
+    /// Lists the queues registered with `queue`, ordered by name.
+    ///
+    /// The default queue is not part of the catalog and is not listed.
+    pub fn queues(&self) -> Result<Vec<QueueInfo>, String> {
+        self.backend
+            .scan(CATALOG_PREFIX)?
+            .into_iter()
+            .map(|(_, value)| bincode::deserialize(&value).map_err(|e| e.to_string()))
+            .collect()
+    }
+
+    /// Deletes a queue with all of its pending, in-flight and dead-lettered messages.
+    ///
+    /// This is a single range delete, so it takes the same time however many messages the
+    /// queue holds. Dropping the default queue empties it. Returns `false` if the queue does
+    /// not exist. Copies of the storage scoped to the dropped queue keep writing to its old,
+    /// unreachable keyspace; scope a new copy with `queue` to recreate it. This blocks while a
+    /// re-encryption page is being rewritten.
+    ///
+    /// # Arguments
+    /// * `name` - The name of the queue to drop.
+    pub fn drop_queue(&self, name: &str) -> Result<bool, String> {
+        let _guard = self.catalog_lock.lock().unwrap();
+        let id = if name == DEFAULT_QUEUE {
+            0
+        } else {
+            match self.backend.load(&catalog_key(name))? {
+                Some(value) => bincode::deserialize::<QueueInfo>(&value).map_err(|e| e.to_string())?.id,
+                None => return Ok(false),
+            }
+        };
+
+        let ops = vec![
+            BatchOp::Delete(catalog_key(name)),
+            BatchOp::Delete(retention_key(id)),
+            BatchOp::DeleteRange(keyspace_prefix(id), keyspace_prefix(id + 1)),
+        ];
+        let mut policies = self.retention_policies()?.lock().unwrap();
+        self.write_blocking(ops, true)?;
+        policies.remove(&id);
+        info!("Dropped queue {} from storage.", name);
+        Ok(true)
+    }
+
+    /// Takes a consistent snapshot of every queue, with its pending, in-flight and dead-lettered
+    /// messages and the queue catalog, while the storage stays in use.
+    ///
+    /// The backend's data is checkpointed to `data` inside the snapshot directory (a RocksDB
+    /// checkpoint for the RocksDB backend) and described by a `manifest.json` next to it.
+    ///
+    /// # Arguments
+    /// * `dir` - The snapshot directory; created if needed, and must not hold a snapshot yet.
+    pub fn snapshot(&self, dir: &str) -> Result<SnapshotManifest, String> {
+        let dir = Path::new(dir);
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        if dir.join(SNAPSHOT_MANIFEST).exists() {
+            return Err(format!("{:?} already holds a snapshot", dir));
+        }
+
+        let data = dir.join(SNAPSHOT_DATA);
+        let config = self.backend.checkpoint(&data.to_string_lossy())?;
+        let queues = Storage::open(&config)?.queues()?;
+
+        let manifest = SnapshotManifest {
+            created_at: epoch_millis(SystemTime::now()),
+            storage: config.with_location(SNAPSHOT_DATA),
+            queues,
+        };
+        let json = serde_json::to_vec_pretty(&manifest).map_err(|e| e.to_string())?;
+        // The manifest is written last, so a directory with one holds a complete snapshot
+        fs::write(dir.join(SNAPSHOT_MANIFEST), json).map_err(|e| e.to_string())?;
+        fs::File::open(dir.join(SNAPSHOT_MANIFEST)).and_then(|file| file.sync_all()).map_err(|e| e.to_string())?;
+
+        info!("Snapshot of {} queues written to {:?}", manifest.queues.len(), dir);
+        Ok(manifest)
+    }
+
+    /// Reads the manifest of a snapshot taken with `snapshot`.
+    ///
+    /// # Arguments
+    /// * `dir` - The snapshot directory.
+    pub fn read_snapshot_manifest(dir: &str) -> Result<SnapshotManifest, String> {
+        let json = fs::read(Path::new(dir).join(SNAPSHOT_MANIFEST)).map_err(|e| format!("{} is not a snapshot: {}", dir, e))?;
+        serde_json::from_slice(&json).map_err(|e| e.to_string())
+    }
+
+    /// Restores a snapshot into an empty storage engine and opens it.
+    ///
+    /// Entries are copied a page at a time, so the target may use a different engine than
+    /// the snapshot. The snapshot itself is left untouched and can be restored again.
+    ///
+    /// # Arguments
+    /// * `snapshot_dir` - The snapshot directory.
+    /// * `target` - The engine to restore into; it must not hold any data.
+    pub fn restore(snapshot_dir: &str, target: &StorageConfig) -> Result<Storage, String> {
+        let manifest = Storage::read_snapshot_manifest(snapshot_dir)?;
+        let source = match manifest.storage.location() {
+            Some(location) => manifest.storage.with_location(&Path::new(snapshot_dir).join(location).to_string_lossy()),
+            None => manifest.storage.clone(),
+        };
+        let source = source.open()?;
+        let backend = target.open()?;
+
+        // Every key this storage writes starts with an ASCII letter
+        let end = [u8::MAX];
+        if !backend.scan_range(&[], &end, 1)?.is_empty() {
+            return Err("cannot restore into a storage that already holds data".to_string());
+        }
+
+        let mut position = Vec::new();
+        let mut restored = 0;
+        loop {
+            let page = source.scan_range(&position, &end, RESTORE_PAGE_SIZE)?;
+            let Some((last, _)) = page.last() else { break };
+            position = last.clone();
+            position.push(0);
+            restored += page.len();
+            backend.write_batch(page.into_iter().map(|(key, value)| BatchOp::Put(key, value)).collect(), false)?;
+        }
+        backend.flush()?;
+
+        info!("Restored {} entries from snapshot {}", restored, snapshot_dir);
+        let storage = Storage::with_backend(backend);
+        storage.migrate()?;
+        Ok(storage)
+    }
+
+    /// Returns `true` if the storage has not been brought to the current layout by `migrate`.
+    ///
+    /// # Errors
+    /// Fails if the storage was written in a newer layout than this version supports.
+    pub fn needs_migration(&self) -> Result<bool, String> {
+        let Some(value) = self.backend.load(FORMAT_VERSION_KEY)? else { return Ok(true) };
+        let version = u32::from_be_bytes(value.as_slice().try_into().map_err(|_| "corrupt format version".to_string())?);
+        if version > FORMAT_VERSION {
+            return Err(format!("storage is in format {}, but only formats up to {} are supported", version, FORMAT_VERSION));
+        }
+        Ok(version < FORMAT_VERSION)
+    }
+
+    /// Brings data written by older versions to the current layout, then records the
+    /// layout version so later calls return right away.
+    ///
+    /// Messages stored before queues had keyspaces, under their bare id or under `m/` and
+    /// `d/`, move to the default queue. Records in an earlier layout are rewritten, encrypted
+    /// records from before checksums get one, and records in no known layout are quarantined.
+    /// `new`, `open` and `restore` call this when they open a storage.
+    ///
+    /// Encrypted dead letters can only be upgraded with their key. Without it they are left
+    /// as they are and the version is not recorded, so this should be called again once the
+    /// keyring is set with `with_encryption`.
+    ///
+    /// Returns the number of records moved, rewritten or quarantined.
+    pub fn migrate(&self) -> Result<usize, String> {
+        if !self.needs_migration()? {
+            return Ok(0);
+        }
+
+        // Records are upgraded in place or moved, so a migration cut short is picked up again
+        let keyring = self.keyring.as_deref();
+        let now = epoch_millis(SystemTime::now());
+        let end = [u8::MAX];
+        let mut position = Vec::new();
+        let mut migrated = 0;
+        let mut needs_key = 0;
+        loop {
+            let page = self.backend.scan_range(&position, &end, MIGRATE_PAGE_SIZE)?;
+            let Some((last, _)) = page.last() else { break };
+            position = last.clone();
+            position.push(0);
+
+            let mut ops = Vec::new();
+            for (key, stored) in page {
+                if let Some((queue_id, kind, message_id)) = parse_message_key(&key) {
+                    match upgrade_record(keyring, kind, &key, message_id, &stored, now) {
+                        Upgrade::Current(entry) => {
+                            ops.extend(entry.map(|entry| BatchOp::Put(entry.encode(queue_id), Vec::new())));
+                            continue;
+                        }
+                        Upgrade::Rewrite(value, entry) => {
+                            ops.extend(entry.map(|entry| BatchOp::Put(entry.encode(queue_id), Vec::new())));
+                            ops.push(BatchOp::Put(key.clone(), encode_value(keyring, &key, &value)));
+                        }
+                        Upgrade::Checksum => ops.push(BatchOp::Put(key, append_checksum(stored))),
+                        Upgrade::NeedsKey => {
+                            needs_key += 1;
+                            continue;
+                        }
+                        Upgrade::Unreadable => {
+                            warn!("Quarantined unreadable {:?} record of message {} in queue {}", kind, message_id, queue_id);
+                            ops.push(BatchOp::Put(quarantine_key(&key), stored));
+                            ops.push(BatchOp::Delete(key));
+                        }
+                    }
+                    migrated += 1;
+                } else if let Some((kind, message_id, value, entry)) = upgrade_unscoped_record(&key, &stored, now) {
+                    // Id 0 is the default queue
+                    let moved = queue_key(0, kind, message_id);
+                    ops.extend(entry.map(|entry| BatchOp::Put(entry.encode(0), Vec::new())));
+                    ops.push(BatchOp::Put(moved.clone(), encode_value(keyring, &moved, &value)));
+                    ops.push(BatchOp::Delete(key));
+                    migrated += 1;
+                }
+            }
+            if !ops.is_empty() {
+                self.backend.write_batch(ops, true)?;
+            }
+        }
+
+        if migrated > 0 {
+            info!("Migrated {} records to storage format {}", migrated, FORMAT_VERSION);
+        }
+        if needs_key > 0 {
+            warn!("{} encrypted dead letters can only be migrated with their keyfile", needs_key);
+            return Ok(migrated);
+        }
+        self.backend.write_batch(vec![BatchOp::Put(FORMAT_VERSION_KEY.to_vec(), FORMAT_VERSION.to_be_bytes().to_vec())], true)?;
+        Ok(migrated)
+    }
+
+    /// Looks up a queue in the catalog, allocating a keyspace for it if it is new.
+    fn queue_info(&self, name: &str) -> Result<QueueInfo, String> {
+        if name == DEFAULT_QUEUE {
+            return Ok(QueueInfo { id: 0, name: DEFAULT_QUEUE.to_string() });
+        }
+
+        let _guard = self.catalog_lock.lock().unwrap();
+        if let Some(value) = self.backend.load(&catalog_key(name))? {
+            return bincode::deserialize(&value).map_err(|e| e.to_string());
+        }
+
+        let id = match self.backend.load(NEXT_QUEUE_ID_KEY)? {
+            Some(value) => u32::from_be_bytes(value.as_slice().try_into().map_err(|_| "corrupt queue id counter".to_string())?),
+            None => 1,
+        };
+        let info = QueueInfo { id, name: name.to_string() };
+        let ops = vec![
+            BatchOp::Put(catalog_key(name), bincode::serialize(&info).map_err(|e| e.to_string())?),
+            BatchOp::Put(NEXT_QUEUE_ID_KEY.to_vec(), (id + 1).to_be_bytes().to_vec()),
+        ];
+        self.backend.write_batch(ops, true)?;
+        info!("Created queue {} in storage with id {}.", name, id);
+        Ok(info)
+    }
+
+    /// Sets when commits are fsynced to disk.
+    ///
+    /// # Arguments
+    /// * `sync_policy` - The durability policy for `commit`, `save_message` and `delete_message`.
+    pub fn with_sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Encrypts message values with ChaCha20-Poly1305 from now on.
+    ///
+    /// Message contents, headers and dead-letter reasons are encrypted; keys, the due index and
+    /// the queue catalog are not. Values written before stay readable, plain or under older
+    /// keys of the keyring, until `reencrypt` rewrites them. Set this before scoping copies
+    /// with `queue`, which inherit it.
+    ///
+    /// # Arguments
+    /// * `keyring` - The keys to encrypt and decrypt with, e.g. from `Keyring::load`.
+    pub fn with_encryption(mut self, keyring: Keyring) -> Self {
+        info!("Storage encryption enabled with key {}", keyring.active_key_id());
+        self.keyring = Some(Arc::new(keyring));
+        self
+    }
+
+    /// Rewrites every message value of every queue that is plain or encrypted with a key other
+    /// than the active one, so that old keys can be retired after a rotation.
+    ///
+    /// The values are rewritten a page at a time while the storage stays in use; writers wait
+    /// only while a page is rewritten. A pass that finds nothing to do leaves the data as it is.
+    ///
+    /// # Arguments
+    /// * `pause` - How long to wait between pages, to limit the load on the storage.
+    pub async fn reencrypt(&self, pause: Duration) -> Result<ReencryptionReport, String> {
+        let keyring = self.keyring.clone().ok_or_else(|| "encryption is not enabled".to_string())?;
+        let end = queue_keys_end();
+        let mut progress = (QUEUE_PREFIX.to_vec(), ReencryptionReport::default());
+        loop {
+            let (keyring, end) = (keyring.clone(), end.clone());
+            let (more, next) = self
+                .blocking(move |storage| {
+                    let (mut position, mut report) = progress;
+                    let more = storage.reencrypt_page(&keyring, &mut position, &end, &mut report)?;
+                    Ok((more, (position, report)))
+                })
+                .await?;
+            progress = next;
+            if !more {
+                break;
+            }
+            if !pause.is_zero() {
+                sleep(pause).await;
+            }
+        }
+        let report = progress.1;
+        info!("Re-encryption to key {} done: {:?}", keyring.active_key_id(), report);
+        Ok(report)
+    }
+
+    /// Runs `reencrypt` in the background, logging its outcome.
+    ///
+    /// # Arguments
+    /// * `pause` - How long to wait between pages, to limit the load on the storage.
+    pub fn spawn_reencryption(&self, pause: Duration) -> tokio::task::JoinHandle<Result<ReencryptionReport, String>> {
+        let storage = self.clone();
+        tokio::spawn(async move {
+            let outcome = storage.reencrypt(pause).await;
+            if let Err(e) = &outcome {
+                warn!("Re-encryption failed: {}", e);
+            }
+            outcome
+        })
+    }
+
+    /// Re-encrypts the page of entries starting at `position` and moves past it. Blocks while
+    /// writes in progress finish, so it runs on a blocking thread.
+    ///
+    /// Returns `false` once there are no entries left.
+    fn reencrypt_page(&self, keyring: &Keyring, position: &mut Vec<u8>, end: &[u8], report: &mut ReencryptionReport) -> Result<bool, String> {
+        // Nothing may change the page between reading and rewriting it
+        let _guard = self.rewrite_lock.write().unwrap();
+        let page = self.backend.scan_range(position, end, REENCRYPT_PAGE_SIZE)?;
+        let Some((last, _)) = page.last() else { return Ok(false) };
+        *position = last.clone();
+        position.push(0);
+
+        let mut ops = Vec::new();
+        for (key, stored) in page {
+            if parse_message_key(&key).is_none() {
+                continue;
+            }
+            report.scanned += 1;
+            let Some(sealed) = verify_checksum(&stored) else {
+                // Left for the next read or `verify` to quarantine
+                warn!("Skipping corrupted record {:?} during re-encryption", key);
+                continue;
+            };
+            if encryption::key_id_of(sealed) == Some(keyring.active_key_id()) {
+                continue;
+            }
+            let value = encryption::open_value(Some(keyring), &key, sealed)?;
+            let stored = encode_value(Some(keyring), &key, &value);
+            ops.push(BatchOp::Put(key, stored));
+        }
+        if !ops.is_empty() {
+            report.rewritten += ops.len();
+            self.backend.write_batch(ops, false)?;
+        }
+        Ok(true)
+    }
+
+    /// Sets how long the queue of this storage keeps acked messages and dead letters.
+    ///
+    /// The policy is stored with the queue, so it still applies after a restart, until the
+    /// queue is dropped. Archiving starts with the next ack; trimming happens when
+    /// `apply_retention` runs, usually on a schedule set up with `Scheduler::start_retention`.
+    ///
+    /// # Arguments
+    /// * `policy` - The retention policy of the queue.
+    pub fn set_retention(&self, policy: RetentionPolicy) -> Result<(), String> {
+        let value = bincode::serialize(&(&self.queue, policy)).map_err(|e| e.to_string())?;
+        let mut policies = self.retention_policies()?.lock().unwrap();
+        self.backend.write_batch(vec![BatchOp::Put(retention_key(self.queue.id), value)], true)?;
+        policies.insert(self.queue.id, (self.queue.clone(), policy));
+        Ok(())
+    }
+
+    /// Returns the retention policy of the queue of this storage.
+    pub fn retention(&self) -> Result<RetentionPolicy, String> {
+        Ok(self.retention_policies()?.lock().unwrap().get(&self.queue.id).map(|(_, policy)| *policy).unwrap_or_default())
+    }
+
+    /// Returns the retention policy of every queue that has one, read from storage on first use.
+    fn retention_policies(&self) -> Result<&Mutex<RetentionPolicies>, String> {
+        if let Some(policies) = self.retention.get() {
+            return Ok(policies);
+        }
+        let mut policies = HashMap::new();
+        for (_, value) in self.backend.scan(RETENTION_PREFIX)? {
+            let (queue, policy): (QueueInfo, RetentionPolicy) = bincode::deserialize(&value).map_err(|e| e.to_string())?;
+            policies.insert(queue.id, (queue, policy));
+        }
+        Ok(self.retention.get_or_init(|| Mutex::new(policies)))
+    }
+
+    /// Removes the archived messages, dead letters and stream entries that every queue with a
+    /// retention policy should no longer keep.
+    ///
+    /// Entries older than the policy's age limit are removed, then the oldest entries until
+    /// the rest fits the size limit. Freed space is reclaimed by the engine's compaction.
+    /// This blocks on storage I/O, so async code runs it on a blocking thread, as
+    /// `Scheduler::start_retention` does.
+    pub fn apply_retention(&self) -> Result<RetentionReport, String> {
+        let policies: Vec<(QueueInfo, RetentionPolicy)> = self.retention_policies()?.lock().unwrap().values().cloned().collect();
+        let now = epoch_millis(SystemTime::now());
+        let cutoff = |age: Option<Duration>| age.map(|age| now.saturating_sub(age.as_millis() as u64));
+
+        let mut report = RetentionReport::default();
+        for (queue, policy) in policies {
+            let mut scoped = self.clone();
+            scoped.queue = queue;
+            // Without `keep_acked`, messages archived under an earlier policy are not kept either
+            let archive_cutoff = cutoff(policy.keep_acked).unwrap_or(now);
+            report.archived_removed += scoped.trim(ARCHIVE, archive_cutoff, policy.archive_max_bytes)?;
+            if policy.dead_letter_max_age.is_some() || policy.dead_letter_max_bytes.is_some() {
+                let dead_letter_cutoff = cutoff(policy.dead_letter_max_age).unwrap_or(0);
+                report.dead_letters_removed += scoped.trim(DEAD_LETTER, dead_letter_cutoff, policy.dead_letter_max_bytes)?;
+            }
+            if policy.stream_max_age.is_some() || policy.stream_max_bytes.is_some() {
+                let stream_cutoff = cutoff(policy.stream_max_age).unwrap_or(0);
+                report.stream_entries_removed += scoped.trim_stream(stream_cutoff, policy.stream_max_bytes)?;
+            }
+        }
+
+        if report != RetentionReport::default() {
+            info!("Retention removed {:?}", report);
+        }
+        Ok(report)
+    }
+
+    /// Removes the records of a kind older than `cutoff`, then the oldest ones until the rest
+    /// fits in `max_bytes`. Returns how many were removed.
+    ///
+    /// Records are walked oldest first and the walk stops at the first one that is kept, so
+    /// apart from summing sizes for `max_bytes`, only the records removed are read.
+    fn trim(&self, kind: u8, cutoff: u64, max_bytes: Option<u64>) -> Result<usize, String> {
+        // Archives are keyed by ack time and dead letters have a time index, both keyed
+        // `prefix | time | id`
+        let prefix = kind_prefix(self.queue.id, if kind == DEAD_LETTER { DEAD_LETTER_INDEX } else { kind });
+        let mut end = prefix.clone();
+        *end.last_mut().unwrap() += 1;
+        let mut remaining = match max_bytes {
+            Some(_) => self.kind_bytes(kind)?,
+            None => 0,
+        };
+        let over = |remaining: u64| max_bytes.is_some_and(|max_bytes| remaining > max_bytes);
+
+        let mut count = 0;
+        let mut position = prefix.clone();
+        loop {
+            let page = self.backend.scan_range(&position, &end, RETENTION_PAGE_SIZE)?;
+            let Some((last, _)) = page.last() else { break };
+            position = last.clone();
+            position.push(0);
+
+            let mut ops = Vec::new();
+            let mut done = false;
+            for (entry, value) in page {
+                let time = u64::from_be_bytes(entry[prefix.len()..prefix.len() + 8].try_into().unwrap());
+                if time >= cutoff && !over(remaining) {
+                    done = true;
+                    break;
+                }
+                if kind == ARCHIVE {
+                    remaining = remaining.saturating_sub(value.len() as u64);
+                    ops.push(BatchOp::Delete(entry));
+                    count += 1;
+                    continue;
+                }
+
+                let id = u64::from_be_bytes(entry[prefix.len() + 8..].try_into().unwrap());
+                let key = queue_key(self.queue.id, kind, id);
+                let Some(stored) = self.backend.load(&key)? else {
+                    ops.push(BatchOp::Delete(entry));
+                    continue;
+                };
+                if kind == DEAD_LETTER {
+                    match self.decode::<DeadLetter>(&key, &stored) {
+                        // The message was dead-lettered again since, so the entry is stale
+                        Ok(Some(dead_letter)) if dead_letter.dead_lettered_at != time => {
+                            ops.push(BatchOp::Delete(entry));
+                            continue;
+                        }
+                        Ok(Some(_)) => {}
+                        // Corrupted records are left for loads and `verify` to quarantine
+                        _ => continue,
+                    }
+                }
+                remaining = remaining.saturating_sub(stored.len() as u64);
+                ops.push(BatchOp::Delete(key));
+                ops.push(BatchOp::Delete(entry));
+                count += 1;
+            }
+            if !ops.is_empty() {
+                self.write_blocking(ops, false)?;
+            }
+            if done {
+                break;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Removes the stream entries older than `cutoff`, then the oldest ones until the rest fits
+    /// in `max_bytes`. Returns how many were removed.
+    ///
+    /// Append times never decrease along the stream, so the first time index entry at or after
+    /// `cutoff` gives the first offset to keep, and everything before it goes in one range
+    /// delete. Entries are only read to sum their sizes for `max_bytes`.
+    fn trim_stream(&self, cutoff: u64, max_bytes: Option<u64>) -> Result<usize, String> {
+        let entries_end = kind_prefix(self.queue.id, STREAM + 1);
+        let index_end = kind_prefix(self.queue.id, STREAM_TIME_INDEX + 1);
+        let offset_of = |key: &[u8]| u64::from_be_bytes(key[key.len() - 8..].try_into().unwrap());
+        let Some((first_key, _)) = self.backend.scan_range(&kind_prefix(self.queue.id, STREAM), &entries_end, 1)?.pop() else {
+            return Ok(0);
+        };
+        let first = offset_of(&first_key);
+
+        // The index entry of the first offset to keep, if any entry is kept at all
+        let mut kept = self.backend.scan_range(&stream_time_key(self.queue.id, cutoff, 0), &index_end, 1)?.pop().map(|(entry, _)| entry);
+        let mut keep_from = match &kept {
+            Some(entry) => offset_of(entry),
+            None => self.stream_head()?.0,
+        };
+
+        if let (Some(max_bytes), Some(entry)) = (max_bytes, kept.clone()) {
+            let mut remaining = self.kind_bytes(STREAM)?;
+            let mut position = first_key;
+            'entries: loop {
+                let page = self.backend.scan_range(&position, &entries_end, RETENTION_PAGE_SIZE)?;
+                let Some((last, _)) = page.last() else { break };
+                position = last.clone();
+                position.push(0);
+                for (key, stored) in page {
+                    let offset = offset_of(&key);
+                    if offset >= keep_from && remaining <= max_bytes {
+                        break 'entries;
+                    }
+                    remaining = remaining.saturating_sub(stored.len() as u64);
+                    keep_from = keep_from.max(offset + 1);
+                }
+            }
+            kept = self.stream_index_entry(entry, keep_from)?;
+        }
+
+        if keep_from <= first {
+            return Ok(0);
+        }
+        self.write_blocking(vec![
+            BatchOp::DeleteRange(queue_key(self.queue.id, STREAM, first), queue_key(self.queue.id, STREAM, keep_from)),
+            BatchOp::DeleteRange(kind_prefix(self.queue.id, STREAM_TIME_INDEX), kept.unwrap_or(index_end)),
+        ], false)?;
+        Ok((keep_from - first) as usize)
+    }
+
+    /// Returns the first time index entry from `position` on whose offset is at least `offset`.
+    fn stream_index_entry(&self, mut position: Vec<u8>, offset: u64) -> Result<Option<Vec<u8>>, String> {
+        let index_end = kind_prefix(self.queue.id, STREAM_TIME_INDEX + 1);
+        loop {
+            let page = self.backend.scan_range(&position, &index_end, RETENTION_PAGE_SIZE)?;
+            let Some((last, _)) = page.last() else { return Ok(None) };
+            position = last.clone();
+            position.push(0);
+            if let Some((entry, _)) = page.into_iter().find(|(entry, _)| u64::from_be_bytes(entry[entry.len() - 8..].try_into().unwrap()) >= offset) {
+                return Ok(Some(entry));
+            }
+        }
+    }
+
+    /// Returns the total size of the stored records of a kind, without decoding them.
+    fn kind_bytes(&self, kind: u8) -> Result<u64, String> {
+        let mut position = kind_prefix(self.queue.id, kind);
+        let end = kind_prefix(self.queue.id, kind + 1);
+        let mut total = 0;
+        loop {
+            let page = self.backend.scan_range(&position, &end, RETENTION_PAGE_SIZE)?;
+            let Some((last, _)) = page.last() else { break };
+            position = last.clone();
+            position.push(0);
+            total += page.iter().map(|(_, stored)| stored.len() as u64).sum::<u64>();
+        }
+        Ok(total)
+    }
+
+    /// Deletes the due index entry of every message the batch moves out of pending, ahead of
+    /// the batch's own writes so a message pushed again keeps its new entry.
+    async fn unindex_pending(&self, mut batch: StorageBatch) -> Result<StorageBatch, String> {
+        let moved: Vec<u64> = batch
+            .writes
+            .iter()
+            .filter_map(|write| match write {
+                BatchWrite::Push(due_key, _) => Some(due_key.id),
+                BatchWrite::Deliver(id, _) | BatchWrite::Ack(id) | BatchWrite::DeadLetter(id, _, _) => Some(*id),
+                _ => None,
+            })
+            .collect();
+
+        let mut unindexed = Vec::new();
+        for id in moved {
+            if let Some(record) = self.load_pending(id).await? {
+                unindexed.push(BatchWrite::Unindex(DueKey { available_at: record.available_at, priority: record.message.priority, id }));
+            }
+        }
+        unindexed.append(&mut batch.writes);
+        batch.writes = unindexed;
+        Ok(batch)
+    }
+
+    /// Adds an archive copy of every message the batch acks, if the queue keeps acked messages.
+    async fn archive_acked(&self, mut batch: StorageBatch) -> Result<StorageBatch, String> {
+        if self.retention()?.keep_acked.is_none() {
+            return Ok(batch);
+        }
+        let acked_at = epoch_millis(SystemTime::now());
+        let acked: Vec<u64> = batch
+            .writes
+            .iter()
+            .filter_map(|write| match write {
+                BatchWrite::Ack(id) => Some(*id),
+                _ => None,
+            })
+            .collect();
+
+        for id in acked {
+            let key = queue_key(self.queue.id, IN_FLIGHT, id);
+            let message = match self.backend.load(&key)? {
+                Some(stored) => self.decode::<Message>(&key, &stored)?,
+                None => self.load_pending(id).await?.map(|record| record.message),
+            };
+            if let Some(message) = message {
+                let value = bincode::serialize(&ArchivedMessage { acked_at, message }).map_err(|e| e.to_string())?;
+                batch.writes.push(BatchWrite::Archive(acked_at, id, value));
+            }
+        }
+        Ok(batch)
+    }
+
+    /// Loads the archived messages of the queue that were acked at or after `since`, oldest
+    /// first, e.g. to replay or audit them.
+    ///
+    /// # Arguments
+    /// * `since` - The earliest ack time to return.
+    pub async fn load_archived(&self, since: SystemTime) -> Result<Vec<ArchivedMessage>, String> {
+        let start = archive_key(self.queue.id, epoch_millis(since), 0);
+        let end = kind_prefix(self.queue.id, ARCHIVE + 1);
+        let mut archived = Vec::new();
+        let mut corrupted = Vec::new();
+        for (key, stored) in self.backend.scan_range(&start, &end, usize::MAX)? {
+            match self.decode(&key, &stored)? {
+                Some(message) => archived.push(message),
+                None => corrupted.push((key, stored)),
+            }
+        }
+        self.quarantine(corrupted).await?;
+        Ok(archived)
+    }
+
+    /// Returns a copy of this storage that appends to and reads the named stream, registering
+    /// the stream in the catalog if it is new.
+    ///
+    /// A stream is an append-only log: messages get increasing offsets and stay readable by any
+    /// number of consumer groups until retention removes them. It shares the catalog with
+    /// queues, so a name is either a queue or a stream; this fails if the name already holds
+    /// queued messages.
+    ///
+    /// # Arguments
+    /// * `name` - The name of the stream.
+    pub fn stream(&self, name: &str) -> Result<Storage, String> {
+        let scoped = self.queue(name)?;
+        let _guard = self.catalog_lock.lock().unwrap();
+        let next_key = kind_prefix(scoped.queue.id, STREAM_NEXT);
+        if scoped.backend.load(&next_key)?.is_none() {
+            let keyspace = keyspace_prefix(scoped.queue.id);
+            let mut keyspace_end = keyspace.clone();
+            *keyspace_end.last_mut().unwrap() += 1;
+            if !scoped.backend.scan_range(&keyspace, &keyspace_end, 1)?.is_empty() {
+                return Err(format!("{} is a queue, not a stream", name));
+            }
+            // The offset counter also marks the keyspace as a stream
+            scoped.backend.write_batch(vec![BatchOp::Put(next_key, [0u64.to_be_bytes(), 0u64.to_be_bytes()].concat())], true)?;
+        }
+        Ok(scoped)
+    }
+
+    /// Returns `true` if the keyspace of this storage was registered as a stream with `stream`.
+    pub fn is_stream(&self) -> Result<bool, String> {
+        Ok(self.backend.load(&kind_prefix(self.queue.id, STREAM_NEXT))?.is_some())
+    }
+
+    /// Returns the next offset of the stream and the append time of its last entry.
+    fn stream_head(&self) -> Result<(u64, u64), String> {
+        match self.backend.load(&kind_prefix(self.queue.id, STREAM_NEXT))? {
+            Some(value) if value.len() == 16 => Ok((
+                u64::from_be_bytes(value[..8].try_into().unwrap()),
+                u64::from_be_bytes(value[8..].try_into().unwrap()),
+            )),
+            Some(_) => Err(format!("corrupt offset counter of stream {}", self.queue.name)),
+            None => Err(format!("{} is not a stream", self.queue.name)),
+        }
+    }
+
+    /// Appends messages to the stream atomically and returns the offset of the first one; the
+    /// others follow it in order.
+    ///
+    /// # Arguments
+    /// * `messages` - The messages to append.
+    pub async fn append(&self, messages: &[Message]) -> Result<u64, String> {
+        let _guard = self.append_lock.lock().await;
+        let (first, last_appended_at) = self.stream_head()?;
+        // Append times never decrease, so the time index is in offset order even if the clock steps back
+        let appended_at = epoch_millis(SystemTime::now()).max(last_appended_at);
+
+        let mut ops = Vec::with_capacity(messages.len() * 2 + 1);
+        for (offset, message) in (first..).zip(messages) {
+            let key = queue_key(self.queue.id, STREAM, offset);
+            let record = StreamRecord { offset, appended_at, message: message.clone() };
+            let value = bincode::serialize(&record).map_err(|e| e.to_string())?;
+            ops.push(BatchOp::Put(key.clone(), encode_value(self.keyring.as_deref(), &key, &value)));
+            ops.push(BatchOp::Put(stream_time_key(self.queue.id, appended_at, offset), Vec::new()));
+        }
+        let next = first + messages.len() as u64;
+        ops.push(BatchOp::Put(kind_prefix(self.queue.id, STREAM_NEXT), [next.to_be_bytes(), appended_at.to_be_bytes()].concat()));
+        self.commit_ops(ops).await?;
+        Ok(first)
+    }
+
+    /// Reads up to `limit` entries of the stream, starting at `offset`.
+    ///
+    /// Entries removed by retention are skipped, so the first entry returned may have a higher
+    /// offset than asked for. An empty result means there is nothing at or after `offset` yet.
+    ///
+    /// # Arguments
+    /// * `offset` - The offset to start reading at.
+    /// * `limit` - The maximum number of entries to return.
+    pub async fn read_stream(&self, offset: u64, limit: usize) -> Result<Vec<StreamRecord>, String> {
+        let start = queue_key(self.queue.id, STREAM, offset);
+        let end = kind_prefix(self.queue.id, STREAM + 1);
+        let mut records = Vec::new();
+        let mut corrupted = Vec::new();
+        for (key, stored) in self.backend.scan_range(&start, &end, limit)? {
+            match self.decode(&key, &stored)? {
+                Some(record) => records.push(record),
+                None => corrupted.push((key, stored)),
+            }
+        }
+        self.quarantine(corrupted).await?;
+        Ok(records)
+    }
+
+    /// Returns the range of offsets the stream holds.
+    pub fn stream_offsets(&self) -> Result<StreamOffsets, String> {
+        let (next, _) = self.stream_head()?;
+        let start = kind_prefix(self.queue.id, STREAM);
+        let end = kind_prefix(self.queue.id, STREAM + 1);
+        let earliest = match self.backend.scan_range(&start, &end, 1)?.first() {
+            Some((key, _)) => u64::from_be_bytes(key[key.len() - 8..].try_into().unwrap()),
+            None => next,
+        };
+        Ok(StreamOffsets { earliest, next })
+    }
+
+    /// Returns the offset of the first entry appended at or after `time`, or the next offset
+    /// if there is none.
+    ///
+    /// # Arguments
+    /// * `time` - The time to seek to.
+    pub async fn stream_offset_at(&self, time: SystemTime) -> Result<u64, String> {
+        let start = stream_time_key(self.queue.id, epoch_millis(time), 0);
+        let end = kind_prefix(self.queue.id, STREAM_TIME_INDEX + 1);
+        match self.backend.scan_range(&start, &end, 1)?.first() {
+            Some((key, _)) => Ok(u64::from_be_bytes(key[key.len() - 8..].try_into().unwrap())),
+            None => Ok(self.stream_head()?.0),
+        }
+    }
+
+    /// Records the offset a consumer group will resume the stream from.
+    ///
+    /// # Arguments
+    /// * `group` - The name of the consumer group.
+    /// * `offset` - The offset of the next entry the group will read.
+    pub async fn commit_stream_offset(&self, group: &str, offset: u64) -> Result<(), String> {
+        let key = [kind_prefix(self.queue.id, STREAM_COMMITTED), group.as_bytes().to_vec()].concat();
+        self.commit_ops(vec![BatchOp::Put(key, offset.to_be_bytes().to_vec())]).await
+    }
+
+    /// Returns the offset a consumer group last committed, if any.
+    ///
+    /// # Arguments
+    /// * `group` - The name of the consumer group.
+    pub async fn committed_stream_offset(&self, group: &str) -> Result<Option<u64>, String> {
+        let key = [kind_prefix(self.queue.id, STREAM_COMMITTED), group.as_bytes().to_vec()].concat();
+        match self.backend.load(&key)? {
+            Some(value) => Ok(Some(u64::from_be_bytes(value.as_slice().try_into().map_err(|_| format!("corrupt committed offset of group {}", group))?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Reclaims the space left by deleted and overwritten entries; see `StorageBackend::compact`.
+    pub fn compact(&self) -> Result<(), String> {
+        self.backend.compact()
+    }
+
+    /// Returns the engine messages are stored in.
+    pub fn backend(&self) -> &Arc<dyn StorageBackend> {
+        &self.backend
+    }
+
+    /// Applies a batch of pushes, acks and dead-letters atomically.
+    ///
+    /// Whether the commit waits for an fsync depends on the storage's `SyncPolicy`.
+    ///
+    /// # Arguments
+    /// * `batch` - The writes to apply.
+    pub async fn commit(&self, batch: StorageBatch) -> Result<(), String> {
+        let batch = self.unindex_pending(batch).await?;
+        let batch = self.archive_acked(batch).await?;
+        self.commit_ops(batch.into_ops(self.queue.id, self.keyring.as_deref())).await
+    }
+
+    /// Applies a batch like `commit`, but waits for it to be fsynced to disk whatever the
+    /// storage's `SyncPolicy`.
+    ///
+    /// Concurrent synced commits share one fsync.
+    ///
+    /// # Arguments
+    /// * `batch` - The writes to apply.
+    pub async fn commit_synced(&self, batch: StorageBatch) -> Result<(), String> {
+        let batch = self.unindex_pending(batch).await?;
+        let batch = self.archive_acked(batch).await?;
+        self.write_synced(batch.into_ops(self.queue.id, self.keyring.as_deref())).await
+    }
+
+    /// Writes backend ops as the storage's `SyncPolicy` requires.
+    async fn commit_ops(&self, ops: Vec<BatchOp>) -> Result<(), String> {
+        match self.sync_policy {
+            SyncPolicy::EveryWrite => self.write_synced(ops).await,
+            SyncPolicy::Interval(interval) => {
+                self.start_flusher(interval);
+                self.write(ops, false).await
+            }
+            SyncPolicy::Never => self.write(ops, false).await,
         }
     }
 
@@ -38,31 +1667,79 @@ impl Storage {
     /// # Arguments
     /// * `message` - The message to persist.
     pub async fn save_message(&self, message: &Message) -> Result<(), String> {
-        let db = self.db.lock().await;
+        self.commit(StorageBatch::new().push(message)?).await?;
 
-        let key = message.id.to_be_bytes();
-        let value = bincode::serialize(&message).map_err(|e| e.to_string())?;
+        debug!("Message saved: {:?}", message);
+        Ok(())
+    }
 
-        db.put(key, value).map_err(|e| e.to_string())?;
+    /// Saves a batch of messages atomically and waits for the write to be fsynced to disk,
+    /// whatever the storage's `SyncPolicy`.
+    ///
+    /// The messages are written with a single backend write batch, and concurrent synced
+    /// writes share one fsync.
+    ///
+    /// # Arguments
+    /// * `messages` - The messages to persist.
+    pub async fn save_messages_synced(&self, messages: &[Message]) -> Result<(), String> {
+        let mut batch = StorageBatch::new();
+        for message in messages {
+            batch = batch.push(message)?;
+        }
+        self.write_synced(batch.into_ops(self.queue.id, self.keyring.as_deref())).await?;
 
-        println!("Message saved: {:?}", message);
+        debug!("{} messages saved and synced.", messages.len());
         Ok(())
     }
 
-    /// Loads all messages from the storage system and returns them as a vector.
+    /// Loads all pending messages of the queue and returns them as a vector.
+    ///
+    /// This reads the whole backlog into memory; use `due_messages` to stream it instead.
     pub async fn load_all_messages(&self) -> Result<Vec<Message>, String> {
-        let db = self.db.lock().await;
-        let mut messages = Vec::new();
+        let records: Vec<PendingRecord> = self.load_kind(PENDING).await?;
+        let messages: Vec<Message> = records.into_iter().map(|record| record.message).collect();
+        debug!("Loaded {} messages of queue {} from storage.", messages.len(), self.queue.name);
+        Ok(messages)
+    }
 
-        let iter = db.iterator(IteratorMode::Start);
-        for item in iter {
-            let (_, value) = item.map_err(|e| e.to_string())?;
-            let message: Message = bincode::deserialize(&value).map_err(|e| e.to_string())?;
-            messages.push(message);
+    /// Counts the queue's pending messages, ready or delayed, reading a page at a time.
+    pub async fn count_pending(&self) -> Result<usize, String> {
+        let mut position = kind_prefix(self.queue.id, PENDING);
+        let end = kind_prefix(self.queue.id, PENDING + 1);
+        let mut count = 0;
+        loop {
+            let page = self.backend.scan_range(&position, &end, COUNT_PAGE_SIZE)?;
+            let Some((last, _)) = page.last() else { break };
+            position = last.clone();
+            position.push(0);
+            count += page.len();
         }
+        Ok(count)
+    }
 
-        println!("Loaded {} messages from storage.", messages.len());
-        Ok(messages)
+    /// Loads the messages that were delivered but not yet acknowledged.
+    ///
+    /// After a restart these should be delivered again.
+    pub async fn load_in_flight(&self) -> Result<Vec<Message>, String> {
+        self.load_kind(IN_FLIGHT).await
+    }
+
+    /// Puts the messages left in flight, e.g. by a broker that stopped before they were
+    /// acknowledged, back in the queue so they are delivered again.
+    ///
+    /// Returns how many messages were put back.
+    pub async fn requeue_in_flight(&self) -> Result<usize, String> {
+        let messages = self.load_in_flight().await?;
+        if messages.is_empty() {
+            return Ok(0);
+        }
+        let mut batch = StorageBatch::new();
+        for message in &messages {
+            batch = batch.push(message)?;
+        }
+        self.commit(batch).await?;
+        info!("Requeued {} in-flight messages of queue {}.", messages.len(), self.queue.name);
+        Ok(messages.len())
     }
 
     /// Deletes a message from the storage system after it has been acknowledged.
@@ -70,11 +1747,8 @@ impl Storage {
     /// # Arguments
     /// * `message_id` - The unique identifier of the message to delete.
     pub async fn delete_message(&self, message_id: u64) -> Result<(), String> {
-        let db = self.db.lock().await;
-        let key = message_id.to_be_bytes();
-
-        db.delete(key).map_err(|e| e.to_string())?;
-        println!("Message with ID {} deleted from storage.", message_id);
+        self.commit(StorageBatch::new().ack(message_id)).await?;
+        debug!("Message with ID {} deleted from storage.", message_id);
         Ok(())
     }
 
@@ -83,14 +1757,258 @@ impl Storage {
     /// # Arguments
     /// * `message_id` - The unique identifier of the message to retrieve.
     pub async fn load_message(&self, message_id: u64) -> Result<Option<Message>, String> {
-        let db = self.db.lock().await;
-        let key = message_id.to_be_bytes();
+        Ok(self.load_pending(message_id).await?.map(|record| record.message))
+    }
 
-        if let Some(value) = db.get(key).map_err(|e| e.to_string())? {
-            let message: Message = bincode::deserialize(&value).map_err(|e| e.to_string())?;
-            Ok(Some(message))
-        } else {
-            Ok(None)
+    /// Returns a cursor over the queue's pending messages in `(available_at, priority, id)`
+    /// order, which reads only as much of the backlog as is asked for.
+    pub fn due_messages(&self) -> DueMessages {
+        let mut cursor = DueMessages { storage: self.clone(), position: Vec::new() };
+        cursor.rewind();
+        cursor
+    }
+
+    /// Loads a pending record; a corrupted one is quarantined and treated as missing.
+    async fn load_pending(&self, message_id: u64) -> Result<Option<PendingRecord>, String> {
+        let key = queue_key(self.queue.id, PENDING, message_id);
+        let Some(stored) = self.backend.load(&key)? else { return Ok(None) };
+        match self.decode(&key, &stored)? {
+            Some(record) => Ok(Some(record)),
+            None => {
+                self.quarantine(vec![(key, stored)]).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Loads every dead-lettered message, oldest first.
+    pub async fn load_dead_letters(&self) -> Result<Vec<DeadLetter>, String> {
+        self.load_kind(DEAD_LETTER).await
+    }
+
+    /// Loads every record of a kind, quarantining the corrupted ones.
+    async fn load_kind<T: serde::de::DeserializeOwned>(&self, kind: u8) -> Result<Vec<T>, String> {
+        let mut values = Vec::new();
+        let mut corrupted = Vec::new();
+        for (key, stored) in self.backend.scan(&kind_prefix(self.queue.id, kind))? {
+            match self.decode(&key, &stored)? {
+                Some(value) => values.push(value),
+                None => corrupted.push((key, stored)),
+            }
+        }
+        self.quarantine(corrupted).await?;
+        Ok(values)
+    }
+
+    /// Unwraps and deserializes a stored message value, or returns `None` if it is corrupted:
+    /// its checksum does not match, or it cannot be decrypted or deserialized.
+    ///
+    /// A value encrypted with a key that is not configured is an error rather than corruption.
+    fn decode<T: serde::de::DeserializeOwned>(&self, key: &[u8], stored: &[u8]) -> Result<Option<T>, String> {
+        let Some(sealed) = verify_checksum(stored) else { return Ok(None) };
+        if let Some(key_id) = encryption::key_id_of(sealed) {
+            if !self.keyring.as_ref().is_some_and(|keyring| keyring.has_key(key_id)) {
+                return Err(format!("value is encrypted with key {}, which is not configured", key_id));
+            }
+        }
+        let Ok(value) = encryption::open_value(self.keyring.as_deref(), key, sealed) else { return Ok(None) };
+        Ok(bincode::deserialize(&value).ok())
+    }
+
+    /// Loads the records of the queue that were quarantined because they were corrupted.
+    pub async fn load_quarantined(&self) -> Result<Vec<QuarantinedRecord>, String> {
+        let prefix = kind_prefix(self.queue.id, QUARANTINE);
+        self.backend
+            .scan(&prefix)?
+            .into_iter()
+            .map(|(key, value)| {
+                // The quarantine key holds the original key without the quarantine marker
+                let original = [&prefix[..prefix.len() - 1], &key[prefix.len()..]].concat();
+                let (_, kind, message_id) = parse_message_key(&original).ok_or_else(|| format!("corrupt quarantine key {:?}", key))?;
+                Ok(QuarantinedRecord { kind, message_id, value })
+            })
+            .collect()
+    }
+
+    /// Moves corrupted message records to the quarantine of their queue, unless they were
+    /// overwritten since they were read.
+    async fn quarantine(&self, records: Vec<(Vec<u8>, Vec<u8>)>) -> Result<usize, String> {
+        if records.is_empty() {
+            return Ok(0);
+        }
+        self.blocking(move |storage| storage.quarantine_blocking(records)).await
+    }
+
+    /// Does the work of `quarantine` on the current thread, which blocks while writes in
+    /// progress finish.
+    fn quarantine_blocking(&self, records: Vec<(Vec<u8>, Vec<u8>)>) -> Result<usize, String> {
+        let _guard = self.rewrite_lock.write().unwrap();
+        let mut ops = Vec::new();
+        for (key, stored) in records {
+            if self.backend.load(&key)?.as_deref() != Some(stored.as_slice()) {
+                continue;
+            }
+            if let Some((queue_id, kind, message_id)) = parse_message_key(&key) {
+                warn!("Quarantined corrupted {:?} record of message {} in queue {}", kind, message_id, queue_id);
+            }
+            ops.push(BatchOp::Put(quarantine_key(&key), stored));
+            ops.push(BatchOp::Delete(key));
         }
+        let quarantined = ops.len() / 2;
+        if !ops.is_empty() {
+            self.backend.write_batch(ops, true)?;
+        }
+        Ok(quarantined)
+    }
+
+    /// Checks that every message record of every queue can be read: that its checksum
+    /// matches and that it can be decrypted and decoded.
+    ///
+    /// Meant to be run offline, on a data directory no broker is using, after `migrate`, as
+    /// records in an older layout count as corrupted. Records encrypted with a key the keyring
+    /// does not hold are only checked against their checksum. With `repair`, the corrupted
+    /// records are quarantined, as a broker would on reading them; they can then be inspected
+    /// with `load_quarantined`.
+    ///
+    /// # Arguments
+    /// * `repair` - Whether to quarantine the corrupted records found.
+    pub fn verify(&self, repair: bool) -> Result<VerifyReport, String> {
+        let mut names: HashMap<u32, String> = self.queues()?.into_iter().map(|queue| (queue.id, queue.name)).collect();
+        names.insert(0, DEFAULT_QUEUE.to_string());
+
+        let mut report = VerifyReport::default();
+        let mut position = QUEUE_PREFIX.to_vec();
+        let end = queue_keys_end();
+        loop {
+            let page = self.backend.scan_range(&position, &end, VERIFY_PAGE_SIZE)?;
+            let Some((last, _)) = page.last() else { break };
+            position = last.clone();
+            position.push(0);
+
+            let mut corrupted = Vec::new();
+            for (key, stored) in page {
+                let Some((queue_id, kind, message_id)) = parse_message_key(&key) else { continue };
+                report.scanned += 1;
+                let readable = match self.is_readable(kind, &key, &stored) {
+                    Ok(readable) => readable,
+                    Err(_) => {
+                        // The checksum matched, but the value cannot be opened without its key
+                        report.unverified += 1;
+                        true
+                    }
+                };
+                if !readable {
+                    let queue = names.get(&queue_id).cloned().unwrap_or_else(|| format!("#{}", queue_id));
+                    report.corrupted.push(CorruptRecord { queue, kind, message_id });
+                    corrupted.push((key, stored));
+                }
+            }
+            if repair {
+                report.quarantined += self.quarantine_blocking(corrupted)?;
+            }
+        }
+
+        info!("Verified {} records: {} corrupted, {} quarantined, {} unverified", report.scanned, report.corrupted.len(), report.quarantined, report.unverified);
+        Ok(report)
+    }
+
+    /// Returns whether a message record of a kind decodes; fails like `decode` if it is
+    /// encrypted with a key that is not configured.
+    fn is_readable(&self, kind: RecordKind, key: &[u8], stored: &[u8]) -> Result<bool, String> {
+        Ok(match kind {
+            RecordKind::Pending => self.decode::<PendingRecord>(key, stored)?.is_some(),
+            RecordKind::InFlight => self.decode::<Message>(key, stored)?.is_some(),
+            RecordKind::DeadLetter => self.decode::<DeadLetter>(key, stored)?.is_some(),
+            RecordKind::Archived => self.decode::<ArchivedMessage>(key, stored)?.is_some(),
+            RecordKind::Streamed => self.decode::<StreamRecord>(key, stored)?.is_some(),
+        })
+    }
+
+    /// Writes ops to the backend from a blocking thread, waiting for a re-encryption page being
+    /// rewritten without holding up the runtime.
+    async fn write(&self, ops: Vec<BatchOp>, sync: bool) -> Result<(), String> {
+        self.blocking(move |storage| storage.write_blocking(ops, sync)).await
+    }
+
+    /// Writes ops to the backend on the current thread, blocking while a re-encryption page is
+    /// being rewritten.
+    fn write_blocking(&self, ops: Vec<BatchOp>, sync: bool) -> Result<(), String> {
+        let _guard = self.rewrite_lock.read().unwrap();
+        self.backend.write_batch(ops, sync)
+    }
+
+    /// Runs `work` with a copy of this storage on the blocking thread pool, for work that takes
+    /// the rewrite lock.
+    async fn blocking<T: Send + 'static>(&self, work: impl FnOnce(Storage) -> Result<T, String> + Send + 'static) -> Result<T, String> {
+        let storage = self.clone();
+        tokio::task::spawn_blocking(move || work(storage)).await.unwrap_or_else(|e| Err(e.to_string()))
+    }
+
+    /// Hands writes to the group-commit task and waits until they are fsynced.
+    async fn write_synced(&self, ops: Vec<BatchOp>) -> Result<(), String> {
+        let (sender, receiver) = oneshot::channel();
+        self.committer()
+            .send((ops, sender))
+            .map_err(|_| "group commit task stopped".to_string())?;
+        receiver.await.map_err(|_| "group commit task stopped".to_string())?
+    }
+
+    /// Returns the sender feeding the group-commit task, starting the task on first use.
+    ///
+    /// The task takes every write that queued up while the previous fsync was running and
+    /// applies them as one synced write batch, so concurrent writers share a single fsync.
+    fn committer(&self) -> &mpsc::UnboundedSender<CommitRequest> {
+        self.committer.get_or_init(|| {
+            let (sender, mut receiver) = mpsc::unbounded_channel::<CommitRequest>();
+            let (backend, rewrite_lock) = (self.backend.clone(), self.rewrite_lock.clone());
+            tokio::spawn(async move {
+                while let Some(first) = receiver.recv().await {
+                    let mut requests = vec![first];
+                    while let Ok(request) = receiver.try_recv() {
+                        requests.push(request);
+                    }
+
+                    let mut ops = Vec::new();
+                    let mut waiters = Vec::with_capacity(requests.len());
+                    for (request_ops, waiter) in requests {
+                        ops.extend(request_ops);
+                        waiters.push(waiter);
+                    }
+
+                    let (backend, rewrite_lock) = (backend.clone(), rewrite_lock.clone());
+                    let result = tokio::task::spawn_blocking(move || {
+                        let _guard = rewrite_lock.read().unwrap();
+                        backend.write_batch(ops, true)
+                    })
+                        .await
+                        .unwrap_or_else(|e| Err(e.to_string()));
+                    if waiters.len() > 1 {
+                        debug!("Group commit of {} writes", waiters.len());
+                    }
+                    for waiter in waiters {
+                        let _ = waiter.send(result.clone());
+                    }
+                }
+            });
+            sender
+        })
+    }
+
+    /// Starts the task that fsyncs outstanding writes every `interval`, if it is not running yet.
+    ///
+    /// The task stops once the backend has been dropped.
+    fn start_flusher(&self, interval: Duration) {
+        self.flusher.get_or_init(|| {
+            let backend: Weak<dyn StorageBackend> = Arc::downgrade(&self.backend);
+            tokio::spawn(async move {
+                loop {
+                    sleep(interval).await;
+                    let Some(backend) = backend.upgrade() else { break };
+                    if let Err(e) = backend.flush() {
+                        warn!("Periodic storage flush failed: {}", e);
+                    }
+                }
+            });
+        });
     }
 }
@@ -0,0 +1,147 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::warn;
+
+/// Custom epoch for message ids (2024-01-01T00:00:00Z), in milliseconds since the Unix epoch.
+pub const EPOCH_MILLIS: u64 = 1_704_067_200_000;
+
+/// Number of bits reserved for the node (worker) id.
+pub const NODE_ID_BITS: u32 = 10;
+
+/// Number of bits reserved for the per-millisecond sequence number.
+pub const SEQUENCE_BITS: u32 = 12;
+
+/// Largest node id that fits in an id.
+pub const MAX_NODE_ID: u16 = (1 << NODE_ID_BITS) - 1;
+
+/// Node id used by `next_id` in a process that never called `init`, i.e. one that is not
+/// part of a cluster.
+pub const STANDALONE_NODE_ID: u16 = 0;
+
+const SEQUENCE_MASK: u64 = (1 << SEQUENCE_BITS) - 1;
+
+/// The generator used by `next_id`, configured once per process by `init`.
+static GLOBAL: OnceLock<IdGenerator> = OnceLock::new();
+
+/// Snowflake-style generator of cluster-unique, roughly time-ordered 64-bit ids.
+///
+/// An id is laid out as `timestamp (42 bits) | node id (10 bits) | sequence (12 bits)`,
+/// where the timestamp counts milliseconds since `EPOCH_MILLIS`. Because the timestamp
+/// occupies the most significant bits, `id.to_be_bytes()` sorts in generation order,
+/// which lets ids be used directly as storage keys.
+///
+/// Ids are unique across nodes as long as every node uses a distinct node id.
+/// Within a node, ids are strictly increasing: if more than 4096 ids are requested in
+/// the same millisecond, or the wall clock steps backwards, the generator keeps counting
+/// from its last id instead of repeating one.
+#[derive(Debug)]
+pub struct IdGenerator {
+    node_id: u64,
+    last: AtomicU64,   // Last issued `timestamp << SEQUENCE_BITS | sequence`
+}
+
+impl IdGenerator {
+    /// Creates a generator for the given node id.
+    ///
+    /// # Arguments
+    ///
+    /// * `node_id` - The id of the node, at most `MAX_NODE_ID`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `node_id` is greater than `MAX_NODE_ID`, since it would not fit in the ids.
+    pub fn new(node_id: u16) -> IdGenerator {
+        check_node_id(node_id);
+        IdGenerator {
+            node_id: node_id as u64,
+            last: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the node id embedded in the ids of this generator.
+    pub fn node_id(&self) -> u16 {
+        self.node_id as u16
+    }
+
+    /// Generates the next id.
+    pub fn next_id(&self) -> u64 {
+        let now = current_millis() << SEQUENCE_BITS;
+        let mut last = self.last.load(Ordering::Relaxed);
+
+        loop {
+            // Move forward with the clock, or keep counting if it has not advanced
+            let next = now.max(last + 1);
+            match self.last.compare_exchange_weak(last, next, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return compose(next >> SEQUENCE_BITS, self.node_id, next & SEQUENCE_MASK),
+                Err(actual) => last = actual,
+            }
+        }
+    }
+}
+
+/// Configures the process-wide generator used by `next_id`.
+///
+/// Calling it again with the same node id does nothing.
+///
+/// # Arguments
+///
+/// * `node_id` - The id of the node this process runs as.
+///
+/// # Errors
+///
+/// Returns the node id the generator already uses if it differs from `node_id`, either
+/// because `init` was called with another one or because `next_id` already ran as
+/// `STANDALONE_NODE_ID`. Ids handed out so far carry that node id, so it cannot change.
+///
+/// # Panics
+///
+/// Panics if `node_id` is greater than `MAX_NODE_ID`.
+pub fn init(node_id: u16) -> Result<(), u16> {
+    check_node_id(node_id);
+    let generator = GLOBAL.get_or_init(|| IdGenerator::new(node_id));
+    if generator.node_id() == node_id {
+        Ok(())
+    } else {
+        Err(generator.node_id())
+    }
+}
+
+/// Generates an id from the process-wide generator.
+///
+/// If `init` has not been called, the process is taken to be standalone and ids carry
+/// `STANDALONE_NODE_ID`; a later `init` with another node id then fails. A node id is never
+/// made up, since two nodes could end up with the same one.
+pub fn next_id() -> u64 {
+    GLOBAL
+        .get_or_init(|| {
+            warn!("Message id generator used before init; generating ids as standalone node {}", STANDALONE_NODE_ID);
+            IdGenerator::new(STANDALONE_NODE_ID)
+        })
+        .next_id()
+}
+
+/// Returns the millisecond timestamp (since `EPOCH_MILLIS`) embedded in an id.
+pub fn timestamp_millis(id: u64) -> u64 {
+    id >> (NODE_ID_BITS + SEQUENCE_BITS)
+}
+
+/// Returns the node id embedded in an id.
+pub fn node_id(id: u64) -> u16 {
+    ((id >> SEQUENCE_BITS) & MAX_NODE_ID as u64) as u16
+}
+
+fn check_node_id(node_id: u16) {
+    assert!(node_id <= MAX_NODE_ID, "node id {} is greater than {}", node_id, MAX_NODE_ID);
+}
+
+fn compose(timestamp: u64, node_id: u64, sequence: u64) -> u64 {
+    (timestamp << (NODE_ID_BITS + SEQUENCE_BITS)) | (node_id << SEQUENCE_BITS) | sequence
+}
+
+fn current_millis() -> u64 {
+    let since_unix = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0));
+    (since_unix.as_millis() as u64).saturating_sub(EPOCH_MILLIS)
+}
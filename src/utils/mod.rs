@@ -0,0 +1,2 @@
+pub mod id_generator;
+pub mod rate_limiter;
@@ -0,0 +1,130 @@
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+/// A token-bucket rate: a sustained number of operations per second plus a burst allowance.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Tokens added to the bucket per second.
+    pub per_second: f64,
+    /// Maximum number of tokens the bucket holds, i.e. how many operations may happen back to back.
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// Creates a rate limit.
+    ///
+    /// # Arguments
+    ///
+    /// * `per_second` - The sustained rate.
+    /// * `burst` - The bucket size; at least one token.
+    pub fn new(per_second: f64, burst: u32) -> RateLimit {
+        RateLimit { per_second, burst: burst.max(1) }
+    }
+}
+
+/// A snapshot of a rate limiter's state.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitStats {
+    /// The configured rate.
+    pub limit: RateLimit,
+    /// Tokens currently in the bucket.
+    pub available_tokens: f64,
+    /// Whether the last acquisition attempt was refused.
+    pub throttled: bool,
+    /// Number of acquisition attempts refused so far.
+    pub throttled_count: u64,
+}
+
+/// A token bucket shared by all of its clones.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    limit: RateLimit,              // The configured rate
+    bucket: Arc<Mutex<Bucket>>,    // Shared bucket state
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    throttled: bool,
+    throttled_count: u64,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter with a full bucket.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The sustained rate and burst size.
+    pub fn new(limit: RateLimit) -> RateLimiter {
+        RateLimiter {
+            limit,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: limit.burst as f64,
+                refilled_at: Instant::now(),
+                throttled: false,
+                throttled_count: 0,
+            })),
+        }
+    }
+
+    /// Takes a token if one is available.
+    ///
+    /// Returns how long to wait until the next token is available if the bucket is empty.
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        self.refill(&mut bucket);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.throttled = false;
+            Ok(())
+        } else {
+            bucket.throttled = true;
+            bucket.throttled_count += 1;
+            Err(self.time_until_token(&bucket))
+        }
+    }
+
+    /// Returns how long until a token is available, without taking one.
+    pub fn wait_time(&self) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        self.refill(&mut bucket);
+        self.time_until_token(&bucket)
+    }
+
+    /// Puts back a token taken by `try_acquire` that ended up unused.
+    pub fn refund(&self) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.tokens = (bucket.tokens + 1.0).min(self.limit.burst as f64);
+    }
+
+    /// Returns the limiter's current state.
+    pub fn stats(&self) -> RateLimitStats {
+        let mut bucket = self.bucket.lock().unwrap();
+        self.refill(&mut bucket);
+        RateLimitStats {
+            limit: self.limit,
+            available_tokens: bucket.tokens,
+            throttled: bucket.throttled,
+            throttled_count: bucket.throttled_count,
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64);
+        bucket.refilled_at = now;
+    }
+
+    fn time_until_token(&self, bucket: &Bucket) -> Duration {
+        if bucket.tokens >= 1.0 {
+            Duration::ZERO
+        } else if self.limit.per_second > 0.0 {
+            Duration::from_secs_f64((1.0 - bucket.tokens) / self.limit.per_second)
+        } else {
+            Duration::MAX
+        }
+    }
+}
@@ -0,0 +1,116 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::SystemTime;
+use hexboltmq::storage::backend::{BatchOp, StorageBackend};
+use hexboltmq::storage::encryption::Keyring;
+use hexboltmq::storage::memory_backend::MemoryBackend;
+use hexboltmq::storage::storage::{CorruptRecord, RecordKind, Storage, StorageBatch};
+use common::stored_message;
+
+/// Flips one bit in the stored value of every message record whose key ends with `message_id`.
+fn corrupt(backend: &Arc<dyn StorageBackend>, message_id: u64) {
+    let ops: Vec<BatchOp> = backend
+        .scan(b"q/")
+        .unwrap()
+        .into_iter()
+        .filter(|(key, value)| key.len() == 15 && key.ends_with(&message_id.to_be_bytes()) && !value.is_empty())
+        .map(|(key, mut value)| {
+            value[3] ^= 0x10;
+            BatchOp::Put(key, value)
+        })
+        .collect();
+    assert!(!ops.is_empty());
+    backend.write_batch(ops, false).unwrap();
+}
+
+#[tokio::test]
+async fn test_corrupted_records_are_quarantined_on_load() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+    let storage = Storage::with_backend(backend.clone()).queue("orders").unwrap();
+    let batch = StorageBatch::new()
+        .push(&stored_message(1)).unwrap()
+        .push(&stored_message(2)).unwrap()
+        .push(&stored_message(3)).unwrap()
+        .dead_letter(&stored_message(4), "expired").unwrap();
+    storage.commit(batch).await.unwrap();
+    corrupt(&backend, 2);
+    corrupt(&backend, 4);
+
+    let ids: Vec<u64> = storage.load_all_messages().await.unwrap().iter().map(|message| message.id).collect();
+    assert_eq!(ids, vec![1, 3]);
+    assert!(storage.load_dead_letters().await.unwrap().is_empty());
+
+    let quarantined = storage.load_quarantined().await.unwrap();
+    let found: Vec<(RecordKind, u64)> = quarantined.iter().map(|record| (record.kind, record.message_id)).collect();
+    assert_eq!(found, vec![(RecordKind::DeadLetter, 4), (RecordKind::Pending, 2)]);
+
+    // The due index no longer leads to the quarantined message
+    let due: Vec<u64> = storage.due_messages().next_batch(SystemTime::now(), 10).await.unwrap().iter().map(|message| message.id).collect();
+    assert_eq!(due, vec![1, 3]);
+}
+
+#[tokio::test]
+async fn test_records_that_pass_the_checksum_but_do_not_decode_are_quarantined() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+    let storage = Storage::with_backend(backend.clone());
+    storage.save_message(&stored_message(1)).await.unwrap();
+
+    // A plain value holding something that is not a pending record, with a valid checksum
+    let key = [b"q/".as_slice(), &0u32.to_be_bytes(), b"m", &2u64.to_be_bytes()].concat();
+    let value = [0u8, 0xff, 0xff].to_vec();
+    let checksum = crc32c::crc32c(&value);
+    backend.save(&key, &[value, checksum.to_be_bytes().to_vec()].concat()).unwrap();
+
+    assert!(storage.load_message(2).await.unwrap().is_none());
+    assert_eq!(storage.load_all_messages().await.unwrap().len(), 1);
+    let quarantined = storage.load_quarantined().await.unwrap();
+    assert_eq!((quarantined[0].kind, quarantined[0].message_id), (RecordKind::Pending, 2));
+}
+
+#[tokio::test]
+async fn test_verify_finds_and_repairs_corruption() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+    let storage = Storage::with_backend(backend.clone());
+    storage.save_message(&stored_message(1)).await.unwrap();
+    let emails = storage.queue("emails").unwrap();
+    emails.commit(StorageBatch::new().push(&stored_message(2)).unwrap().deliver(&stored_message(2)).unwrap()).await.unwrap();
+    emails.save_message(&stored_message(3)).await.unwrap();
+    corrupt(&backend, 2);
+
+    let report = storage.verify(false).unwrap();
+    assert_eq!(report.scanned, 3);
+    assert_eq!(report.corrupted, vec![CorruptRecord { queue: "emails".to_string(), kind: RecordKind::InFlight, message_id: 2 }]);
+    assert_eq!(report.quarantined, 0);
+    assert_eq!(emails.load_quarantined().await.unwrap().len(), 0);
+
+    assert_eq!(storage.verify(true).unwrap().quarantined, 1);
+    assert_eq!(emails.load_quarantined().await.unwrap()[0].message_id, 2);
+    let report = storage.verify(false).unwrap();
+    assert_eq!((report.scanned, report.corrupted.len()), (2, 0));
+}
+
+#[tokio::test]
+async fn test_verify_finds_records_that_do_not_decrypt_or_decode() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+    let storage = Storage::with_backend(backend.clone()).with_encryption(Keyring::new(1, [1; 32]));
+    storage.save_message(&stored_message(1)).await.unwrap();
+
+    // A plain value that is not a pending record, with a valid checksum
+    let key = [b"q/".as_slice(), &0u32.to_be_bytes(), b"m", &2u64.to_be_bytes()].concat();
+    let value = [0u8, 0xff, 0xff].to_vec();
+    let checksum = crc32c::crc32c(&value);
+    backend.save(&key, &[value, checksum.to_be_bytes().to_vec()].concat()).unwrap();
+
+    let report = storage.verify(false).unwrap();
+    assert_eq!(report.corrupted, vec![CorruptRecord { queue: "default".to_string(), kind: RecordKind::Pending, message_id: 2 }]);
+    assert_eq!(report.unverified, 0);
+
+    // Without the key the encrypted record can only be checked against its checksum
+    let report = Storage::with_backend(backend.clone()).verify(false).unwrap();
+    assert_eq!((report.corrupted.len(), report.unverified), (1, 1));
+
+    // Under the wrong key material it does not decrypt
+    let report = Storage::with_backend(backend.clone()).with_encryption(Keyring::new(1, [2; 32])).verify(false).unwrap();
+    assert_eq!(report.corrupted.len(), 2);
+}
@@ -0,0 +1,257 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use hexboltmq::consumer::circuit_breaker::{CircuitBreaker, CircuitBreakerConfig, CircuitState};
+use hexboltmq::consumer::consumer::{BatchConsumerConfig, Consumer, WorkerPoolConfig};
+use hexboltmq::consumer::delivery::HandlerError;
+use hexboltmq::consumer::shutdown::Shutdown;
+use hexboltmq::producer::message_builder::MessageBuilder;
+use hexboltmq::producer::producer::Producer;
+use hexboltmq::queue::Queue;
+use hexboltmq::utils::rate_limiter::RateLimit;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout, Duration};
+
+/// Records an outcome for a delivery let through while the circuit is closed.
+fn record(breaker: &CircuitBreaker, outcome: Result<(), HandlerError>) {
+    let permit = breaker.try_acquire().unwrap();
+    breaker.record(permit, &outcome);
+}
+
+fn retryable() -> Result<(), HandlerError> {
+    Err(HandlerError::Retryable("downstream unavailable".to_string()))
+}
+
+#[tokio::test]
+async fn test_consecutive_failures_open_then_probe_closes() {
+    let breaker = CircuitBreaker::new("orders", CircuitBreakerConfig {
+        consecutive_failures: 3,
+        cool_down: Duration::from_millis(50),
+        ..CircuitBreakerConfig::default()
+    });
+
+    record(&breaker, retryable());
+    record(&breaker, retryable());
+    record(&breaker, Ok(()));
+    record(&breaker, retryable());
+    record(&breaker, Err(HandlerError::Permanent("bad payload".to_string())));
+    record(&breaker, retryable());
+    assert_eq!(breaker.state(), CircuitState::Closed);
+
+    record(&breaker, retryable());
+    assert_eq!(breaker.state(), CircuitState::Open);
+    assert!(breaker.try_acquire().is_none());
+    assert!(breaker.remaining_cool_down().is_some());
+
+    // After the cool-down only one probe is let through; its failure re-opens the circuit
+    sleep(Duration::from_millis(60)).await;
+    let probe = breaker.try_acquire().unwrap();
+    assert!(breaker.try_acquire().is_none());
+    breaker.record(probe, &retryable());
+    assert_eq!(breaker.state(), CircuitState::Open);
+
+    sleep(Duration::from_millis(60)).await;
+    assert_eq!(breaker.state(), CircuitState::HalfOpen);
+    let probe = breaker.try_acquire().unwrap();
+    breaker.record(probe, &Ok(()));
+    assert_eq!(breaker.state(), CircuitState::Closed);
+    assert!(breaker.try_acquire().is_some());
+}
+
+#[tokio::test]
+async fn test_only_the_probe_decides_a_half_open_circuit() {
+    let breaker = CircuitBreaker::new("orders", CircuitBreakerConfig {
+        consecutive_failures: 1,
+        cool_down: Duration::from_millis(50),
+        ..CircuitBreakerConfig::default()
+    });
+
+    // A slow delivery is still running when another one trips the circuit
+    let slow = breaker.try_acquire().unwrap();
+    record(&breaker, retryable());
+    sleep(Duration::from_millis(60)).await;
+
+    let probe = breaker.try_acquire().unwrap();
+    breaker.record(slow, &Ok(()));
+    assert_eq!(breaker.state(), CircuitState::HalfOpen);
+    breaker.record(probe, &retryable());
+    assert_eq!(breaker.state(), CircuitState::Open);
+}
+
+#[tokio::test]
+async fn test_aborted_probe_lets_another_probe_through() {
+    let breaker = CircuitBreaker::new("orders", CircuitBreakerConfig {
+        consecutive_failures: 1,
+        cool_down: Duration::from_millis(50),
+        ..CircuitBreakerConfig::default()
+    });
+    record(&breaker, retryable());
+    sleep(Duration::from_millis(60)).await;
+
+    // The probe's handler is aborted before its outcome is recorded
+    let probe = breaker.try_acquire().unwrap();
+    let handler_breaker = breaker.clone();
+    let handler = tokio::spawn(async move {
+        sleep(Duration::from_secs(60)).await;
+        handler_breaker.record(probe, &Ok(()));
+    });
+    assert!(breaker.try_acquire().is_none());
+    handler.abort();
+    assert!(handler.await.unwrap_err().is_cancelled());
+
+    assert_eq!(breaker.state(), CircuitState::HalfOpen);
+    let probe = breaker.try_acquire().unwrap();
+    breaker.record(probe, &Ok(()));
+    assert_eq!(breaker.state(), CircuitState::Closed);
+}
+
+#[tokio::test]
+async fn test_failure_rate_opens_circuit() {
+    let breaker = CircuitBreaker::new("orders", CircuitBreakerConfig {
+        consecutive_failures: 0,
+        failure_rate: Some(0.5),
+        window: 4,
+        ..CircuitBreakerConfig::default()
+    });
+
+    record(&breaker, retryable());
+    record(&breaker, Ok(()));
+    record(&breaker, retryable());
+    assert_eq!(breaker.state(), CircuitState::Closed);
+
+    record(&breaker, Ok(()));
+    assert_eq!(breaker.state(), CircuitState::Open);
+}
+
+#[tokio::test]
+async fn test_open_circuit_pauses_consumer() {
+    let queue = Arc::new(Mutex::new(Queue::new()));
+    let producer = Producer::new(queue.clone());
+    for i in 0..5 {
+        producer.send(MessageBuilder::new(format!("message {}", i)).max_retries(0)).await.unwrap();
+    }
+
+    let breaker = CircuitBreaker::new("orders", CircuitBreakerConfig {
+        consecutive_failures: 2,
+        cool_down: Duration::from_millis(300),
+        ..CircuitBreakerConfig::default()
+    });
+    let shutdown = Shutdown::new();
+    let consumer = Consumer::new(queue.clone())
+        .with_shutdown(shutdown.clone())
+        .with_circuit_breaker(breaker.clone());
+    let config = WorkerPoolConfig {
+        concurrency: 1,
+        prefetch: 1,
+        poll_interval: Duration::from_millis(10),
+        ..WorkerPoolConfig::default()
+    };
+
+    let healthy = Arc::new(AtomicBool::new(false));
+    let calls = Arc::new(AtomicUsize::new(0));
+    let (healthy_c, calls_c) = (healthy.clone(), calls.clone());
+    let handle = consumer.spawn(config, move |_delivery| {
+        let (healthy, calls) = (healthy_c.clone(), calls_c.clone());
+        async move {
+            calls.fetch_add(1, Ordering::SeqCst);
+            if healthy.load(Ordering::SeqCst) { Ok(()) } else { retryable() }
+        }
+    });
+
+    // Two failures trip the breaker; the remaining messages are left alone during the cool-down
+    sleep(Duration::from_millis(150)).await;
+    assert_eq!(breaker.state(), CircuitState::Open);
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    assert_eq!(queue.lock().await.size().await.unwrap(), 3);
+
+    // The dependency recovers: the probe succeeds and delivery resumes
+    healthy.store(true, Ordering::SeqCst);
+    timeout(Duration::from_secs(5), async {
+        while queue.lock().await.size().await.unwrap() > 0 {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    shutdown.trigger();
+    handle.await.unwrap();
+
+    assert_eq!(calls.load(Ordering::SeqCst), 5);
+    assert_eq!(breaker.state(), CircuitState::Closed);
+    assert_eq!(queue.lock().await.dead_letters().await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_open_circuit_pauses_batch_consumer() {
+    let queue = Arc::new(Mutex::new(Queue::new()));
+    let producer = Producer::new(queue.clone());
+    for i in 0..4 {
+        producer.send(MessageBuilder::new(format!("message {}", i)).max_retries(0)).await.unwrap();
+    }
+
+    let breaker = CircuitBreaker::new("orders", CircuitBreakerConfig {
+        consecutive_failures: 1,
+        cool_down: Duration::from_secs(10),
+        ..CircuitBreakerConfig::default()
+    });
+    let shutdown = Shutdown::new();
+    let consumer = Consumer::new(queue.clone())
+        .with_shutdown(shutdown.clone())
+        .with_circuit_breaker(breaker.clone());
+    let config = BatchConsumerConfig {
+        max_size: 2,
+        poll_interval: Duration::from_millis(10),
+        ..BatchConsumerConfig::default()
+    };
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let calls_c = calls.clone();
+    let handle = tokio::spawn(async move {
+        consumer
+            .consume_batches(config, move |_deliveries| {
+                let calls = calls_c.clone();
+                async move {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Err(HandlerError::Retryable("downstream unavailable".to_string()))
+                }
+            })
+            .await
+    });
+
+    // The failed batch trips the breaker and the next batch is left in the queue
+    sleep(Duration::from_millis(150)).await;
+    assert_eq!(breaker.state(), CircuitState::Open);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert_eq!(queue.lock().await.size().await.unwrap(), 2);
+
+    shutdown.trigger();
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_open_circuit_gives_back_rate_limit_tokens() {
+    let queue = Arc::new(Mutex::new(Queue::new()));
+    Producer::new(queue.clone()).send(MessageBuilder::new("waiting")).await.unwrap();
+
+    let breaker = CircuitBreaker::new("orders", CircuitBreakerConfig {
+        consecutive_failures: 1,
+        cool_down: Duration::from_secs(60),
+        ..CircuitBreakerConfig::default()
+    });
+    record(&breaker, retryable());
+    let shutdown = Shutdown::new();
+    let consumer = Consumer::new(queue.clone())
+        .with_shutdown(shutdown.clone())
+        .with_circuit_breaker(breaker.clone())
+        .with_rate_limit(RateLimit::new(0.1, 3));
+    let config = WorkerPoolConfig { poll_interval: Duration::from_millis(10), ..WorkerPoolConfig::default() };
+
+    // The consumer finds the circuit open and waits out the cool-down without delivering
+    let handle = consumer.spawn(config, |_delivery| async { Ok(()) });
+    sleep(Duration::from_millis(50)).await;
+    shutdown.trigger();
+    handle.await.unwrap();
+
+    let stats = consumer.rate_limit_stats().unwrap();
+    assert!(stats.available_tokens > 2.9);
+    assert_eq!(queue.lock().await.size().await.unwrap(), 1);
+}
@@ -0,0 +1,91 @@
+use std::sync::Arc;
+use hexboltmq::cluster::cluster::{Cluster, ClusterError, Node, MAX_RECORD_LEN};
+use hexboltmq::storage::backend::StorageBackend;
+use hexboltmq::storage::memory_backend::MemoryBackend;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout, Duration, Instant};
+use uuid::Uuid;
+
+/// Returns a local address nothing listens on yet.
+fn free_address() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Message id node id of every cluster created in this process; the generator is process-wide.
+const NODE_ID: u16 = 1;
+
+/// Starts a replica listening on a fresh address, persisting to `backend`.
+async fn start_replica(backend: Arc<dyn StorageBackend>) -> (Cluster, String) {
+    let address = free_address();
+    let replica = Cluster::new(address.clone(), NODE_ID).await.unwrap().with_replica_storage(backend).unwrap();
+    let listener = replica.clone();
+    tokio::spawn(async move { listener.start_listener().await.unwrap() });
+    sleep(Duration::from_millis(50)).await;
+    (replica, address)
+}
+
+fn peer(address: &str, id_generator_node_id: u16) -> Node {
+    Node { id: Uuid::new_v4(), id_generator_node_id, is_leader: false, address: address.to_string(), last_heartbeat: Instant::now() }
+}
+
+#[tokio::test]
+async fn test_replicated_records_are_persisted_before_the_ack() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+    let (_replica, address) = start_replica(backend.clone()).await;
+
+    let leader = Cluster::new(free_address(), NODE_ID).await.unwrap();
+    leader.add_node(peer(&address, 2)).await.unwrap();
+    for i in 0..3 {
+        assert_eq!(leader.replicate(format!("record {}", i).as_bytes()).await, 1);
+    }
+
+    // A replica reopened on the same storage still has every acknowledged record
+    let reopened = Cluster::new(free_address(), NODE_ID).await.unwrap().with_replica_storage(backend.clone()).unwrap();
+    let records = reopened.take_replicated().await.unwrap();
+    assert_eq!(records, vec![b"record 0".to_vec(), b"record 1".to_vec(), b"record 2".to_vec()]);
+    assert!(reopened.take_replicated().await.unwrap().is_empty());
+
+    // The sequence continues after the records taken
+    assert_eq!(leader.replicate(b"record 3").await, 1);
+    assert_eq!(reopened.take_replicated().await.unwrap(), vec![b"record 3".to_vec()]);
+}
+
+#[tokio::test]
+async fn test_listener_rejects_oversized_frames_and_requires_replica_storage() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+    let (replica, address) = start_replica(backend).await;
+
+    let mut stream = TcpStream::connect(&address).await.unwrap();
+    stream.write_u32(MAX_RECORD_LEN + 1).await.unwrap();
+    let mut buffer = [0u8; 1];
+    let read = timeout(Duration::from_secs(1), stream.read(&mut buffer)).await.unwrap();
+    assert!(matches!(read, Ok(0) | Err(_)), "oversized frame was not rejected");
+    assert!(replica.take_replicated().await.unwrap().is_empty());
+
+    let unpersisted = Cluster::new(free_address(), NODE_ID).await.unwrap();
+    assert!(unpersisted.start_listener().await.is_err());
+}
+
+#[tokio::test]
+async fn test_nodes_need_distinct_message_id_node_ids() {
+    assert_eq!(Cluster::new(free_address(), 1024).await.err(), Some(ClusterError::InvalidNodeId(1024)));
+
+    let cluster = Cluster::new(free_address(), NODE_ID).await.unwrap();
+    // The process already generates message ids as NODE_ID
+    assert_eq!(
+        Cluster::new(free_address(), 3).await.err(),
+        Some(ClusterError::NodeIdMismatch { configured: 3, existing: NODE_ID })
+    );
+
+    let node = peer("127.0.0.1:1", 8);
+    cluster.add_node(node.clone()).await.unwrap();
+    // Adding the same node again updates it
+    cluster.add_node(node.clone()).await.unwrap();
+
+    let result = cluster.add_node(peer("127.0.0.1:2", 8)).await;
+    assert_eq!(result, Err(ClusterError::DuplicateNodeId { node_id: 8, existing: node.id }));
+    assert!(matches!(cluster.add_node(peer("127.0.0.1:3", NODE_ID)).await, Err(ClusterError::DuplicateNodeId { node_id: NODE_ID, .. })));
+    assert_eq!(cluster.add_node(peer("127.0.0.1:4", 2048)).await, Err(ClusterError::InvalidNodeId(2048)));
+}
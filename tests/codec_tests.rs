@@ -0,0 +1,111 @@
+use std::sync::Arc;
+use hexboltmq::broker::broker::Broker;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hexboltmq::codec::codec::{self, BincodeCodec, Codec, CodecError, JsonCodec};
+use hexboltmq::consumer::typed_consumer::{TypedConsumer, DECODE_ERROR_REASON};
+use hexboltmq::producer::message_builder::MessageBuilder;
+use hexboltmq::producer::producer::{Producer, ProducerError};
+use hexboltmq::producer::typed_producer::TypedProducer;
+use hexboltmq::queue::{Queue, CONTENT_TYPE_HEADER, DEAD_LETTER_REASON_HEADER};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct OrderCreated {
+    order_id: u64,
+    customer: String,
+    items: Vec<String>,
+}
+
+fn order() -> OrderCreated {
+    OrderCreated {
+        order_id: 42,
+        customer: "ada".to_string(),
+        items: vec!["keyboard".to_string(), "mouse".to_string()],
+    }
+}
+
+#[tokio::test]
+async fn test_json_round_trip_sets_content_type() {
+    let queue = Arc::new(Mutex::new(Queue::new()));
+    let producer: TypedProducer<OrderCreated> = TypedProducer::new(Producer::new(queue.clone()));
+    let consumer: TypedConsumer<OrderCreated> = TypedConsumer::new(queue);
+
+    producer.send(&order()).await.unwrap();
+
+    let (value, message) = consumer.receive().await.unwrap().unwrap();
+    assert_eq!(value, order());
+    assert_eq!(message.headers.get(CONTENT_TYPE_HEADER).map(String::as_str), Some("application/json"));
+}
+
+#[tokio::test]
+async fn test_codec_is_chosen_per_queue() {
+    let broker = Broker::new();
+    let events = broker.declare_queue("events").await;
+    let orders = broker.declare_queue_with_codec("orders", &BincodeCodec).await;
+    let producer = TypedProducer::with_codec(Producer::with_broker(Arc::new(Mutex::new(events)), broker), BincodeCodec);
+    let consumer: TypedConsumer<OrderCreated, _> = TypedConsumer::with_codec(Arc::new(Mutex::new(orders)), BincodeCodec);
+
+    producer.send_with(&order(), |message| message.priority(3).queue("orders")).await.unwrap();
+
+    let (value, message) = consumer.receive().await.unwrap().unwrap();
+    assert_eq!(value, order());
+    assert_eq!(message.priority, 3);
+    assert_eq!(message.headers.get(CONTENT_TYPE_HEADER).map(String::as_str), Some("application/x-bincode"));
+    // The payload is plain bincode of the value
+    let payload = BASE64.decode(&message.content).unwrap();
+    assert_eq!(bincode::deserialize::<OrderCreated>(&payload).unwrap(), order());
+
+    // Queues using another codec are refused
+    let refused = producer.send(&order()).await.unwrap_err();
+    assert!(matches!(refused, ProducerError::Codec(CodecError::ContentTypeMismatch { ref expected, .. }) if expected == "application/json"));
+}
+
+#[tokio::test]
+async fn test_undecodable_message_is_dead_lettered() {
+    let queue = Queue::new();
+    let shared = Arc::new(Mutex::new(queue.clone()));
+    let consumer: TypedConsumer<OrderCreated> = TypedConsumer::new(shared.clone());
+    let producer = Producer::new(shared);
+
+    // Plain producer, so no content type: the payload is attempted and fails to decode
+    producer.send_message("not json".to_string(), 1, Duration::from_secs(0)).await.unwrap();
+    // Wrong content type: rejected without decoding
+    let content = codec::encode(&BincodeCodec, &order()).unwrap();
+    producer.send(MessageBuilder::new(content).header(CONTENT_TYPE_HEADER, "application/x-bincode")).await.unwrap();
+
+    assert!(consumer.receive().await.unwrap().is_none());
+
+    let dead_letters = queue.dead_letters().await.unwrap();
+    assert_eq!(dead_letters.len(), 2);
+    for message in dead_letters {
+        assert_eq!(message.headers.get(DEAD_LETTER_REASON_HEADER).map(String::as_str), Some(DECODE_ERROR_REASON));
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum Event {
+    Refunded { amount: f64, delta: i64 },
+    Cancelled(Option<String>),
+    Counted(u128),
+}
+
+fn check_round_trip<C: Codec>(codec: &C) {
+    let events = vec![Event::Refunded { amount: 12.5, delta: i64::MIN }, Event::Cancelled(None), Event::Counted(u128::MAX)];
+    let content = codec::encode(codec, &events).unwrap();
+    assert_eq!(codec::decode::<_, Vec<Event>>(codec, &content).unwrap(), events);
+    assert!(codec::decode::<_, Vec<Event>>(codec, "a$==").is_err());
+}
+
+#[test]
+fn test_codecs_round_trip() {
+    check_round_trip(&JsonCodec);
+    check_round_trip(&BincodeCodec);
+
+    // Bincode keeps values JSON cannot represent
+    let content = codec::encode(&BincodeCodec, &f64::NAN).unwrap();
+    assert!(codec::decode::<_, f64>(&BincodeCodec, &content).unwrap().is_nan());
+    assert_eq!(codec::encode(&JsonCodec, &order()).unwrap(), r#"{"order_id":42,"customer":"ada","items":["keyboard","mouse"]}"#);
+}
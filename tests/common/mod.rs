@@ -0,0 +1,37 @@
+// Fixtures shared by the integration tests; each test crate uses only some of them.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use hexboltmq::queue;
+use hexboltmq::storage::storage;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// Returns a fresh path under the temp directory, e.g. for a data directory or a snapshot.
+pub fn temp_path() -> String {
+    std::env::temp_dir()
+        .join(format!("hexboltmq-test-{}", Uuid::new_v4()))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Returns a queue message that is available right away and may be retried three times.
+pub fn message(id: u64, priority: u8, content: &str) -> queue::Message {
+    queue::Message {
+        id,
+        content: content.to_string(),
+        priority,
+        available_at: Instant::now(),
+        retry_count: 0,
+        max_retries: 3,
+        headers: HashMap::new(),
+        expires_at: None,
+        group_key: None,
+        dedup_id: None,
+    }
+}
+
+/// Returns a stored message with the content "message <id>" that may be retried three times.
+pub fn stored_message(id: u64) -> storage::Message {
+    storage::Message { id, content: format!("message {}", id), priority: 1, retry_count: 0, max_retries: 3, ..Default::default() }
+}
@@ -0,0 +1,171 @@
+use std::sync::Arc;
+use hexboltmq::broker::broker::Broker;
+use hexboltmq::consumer::consumer::{Consumer, WorkerPoolConfig};
+use hexboltmq::consumer::shutdown::Shutdown;
+use hexboltmq::producer::message_builder::MessageBuilder;
+use hexboltmq::producer::producer::Producer;
+use hexboltmq::queue::lazy::LazyQueueConfig;
+use hexboltmq::scheduler::scheduler::Scheduler;
+use hexboltmq::storage::memory_backend::MemoryBackend;
+use hexboltmq::storage::storage::Storage;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration};
+
+async fn fill_queue(broker: &Broker, queue: &str, count: usize) {
+    let queue = broker.declare_queue(queue).await;
+    let producer = Producer::new(Arc::new(Mutex::new(queue)));
+    for i in 0..count {
+        producer.send(MessageBuilder::new(format!("message {}", i))).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_members_are_listed_with_lag() {
+    let broker = Broker::new();
+    fill_queue(&broker, "orders", 3).await;
+
+    let first = broker.join_group("billing", "orders", Duration::from_secs(10)).await;
+    let second = broker.join_group("billing", "orders", Duration::from_secs(10)).await;
+    assert_ne!(first.member_id(), second.member_id());
+
+    let stats = broker.group_stats("billing").await.unwrap().unwrap();
+    assert_eq!(stats.lag, 3);
+    assert_eq!(stats.members.len(), 2);
+
+    first.leave().await.unwrap();
+    let stats = broker.group_stats("billing").await.unwrap().unwrap();
+    assert_eq!(stats.members.len(), 1);
+    assert_eq!(stats.members[0].member_id, second.member_id());
+
+    assert!(broker.group_stats("shipping").await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_dead_member_leases_are_reclaimed() {
+    let broker = Broker::new();
+    fill_queue(&broker, "orders", 2).await;
+    let queue = broker.declare_queue("orders").await;
+
+    let member = broker.join_group("billing", "orders", Duration::from_millis(50)).await;
+    for message in queue.pop_batch(2).await.unwrap() {
+        assert!(member.lease(&message).await);
+    }
+    assert_eq!(queue.size().await.unwrap(), 0);
+    assert_eq!(broker.group_stats("billing").await.unwrap().unwrap().members[0].in_flight, 2);
+
+    // The member stops heartbeating; its leases go back to the queue as soon as that is noticed
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(broker.reap_dead_members().await, 2);
+    assert_eq!(queue.size().await.unwrap(), 2);
+    assert!(!member.heartbeat().await);
+
+    let stats = broker.group_stats("billing").await.unwrap().unwrap();
+    assert!(stats.members.is_empty());
+    assert_eq!(stats.lag, 2);
+}
+
+#[tokio::test]
+async fn test_lazy_queue_lag_counts_the_backlog_in_storage() {
+    let broker = Broker::new();
+    let storage = Storage::with_backend(Arc::new(MemoryBackend::new()));
+    let config = LazyQueueConfig { max_messages: 10, max_bytes: usize::MAX };
+    let queue = broker.declare_lazy_queue("orders", &storage, config).await.unwrap();
+    let producer = Producer::new(Arc::new(Mutex::new(queue.clone())));
+    for i in 0..50 {
+        producer.send(MessageBuilder::new(format!("message {}", i))).await.unwrap();
+    }
+
+    broker.join_group("billing", "orders", Duration::from_secs(10)).await;
+    assert_eq!(broker.group_stats("billing").await.unwrap().unwrap().lag, 50);
+
+    // Only the window is in memory, but the lag covers everything not yet delivered
+    for message in queue.pop_batch(5).await.unwrap() {
+        queue.acknowledge(message.id).await.unwrap();
+    }
+    assert!(queue.size().await.unwrap() <= 10);
+    assert_eq!(broker.group_stats("billing").await.unwrap().unwrap().lag, 45);
+}
+
+#[tokio::test]
+async fn test_group_consumer_reports_throughput_and_leaves() {
+    let broker = Broker::new();
+    fill_queue(&broker, "orders", 4).await;
+
+    let shutdown = Shutdown::new();
+    let consumer = Consumer::join_group(broker.clone(), "billing", "orders", Duration::from_millis(300))
+        .await
+        .with_shutdown(shutdown.clone());
+    let member_id = consumer.membership().unwrap().member_id();
+
+    let config = WorkerPoolConfig {
+        concurrency: 2,
+        poll_interval: Duration::from_millis(10),
+        ..WorkerPoolConfig::default()
+    };
+    let pool = consumer.spawn(config, |_delivery| async { Ok(()) });
+
+    // Outlive the session timeout to make sure the consumer keeps heartbeating
+    sleep(Duration::from_millis(500)).await;
+    let stats = broker.group_stats("billing").await.unwrap().unwrap();
+    assert_eq!(stats.lag, 0);
+    assert_eq!(stats.members.len(), 1);
+    assert_eq!(stats.members[0].member_id, member_id);
+    assert_eq!(stats.members[0].processed, 4);
+    assert_eq!(stats.members[0].in_flight, 0);
+    assert!(stats.members[0].throughput > 0.0);
+
+    shutdown.trigger();
+    pool.await.unwrap();
+    assert!(broker.group_stats("billing").await.unwrap().unwrap().members.is_empty());
+}
+
+#[tokio::test]
+async fn test_reaper_reclaims_leases_of_idle_groups() {
+    let broker = Broker::new();
+    fill_queue(&broker, "orders", 2).await;
+    let queue = broker.declare_queue("orders").await;
+
+    let member = broker.join_group("billing", "orders", Duration::from_millis(50)).await;
+    for message in queue.pop_batch(2).await.unwrap() {
+        assert!(member.lease(&message).await);
+    }
+
+    // Nothing touches the group again; only the reaper notices the member is gone
+    let reaper = Scheduler::new(Duration::from_secs(1), Duration::from_secs(1)).start_group_reaper(broker.clone(), Duration::from_millis(20));
+    sleep(Duration::from_millis(150)).await;
+    assert_eq!(queue.size().await.unwrap(), 2);
+    reaper.abort();
+}
+
+#[tokio::test]
+async fn test_expired_member_rejoins_when_a_lease_is_refused() {
+    let broker = Broker::new();
+    fill_queue(&broker, "orders", 3).await;
+
+    let shutdown = Shutdown::new();
+    let consumer = Consumer::join_group(broker.clone(), "billing", "orders", Duration::from_millis(50))
+        .await
+        .with_shutdown(shutdown.clone());
+    let expired_id = consumer.membership().unwrap().member_id();
+
+    // The member misses its heartbeats before it starts consuming
+    sleep(Duration::from_millis(100)).await;
+    broker.reap_dead_members().await;
+
+    let config = WorkerPoolConfig {
+        poll_interval: Duration::from_millis(10),
+        ..WorkerPoolConfig::default()
+    };
+    let pool = consumer.spawn(config, |_delivery| async { Ok(()) });
+    sleep(Duration::from_millis(200)).await;
+
+    let stats = broker.group_stats("billing").await.unwrap().unwrap();
+    assert_eq!(stats.lag, 0);
+    assert_eq!(stats.members.len(), 1);
+    assert_ne!(stats.members[0].member_id, expired_id);
+    assert_eq!(stats.members[0].member_id, consumer.membership().unwrap().member_id());
+    assert_eq!(stats.members[0].processed, 3);
+
+    shutdown.trigger();
+    pool.await.unwrap();
+}
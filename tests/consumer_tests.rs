@@ -0,0 +1,350 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use hexboltmq::consumer::consumer::{BatchConsumerConfig, Consumer, DrainReport, WorkerPoolConfig};
+use hexboltmq::consumer::shutdown::Shutdown;
+use hexboltmq::consumer::delivery::{BatchOutcome, Delivery, HandlerError};
+use hexboltmq::producer::message_builder::MessageBuilder;
+use hexboltmq::producer::producer::Producer;
+use hexboltmq::queue::{Queue, DEAD_LETTER_REASON_HEADER};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout, Duration};
+
+async fn fill_queue(queue: &Arc<Mutex<Queue>>, count: usize) {
+    let producer = Producer::new(queue.clone());
+    for i in 0..count {
+        producer
+            .send_message(format!("message {}", i), 1, Duration::from_secs(0))
+            .await
+            .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_worker_pool_bounds_parallelism() {
+    let queue = Arc::new(Mutex::new(Queue::new()));
+    fill_queue(&queue, 8).await;
+
+    let running = Arc::new(AtomicUsize::new(0));
+    let max_running = Arc::new(AtomicUsize::new(0));
+    let processed = Arc::new(AtomicUsize::new(0));
+
+    let consumer = Consumer::new(queue.clone());
+    let config = WorkerPoolConfig {
+        concurrency: 3,
+        prefetch: 2,
+        poll_interval: Duration::from_millis(10),
+        ..WorkerPoolConfig::default()
+    };
+
+    let (running_c, max_running_c, processed_c) = (running.clone(), max_running.clone(), processed.clone());
+    let shared_queue = queue.clone();
+    let pool = tokio::spawn(async move {
+        consumer
+            .consume_concurrently(config, move |_message| {
+                let (running, max_running, processed) = (running_c.clone(), max_running_c.clone(), processed_c.clone());
+                let shared_queue = shared_queue.clone();
+                async move {
+                    let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now_running, Ordering::SeqCst);
+
+                    // Handlers can use the shared queue: the consumer must not be holding its lock
+                    shared_queue.lock().await.size().await.unwrap();
+                    sleep(Duration::from_millis(50)).await;
+
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    processed.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                }
+            })
+            .await
+    });
+
+    timeout(Duration::from_secs(5), async {
+        while processed.load(Ordering::SeqCst) < 8 {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    pool.abort();
+
+    assert_eq!(max_running.load(Ordering::SeqCst), 3);
+    assert_eq!(queue.lock().await.size().await.unwrap(), 0);
+}
+
+/// Starts a sequential consumer with an async, fallible handler in the background.
+fn spawn_consumer<F, Fut>(queue: &Arc<Mutex<Queue>>, handler: F) -> tokio::task::JoinHandle<DrainReport>
+where
+    F: Fn(Delivery) -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = Result<(), HandlerError>> + Send + 'static,
+{
+    let consumer = Consumer::new(queue.clone());
+    tokio::spawn(async move { consumer.consume_with(handler).await })
+}
+
+/// Waits until the queue holds the given number of dead letters.
+async fn wait_for_dead_letters(queue: &Arc<Mutex<Queue>>, count: usize) {
+    let inner = queue.lock().await.clone();
+    timeout(Duration::from_secs(5), async {
+        while inner.dead_letters().await.unwrap().len() < count {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_handler_receives_metadata_and_acks_on_success() {
+    let queue = Arc::new(Mutex::new(Queue::new()));
+    Producer::new(queue.clone())
+        .send(MessageBuilder::new("hello").header("tenant", "acme"))
+        .await
+        .unwrap();
+
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let task = spawn_consumer(&queue, move |delivery: Delivery| {
+        let sender = sender.clone();
+        async move {
+            let tenant = delivery.headers().get("tenant").cloned();
+            sender.send((delivery.content().to_string(), delivery.retry_count(), tenant)).unwrap();
+            Ok(())
+        }
+    });
+
+    let seen = timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+    task.abort();
+
+    assert_eq!(seen, ("hello".to_string(), 0, Some("acme".to_string())));
+    assert!(queue.lock().await.dead_letters().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_permanent_error_dead_letters_with_reason() {
+    let queue = Arc::new(Mutex::new(Queue::new()));
+    fill_queue(&queue, 1).await;
+
+    let task = spawn_consumer(&queue, |_delivery| async {
+        Err(HandlerError::Permanent("invalid order".to_string()))
+    });
+    wait_for_dead_letters(&queue, 1).await;
+    task.abort();
+
+    let dead_letters = queue.lock().await.dead_letters().await.unwrap();
+    assert_eq!(dead_letters[0].headers.get(DEAD_LETTER_REASON_HEADER).map(String::as_str), Some("invalid order"));
+}
+
+#[tokio::test]
+async fn test_retryable_error_and_panic_are_retried() {
+    let queue = Arc::new(Mutex::new(Queue::new()));
+    let producer = Producer::new(queue.clone());
+    producer.send(MessageBuilder::new("flaky").max_retries(3)).await.unwrap();
+    producer.send(MessageBuilder::new("panics").max_retries(0)).await.unwrap();
+
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let attempts_c = attempts.clone();
+    let task = spawn_consumer(&queue, move |delivery: Delivery| {
+        let attempts = attempts_c.clone();
+        async move {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            if delivery.content() == "panics" {
+                panic!("handler bug");
+            }
+            Err(HandlerError::Retryable("downstream unavailable".to_string()))
+        }
+    });
+    wait_for_dead_letters(&queue, 1).await;
+    sleep(Duration::from_millis(50)).await;
+    task.abort();
+
+    // The flaky message waits out its backoff in the queue; the panicking one ran out of retries
+    let inner = queue.lock().await.clone();
+    assert_eq!(inner.size().await.unwrap(), 1);
+    assert_eq!(inner.dead_letters().await.unwrap()[0].content, "panics");
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_shutdown_waits_for_in_flight_handlers() {
+    let queue = Arc::new(Mutex::new(Queue::new()));
+    fill_queue(&queue, 4).await;
+
+    let shutdown = Shutdown::new();
+    let consumer = Consumer::new(queue.clone()).with_shutdown(shutdown.clone());
+    let config = WorkerPoolConfig {
+        concurrency: 2,
+        prefetch: 2,
+        poll_interval: Duration::from_millis(10),
+        grace_period: Duration::from_secs(5),
+    };
+
+    let (started, mut started_rx) = tokio::sync::mpsc::unbounded_channel();
+    let handle = consumer.spawn(config, move |_delivery| {
+        let started = started.clone();
+        async move {
+            started.send(()).unwrap();
+            sleep(Duration::from_millis(100)).await;
+            Ok(())
+        }
+    });
+
+    // Shut down once both workers are busy and the buffer holds the remaining two
+    started_rx.recv().await.unwrap();
+    started_rx.recv().await.unwrap();
+    shutdown.trigger();
+
+    let report = timeout(Duration::from_secs(5), handle).await.unwrap().unwrap();
+    assert_eq!(report.completed_in_flight, 2);
+    assert_eq!(report.returned_in_flight, 0);
+    assert_eq!(report.returned_prefetched, 2);
+    assert_eq!(queue.lock().await.size().await.unwrap(), 2);
+}
+
+#[tokio::test]
+async fn test_shutdown_returns_messages_after_grace_period() {
+    let queue = Arc::new(Mutex::new(Queue::new()));
+    fill_queue(&queue, 1).await;
+
+    let shutdown = Shutdown::new();
+    let consumer = Consumer::new(queue.clone()).with_shutdown(shutdown.clone());
+    let config = WorkerPoolConfig {
+        concurrency: 1,
+        prefetch: 1,
+        poll_interval: Duration::from_millis(10),
+        grace_period: Duration::from_millis(50),
+    };
+
+    let (started, mut started_rx) = tokio::sync::mpsc::unbounded_channel();
+    let handle = consumer.spawn(config, move |_delivery| {
+        let started = started.clone();
+        async move {
+            started.send(()).unwrap();
+            sleep(Duration::from_secs(60)).await;
+            Ok(())
+        }
+    });
+
+    started_rx.recv().await.unwrap();
+    shutdown.trigger();
+
+    let report = timeout(Duration::from_secs(5), handle).await.unwrap().unwrap();
+    assert_eq!(report.returned_in_flight, 1);
+    assert_eq!(queue.lock().await.size().await.unwrap(), 1);
+
+    // The abandoned handler was stopped, dropping its sender, instead of running on
+    assert!(timeout(Duration::from_secs(1), started_rx.recv()).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_batches_respect_max_size_and_max_wait() {
+    let queue = Arc::new(Mutex::new(Queue::new()));
+    fill_queue(&queue, 5).await;
+
+    let shutdown = Shutdown::new();
+    let consumer = Consumer::new(queue.clone()).with_shutdown(shutdown.clone());
+    let config = BatchConsumerConfig {
+        max_size: 2,
+        max_wait: Duration::from_millis(100),
+        poll_interval: Duration::from_millis(10),
+        ..BatchConsumerConfig::default()
+    };
+
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let handle = tokio::spawn(async move {
+        consumer
+            .consume_batches(config, move |deliveries: Vec<Delivery>| {
+                let sender = sender.clone();
+                async move {
+                    sender.send(deliveries.len()).unwrap();
+                    Ok(BatchOutcome::new())
+                }
+            })
+            .await
+    });
+
+    // Two full batches, then the leftover message once max_wait has passed
+    let mut sizes = Vec::new();
+    for _ in 0..3 {
+        sizes.push(timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap());
+    }
+    shutdown.trigger();
+    timeout(Duration::from_secs(5), handle).await.unwrap().unwrap();
+
+    assert_eq!(sizes, vec![2, 2, 1]);
+    assert_eq!(queue.lock().await.size().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_batch_outcomes_are_settled_per_message() {
+    let queue = Arc::new(Mutex::new(Queue::new()));
+    let producer = Producer::new(queue.clone());
+    for content in ["ok", "retry", "reject"] {
+        producer.send(MessageBuilder::new(content).max_retries(3)).await.unwrap();
+    }
+
+    let shutdown = Shutdown::new();
+    let consumer = Consumer::new(queue.clone()).with_shutdown(shutdown.clone());
+    let config = BatchConsumerConfig {
+        max_size: 3,
+        poll_interval: Duration::from_millis(10),
+        ..BatchConsumerConfig::default()
+    };
+
+    let handle = tokio::spawn(async move {
+        consumer
+            .consume_batches(config, |deliveries: Vec<Delivery>| async move {
+                let mut outcome = BatchOutcome::new();
+                for delivery in deliveries {
+                    match delivery.content() {
+                        "retry" => outcome.fail(delivery.id(), HandlerError::Retryable("busy".to_string())),
+                        "reject" => outcome.fail(delivery.id(), HandlerError::Permanent("bad row".to_string())),
+                        _ => {}
+                    }
+                }
+                Ok(outcome)
+            })
+            .await
+    });
+    wait_for_dead_letters(&queue, 1).await;
+    shutdown.trigger();
+    timeout(Duration::from_secs(5), handle).await.unwrap().unwrap();
+
+    // Only the retried message is back in the queue, waiting out its backoff
+    let inner = queue.lock().await.clone();
+    assert_eq!(inner.size().await.unwrap(), 1);
+    let dead_letters = inner.dead_letters().await.unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].content, "reject");
+    assert_eq!(dead_letters[0].headers.get(DEAD_LETTER_REASON_HEADER).map(String::as_str), Some("bad row"));
+}
+
+#[tokio::test]
+async fn test_consume_runs_the_closure_without_the_queue_locked() {
+    let queue = Arc::new(Mutex::new(Queue::new()));
+    fill_queue(&queue, 2).await;
+
+    let shutdown = Shutdown::new();
+    let consumer = Consumer::new(queue.clone()).with_shutdown(shutdown.clone());
+    let unlocked = Arc::new(AtomicUsize::new(0));
+    let (closure_queue, closure_unlocked) = (queue.clone(), unlocked.clone());
+    let handle = tokio::spawn(async move {
+        consumer
+            .consume(move |_content| {
+                if closure_queue.try_lock().is_ok() {
+                    closure_unlocked.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+            .await
+    });
+
+    timeout(Duration::from_secs(1), async {
+        while queue.lock().await.size().await.unwrap() > 0 {
+            sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .unwrap();
+    shutdown.trigger();
+    handle.await.unwrap();
+    assert_eq!(unlocked.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use std::sync::Arc;
+use hexboltmq::storage::backend::StorageBackend;
+use hexboltmq::storage::encryption::Keyring;
+use hexboltmq::storage::memory_backend::MemoryBackend;
+use hexboltmq::storage::storage::{Message, ReencryptionReport, Storage, StorageBatch};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use tokio::time::Duration;
+
+fn stored_message(id: u64) -> Message {
+    Message { content: format!("secret {}", id), ..common::stored_message(id) }
+}
+
+#[test]
+fn test_keyring_parses_keyfile() {
+    let keyring = Keyring::parse(&format!(
+        "# rotated 2024-06-01\n1 {}\n\n3 {}\n2 {}\n",
+        BASE64.encode([1; 32]),
+        BASE64.encode([3; 32]),
+        BASE64.encode([2; 32]),
+    ))
+    .unwrap();
+    assert_eq!(keyring.active_key_id(), 3);
+    assert_eq!(keyring.key_ids(), vec![1, 2, 3]);
+    // Keys are kept out of logs
+    assert!(!format!("{:?}", keyring).contains(&BASE64.encode([3; 32])));
+
+    assert!(Keyring::parse("").is_err());
+    assert!(Keyring::parse(&format!("x {}", BASE64.encode([1; 32]))).is_err());
+    assert!(Keyring::parse(&format!("1 {}", BASE64.encode([1; 16]))).is_err());
+    assert!(Keyring::parse(&format!("1 {0}\n1 {0}", BASE64.encode([1; 32]))).is_err());
+}
+
+#[tokio::test]
+async fn test_encrypted_storage_hides_messages_at_rest() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+    let storage = Storage::with_backend(backend.clone()).with_encryption(Keyring::new(1, [7; 32]));
+    let queue = storage.queue("payments").unwrap();
+    let batch = StorageBatch::new()
+        .push(&stored_message(1)).unwrap()
+        .push(&stored_message(2)).unwrap()
+        .deliver(&stored_message(2)).unwrap()
+        .dead_letter(&stored_message(3), "secret reason").unwrap();
+    queue.commit(batch).await.unwrap();
+
+    for (_, value) in backend.scan(b"q/").unwrap() {
+        let text = String::from_utf8_lossy(&value);
+        assert!(!text.contains("secret"), "plaintext at rest: {:?}", text);
+    }
+    assert_eq!(queue.load_message(1).await.unwrap().unwrap().content, "secret 1");
+    assert_eq!(queue.load_in_flight().await.unwrap()[0].content, "secret 2");
+    assert_eq!(queue.load_dead_letters().await.unwrap()[0].reason, "secret reason");
+
+    // Without the key nothing can be read; a value that does not open with its key is quarantined
+    let unkeyed = Storage::with_backend(backend.clone()).queue("payments").unwrap();
+    assert!(unkeyed.load_message(1).await.is_err());
+    let wrong_key = Storage::with_backend(backend.clone()).with_encryption(Keyring::new(1, [8; 32])).queue("payments").unwrap();
+    assert!(wrong_key.load_message(1).await.unwrap().is_none());
+    assert_eq!(wrong_key.load_quarantined().await.unwrap()[0].message_id, 1);
+}
+
+#[tokio::test]
+async fn test_reencrypt_moves_every_value_to_the_active_key() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+
+    // Some messages were stored before encryption was enabled, others under the first key
+    let plain = Storage::with_backend(backend.clone()).queue("orders").unwrap();
+    plain.save_message(&stored_message(1)).await.unwrap();
+    let first_key = Storage::with_backend(backend.clone()).with_encryption(Keyring::new(1, [1; 32]));
+    first_key.queue("orders").unwrap().save_message(&stored_message(2)).await.unwrap();
+    first_key.save_message(&stored_message(3)).await.unwrap();
+
+    let rotated = Storage::with_backend(backend.clone()).with_encryption(Keyring::new(1, [1; 32]).with_key(2, [2; 32]));
+    assert!(plain.reencrypt(Duration::ZERO).await.is_err());
+    let report = rotated.spawn_reencryption(Duration::from_millis(1)).await.unwrap().unwrap();
+    assert_eq!(report, ReencryptionReport { scanned: 3, rewritten: 3 });
+    assert_eq!(rotated.reencrypt(Duration::ZERO).await.unwrap(), ReencryptionReport { scanned: 3, rewritten: 0 });
+
+    // The first key can now be retired
+    let second_key = Storage::with_backend(backend.clone()).with_encryption(Keyring::new(2, [2; 32]));
+    let orders = second_key.queue("orders").unwrap();
+    let mut contents: Vec<String> = orders.load_all_messages().await.unwrap().into_iter().map(|message| message.content).collect();
+    contents.sort();
+    assert_eq!(contents, vec!["secret 1", "secret 2"]);
+    assert_eq!(second_key.load_message(3).await.unwrap().unwrap().content, "secret 3");
+}
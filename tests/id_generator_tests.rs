@@ -0,0 +1,68 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use hexboltmq::utils::id_generator::{self, IdGenerator};
+
+#[test]
+fn test_ids_are_strictly_increasing() {
+    let generator = IdGenerator::new(7);
+
+    let mut previous = generator.next_id();
+    for _ in 0..10_000 {
+        let id = generator.next_id();
+        assert!(id > previous);
+        // Big-endian keys must sort like the ids themselves
+        assert!(id.to_be_bytes() > previous.to_be_bytes());
+        previous = id;
+    }
+}
+
+#[test]
+fn test_ids_embed_node_id() {
+    let generator = IdGenerator::new(42);
+    let id = generator.next_id();
+
+    assert_eq!(id_generator::node_id(id), 42);
+    assert!(id_generator::timestamp_millis(id) > 0);
+}
+
+#[test]
+fn test_largest_node_id_is_accepted() {
+    let generator = IdGenerator::new(id_generator::MAX_NODE_ID);
+    assert_eq!(id_generator::node_id(generator.next_id()), id_generator::MAX_NODE_ID);
+}
+
+#[test]
+#[should_panic(expected = "node id 1025")]
+fn test_node_ids_that_do_not_fit_are_rejected() {
+    // 1025 would otherwise be masked to node 1 and collide with it
+    IdGenerator::new(id_generator::MAX_NODE_ID + 2);
+}
+
+#[test]
+fn test_ids_are_unique_across_nodes_and_threads() {
+    let generators = [Arc::new(IdGenerator::new(1)), Arc::new(IdGenerator::new(2))];
+
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let generator = generators[i % 2].clone();
+            std::thread::spawn(move || (0..5_000).map(|_| generator.next_id()).collect::<Vec<_>>())
+        })
+        .collect();
+
+    let mut seen = HashSet::new();
+    for handle in handles {
+        for id in handle.join().unwrap() {
+            assert!(seen.insert(id), "duplicate id {}", id);
+        }
+    }
+}
+
+#[test]
+fn test_init_keeps_the_node_id_ids_were_generated_with() {
+    assert_eq!(id_generator::init(5), Ok(()));
+    assert_eq!(id_generator::init(5), Ok(()));
+    assert_eq!(id_generator::init(6), Err(5));
+    // Masked to 10 bits, 1029 would be mistaken for node 5
+    assert!(std::panic::catch_unwind(|| id_generator::init(5 + id_generator::MAX_NODE_ID + 1)).is_err());
+    assert_eq!(id_generator::node_id(id_generator::next_id()), 5);
+}
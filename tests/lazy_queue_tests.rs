@@ -0,0 +1,129 @@
+mod common;
+
+use std::sync::Arc;
+use hexboltmq::broker::broker::Broker;
+use hexboltmq::queue::lazy::LazyQueueConfig;
+use hexboltmq::queue::{Message, Queue, QueueError, DEAD_LETTER_REASON_HEADER, REPLY_TO_HEADER};
+use hexboltmq::storage::memory_backend::MemoryBackend;
+use hexboltmq::storage::storage::Storage;
+use common::message;
+use tokio::time::Duration;
+
+fn storage() -> Storage {
+    Storage::with_backend(Arc::new(MemoryBackend::new()))
+}
+
+#[tokio::test]
+async fn test_lazy_queue_keeps_a_bounded_window() -> Result<(), QueueError> {
+    let storage = storage().queue("backlog").unwrap();
+    let queue = Queue::lazy(storage.clone(), LazyQueueConfig { max_messages: 10, max_bytes: usize::MAX });
+
+    let messages = (1..=100).map(|id| (message(id, (id % 3) as u8, "payload"), Duration::ZERO)).collect();
+    queue.push_batch(messages).await?;
+    assert_eq!(queue.size().await?, 0);
+
+    // Higher priorities are paged in first, and the window never exceeds its limit
+    let first = queue.pop_batch(5).await?;
+    assert!(first.iter().all(|message| message.priority == 2));
+    assert!(queue.window_stats().await.unwrap().messages <= 10);
+    assert_eq!(storage.load_in_flight().await.unwrap().len(), 5);
+
+    let mut delivered = first.len();
+    for message in &first {
+        queue.acknowledge(message.id).await?;
+    }
+    while delivered < 100 {
+        let batch = queue.pop_batch(4).await?;
+        assert!(!batch.is_empty());
+        assert!(queue.window_stats().await.unwrap().messages <= 10);
+        for message in &batch {
+            queue.acknowledge(message.id).await?;
+        }
+        delivered += batch.len();
+    }
+
+    assert!(queue.pop().await?.is_none());
+    assert!(storage.load_all_messages().await.unwrap().is_empty());
+    assert!(storage.load_in_flight().await.unwrap().is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lazy_queue_enforces_memory_limit() -> Result<(), QueueError> {
+    let content = "x".repeat(1000);
+    let size_of_one = 1000 + std::mem::size_of::<Message>();
+    let queue = Queue::lazy(storage(), LazyQueueConfig { max_messages: 100, max_bytes: 3 * size_of_one });
+
+    let messages = (1..=10).map(|id| (message(id, 1, &content), Duration::ZERO)).collect();
+    queue.push_batch(messages).await?;
+
+    let batch = queue.pop_batch(10).await?;
+    assert_eq!(batch.len(), 3);
+    let stats = queue.window_stats().await.unwrap();
+    assert_eq!((stats.messages, stats.bytes), (0, 0));
+
+    // A message larger than the limit still gets through on its own
+    let queue = Queue::lazy(storage(), LazyQueueConfig { max_messages: 100, max_bytes: 10 });
+    queue.push(message(1, 1, &content), Duration::ZERO).await?;
+    assert_eq!(queue.pop().await?.unwrap().content, content);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lazy_queue_recovers_from_storage() -> Result<(), QueueError> {
+    let storage = storage();
+    let config = LazyQueueConfig::default();
+    {
+        let broker = Broker::new();
+        let queue = broker.declare_lazy_queue("orders", &storage, config).await?;
+        let mut failing = message(3, 9, "failing");
+        failing.max_retries = 0;
+        queue.push(failing, Duration::ZERO).await?;
+
+        let mut request = message(1, 1, "request");
+        request.headers.insert(REPLY_TO_HEADER.to_string(), "replies".to_string());
+        queue.push(request, Duration::ZERO).await?;
+        queue.push(message(2, 1, "delayed"), Duration::from_millis(200)).await?;
+        let popped = queue.pop().await?.unwrap();
+        assert_eq!(popped.id, 3);
+        queue.retry(popped).await?;
+    }
+
+    // A new broker finds the backlog and dead letters in storage
+    let broker = Broker::new();
+    let queue = broker.declare_lazy_queue("orders", &storage, config).await?;
+    let dead_letters = queue.dead_letters().await?;
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].headers[DEAD_LETTER_REASON_HEADER], "max retries exceeded");
+
+    let recovered = queue.pop().await?.unwrap();
+    assert_eq!(recovered.id, 1);
+    assert_eq!(recovered.headers[REPLY_TO_HEADER], "replies");
+    assert!(queue.pop().await?.is_none());
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(queue.pop().await?.unwrap().id, 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lazy_queue_redelivers_unacknowledged_messages_after_restart() -> Result<(), QueueError> {
+    let storage = storage().queue("orders").unwrap();
+    let config = LazyQueueConfig::default();
+    {
+        let queue = Queue::lazy(storage.clone(), config);
+        queue.push(message(1, 1, "acked"), Duration::ZERO).await?;
+        queue.push(message(2, 1, "unacked"), Duration::ZERO).await?;
+        assert_eq!(queue.pop_batch(2).await?.len(), 2);
+        queue.acknowledge(1).await?;
+        assert_eq!(storage.load_in_flight().await.unwrap().len(), 1);
+    }
+
+    // The broker stopped before message 2 was acknowledged, so it is delivered again
+    let queue = Queue::lazy(storage.clone(), config);
+    let redelivered = queue.pop().await?.unwrap();
+    assert_eq!((redelivered.id, redelivered.content.as_str()), (2, "unacked"));
+    assert!(queue.pop().await?.is_none());
+    assert_eq!(storage.load_in_flight().await.unwrap().len(), 1);
+    Ok(())
+}
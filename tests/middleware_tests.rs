@@ -0,0 +1,174 @@
+use std::sync::{Arc, Mutex as StdMutex};
+use hexboltmq::consumer::consumer::{Consumer, WorkerPoolConfig};
+use hexboltmq::consumer::delivery::{BatchOutcome, Delivery, HandlerError};
+use hexboltmq::consumer::middleware::{from_fn, CatchPanic, Deadline, Logging, MiddlewareStack, Timing};
+use hexboltmq::consumer::shutdown::Shutdown;
+use hexboltmq::producer::message_builder::MessageBuilder;
+use hexboltmq::producer::producer::Producer;
+use hexboltmq::queue::Queue;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout, Duration};
+
+fn delivery(content: &str) -> Delivery {
+    Delivery::new(MessageBuilder::new(content).build().message)
+}
+
+/// Records the name of a layer when a delivery passes through it.
+fn tracing_layer(name: &'static str, trace: Arc<StdMutex<Vec<String>>>) -> impl hexboltmq::consumer::middleware::Middleware {
+    from_fn(move |delivery, next| {
+        let trace = trace.clone();
+        async move {
+            trace.lock().unwrap().push(format!("{} in", name));
+            let outcome = next(delivery).await;
+            trace.lock().unwrap().push(format!("{} out", name));
+            outcome
+        }
+    })
+}
+
+#[tokio::test]
+async fn test_layers_run_outermost_first() {
+    let trace = Arc::new(StdMutex::new(Vec::new()));
+    let stack = MiddlewareStack::new()
+        .layer(tracing_layer("outer", trace.clone()))
+        .layer(tracing_layer("inner", trace.clone()));
+
+    let handler_trace = trace.clone();
+    let handler = stack.apply(move |_delivery| {
+        let trace = handler_trace.clone();
+        async move {
+            trace.lock().unwrap().push("handler".to_string());
+            Ok(())
+        }
+    });
+    handler(delivery("hello")).await.unwrap();
+
+    assert_eq!(*trace.lock().unwrap(), vec!["outer in", "inner in", "handler", "inner out", "outer out"]);
+}
+
+#[tokio::test]
+async fn test_builtins_capture_panics_deadlines_and_timings() {
+    let timing = Timing::new();
+    let stack = MiddlewareStack::new()
+        .layer(Logging)
+        .layer(timing.clone())
+        .layer(CatchPanic)
+        .layer(Deadline::new(Duration::from_millis(50)));
+
+    let handler = stack.apply(|delivery: Delivery| async move {
+        match delivery.content() {
+            "slow" => sleep(Duration::from_secs(60)).await,
+            "panics" => panic!("handler bug"),
+            _ => {}
+        }
+        Ok(())
+    });
+
+    assert_eq!(handler(delivery("fast")).await, Ok(()));
+    assert!(matches!(handler(delivery("slow")).await, Err(HandlerError::Retryable(reason)) if reason.contains("deadline")));
+    assert!(matches!(handler(delivery("panics")).await, Err(HandlerError::Retryable(reason)) if reason.contains("panicked")));
+
+    let stats = timing.stats();
+    assert_eq!(stats.count, 3);
+    assert!(stats.max >= Duration::from_millis(50));
+}
+
+#[tokio::test]
+async fn test_abandoned_handlers_stop_behind_catch_panic() {
+    let finished = Arc::new(StdMutex::new(false));
+    let handler_finished = finished.clone();
+    let handler = MiddlewareStack::new().layer(CatchPanic).apply(move |_delivery| {
+        let finished = handler_finished.clone();
+        async move {
+            sleep(Duration::from_millis(100)).await;
+            *finished.lock().unwrap() = true;
+            Ok(())
+        }
+    });
+
+    // Giving up on the handler, as a consumer does after its grace period, stops it
+    assert!(timeout(Duration::from_millis(20), handler(delivery("slow"))).await.is_err());
+    sleep(Duration::from_millis(200)).await;
+    assert!(!*finished.lock().unwrap());
+}
+
+#[tokio::test]
+async fn test_consumer_applies_middleware() {
+    let queue = Arc::new(Mutex::new(Queue::new()));
+    let producer = Producer::new(queue.clone());
+    producer.send(MessageBuilder::new("first")).await.unwrap();
+    producer.send(MessageBuilder::new("second").header("skip", "true")).await.unwrap();
+
+    // A simple idempotency check: skip messages flagged as already processed
+    let stack = MiddlewareStack::new().layer(from_fn(|delivery: Delivery, next| async move {
+        if delivery.headers().contains_key("skip") {
+            return Ok(());
+        }
+        next(delivery).await
+    }));
+
+    let shutdown = Shutdown::new();
+    let consumer = Consumer::new(queue.clone()).with_shutdown(shutdown.clone()).with_middleware(stack);
+    let config = WorkerPoolConfig {
+        poll_interval: Duration::from_millis(10),
+        ..WorkerPoolConfig::default()
+    };
+
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let handle = consumer.spawn(config, move |delivery| {
+        let sender = sender.clone();
+        async move {
+            sender.send(delivery.content().to_string()).unwrap();
+            Ok(())
+        }
+    });
+
+    assert_eq!(timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap(), "first");
+    sleep(Duration::from_millis(50)).await;
+    shutdown.trigger();
+    handle.await.unwrap();
+
+    assert!(receiver.try_recv().is_err());
+    assert_eq!(queue.lock().await.size().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_batches_run_each_message_through_the_middleware() {
+    let timing = Timing::new();
+    let stack = MiddlewareStack::new()
+        .layer(timing.clone())
+        // Messages marked as tests are acknowledged without reaching the handler
+        .layer(from_fn(|delivery: Delivery, next| async move {
+            if delivery.content().starts_with("test") { Ok(()) } else { next(delivery).await }
+        }));
+
+    let deliveries = vec![delivery("order 1"), delivery("test order"), delivery("order 2"), delivery("bad order")];
+    let bad = deliveries[3].id();
+    let seen = Arc::new(StdMutex::new(Vec::new()));
+    let handler_seen = seen.clone();
+    let outcome = stack
+        .run_batch(
+            &move |deliveries: Vec<Delivery>| {
+                let seen = handler_seen.clone();
+                async move {
+                    let mut outcome = BatchOutcome::new();
+                    for delivery in deliveries {
+                        if delivery.content() == "bad order" {
+                            outcome.fail(delivery.id(), HandlerError::Permanent("unknown customer".to_string()));
+                        }
+                        seen.lock().unwrap().push(delivery.content().to_string());
+                    }
+                    Ok(outcome)
+                }
+            },
+            deliveries,
+        )
+        .await
+        .unwrap();
+
+    let mut seen = seen.lock().unwrap().clone();
+    seen.sort();
+    assert_eq!(seen, vec!["bad order", "order 1", "order 2"]);
+    assert!(matches!(outcome.outcome_for(bad), Err(HandlerError::Permanent(_))));
+    assert_eq!(timing.stats().count, 4);
+}
@@ -0,0 +1,129 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::SystemTime;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use serde::Serialize;
+use hexboltmq::storage::backend::{BatchOp, StorageBackend};
+use hexboltmq::storage::encryption::Keyring;
+use hexboltmq::storage::memory_backend::MemoryBackend;
+use hexboltmq::storage::storage::{Message, RecordKind, RetentionPolicy, Storage, FORMAT_VERSION};
+use common::stored_message;
+use tokio::time::Duration;
+
+/// A message as it was stored before headers, group keys and expiry times were added.
+#[derive(Serialize)]
+struct MessageV0 {
+    id: u64,
+    content: String,
+    priority: u8,
+    retry_count: u8,
+    max_retries: u8,
+}
+
+#[derive(Serialize)]
+struct DeadLetterV0 {
+    message: MessageV0,
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct DeadLetterV1 {
+    message: Message,
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct PendingRecord {
+    available_at: u64,
+    message: Message,
+}
+
+fn message_v0(id: u64) -> MessageV0 {
+    MessageV0 { id, content: format!("message {}", id), priority: 1, retry_count: 0, max_retries: 3 }
+}
+
+fn queue_key(kind: u8, id: u64) -> Vec<u8> {
+    [b"q/".as_slice(), &0u32.to_be_bytes(), &[kind], &id.to_be_bytes()].concat()
+}
+
+fn with_checksum(value: Vec<u8>) -> Vec<u8> {
+    let checksum = crc32c::crc32c(&value);
+    [value, checksum.to_be_bytes().to_vec()].concat()
+}
+
+#[tokio::test]
+async fn test_migrate_upgrades_every_earlier_layout() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+    let plain = |value: Vec<u8>| [vec![0], value].concat();
+    backend.write_batch(vec![
+        // Before keyspaces: bare ids, then `m/` and `d/`
+        BatchOp::Put(1u64.to_be_bytes().to_vec(), bincode::serialize(&message_v0(1)).unwrap()),
+        BatchOp::Put([b"m/".as_slice(), &2u64.to_be_bytes()].concat(), bincode::serialize(&message_v0(2)).unwrap()),
+        BatchOp::Put([b"d/".as_slice(), &3u64.to_be_bytes()].concat(), bincode::serialize(&DeadLetterV0 { message: message_v0(3), reason: "expired".to_string() }).unwrap()),
+        // Keyspaces with bare records, then framed ones, then checksummed ones
+        BatchOp::Put(queue_key(b'f', 4), bincode::serialize(&message_v0(4)).unwrap()),
+        BatchOp::Put(queue_key(b'm', 5), plain(bincode::serialize(&PendingRecord { available_at: 0, message: stored_message(5) }).unwrap())),
+        BatchOp::Put(queue_key(b'd', 6), with_checksum(plain(bincode::serialize(&DeadLetterV1 { message: stored_message(6), reason: "rejected".to_string() }).unwrap()))),
+        BatchOp::Put(queue_key(b'm', 7), b"not a message".to_vec()),
+    ], false).unwrap();
+
+    let storage = Storage::with_backend(backend.clone());
+    assert_eq!(storage.migrate().unwrap(), 7);
+    assert_eq!(storage.migrate().unwrap(), 0);
+    assert_eq!(backend.load(b"c/format_version").unwrap(), Some(FORMAT_VERSION.to_be_bytes().to_vec()));
+
+    let mut pending: Vec<u64> = storage.load_all_messages().await.unwrap().into_iter().map(|message| message.id).collect();
+    pending.sort();
+    assert_eq!(pending, vec![1, 2, 5]);
+    assert_eq!(storage.load_message(1).await.unwrap().unwrap().content, "message 1");
+    let due: Vec<u64> = storage.due_messages().next_batch(SystemTime::now(), 10).await.unwrap().into_iter().map(|message| message.id).collect();
+    assert_eq!(due, vec![5, 1, 2]);
+
+    assert_eq!(storage.load_in_flight().await.unwrap()[0].id, 4);
+    let mut reasons: Vec<String> = storage.load_dead_letters().await.unwrap().into_iter().map(|dead_letter| dead_letter.reason).collect();
+    reasons.sort();
+    assert_eq!(reasons, vec!["expired", "rejected"]);
+
+    let quarantined = storage.load_quarantined().await.unwrap();
+    assert_eq!(quarantined.len(), 1);
+    assert_eq!((quarantined[0].kind, quarantined[0].message_id), (RecordKind::Pending, 7));
+
+    // The records that were moved are gone from their old keys
+    assert!(backend.load(&1u64.to_be_bytes()).unwrap().is_none());
+    assert!(backend.scan(b"m/").unwrap().is_empty());
+    assert!(backend.scan(b"d/").unwrap().is_empty());
+
+    // Dead letters were indexed for retention
+    storage.set_retention(RetentionPolicy { dead_letter_max_age: Some(Duration::ZERO), ..Default::default() }).unwrap();
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    assert_eq!(storage.apply_retention().unwrap().dead_letters_removed, 2);
+}
+
+#[tokio::test]
+async fn test_migrate_upgrades_encrypted_dead_letters_once_the_key_is_set() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+    let key = queue_key(b'd', 1);
+    let dead_letter = bincode::serialize(&DeadLetterV1 { message: stored_message(1), reason: "rejected".to_string() }).unwrap();
+    let nonce = [3; 12];
+    let sealed = ChaCha20Poly1305::new(&[7; 32].into()).encrypt(Nonce::from_slice(&nonce), Payload { msg: &dead_letter, aad: &key }).unwrap();
+    backend.save(&key, &with_checksum([&[1][..], &1u32.to_be_bytes(), &nonce, &sealed].concat())).unwrap();
+
+    // Without the key the dead letter is left as it is and the format is not recorded
+    let storage = Storage::with_backend(backend.clone());
+    assert_eq!(storage.migrate().unwrap(), 0);
+    assert!(backend.load(b"c/format_version").unwrap().is_none());
+
+    let keyed = storage.with_encryption(Keyring::new(1, [7; 32]));
+    assert_eq!(keyed.migrate().unwrap(), 1);
+    assert_eq!(keyed.load_dead_letters().await.unwrap()[0].reason, "rejected");
+    assert_eq!(backend.load(b"c/format_version").unwrap(), Some(FORMAT_VERSION.to_be_bytes().to_vec()));
+}
+
+#[tokio::test]
+async fn test_migrate_rejects_newer_formats() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+    backend.save(b"c/format_version", &(FORMAT_VERSION + 1).to_be_bytes()).unwrap();
+    assert!(Storage::with_backend(backend).migrate().is_err());
+}
@@ -0,0 +1,329 @@
+mod common;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use hexboltmq::broker::broker::Broker;
+use hexboltmq::cluster::cluster::{Cluster, Node};
+use hexboltmq::producer::message_builder::MessageBuilder;
+use hexboltmq::producer::producer::{BatchConfig, Confirmation, Durability, Producer, ProducerError, ReplicatedRecord};
+use hexboltmq::queue::lazy::LazyQueueConfig;
+use hexboltmq::queue::{Queue, QueueError};
+use hexboltmq::storage::backend::{BatchOp, Entry, StorageBackend, StorageConfig};
+use hexboltmq::storage::memory_backend::MemoryBackend;
+use hexboltmq::storage::storage::Storage;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration, Instant};
+use uuid::Uuid;
+use common::temp_path;
+
+/// Wraps the in-memory backend and fails every write batch once `failing` is set.
+#[derive(Debug, Default)]
+struct FailingBackend {
+    inner: MemoryBackend,
+    failing: AtomicBool,
+}
+
+impl StorageBackend for FailingBackend {
+    fn save(&self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        self.inner.save(key, value)
+    }
+
+    fn load(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.inner.load(key)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), String> {
+        self.inner.delete(key)
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Result<Vec<Entry>, String> {
+        self.inner.scan(prefix)
+    }
+
+    fn scan_range(&self, start: &[u8], end: &[u8], limit: usize) -> Result<Vec<Entry>, String> {
+        self.inner.scan_range(start, end, limit)
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>, sync: bool) -> Result<(), String> {
+        if self.failing.load(Ordering::SeqCst) {
+            return Err("disk full".to_string());
+        }
+        self.inner.write_batch(ops, sync)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.inner.flush()
+    }
+
+    fn checkpoint(&self, dir: &str) -> Result<StorageConfig, String> {
+        self.inner.checkpoint(dir)
+    }
+
+    fn compact(&self) -> Result<(), String> {
+        self.inner.compact()
+    }
+}
+
+#[tokio::test]
+async fn test_send_message_confirms_in_memory() {
+    let queue = Queue::new();
+    let producer = Producer::new(Arc::new(Mutex::new(queue.clone())));
+
+    let confirmation = producer
+        .send_message("hello".to_string(), 1, Duration::from_secs(0))
+        .await
+        .unwrap();
+
+    assert_eq!(confirmation.durability, Durability::InMemory);
+    assert_eq!(queue.pop().await.unwrap().unwrap().id, confirmation.id);
+}
+
+#[tokio::test]
+async fn test_send_message_confirms_fsynced_to_a_lazy_queue() {
+    let storage = Storage::new(&temp_path());
+    let queue = Queue::lazy(storage.queue("orders").unwrap(), LazyQueueConfig::default());
+    let producer = Producer::new(Arc::new(Mutex::new(queue))).with_fsync();
+
+    let confirmation = producer
+        .send_message("durable".to_string(), 1, Duration::from_secs(0))
+        .await
+        .unwrap();
+
+    assert_eq!(confirmation.durability, Durability::Fsynced);
+    let stored = storage.queue("orders").unwrap().load_message(confirmation.id).await.unwrap().unwrap();
+    assert_eq!(stored.content, "durable");
+    // Nothing is written outside the queue's keyspace
+    assert!(storage.load_all_messages().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_messages_sent_to_a_named_queue_are_stored_in_its_keyspace_until_acked() {
+    let storage = Storage::new(&temp_path());
+    let broker = Broker::new();
+    let orders = broker.declare_lazy_queue("orders", &storage, LazyQueueConfig::default()).await.unwrap();
+    let producer = Producer::with_broker(Arc::new(Mutex::new(Queue::new())), broker).with_fsync();
+
+    let confirmation = producer.send(MessageBuilder::new("order created").queue("orders")).await.unwrap();
+    assert_eq!(confirmation.durability, Durability::Fsynced);
+    assert!(storage.load_all_messages().await.unwrap().is_empty());
+
+    let message = orders.pop().await.unwrap().unwrap();
+    orders.acknowledge(message.id).await.unwrap();
+    let scoped = storage.queue("orders").unwrap();
+    assert!(scoped.load_all_messages().await.unwrap().is_empty());
+    assert!(scoped.load_in_flight().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_duplicates_are_confirmed_with_the_original_id_and_not_written() {
+    let storage = Storage::new(&temp_path());
+    let queue = Queue::lazy(storage.queue("orders").unwrap(), LazyQueueConfig::default());
+    let producer = Producer::new(Arc::new(Mutex::new(queue))).with_fsync();
+
+    let original = producer.send(MessageBuilder::new("order created").dedup_id("order-1")).await.unwrap();
+    let duplicate = producer.send(MessageBuilder::new("order created").dedup_id("order-1")).await.unwrap();
+
+    assert_eq!(original.durability, Durability::Fsynced);
+    assert_eq!(duplicate, Confirmation { id: original.id, durability: Durability::Duplicate });
+    let stored = storage.queue("orders").unwrap().load_all_messages().await.unwrap();
+    assert_eq!(stored.iter().map(|message| message.id).collect::<Vec<_>>(), vec![original.id]);
+}
+
+#[tokio::test]
+async fn test_send_message_to_a_regular_queue_stays_in_memory_with_fsync() {
+    let queue = Queue::new();
+    let producer = Producer::new(Arc::new(Mutex::new(queue.clone()))).with_fsync();
+
+    let confirmation = producer
+        .send_message("volatile".to_string(), 1, Duration::from_secs(0))
+        .await
+        .unwrap();
+
+    assert_eq!(confirmation.durability, Durability::InMemory);
+    assert_eq!(queue.pop().await.unwrap().unwrap().id, confirmation.id);
+}
+
+#[tokio::test]
+async fn test_send_message_fails_without_enough_replicas() {
+    let cluster = Cluster::new("127.0.0.1:0".to_string(), 0).await.unwrap();
+    cluster
+        .add_node(Node {
+            id: Uuid::new_v4(),
+            id_generator_node_id: 1,
+            is_leader: false,
+            address: "127.0.0.1:1".to_string(), // Nothing listens here
+            last_heartbeat: Instant::now(),
+        })
+        .await
+        .unwrap();
+
+    let queue = Queue::new();
+    let producer = Producer::new(Arc::new(Mutex::new(queue.clone()))).replicate_to(cluster, 1);
+
+    let result = producer
+        .send_message("replicated".to_string(), 1, Duration::from_secs(0))
+        .await;
+
+    assert!(matches!(result, Err(ProducerError::InsufficientReplicas { required: 1, acknowledged: 0 })));
+    assert_eq!(queue.size().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_failed_push_retracts_the_replicated_messages() {
+    // A replica persisting what it is sent
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    let replica = Cluster::new(address.clone(), 0).await.unwrap().with_replica_storage(Arc::new(MemoryBackend::new())).unwrap();
+    let replica_listener = replica.clone();
+    tokio::spawn(async move { replica_listener.start_listener().await.unwrap() });
+    sleep(Duration::from_millis(50)).await;
+
+    let cluster = Cluster::new("127.0.0.1:0".to_string(), 0).await.unwrap();
+    cluster
+        .add_node(Node { id: Uuid::new_v4(), id_generator_node_id: 1, is_leader: false, address, last_heartbeat: Instant::now() })
+        .await
+        .unwrap();
+
+    let backend = Arc::new(FailingBackend::default());
+    let storage = Storage::with_backend(backend.clone());
+    let queue = Queue::lazy(storage.queue("orders").unwrap(), LazyQueueConfig::default());
+    backend.failing.store(true, Ordering::SeqCst);
+    let producer = Producer::new(Arc::new(Mutex::new(queue))).replicate_to(cluster, 1);
+
+    let result = producer.send_message("lost".to_string(), 1, Duration::from_secs(0)).await;
+    assert!(matches!(result, Err(ProducerError::Queue(QueueError::Storage(_)))));
+
+    let records: Vec<ReplicatedRecord> = replica
+        .take_replicated()
+        .await
+        .unwrap()
+        .iter()
+        .map(|record| bincode::deserialize(record).unwrap())
+        .collect();
+    let [ReplicatedRecord::Publish(published), ReplicatedRecord::Retract(retracted)] = records.as_slice() else {
+        panic!("expected a publish and its retraction, got {:?}", records);
+    };
+    assert_eq!(published.len(), 1);
+    assert_eq!(retracted, &vec![published[0].id]);
+}
+
+#[tokio::test]
+async fn test_pipelined_confirmations_resolve_in_order() {
+    let queue = Queue::new();
+    let producer = Producer::new(Arc::new(Mutex::new(queue.clone())));
+
+    let pending: Vec<_> = (0..100)
+        .map(|i| producer.send_message_pipelined(format!("message {}", i), 1, Duration::from_secs(0)))
+        .collect();
+
+    let mut previous_id = 0;
+    for confirmation in pending {
+        let confirmation = confirmation.await.unwrap();
+        assert!(confirmation.id > previous_id);
+        previous_id = confirmation.id;
+    }
+    assert_eq!(queue.size().await.unwrap(), 100);
+}
+
+#[tokio::test]
+async fn test_batched_messages_confirm_individually() {
+    let storage = Storage::new(&temp_path());
+    let queue = Queue::lazy(storage.queue("telemetry").unwrap(), LazyQueueConfig::default());
+    let producer = Producer::new(Arc::new(Mutex::new(queue)))
+        .with_fsync()
+        .with_batching(BatchConfig {
+            max_messages: 10,
+            max_bytes: 1024,
+            linger: Duration::from_millis(50),
+        });
+
+    let pending: Vec<_> = (0..25)
+        .map(|i| producer.send_message_pipelined(format!("telemetry {}", i), 1, Duration::from_secs(0)))
+        .collect();
+
+    let mut ids = Vec::new();
+    for confirmation in pending {
+        let confirmation = confirmation.await.unwrap();
+        assert_eq!(confirmation.durability, Durability::Fsynced);
+        ids.push(confirmation.id);
+    }
+
+    assert_eq!(storage.queue("telemetry").unwrap().load_all_messages().await.unwrap().len(), 25);
+    assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[tokio::test]
+async fn test_batched_send_message_flushes_after_linger() {
+    let queue = Queue::new();
+    let producer = Producer::new(Arc::new(Mutex::new(queue.clone()))).with_batching(BatchConfig {
+        max_messages: 100,
+        max_bytes: usize::MAX,
+        linger: Duration::from_millis(20),
+    });
+
+    let started = Instant::now();
+    let confirmation = producer
+        .send_message("lonely".to_string(), 1, Duration::from_secs(0))
+        .await
+        .unwrap();
+
+    assert!(started.elapsed() >= Duration::from_millis(20));
+    assert_eq!(queue.pop().await.unwrap().unwrap().id, confirmation.id);
+}
+
+#[tokio::test]
+async fn test_message_builder_options_reach_the_queue() {
+    let broker = Broker::new();
+    let orders = broker.declare_queue("orders").await;
+    let default_queue = Queue::new();
+    let producer = Producer::with_broker(Arc::new(Mutex::new(default_queue.clone())), broker);
+
+    let confirmation = producer
+        .send(
+            MessageBuilder::new("order created")
+                .priority(7)
+                .max_retries(2)
+                .ttl(Duration::from_secs(60))
+                .header("region", "eu")
+                .group_key("order-42")
+                .dedup_id("order-42-created")
+                .queue("orders"),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(default_queue.size().await.unwrap(), 0);
+    let message = orders.pop().await.unwrap().unwrap();
+    assert_eq!(message.id, confirmation.id);
+    assert_eq!(message.priority, 7);
+    assert_eq!(message.max_retries, 2);
+    assert!(message.expires_at.is_some());
+    assert_eq!(message.headers.get("region").map(String::as_str), Some("eu"));
+    assert_eq!(message.group_key.as_deref(), Some("order-42"));
+    assert_eq!(message.dedup_id.as_deref(), Some("order-42-created"));
+}
+
+#[tokio::test]
+async fn test_message_builder_deliver_at_delays_availability() {
+    let queue = Queue::new();
+    let producer = Producer::new(Arc::new(Mutex::new(queue.clone())));
+
+    producer
+        .send(MessageBuilder::new("later").deliver_at(Instant::now() + Duration::from_millis(100)))
+        .await
+        .unwrap();
+
+    assert!(queue.pop().await.unwrap().is_none());
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(queue.pop().await.unwrap().unwrap().content, "later");
+}
+
+#[tokio::test]
+async fn test_send_to_unknown_queue_fails() {
+    let producer = Producer::with_broker(Arc::new(Mutex::new(Queue::new())), Broker::new());
+
+    let result = producer.send(MessageBuilder::new("lost").queue("missing")).await;
+
+    assert!(matches!(result, Err(ProducerError::QueueNotFound(name)) if name == "missing"));
+}
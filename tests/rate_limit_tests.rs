@@ -0,0 +1,151 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use hexboltmq::consumer::consumer::{BatchConsumerConfig, Consumer, WorkerPoolConfig};
+use hexboltmq::consumer::delivery::BatchOutcome;
+use hexboltmq::consumer::shutdown::Shutdown;
+use hexboltmq::producer::producer::Producer;
+use hexboltmq::queue::Queue;
+use hexboltmq::utils::rate_limiter::{RateLimit, RateLimiter};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+async fn fill_queue(queue: &Arc<Mutex<Queue>>, count: usize) {
+    let producer = Producer::new(queue.clone());
+    for i in 0..count {
+        producer
+            .send_message(format!("message {}", i), 1, Duration::from_secs(0))
+            .await
+            .unwrap();
+    }
+}
+
+/// Consumes `count` messages with the given consumers and returns how long it took.
+async fn consume_all(queue: &Arc<Mutex<Queue>>, consumers: Vec<Consumer>, count: usize) -> Duration {
+    let shutdown = Shutdown::new();
+    let config = WorkerPoolConfig {
+        poll_interval: Duration::from_millis(5),
+        ..WorkerPoolConfig::default()
+    };
+
+    let processed = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+    let handles: Vec<_> = consumers
+        .into_iter()
+        .map(|consumer| {
+            let processed = processed.clone();
+            consumer.with_shutdown(shutdown.clone()).spawn(config, move |_delivery| {
+                let processed = processed.clone();
+                async move {
+                    processed.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                }
+            })
+        })
+        .collect();
+
+    timeout(Duration::from_secs(5), async {
+        while processed.load(Ordering::SeqCst) < count {
+            sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .unwrap();
+    let elapsed = started.elapsed();
+    assert_eq!(queue.lock().await.size().await.unwrap(), 0);
+
+    shutdown.trigger();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    elapsed
+}
+
+#[tokio::test]
+async fn test_token_bucket_allows_burst_then_throttles() {
+    let limiter = RateLimiter::new(RateLimit::new(10.0, 2));
+    assert!(limiter.try_acquire().is_ok());
+    assert!(limiter.try_acquire().is_ok());
+
+    let wait = limiter.try_acquire().unwrap_err();
+    assert!(wait > Duration::from_millis(50) && wait <= Duration::from_millis(100));
+    let stats = limiter.stats();
+    assert!(stats.throttled);
+    assert_eq!(stats.throttled_count, 1);
+
+    sleep(Duration::from_millis(110)).await;
+    assert!(limiter.try_acquire().is_ok());
+    assert!(!limiter.stats().throttled);
+}
+
+#[tokio::test]
+async fn test_consumer_rate_limit_throttles_dispatch() {
+    let queue = Arc::new(Mutex::new(Queue::new()));
+    fill_queue(&queue, 6).await;
+
+    // One message right away, then one every 50ms
+    let consumer = Consumer::new(queue.clone()).with_rate_limit(RateLimit::new(20.0, 1));
+    let observer = consumer.clone();
+    let elapsed = consume_all(&queue, vec![consumer], 6).await;
+
+    assert!(elapsed >= Duration::from_millis(240), "took {:?}", elapsed);
+    assert!(observer.rate_limit_stats().unwrap().throttled_count > 0);
+}
+
+#[tokio::test]
+async fn test_queue_rate_limit_is_shared_by_consumers() {
+    let queue = Arc::new(Mutex::new(Queue::new()));
+    fill_queue(&queue, 6).await;
+    queue.lock().await.set_rate_limit(Some(RateLimit::new(20.0, 1))).await;
+
+    let consumers = vec![Consumer::new(queue.clone()), Consumer::new(queue.clone())];
+    let elapsed = consume_all(&queue, consumers, 6).await;
+
+    assert!(elapsed >= Duration::from_millis(240), "took {:?}", elapsed);
+    let stats = queue.lock().await.rate_limit_stats().await.unwrap();
+    assert!(stats.throttled_count > 0);
+}
+
+#[tokio::test]
+async fn test_batch_consumer_takes_a_token_per_message() {
+    let queue = Arc::new(Mutex::new(Queue::new()));
+    fill_queue(&queue, 6).await;
+
+    // Two messages right away, then one every 50ms
+    let shutdown = Shutdown::new();
+    let consumer = Consumer::new(queue.clone()).with_rate_limit(RateLimit::new(20.0, 2)).with_shutdown(shutdown.clone());
+    let config = BatchConsumerConfig {
+        max_size: 4,
+        max_wait: Duration::from_millis(20),
+        poll_interval: Duration::from_millis(5),
+        ..BatchConsumerConfig::default()
+    };
+
+    let batches = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let handler_batches = batches.clone();
+    let started = Instant::now();
+    let handle = tokio::spawn(async move {
+        consumer
+            .consume_batches(config, move |deliveries| {
+                let batches = handler_batches.clone();
+                async move {
+                    batches.lock().unwrap().push(deliveries.len());
+                    Ok(BatchOutcome::new())
+                }
+            })
+            .await
+    });
+
+    timeout(Duration::from_secs(5), async {
+        while batches.lock().unwrap().iter().sum::<usize>() < 6 {
+            sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(190), "took {:?}", started.elapsed());
+    assert_eq!(batches.lock().unwrap()[0], 2);
+    assert!(batches.lock().unwrap().iter().all(|size| *size <= 2));
+
+    shutdown.trigger();
+    handle.await.unwrap();
+}
@@ -0,0 +1,49 @@
+use std::sync::Arc;
+use hexboltmq::broker::broker::Broker;
+use hexboltmq::consumer::consumer::Consumer;
+use hexboltmq::producer::producer::{Producer, ProducerError};
+use hexboltmq::queue::{Queue, CORRELATION_ID_HEADER};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration};
+
+#[tokio::test]
+async fn test_request_receives_matching_reply() {
+    let broker = Broker::new();
+    let requests = broker.declare_queue("rpc").await;
+
+    let producer = Producer::with_broker(Arc::new(Mutex::new(Queue::new())), broker.clone());
+    let consumer = Consumer::with_broker(Arc::new(Mutex::new(requests.clone())), broker.clone());
+
+    // Answer the first request that shows up on the rpc queue
+    let responder = tokio::spawn(async move {
+        loop {
+            if let Some(request) = requests.pop().await.unwrap() {
+                consumer.reply(&request, format!("pong: {}", request.content)).await.unwrap();
+                return request;
+            }
+            sleep(Duration::from_millis(5)).await;
+        }
+    });
+
+    let reply = producer
+        .request("rpc", "ping".to_string(), Duration::from_secs(2))
+        .await
+        .unwrap();
+    let request = responder.await.unwrap();
+
+    assert_eq!(reply.content, "pong: ping");
+    assert_eq!(reply.headers.get(CORRELATION_ID_HEADER), request.headers.get(CORRELATION_ID_HEADER));
+}
+
+#[tokio::test]
+async fn test_request_times_out_without_reply() {
+    let broker = Broker::new();
+    broker.declare_queue("rpc").await;
+
+    let producer = Producer::with_broker(Arc::new(Mutex::new(Queue::new())), broker);
+    let result = producer
+        .request("rpc", "ping".to_string(), Duration::from_millis(50))
+        .await;
+
+    assert!(matches!(result, Err(ProducerError::Timeout)));
+}
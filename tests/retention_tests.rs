@@ -0,0 +1,127 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::SystemTime;
+use hexboltmq::scheduler::scheduler::Scheduler;
+use hexboltmq::storage::backend::{StorageBackend, StorageConfig};
+use hexboltmq::storage::file_log_backend::FileLogBackend;
+use hexboltmq::storage::memory_backend::MemoryBackend;
+use hexboltmq::storage::storage::{RetentionPolicy, RetentionReport, Storage, StorageBatch};
+use tokio::time::{sleep, Duration};
+use common::{stored_message, temp_path};
+
+/// Pushes, delivers and acks messages with the given ids.
+async fn ack_all(storage: &Storage, ids: impl IntoIterator<Item = u64>) {
+    for id in ids {
+        storage.commit(StorageBatch::new().push(&stored_message(id)).unwrap().deliver(&stored_message(id)).unwrap()).await.unwrap();
+        storage.commit(StorageBatch::new().ack(id)).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_acked_messages_are_archived_for_the_retention_period() {
+    let storage = Storage::with_backend(Arc::new(MemoryBackend::new()));
+    let orders = storage.queue("orders").unwrap();
+    ack_all(&orders, [1]).await;
+    assert!(orders.load_archived(SystemTime::UNIX_EPOCH).await.unwrap().is_empty());
+
+    orders.set_retention(RetentionPolicy { keep_acked: Some(Duration::from_millis(200)), ..Default::default() }).unwrap();
+    assert_eq!(storage.queue("orders").unwrap().retention().unwrap().keep_acked, Some(Duration::from_millis(200)));
+    let before = SystemTime::now();
+    ack_all(&orders, [2, 3]).await;
+
+    let archived = orders.load_archived(before).await.unwrap();
+    let ids: Vec<u64> = archived.iter().map(|archived| archived.message.id).collect();
+    assert_eq!(ids, vec![2, 3]);
+    assert_eq!(archived[0].message.content, "message 2");
+    assert!(orders.load_archived(SystemTime::now() + Duration::from_secs(1)).await.unwrap().is_empty());
+    assert!(orders.load_in_flight().await.unwrap().is_empty());
+
+    assert_eq!(storage.apply_retention().unwrap(), RetentionReport::default());
+    sleep(Duration::from_millis(250)).await;
+    assert_eq!(storage.apply_retention().unwrap(), RetentionReport { archived_removed: 2, ..Default::default() });
+    assert!(orders.load_archived(SystemTime::UNIX_EPOCH).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_retention_trims_by_size_oldest_first() {
+    let storage = Storage::with_backend(Arc::new(MemoryBackend::new()));
+    let emails = storage.queue("emails").unwrap();
+    let mut batch = StorageBatch::new();
+    for id in 1..=10 {
+        batch = batch.dead_letter(&stored_message(id), "rejected").unwrap();
+    }
+    emails.commit(batch).await.unwrap();
+    emails.set_retention(RetentionPolicy { keep_acked: Some(Duration::from_secs(3600)), archive_max_bytes: Some(0), ..Default::default() }).unwrap();
+    ack_all(&emails, [11, 12]).await;
+
+    // Dead letters are kept forever by default
+    assert_eq!(storage.apply_retention().unwrap(), RetentionReport { archived_removed: 2, ..Default::default() });
+
+    let size = |count: u64| {
+        let one = storage.backend().scan(b"q/").unwrap().into_iter().filter(|(key, _)| key.len() == 15).map(|(_, value)| value.len() as u64).max().unwrap();
+        one * count
+    };
+    emails.set_retention(RetentionPolicy { dead_letter_max_bytes: Some(size(3)), ..Default::default() }).unwrap();
+    assert_eq!(storage.apply_retention().unwrap().dead_letters_removed, 7);
+    let ids: Vec<u64> = emails.load_dead_letters().await.unwrap().iter().map(|dead_letter| dead_letter.message.id).collect();
+    assert_eq!(ids, vec![8, 9, 10]);
+
+    emails.set_retention(RetentionPolicy { dead_letter_max_age: Some(Duration::ZERO), ..Default::default() }).unwrap();
+    sleep(Duration::from_millis(5)).await;
+    assert_eq!(storage.apply_retention().unwrap().dead_letters_removed, 3);
+}
+
+#[tokio::test]
+async fn test_scheduler_drives_retention_and_compaction() {
+    let dir = temp_path();
+    let backend: Arc<dyn StorageBackend> = Arc::new(FileLogBackend::open(&dir, 64 * 1024).unwrap());
+    let storage = Storage::with_backend(backend);
+    let queue = storage.queue("audit").unwrap();
+    queue.set_retention(RetentionPolicy { keep_acked: Some(Duration::from_millis(50)), ..Default::default() }).unwrap();
+    ack_all(&queue, 1..=200).await;
+    storage.save_message(&stored_message(1000)).await.unwrap();
+
+    let log_size = || std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().metadata().unwrap().len()).sum::<u64>();
+    let before = log_size();
+
+    let scheduler = Scheduler::new(Duration::from_secs(1), Duration::from_millis(20));
+    let retention = scheduler.start_retention(storage.clone());
+    let compaction = scheduler.start_compaction(storage.clone(), Duration::from_millis(150));
+    sleep(Duration::from_millis(400)).await;
+    retention.abort();
+    compaction.abort();
+
+    assert!(queue.load_archived(SystemTime::UNIX_EPOCH).await.unwrap().is_empty());
+    assert!(log_size() < before / 10, "log not compacted: {} -> {} bytes", before, log_size());
+
+    // Only live entries were kept, and they survive a reopen
+    drop(storage);
+    let reopened = Storage::open(&StorageConfig::FileLog { dir, segment_bytes: 64 * 1024 }).unwrap();
+    let ids: Vec<u64> = reopened.load_all_messages().await.unwrap().iter().map(|message| message.id).collect();
+    assert_eq!(ids, vec![1000]);
+    let audit = reopened.queue("audit").unwrap();
+    assert!(audit.load_all_messages().await.unwrap().is_empty());
+
+    // The policy was stored with the queue
+    assert_eq!(audit.retention().unwrap().keep_acked, Some(Duration::from_millis(50)));
+    ack_all(&audit, [2000]).await;
+    assert_eq!(audit.load_archived(SystemTime::UNIX_EPOCH).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_dead_letter_retention_counts_from_the_latest_dead_lettering() {
+    let storage = Storage::with_backend(Arc::new(MemoryBackend::new()));
+    let emails = storage.queue("emails").unwrap();
+    emails.commit(StorageBatch::new().dead_letter(&stored_message(1), "rejected").unwrap()).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    emails.commit(StorageBatch::new().dead_letter(&stored_message(1), "rejected again").unwrap()).await.unwrap();
+
+    emails.set_retention(RetentionPolicy { dead_letter_max_age: Some(Duration::from_millis(50)), ..Default::default() }).unwrap();
+    assert_eq!(storage.apply_retention().unwrap().dead_letters_removed, 0);
+    assert_eq!(emails.load_dead_letters().await.unwrap()[0].reason, "rejected again");
+
+    sleep(Duration::from_millis(60)).await;
+    assert_eq!(storage.apply_retention().unwrap().dead_letters_removed, 1);
+    assert!(emails.load_dead_letters().await.unwrap().is_empty());
+}
@@ -0,0 +1,149 @@
+use std::sync::Arc;
+use hexboltmq::consumer::consumer::{Consumer, WorkerPoolConfig};
+use hexboltmq::consumer::shutdown::Shutdown;
+use hexboltmq::producer::message_builder::MessageBuilder;
+use hexboltmq::producer::producer::Producer;
+use hexboltmq::queue::selector::{Selector, MAX_SELECTOR_DEPTH};
+use hexboltmq::queue::{Queue, MAX_SELECTOR_SKIPS};
+use tokio::sync::Mutex;
+use tokio::time::{timeout, Duration};
+
+#[test]
+fn test_selector_evaluates_headers_priority_and_content() {
+    let eu_urgent = MessageBuilder::new("order 42 created").header("region", "eu").priority(7).build().message;
+    let eu_routine = MessageBuilder::new("order 43 created").header("region", "eu").priority(2).build().message;
+    let untagged = MessageBuilder::new("heartbeat").priority(9).build().message;
+
+    let selector = Selector::parse("region = 'eu' AND priority > 5").unwrap();
+    assert!(selector.matches(&eu_urgent));
+    assert!(!selector.matches(&eu_routine));
+    assert!(!selector.matches(&untagged));
+
+    let selector = Selector::parse("not (region <> 'eu') or content like 'heart%'").unwrap();
+    assert!(selector.matches(&eu_routine));
+    assert!(selector.matches(&untagged));
+
+    let selector = Selector::parse("priority >= 2 AND priority <= 7 AND NOT content LIKE '%42%'").unwrap();
+    assert!(selector.matches(&eu_routine));
+    assert!(!selector.matches(&eu_urgent));
+}
+
+#[test]
+fn test_invalid_selectors_report_position() {
+    let error = Selector::parse("region = ").unwrap_err();
+    assert_eq!(error.position, 9);
+
+    let error = Selector::parse("region = 'eu' AND AND").unwrap_err();
+    assert_eq!(error.position, 18);
+
+    assert!(Selector::parse("region = 'eu").is_err());
+    assert!(Selector::parse("(priority > 5").is_err());
+    assert!(Selector::parse("priority > 5 region").is_err());
+}
+
+#[test]
+fn test_deeply_nested_selectors_are_rejected() {
+    let nots = format!("{}priority > 5", "NOT ".repeat(MAX_SELECTOR_DEPTH));
+    assert!(Selector::parse(&nots).is_ok());
+
+    let error = Selector::parse(&format!("NOT {}", nots)).unwrap_err();
+    assert_eq!(error.position, 4 * MAX_SELECTOR_DEPTH);
+
+    let depth = MAX_SELECTOR_DEPTH + 1;
+    let parens = format!("{}priority > 5{}", "(".repeat(depth), ")".repeat(depth));
+    let error = Selector::parse(&parens).unwrap_err();
+    assert_eq!(error.position, MAX_SELECTOR_DEPTH);
+
+    assert!(Selector::parse(&"NOT ".repeat(100_000)).is_err());
+    assert!(Selector::parse(&"(".repeat(100_000)).is_err());
+}
+
+#[test]
+fn test_like_patterns_with_many_wildcards_match_quickly() {
+    let message = MessageBuilder::new("a".repeat(60)).build().message;
+
+    let selector = Selector::parse(&format!("content LIKE '{}b'", "%a".repeat(20))).unwrap();
+    assert!(!selector.matches(&message));
+
+    let selector = Selector::parse(&format!("content LIKE '{}'", "%%a".repeat(20))).unwrap();
+    assert!(selector.matches(&message));
+
+    let selector = Selector::parse("content LIKE '_a%a_'").unwrap();
+    assert!(selector.matches(&message));
+    assert!(!Selector::parse("content LIKE '%ab%'").unwrap().matches(&message));
+}
+
+#[tokio::test]
+async fn test_pop_matching_skips_a_bounded_number_of_messages() {
+    let queue = Queue::new();
+    let producer = Producer::new(Arc::new(Mutex::new(queue.clone())));
+    for _ in 0..MAX_SELECTOR_SKIPS {
+        producer.send(MessageBuilder::new("us").header("region", "us").priority(9)).await.unwrap();
+    }
+    producer.send(MessageBuilder::new("eu").header("region", "eu").priority(1)).await.unwrap();
+
+    let selector = Selector::parse("region = 'eu'").unwrap();
+    assert!(queue.pop_matching(&selector, 1).await.unwrap().is_empty());
+    assert_eq!(queue.size().await.unwrap(), MAX_SELECTOR_SKIPS + 1);
+
+    queue.pop_batch(1).await.unwrap();
+    let popped = queue.pop_matching(&selector, 1).await.unwrap();
+    assert_eq!(popped.len(), 1);
+    assert_eq!(popped[0].content, "eu");
+}
+
+#[tokio::test]
+async fn test_pop_matching_leaves_other_messages() {
+    let queue = Queue::new();
+    let producer = Producer::new(Arc::new(Mutex::new(queue.clone())));
+    for region in ["eu", "us", "eu", "apac"] {
+        producer.send(MessageBuilder::new(region).header("region", region)).await.unwrap();
+    }
+
+    let selector = Selector::parse("region = 'eu'").unwrap();
+    let popped = queue.pop_matching(&selector, 10).await.unwrap();
+    assert_eq!(popped.len(), 2);
+    assert!(popped.iter().all(|message| message.content == "eu"));
+
+    let remaining: Vec<String> = queue.pop_batch(10).await.unwrap().into_iter().map(|message| message.content).collect();
+    assert_eq!(remaining.len(), 2);
+    assert!(remaining.contains(&"us".to_string()) && remaining.contains(&"apac".to_string()));
+}
+
+#[tokio::test]
+async fn test_selective_consumer_only_receives_matching_messages() {
+    let queue = Arc::new(Mutex::new(Queue::new()));
+    let producer = Producer::new(queue.clone());
+    for priority in [1, 8, 3, 9] {
+        producer.send(MessageBuilder::new(format!("p{}", priority)).priority(priority)).await.unwrap();
+    }
+
+    let shutdown = Shutdown::new();
+    let consumer = Consumer::new(queue.clone())
+        .with_shutdown(shutdown.clone())
+        .with_selector(Selector::parse("priority > 5").unwrap());
+    let config = WorkerPoolConfig {
+        poll_interval: Duration::from_millis(10),
+        ..WorkerPoolConfig::default()
+    };
+
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let handle = consumer.spawn(config, move |delivery| {
+        let sender = sender.clone();
+        async move {
+            sender.send(delivery.content().to_string()).unwrap();
+            Ok(())
+        }
+    });
+
+    let mut received = Vec::new();
+    for _ in 0..2 {
+        received.push(timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap());
+    }
+    shutdown.trigger();
+    handle.await.unwrap();
+
+    received.sort();
+    assert_eq!(received, vec!["p8".to_string(), "p9".to_string()]);
+    assert_eq!(queue.lock().await.size().await.unwrap(), 2);
+}
@@ -0,0 +1,151 @@
+mod common;
+
+use hexboltmq::api::admin::{send_request, AdminApi, AdminRequest, AdminResponse, MAX_REQUEST_BYTES};
+use hexboltmq::broker::broker::Broker;
+use hexboltmq::consumer::shutdown::Shutdown;
+use hexboltmq::queue::lazy::LazyQueueConfig;
+use hexboltmq::queue::Message;
+use hexboltmq::storage::backend::StorageConfig;
+use hexboltmq::storage::storage::Storage;
+use tokio::time::{sleep, timeout, Duration};
+use common::temp_path;
+
+fn message(id: u64) -> Message {
+    Message { max_retries: 0, ..common::message(id, 1, &format!("message {}", id)) }
+}
+
+/// Fills a broker with one pending, one in-flight and one dead-lettered message per queue.
+async fn populate(broker: &Broker, storage: &Storage) {
+    for name in ["orders", "emails"] {
+        let queue = broker.declare_lazy_queue(name, storage, LazyQueueConfig::default()).await.unwrap();
+        let messages = (1..=3).map(|id| (message(id), Duration::ZERO)).collect();
+        queue.push_batch(messages).await.unwrap();
+        let first = queue.pop().await.unwrap().unwrap();
+        queue.retry(first).await.unwrap();
+        queue.pop().await.unwrap().unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_snapshot_restores_on_every_engine() {
+    let sources = [
+        StorageConfig::RocksDb { path: temp_path() },
+        StorageConfig::InMemory,
+        StorageConfig::FileLog { dir: temp_path(), segment_bytes: 512 },
+    ];
+    for source in sources {
+        let storage = Storage::open(&source).unwrap();
+        let broker = Broker::new();
+        populate(&broker, &storage).await;
+
+        let snapshot_dir = temp_path();
+        let manifest = storage.snapshot(&snapshot_dir).unwrap();
+        let names: Vec<String> = manifest.queues.iter().map(|queue| queue.name.clone()).collect();
+        assert_eq!(names, vec!["emails", "orders"]);
+        assert!(storage.snapshot(&snapshot_dir).is_err());
+
+        // Writes after the snapshot are not part of it
+        let orders = broker.get_queue("orders").await.unwrap();
+        orders.pop().await.unwrap().unwrap();
+
+        let admin = AdminApi::restore(&snapshot_dir, &StorageConfig::InMemory, LazyQueueConfig::default()).await.unwrap();
+        let mut queues = admin.broker().queue_names().await;
+        queues.sort();
+        assert_eq!(queues, vec!["emails", "orders"]);
+        for name in ["orders", "emails"] {
+            let restored = admin.storage().queue(name).unwrap();
+            assert_eq!(restored.load_all_messages().await.unwrap().len(), 1, "{:?}", source);
+            assert_eq!(restored.load_in_flight().await.unwrap().len(), 1);
+            assert_eq!(restored.load_dead_letters().await.unwrap().len(), 1);
+        }
+        // The message that was in flight is delivered again along with the pending one
+        let queue = admin.broker().get_queue("orders").await.unwrap();
+        let mut delivered = vec![queue.pop().await.unwrap().unwrap().id, queue.pop().await.unwrap().unwrap().id];
+        delivered.sort();
+        assert_eq!(delivered, vec![2, 3]);
+    }
+}
+
+#[tokio::test]
+async fn test_restore_refuses_a_storage_with_data() {
+    let storage = Storage::open(&StorageConfig::InMemory).unwrap();
+    populate(&Broker::new(), &storage).await;
+    let snapshot_dir = temp_path();
+    storage.snapshot(&snapshot_dir).unwrap();
+
+    let target = StorageConfig::FileLog { dir: temp_path(), segment_bytes: 4096 };
+    Storage::restore(&snapshot_dir, &target).unwrap();
+    assert!(Storage::restore(&snapshot_dir, &target).is_err());
+    assert!(Storage::restore(&temp_path(), &StorageConfig::InMemory).is_err());
+}
+
+#[tokio::test]
+async fn test_admin_api_takes_snapshots() {
+    let storage = Storage::open(&StorageConfig::InMemory).unwrap();
+    let broker = Broker::new();
+    populate(&broker, &storage).await;
+
+    // Find a free port for the admin API
+    let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let snapshot_root = temp_path();
+    let admin = AdminApi::new(broker, storage).with_snapshot_dir(&snapshot_root);
+    let serving_address = address.clone();
+    tokio::spawn(async move { admin.serve(&serving_address).await.unwrap() });
+    sleep(Duration::from_millis(100)).await;
+
+    let response = send_request(&address, &AdminRequest::ListQueues).await.unwrap();
+    assert_eq!(response, AdminResponse::Queues { queues: vec!["emails".to_string(), "orders".to_string()] });
+
+    let response = send_request(&address, &AdminRequest::Snapshot { dir: "today".to_string() }).await.unwrap();
+    assert!(matches!(response, AdminResponse::Snapshot { ref manifest } if manifest.queues.len() == 2));
+    let snapshot_dir = format!("{}/today", snapshot_root);
+    assert_eq!(Storage::read_snapshot_manifest(&snapshot_dir).unwrap().queues.len(), 2);
+
+    let response = send_request(&address, &AdminRequest::Snapshot { dir: "today".to_string() }).await.unwrap();
+    assert!(matches!(response, AdminResponse::Error { .. }));
+
+    // Snapshots cannot be written outside the snapshot directory
+    for dir in [temp_path(), "../escaped".to_string(), "today/../../escaped".to_string(), String::new()] {
+        let response = send_request(&address, &AdminRequest::Snapshot { dir: dir.clone() }).await.unwrap();
+        assert!(matches!(response, AdminResponse::Error { .. }));
+        assert!(!std::path::Path::new(&dir).exists());
+    }
+    assert!(!std::path::Path::new(&snapshot_root).join("../escaped").exists());
+}
+
+#[tokio::test]
+async fn test_admin_api_refuses_snapshots_without_a_snapshot_dir_and_long_requests() {
+    let storage = Storage::open(&StorageConfig::InMemory).unwrap();
+    let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let admin = AdminApi::new(Broker::new(), storage);
+    let serving_address = address.clone();
+    tokio::spawn(async move { admin.serve(&serving_address).await.unwrap() });
+    sleep(Duration::from_millis(100)).await;
+
+    let response = send_request(&address, &AdminRequest::Snapshot { dir: "today".to_string() }).await.unwrap();
+    assert!(matches!(response, AdminResponse::Error { .. }));
+
+    // A request line over the limit is refused
+    let response = send_request(&address, &AdminRequest::Snapshot { dir: "x".repeat(3 * MAX_REQUEST_BYTES) }).await.unwrap();
+    assert!(matches!(response, AdminResponse::Error { ref message } if message.contains("longer than")));
+}
+
+#[tokio::test]
+async fn test_admin_api_stops_serving_on_shutdown() {
+    let storage = Storage::open(&StorageConfig::InMemory).unwrap();
+    let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let shutdown = Shutdown::new();
+    let admin = AdminApi::new(Broker::new(), storage).with_shutdown(shutdown.clone());
+    let serving_address = address.clone();
+    let serving = tokio::spawn(async move { admin.serve(&serving_address).await.unwrap() });
+    sleep(Duration::from_millis(100)).await;
+
+    // An idle connection does not hold up the shutdown
+    let idle = tokio::net::TcpStream::connect(&address).await.unwrap();
+    assert!(send_request(&address, &AdminRequest::ListQueues).await.is_ok());
+
+    shutdown.trigger();
+    timeout(Duration::from_secs(1), serving).await.unwrap().unwrap();
+    assert!(send_request(&address, &AdminRequest::ListQueues).await.is_err());
+    drop(idle);
+}
@@ -0,0 +1,284 @@
+mod common;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use hexboltmq::storage::backend::{BatchOp, StorageBackend, StorageConfig};
+use hexboltmq::storage::file_log_backend::{FileLogBackend, SegmentFile};
+use hexboltmq::storage::storage::{Message, Storage};
+use common::temp_path;
+
+fn configs() -> Vec<StorageConfig> {
+    vec![
+        StorageConfig::RocksDb { path: temp_path() },
+        StorageConfig::InMemory,
+        StorageConfig::FileLog { dir: temp_path(), segment_bytes: 256 },
+    ]
+}
+
+/// Failures to inject into the segment files of a file log.
+#[derive(Debug, Default)]
+struct Faults {
+    write: AtomicBool,      // Writes stop halfway and fail, as when the disk fills up
+    sync: AtomicBool,       // Syncs fail
+    truncate: AtomicBool,   // Truncates fail
+}
+
+/// A segment file that fails as `Faults` says.
+#[derive(Debug)]
+struct FaultySegment {
+    file: File,
+    faults: Arc<Faults>,
+}
+
+impl Write for FaultySegment {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.faults.write.load(Ordering::SeqCst) {
+            self.file.write_all(&buf[..buf.len() / 2])?;
+            return Err(io::Error::other("no space left on device"));
+        }
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl SegmentFile for FaultySegment {
+    fn sync_data(&self) -> io::Result<()> {
+        if self.faults.sync.load(Ordering::SeqCst) {
+            return Err(io::Error::other("sync failed"));
+        }
+        self.file.sync_data()
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        if self.faults.truncate.load(Ordering::SeqCst) {
+            return Err(io::Error::other("truncate failed"));
+        }
+        self.file.set_len(len)
+    }
+}
+
+/// Checks the behaviour every backend must share.
+fn check_conformance(backend: Arc<dyn StorageBackend>) {
+    assert_eq!(backend.load(b"missing").unwrap(), None);
+
+    backend.save(b"a/2", b"two").unwrap();
+    backend.save(b"a/1", b"one").unwrap();
+    backend.save(b"b/1", b"other").unwrap();
+    backend.save(b"a/2", b"TWO").unwrap();
+    assert_eq!(backend.load(b"a/2").unwrap(), Some(b"TWO".to_vec()));
+
+    // Scans are ordered by key and limited to the prefix
+    let scanned = backend.scan(b"a/").unwrap();
+    assert_eq!(scanned, vec![(b"a/1".to_vec(), b"one".to_vec()), (b"a/2".to_vec(), b"TWO".to_vec())]);
+    assert_eq!(backend.scan(b"").unwrap().len(), 3);
+    assert!(backend.scan(b"c/").unwrap().is_empty());
+
+    backend.delete(b"a/1").unwrap();
+    backend.delete(b"a/1").unwrap();
+    assert_eq!(backend.load(b"a/1").unwrap(), None);
+
+    // Later ops in a batch see the effect of earlier ones
+    backend
+        .write_batch(
+            vec![
+                BatchOp::Put(b"c/1".to_vec(), b"x".to_vec()),
+                BatchOp::Delete(b"b/1".to_vec()),
+                BatchOp::Put(b"c/1".to_vec(), b"y".to_vec()),
+                BatchOp::Put(b"c/2".to_vec(), vec![0; 300]),
+            ],
+            true,
+        )
+        .unwrap();
+    assert_eq!(backend.load(b"b/1").unwrap(), None);
+    assert_eq!(backend.load(b"c/1").unwrap(), Some(b"y".to_vec()));
+    assert_eq!(backend.load(b"c/2").unwrap(), Some(vec![0; 300]));
+
+    let keys: Vec<Vec<u8>> = backend.scan(b"").unwrap().into_iter().map(|(key, _)| key).collect();
+    assert_eq!(keys, vec![b"a/2".to_vec(), b"c/1".to_vec(), b"c/2".to_vec()]);
+
+    let page = backend.scan_range(b"a/", b"c/2", 2).unwrap();
+    assert_eq!(page, vec![(b"a/2".to_vec(), b"TWO".to_vec()), (b"c/1".to_vec(), b"y".to_vec())]);
+    assert_eq!(backend.scan_range(b"c/", b"c/2", 10).unwrap().len(), 1);
+
+    // Range deletes cover `[start, end)`
+    backend.save(b"c/3", b"z").unwrap();
+    backend.write_batch(vec![BatchOp::DeleteRange(b"c/".to_vec(), b"c/3".to_vec())], false).unwrap();
+    let keys: Vec<Vec<u8>> = backend.scan(b"").unwrap().into_iter().map(|(key, _)| key).collect();
+    assert_eq!(keys, vec![b"a/2".to_vec(), b"c/3".to_vec()]);
+}
+
+#[test]
+fn test_backends_pass_conformance_suite() {
+    for config in configs() {
+        println!("Checking {:?}", config);
+        check_conformance(config.open().unwrap());
+    }
+}
+
+#[tokio::test]
+async fn test_storage_works_on_every_backend() {
+    for config in configs() {
+        let storage = Storage::open(&config).unwrap();
+        let messages: Vec<Message> = (1..=3)
+            .map(|id| Message { id, content: format!("message {}", id), priority: 1, retry_count: 0, max_retries: 3, ..Default::default() })
+            .collect();
+
+        storage.save_messages_synced(&messages).await.unwrap();
+        storage.delete_message(2).await.unwrap();
+
+        let loaded: Vec<u64> = storage.load_all_messages().await.unwrap().iter().map(|message| message.id).collect();
+        assert_eq!(loaded, vec![1, 3]);
+        assert_eq!(storage.load_message(3).await.unwrap().unwrap().content, "message 3");
+    }
+}
+
+#[test]
+fn test_storage_config_deserializes() {
+    let config: StorageConfig = serde_json::from_str(r#"{ "engine": "file_log", "dir": "/var/lib/hexbolt" }"#).unwrap();
+    assert!(matches!(config, StorageConfig::FileLog { ref dir, .. } if dir == "/var/lib/hexbolt"));
+
+    let config: StorageConfig = serde_json::from_str(r#"{ "engine": "in_memory" }"#).unwrap();
+    assert_eq!(config, StorageConfig::InMemory);
+}
+
+#[tokio::test]
+async fn test_storage_config_is_detected_from_the_data_directory() {
+    let dir = temp_path();
+    assert!(StorageConfig::detect(&dir).is_err());
+    std::fs::create_dir_all(&dir).unwrap();
+    assert!(StorageConfig::detect(&dir).is_err());
+
+    let storage = Storage::open(&StorageConfig::FileLog { dir: dir.clone(), segment_bytes: 1024 }).unwrap();
+    storage.save_message(&Message { id: 1, content: "message 1".to_string(), ..Default::default() }).await.unwrap();
+    drop(storage);
+    let config = StorageConfig::detect(&dir).unwrap();
+    assert!(matches!(config, StorageConfig::FileLog { dir: ref detected, .. } if *detected == dir));
+    assert_eq!(Storage::open(&config).unwrap().verify(false).unwrap().scanned, 1);
+
+    let rocksdb = temp_path();
+    std::fs::create_dir_all(&rocksdb).unwrap();
+    std::fs::write(std::path::Path::new(&rocksdb).join("CURRENT"), "MANIFEST-000001\n").unwrap();
+    assert_eq!(StorageConfig::detect(&rocksdb).unwrap(), StorageConfig::RocksDb { path: rocksdb.clone() });
+}
+
+#[test]
+fn test_file_log_survives_reopen_and_discards_torn_writes() {
+    let dir = temp_path();
+    {
+        let backend = FileLogBackend::open(&dir, 128).unwrap();
+        for i in 0..20u32 {
+            backend.save(&i.to_be_bytes(), format!("value {}", i).as_bytes()).unwrap();
+        }
+        backend.delete(&3u32.to_be_bytes()).unwrap();
+    }
+
+    // Rollover produced several segments; simulate a crash halfway through appending a frame
+    let mut segments: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+    segments.sort();
+    assert!(segments.len() > 1);
+    let mut last = OpenOptions::new().append(true).open(segments.last().unwrap()).unwrap();
+    last.write_all(&[0, 0, 0, 40, 0, 0]).unwrap();
+    drop(last);
+
+    let backend = FileLogBackend::open(&dir, 128).unwrap();
+    assert_eq!(backend.scan(b"").unwrap().len(), 19);
+    assert_eq!(backend.load(&3u32.to_be_bytes()).unwrap(), None);
+    assert_eq!(backend.load(&19u32.to_be_bytes()).unwrap(), Some(b"value 19".to_vec()));
+
+    // Writes after recovery land after the discarded bytes and are readable after another reopen
+    backend.save(b"after", b"crash").unwrap();
+    drop(backend);
+    let backend = FileLogBackend::open(&dir, 128).unwrap();
+    assert_eq!(backend.load(b"after").unwrap(), Some(b"crash".to_vec()));
+    assert_eq!(backend.scan(b"").unwrap().len(), 20);
+}
+
+#[test]
+fn test_file_log_compaction_keeps_writes_made_while_it_runs() {
+    let dir = temp_path();
+    let backend = Arc::new(FileLogBackend::open(&dir, 4096).unwrap());
+    for round in 0..10u32 {
+        for i in 0..500u32 {
+            backend.save(&i.to_be_bytes(), format!("value {} {}", i, round).as_bytes()).unwrap();
+        }
+    }
+
+    let writer = {
+        let backend = backend.clone();
+        std::thread::spawn(move || {
+            for i in 0..500u32 {
+                if i % 2 == 0 {
+                    backend.save(&i.to_be_bytes(), format!("new value {}", i).as_bytes()).unwrap();
+                } else {
+                    backend.delete(&i.to_be_bytes()).unwrap();
+                }
+            }
+        })
+    };
+    backend.compact().unwrap();
+    writer.join().unwrap();
+
+    let check = |backend: &FileLogBackend| {
+        let entries = backend.scan(b"").unwrap();
+        assert_eq!(entries.len(), 250);
+        for (key, value) in entries {
+            let i = u32::from_be_bytes(key.try_into().unwrap());
+            assert_eq!(value, format!("new value {}", i).into_bytes());
+        }
+    };
+    check(&backend);
+    drop(backend);
+    check(&FileLogBackend::open(&dir, 4096).unwrap());
+}
+
+#[test]
+fn test_file_log_undoes_failed_writes() {
+    let dir = temp_path();
+    let faults = Arc::new(Faults::default());
+    let opener_faults = faults.clone();
+    let backend = FileLogBackend::open_with(
+        &dir,
+        4096,
+        Arc::new(move |path: &Path| {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            Ok(Box::new(FaultySegment { file, faults: opener_faults.clone() }) as Box<dyn SegmentFile>)
+        }),
+    )
+    .unwrap();
+    backend.save(b"a", b"one").unwrap();
+
+    // Neither a torn write nor a frame whose sync failed is left in the way of the next write
+    faults.write.store(true, Ordering::SeqCst);
+    assert!(backend.save(b"b", b"lost").is_err());
+    faults.write.store(false, Ordering::SeqCst);
+    backend.save(b"c", b"three").unwrap();
+    faults.sync.store(true, Ordering::SeqCst);
+    assert!(backend.write_batch(vec![BatchOp::Put(b"d".to_vec(), b"lost".to_vec())], true).is_err());
+    faults.sync.store(false, Ordering::SeqCst);
+    backend.save(b"e", b"five").unwrap();
+
+    let expected = vec![(b"a".to_vec(), b"one".to_vec()), (b"c".to_vec(), b"three".to_vec()), (b"e".to_vec(), b"five".to_vec())];
+    assert_eq!(backend.scan(b"").unwrap(), expected);
+
+    // A failed write that cannot be cut off stops all later writes
+    faults.write.store(true, Ordering::SeqCst);
+    faults.truncate.store(true, Ordering::SeqCst);
+    assert!(backend.save(b"f", b"lost").is_err());
+    faults.write.store(false, Ordering::SeqCst);
+    faults.truncate.store(false, Ordering::SeqCst);
+    assert!(backend.save(b"g", b"refused").is_err());
+    assert_eq!(backend.scan(b"").unwrap(), expected);
+    drop(backend);
+
+    // Reopening discards the torn frame
+    let backend = FileLogBackend::open(&dir, 4096).unwrap();
+    assert_eq!(backend.scan(b"").unwrap(), expected);
+    backend.save(b"g", b"seven").unwrap();
+    assert_eq!(backend.load(b"g").unwrap(), Some(b"seven".to_vec()));
+}
@@ -0,0 +1,129 @@
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use hexboltmq::storage::backend::{BatchOp, Entry, StorageBackend, StorageConfig};
+use hexboltmq::storage::memory_backend::MemoryBackend;
+use hexboltmq::storage::storage::{Storage, StorageBatch, SyncPolicy};
+use common::stored_message as message;
+use tokio::time::{sleep, Duration};
+
+/// Wraps the in-memory backend and counts write batches and syncs.
+#[derive(Debug, Default)]
+struct CountingBackend {
+    inner: MemoryBackend,
+    batches: Mutex<Vec<(usize, bool)>>,   // Size and sync flag of every write batch
+    flushes: AtomicUsize,
+}
+
+impl StorageBackend for CountingBackend {
+    fn save(&self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        self.inner.save(key, value)
+    }
+
+    fn load(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.inner.load(key)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), String> {
+        self.inner.delete(key)
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Result<Vec<Entry>, String> {
+        self.inner.scan(prefix)
+    }
+
+    fn scan_range(&self, start: &[u8], end: &[u8], limit: usize) -> Result<Vec<Entry>, String> {
+        self.inner.scan_range(start, end, limit)
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>, sync: bool) -> Result<(), String> {
+        if sync {
+            // Make the fsync slow enough for other writers to queue up behind it
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        self.batches.lock().unwrap().push((ops.len(), sync));
+        self.inner.write_batch(ops, sync)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn checkpoint(&self, dir: &str) -> Result<StorageConfig, String> {
+        self.inner.checkpoint(dir)
+    }
+
+    fn compact(&self) -> Result<(), String> {
+        self.inner.compact()
+    }
+}
+
+#[tokio::test]
+async fn test_batch_pushes_acks_and_dead_letters_together() {
+    let backend = Arc::new(CountingBackend::default());
+    let storage = Storage::with_backend(backend.clone());
+    storage.save_messages_synced(&[message(1), message(2)]).await.unwrap();
+
+    let batch = StorageBatch::new()
+        .push(&message(3))
+        .unwrap()
+        .ack(1)
+        .dead_letter(&message(2), "max retries exceeded")
+        .unwrap();
+    assert_eq!(batch.len(), 3);
+    storage.commit(batch).await.unwrap();
+
+    // One backend write for the whole batch
+    assert_eq!(backend.batches.lock().unwrap().len(), 2);
+    assert!(!backend.batches.lock().unwrap()[1].1);
+    let pending: Vec<u64> = storage.load_all_messages().await.unwrap().iter().map(|message| message.id).collect();
+    assert_eq!(pending, vec![3]);
+
+    let dead_letters = storage.load_dead_letters().await.unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].message.id, 2);
+    assert_eq!(dead_letters[0].reason, "max retries exceeded");
+}
+
+#[tokio::test]
+async fn test_concurrent_synced_writers_share_fsyncs() {
+    let backend = Arc::new(CountingBackend::default());
+    let storage = Storage::with_backend(backend.clone()).with_sync_policy(SyncPolicy::EveryWrite);
+
+    let writers: Vec<_> = (1..=20)
+        .map(|id| {
+            let storage = storage.clone();
+            tokio::spawn(async move { storage.save_message(&message(id)).await })
+        })
+        .collect();
+    for writer in writers {
+        writer.await.unwrap().unwrap();
+    }
+
+    assert_eq!(storage.load_all_messages().await.unwrap().len(), 20);
+    let batches = backend.batches.lock().unwrap();
+    assert!(batches.iter().all(|(_, sync)| *sync));
+    // Every push writes its pending record and due index entry and clears any in-flight record
+    assert_eq!(batches.iter().map(|(len, _)| len).sum::<usize>(), 60);
+    assert!(batches.len() < 20, "expected writes to be grouped, got {} fsyncs", batches.len());
+}
+
+#[tokio::test]
+async fn test_sync_policies() {
+    let backend = Arc::new(CountingBackend::default());
+    let storage = Storage::with_backend(backend.clone());
+    storage.save_message(&message(1)).await.unwrap();
+    assert_eq!(backend.batches.lock().unwrap().last(), Some(&(3, false)));
+
+    let storage = storage.with_sync_policy(SyncPolicy::Interval(Duration::from_millis(10)));
+    storage.save_message(&message(2)).await.unwrap();
+    assert_eq!(backend.batches.lock().unwrap().last(), Some(&(3, false)));
+    sleep(Duration::from_millis(100)).await;
+    assert!(backend.flushes.load(Ordering::SeqCst) >= 2);
+
+    // Synced saves are fsynced whatever the policy
+    storage.save_messages_synced(&[message(3)]).await.unwrap();
+    assert_eq!(backend.batches.lock().unwrap().last(), Some(&(3, true)));
+}
@@ -0,0 +1,137 @@
+mod common;
+
+use hexboltmq::storage::backend::StorageBackend;
+use hexboltmq::storage::file_log_backend::FileLogBackend;
+use hexboltmq::storage::memory_backend::MemoryBackend;
+use hexboltmq::storage::storage::{Message, Storage, StorageBatch, DEFAULT_QUEUE};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use common::{stored_message as message, temp_path};
+
+fn ids(messages: &[Message]) -> Vec<u64> {
+    messages.iter().map(|message| message.id).collect()
+}
+
+#[tokio::test]
+async fn test_queues_have_separate_keyspaces() {
+    let storage = Storage::with_backend(Arc::new(MemoryBackend::new()));
+    let orders = storage.queue("orders").unwrap();
+    let emails = storage.queue("emails").unwrap();
+
+    orders.save_messages_synced(&[message(1), message(2)]).await.unwrap();
+    emails.save_message(&message(1)).await.unwrap();
+    storage.save_message(&message(7)).await.unwrap();
+    orders
+        .commit(StorageBatch::new().deliver(&message(1)).unwrap().dead_letter(&message(2), "bad").unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(orders.queue_name(), "orders");
+    assert_eq!(ids(&orders.load_all_messages().await.unwrap()), Vec::<u64>::new());
+    assert_eq!(ids(&orders.load_in_flight().await.unwrap()), vec![1]);
+    assert_eq!(orders.load_dead_letters().await.unwrap().len(), 1);
+    assert_eq!(ids(&emails.load_all_messages().await.unwrap()), vec![1]);
+    assert!(emails.load_in_flight().await.unwrap().is_empty());
+    assert_eq!(ids(&storage.load_all_messages().await.unwrap()), vec![7]);
+
+    // Scoping again finds the same keyspace
+    let again = storage.queue("orders").unwrap();
+    assert_eq!(ids(&again.load_in_flight().await.unwrap()), vec![1]);
+    let names: Vec<String> = storage.queues().unwrap().into_iter().map(|queue| queue.name).collect();
+    assert_eq!(names, vec!["emails", "orders"]);
+    assert_eq!(storage.queue(DEFAULT_QUEUE).unwrap().queue_name(), DEFAULT_QUEUE);
+}
+
+#[tokio::test]
+async fn test_acked_and_requeued_messages_leave_in_flight() {
+    let storage = Storage::with_backend(Arc::new(MemoryBackend::new())).queue("jobs").unwrap();
+    storage.save_messages_synced(&[message(1), message(2)]).await.unwrap();
+    storage
+        .commit(StorageBatch::new().deliver(&message(1)).unwrap().deliver(&message(2)).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(ids(&storage.load_in_flight().await.unwrap()), vec![1, 2]);
+
+    storage.commit(StorageBatch::new().ack(1).push(&message(2)).unwrap()).await.unwrap();
+    assert!(storage.load_in_flight().await.unwrap().is_empty());
+    assert_eq!(ids(&storage.load_all_messages().await.unwrap()), vec![2]);
+}
+
+#[tokio::test]
+async fn test_drop_queue_removes_only_that_queue() {
+    let dir = temp_path();
+    {
+        let storage = Storage::with_backend(Arc::new(FileLogBackend::open(&dir, 1024).unwrap()));
+        let orders = storage.queue("orders").unwrap();
+        let emails = storage.queue("emails").unwrap();
+        let messages: Vec<Message> = (1..=50).map(message).collect();
+        orders.save_messages_synced(&messages).await.unwrap();
+        emails.save_messages_synced(&messages[..3]).await.unwrap();
+
+        assert!(storage.drop_queue("orders").unwrap());
+        assert!(!storage.drop_queue("orders").unwrap());
+        assert!(!storage.drop_queue("missing").unwrap());
+    }
+
+    // The drop survives a restart, and each remaining queue recovers on its own
+    let backend = Arc::new(FileLogBackend::open(&dir, 1024).unwrap());
+    let storage = Storage::with_backend(backend.clone());
+    let queues = storage.queues().unwrap();
+    assert_eq!(queues.len(), 1);
+    assert_eq!(queues[0].name, "emails");
+    assert_eq!(storage.queue("emails").unwrap().load_all_messages().await.unwrap().len(), 3);
+
+    // A recreated queue starts empty in a fresh keyspace
+    let orders = storage.queue("orders").unwrap();
+    assert!(orders.load_all_messages().await.unwrap().is_empty());
+    assert_eq!(orders.queues().unwrap().iter().find(|queue| queue.name == "orders").unwrap().id, 3);
+    // Only the three emails are left: their pending records and due index entries
+    assert_eq!(backend.scan(b"q/").unwrap().len(), 6);
+}
+
+#[tokio::test]
+async fn test_due_messages_stream_in_delivery_order() {
+    let backend = Arc::new(MemoryBackend::new());
+    let storage = Storage::with_backend(backend.clone()).queue("jobs").unwrap();
+    let now = SystemTime::now();
+    let prioritized = |id, priority| Message { priority, ..message(id) };
+
+    let batch = StorageBatch::new()
+        .push_at(&prioritized(1, 1), now - Duration::from_secs(10))
+        .unwrap()
+        .push_at(&prioritized(2, 1), now - Duration::from_secs(5))
+        .unwrap()
+        .push_at(&prioritized(3, 9), now - Duration::from_secs(5))
+        .unwrap()
+        .push_at(&prioritized(4, 9), now + Duration::from_secs(60))
+        .unwrap()
+        .push_at(&prioritized(5, 1), now - Duration::from_secs(1))
+        .unwrap()
+        .ack(1)
+        .deliver(&prioritized(5, 1))
+        .unwrap();
+    storage.commit(batch).await.unwrap();
+
+    // The index entries of the acked and delivered messages were deleted with them
+    let index_entries = || backend.scan(b"").unwrap().into_iter().filter(|(key, value)| key.len() == 24 && value.is_empty()).count();
+    assert_eq!(index_entries(), 3);
+
+    // Higher priority first among messages available at the same time; acked, delivered and
+    // future messages are skipped
+    let mut due = storage.due_messages();
+    assert_eq!(ids(&due.next_batch(now, 1).await.unwrap()), vec![3]);
+    assert_eq!(ids(&due.next_batch(now, 10).await.unwrap()), vec![2]);
+    assert!(due.next_batch(now, 10).await.unwrap().is_empty());
+
+    // Also when the message was pushed in an earlier batch
+    storage.commit(StorageBatch::new().deliver(&prioritized(3, 9)).unwrap().push(&prioritized(3, 9)).unwrap()).await.unwrap();
+    storage.commit(StorageBatch::new().dead_letter(&prioritized(2, 1), "bad").unwrap()).await.unwrap();
+    assert_eq!(index_entries(), 2);
+    storage.commit(StorageBatch::new().push(&prioritized(2, 1)).unwrap()).await.unwrap();
+
+    // A requeued message is found again after rewinding, and a delayed one once it is due
+    storage.commit(StorageBatch::new().push(&prioritized(5, 1)).unwrap()).await.unwrap();
+    due.rewind();
+    let later = now + Duration::from_secs(61);
+    assert_eq!(ids(&due.next_batch(later, 10).await.unwrap()), vec![3, 2, 5, 4]);
+}
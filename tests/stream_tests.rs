@@ -0,0 +1,138 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::SystemTime;
+use hexboltmq::broker::broker::Broker;
+use hexboltmq::queue::lazy::LazyQueueConfig;
+use hexboltmq::queue::stream::Stream;
+use hexboltmq::queue::{Message, QueueError};
+use hexboltmq::storage::memory_backend::MemoryBackend;
+use hexboltmq::storage::storage::{RetentionPolicy, Storage, StreamOffsets};
+use tokio::time::{sleep, Duration};
+
+fn message(id: u64) -> Message {
+    common::message(id, 1, &format!("event {}", id))
+}
+
+fn contents(entries: &[hexboltmq::queue::stream::StreamEntry]) -> Vec<(u64, String)> {
+    entries.iter().map(|entry| (entry.offset, entry.message.content.clone())).collect()
+}
+
+#[tokio::test]
+async fn test_consumer_groups_read_independently_and_resume_from_committed_offsets() {
+    let storage = Storage::with_backend(Arc::new(MemoryBackend::new()));
+    let broker = Broker::new();
+    let stream = broker.declare_stream("events", &storage).await.unwrap();
+    assert_eq!(stream.append(message(1)).await.unwrap(), 0);
+    assert_eq!(stream.append_batch(vec![message(2), message(3)]).await.unwrap(), 1);
+    assert_eq!(stream.offsets().unwrap(), StreamOffsets { earliest: 0, next: 3 });
+
+    let mut billing = stream.reader("billing").await.unwrap();
+    let mut audit = stream.reader("audit").await.unwrap();
+    let read = billing.poll(2).await.unwrap();
+    assert_eq!(contents(&read), vec![(0, "event 1".to_string()), (1, "event 2".to_string())]);
+    assert_eq!(billing.position(), 2);
+    billing.commit().await.unwrap();
+
+    // Consuming removes nothing: another group sees every entry
+    assert_eq!(audit.poll(10).await.unwrap().len(), 3);
+    assert!(audit.poll(10).await.unwrap().is_empty());
+    stream.append(message(4)).await.unwrap();
+    assert_eq!(contents(&audit.poll(10).await.unwrap()), vec![(3, "event 4".to_string())]);
+
+    // After a restart the stream is recovered as a stream, and the group resumes where it committed
+    let recovered = Broker::recover(&storage, LazyQueueConfig::default()).await.unwrap();
+    assert!(recovered.get_queue("events").await.is_none());
+    let stream = recovered.get_stream("events").await.unwrap();
+    let mut billing = stream.reader("billing").await.unwrap();
+    assert_eq!(billing.position(), 2);
+    let offsets: Vec<u64> = billing.poll(10).await.unwrap().iter().map(|entry| entry.offset).collect();
+    assert_eq!(offsets, vec![2, 3]);
+    assert_eq!(stream.offsets().unwrap().next, 4);
+}
+
+#[tokio::test]
+async fn test_readers_seek_by_offset_and_time() {
+    let storage = Storage::with_backend(Arc::new(MemoryBackend::new()));
+    let stream = Stream::open(&storage, "clicks").unwrap();
+    stream.append_batch(vec![message(1), message(2)]).await.unwrap();
+    sleep(Duration::from_millis(20)).await;
+    let midpoint = SystemTime::now();
+    sleep(Duration::from_millis(20)).await;
+    stream.append_batch(vec![message(3), message(4)]).await.unwrap();
+
+    let mut reader = stream.reader("replay").await.unwrap();
+    assert_eq!(reader.seek_to_time(midpoint).await.unwrap(), 2);
+    assert_eq!(contents(&reader.poll(10).await.unwrap()), vec![(2, "event 3".to_string()), (3, "event 4".to_string())]);
+    let entries = stream.read(0, 10).await.unwrap();
+    assert!(entries[1].appended_at < midpoint && entries[2].appended_at >= midpoint);
+
+    reader.seek(1);
+    assert_eq!(reader.poll(1).await.unwrap()[0].offset, 1);
+    assert_eq!(reader.seek_to_time(SystemTime::now() + Duration::from_secs(60)).await.unwrap(), 4);
+    assert_eq!(reader.seek_to_time(SystemTime::UNIX_EPOCH).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_stream_retention_removes_the_oldest_entries() {
+    let storage = Storage::with_backend(Arc::new(MemoryBackend::new()));
+    let stream = Stream::open(&storage, "metrics").unwrap();
+    let lagging = stream.reader("lagging").await.unwrap();
+    lagging.commit().await.unwrap();
+    for id in 1..=10 {
+        stream.append(message(id)).await.unwrap();
+    }
+
+    let entry_bytes = storage.queue("metrics").unwrap().backend().scan(b"q/").unwrap().iter().map(|(_, value)| value.len() as u64).max().unwrap();
+    stream.storage().set_retention(RetentionPolicy { stream_max_bytes: Some(entry_bytes * 4), ..Default::default() }).unwrap();
+    assert_eq!(storage.apply_retention().unwrap().stream_entries_removed, 6);
+    assert_eq!(stream.offsets().unwrap(), StreamOffsets { earliest: 6, next: 10 });
+
+    // A group behind retention continues from the oldest entry left
+    let mut lagging = stream.reader("lagging").await.unwrap();
+    assert_eq!(lagging.position(), 0);
+    assert_eq!(lagging.poll(1).await.unwrap()[0].offset, 6);
+    assert_eq!(lagging.seek_to_time(SystemTime::UNIX_EPOCH).await.unwrap(), 6);
+
+    stream.storage().set_retention(RetentionPolicy { stream_max_age: Some(Duration::ZERO), ..Default::default() }).unwrap();
+    sleep(Duration::from_millis(5)).await;
+    assert_eq!(storage.apply_retention().unwrap().stream_entries_removed, 4);
+    assert_eq!(stream.offsets().unwrap(), StreamOffsets { earliest: 10, next: 10 });
+    assert_eq!(stream.append(message(11)).await.unwrap(), 10);
+}
+
+#[tokio::test]
+async fn test_stream_age_retention_keeps_newer_entries() {
+    let storage = Storage::with_backend(Arc::new(MemoryBackend::new()));
+    let stream = Stream::open(&storage, "metrics").unwrap();
+    stream.append_batch(vec![message(1), message(2), message(3)]).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    stream.append_batch(vec![message(4), message(5)]).await.unwrap();
+
+    stream.storage().set_retention(RetentionPolicy { stream_max_age: Some(Duration::from_millis(50)), ..Default::default() }).unwrap();
+    assert_eq!(storage.apply_retention().unwrap().stream_entries_removed, 3);
+    assert_eq!(stream.offsets().unwrap(), StreamOffsets { earliest: 3, next: 5 });
+    assert_eq!(storage.apply_retention().unwrap().stream_entries_removed, 0);
+
+    // The time index entries of the removed entries are gone too
+    let mut reader = stream.reader("replay").await.unwrap();
+    assert_eq!(reader.seek_to_time(SystemTime::UNIX_EPOCH).await.unwrap(), 3);
+    assert_eq!(contents(&reader.poll(10).await.unwrap()), vec![(3, "event 4".to_string()), (4, "event 5".to_string())]);
+}
+
+#[tokio::test]
+async fn test_queue_and_stream_names_do_not_clash() {
+    let storage = Storage::with_backend(Arc::new(MemoryBackend::new()));
+    let broker = Broker::new();
+    let queue = broker.declare_lazy_queue("orders", &storage, LazyQueueConfig::default()).await.unwrap();
+    queue.push(message(1), Duration::ZERO).await.unwrap();
+    broker.declare_stream("events", &storage).await.unwrap();
+
+    assert!(matches!(broker.declare_stream("orders", &storage).await, Err(QueueError::NameInUse(_))));
+    assert!(matches!(broker.declare_lazy_queue("events", &storage, LazyQueueConfig::default()).await, Err(QueueError::NameInUse(_))));
+
+    // The names are taken in storage as well, not only on this broker
+    let other = Broker::new();
+    assert!(matches!(other.declare_lazy_queue("events", &storage, LazyQueueConfig::default()).await, Err(QueueError::NameInUse(_))));
+    assert!(Stream::open(&storage, "orders").is_err());
+}